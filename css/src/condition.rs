@@ -0,0 +1,114 @@
+//! Reading the conditions of `@media` and `@supports` rules, which are both made of `not`, `and`
+//! and `or` joining parenthesised blocks
+
+use crate::tokenizer::Token;
+use std::ops::Range;
+
+/// A token, or a token along with where it is in the input
+pub(crate) trait AsToken<'a> {
+    fn token(&self) -> &Token<'a>;
+}
+
+impl<'a> AsToken<'a> for Token<'a> {
+    fn token(&self) -> &Token<'a> {
+        self
+    }
+}
+
+impl<'a> AsToken<'a> for (Token<'a>, Range<usize>) {
+    fn token(&self) -> &Token<'a> {
+        &self.0
+    }
+}
+
+/// The lowercased name of an identifier token
+pub(crate) fn ident<'a>(token: Option<&Token<'a>>) -> Option<String> {
+    match token {
+        Some(Token::Ident(name)) => Some(name.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// Steps through a slice of tokens. Conditions are read from tokens without whitespace
+pub(crate) struct Tokens<'t, T> {
+    tokens: &'t [T],
+    pos: usize,
+}
+
+impl<'t, 'a: 't, T: AsToken<'a>> Tokens<'t, T> {
+    pub fn new(tokens: &'t [T]) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    /// The tokens which haven't been moved past
+    pub fn rest(&self) -> &'t [T] {
+        &self.tokens[self.pos.min(self.tokens.len())..]
+    }
+
+    /// The next token, without moving past it
+    pub fn peek(&self) -> Option<&'t Token<'a>> {
+        self.tokens.get(self.pos).map(AsToken::token)
+    }
+
+    /// Move past the next token, returning it
+    pub fn bump(&mut self) -> Option<&'t Token<'a>> {
+        let token = self.peek()?;
+        self.pos += 1;
+        Some(token)
+    }
+
+    /// Move past the next token if it's `token`
+    pub fn eat(&mut self, token: &Token) -> bool {
+        let matches = self.peek() == Some(token);
+        if matches {
+            self.pos += 1;
+        }
+        matches
+    }
+
+    /// Move past any whitespace, returning whether there was some
+    pub fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.eat(&Token::Whitespace) {}
+        self.pos > start
+    }
+
+    /// The lowercased name of the next token, if it's an identifier
+    pub fn ident(&self) -> Option<String> {
+        ident(self.peek())
+    }
+
+    /// Move past the next token if it's the identifier `name`
+    pub fn eat_ident(&mut self, name: &str) -> bool {
+        let matches = self.ident().as_deref() == Some(name);
+        if matches {
+            self.pos += 1;
+        }
+        matches
+    }
+
+    /// Skip over a parenthesised block or function, returning the tokens inside of it. A
+    /// block which isn't closed runs to the end
+    pub fn block(&mut self) -> &'t [T] {
+        let start = self.pos + 1;
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            self.pos += 1;
+            match token {
+                Token::OpenParen | Token::Function(_) => depth += 1,
+                Token::CloseParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return &self.tokens[start..self.pos - 1];
+                    }
+                }
+                _ => {}
+            }
+        }
+        &self.tokens[start..]
+    }
+}
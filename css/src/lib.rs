@@ -1,6 +1,213 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Stylesheet<'a> {
-    pub rules: Vec<Ruleset<'a>>,
+    // The stylesheets brought in with `@import`, whose rules come before the stylesheet's own
+    pub imports: Vec<Import<'a>>,
+    // Style rules and at-rules, in the order they were written
+    pub rules: Vec<Rule<'a>>,
+}
+
+impl<'a> Stylesheet<'a> {
+    /// The style rules which apply on `device`: those at the top level, in `@layer` blocks, and
+    /// in `@media` and `@supports` rules whose conditions hold, each with the rank of its layer.
+    /// The layers the stylesheet declares are added to `layers`, which is shared by every
+    /// stylesheet of the same origin. `layer` is the layer the stylesheet was imported into,
+    /// which is the outermost layer for stylesheets which weren't. Rules are in the order they
+    /// cascade, which is by the rank of their layer and then the order they were written
+    pub fn rulesets(
+        &self,
+        device: &Device,
+        layers: &mut CascadeLayers,
+        layer: &Layer,
+    ) -> Vec<(LayerRank, &Ruleset<'a>)> {
+        let mut rulesets = vec![];
+        collect_rulesets(&self.rules, device, layers, layer, &mut rulesets);
+        // The sort is stable, so rules in the same layer keep their order
+        rulesets.sort_by(|(a, _), (b, _)| a.cmp(b));
+        rulesets
+    }
+}
+
+/// Add the style rules in `rules` which apply on `device` to `rulesets`, with the rank of their
+/// layer. `layer` is the layer `rules` are in
+fn collect_rulesets<'s, 'a>(
+    rules: &'s [Rule<'a>],
+    device: &Device,
+    layers: &mut CascadeLayers,
+    layer: &Layer,
+    rulesets: &mut Vec<(LayerRank, &'s Ruleset<'a>)>,
+) {
+    for rule in rules {
+        match rule {
+            Rule::Style(ruleset) => rulesets.push((layer.rank(), ruleset)),
+            Rule::At(AtRule::Media { query, rules }) if query.matches(device) => {
+                collect_rulesets(rules, device, layers, layer, rulesets)
+            }
+            Rule::At(AtRule::Supports { condition, rules }) if supports(condition) => {
+                collect_rulesets(rules, device, layers, layer, rulesets)
+            }
+            Rule::At(AtRule::Layer { names, rules: None }) => {
+                for &name in names {
+                    layers.layer(layer, Some(name));
+                }
+            }
+            Rule::At(AtRule::Layer {
+                names,
+                rules: Some(rules),
+            }) => {
+                let layer = layers.layer(layer, names.first().copied());
+                collect_rulesets(rules, device, layers, &layer, rulesets);
+            }
+            Rule::At(_) => {}
+        }
+    }
+}
+
+/// A cascade layer, as its position among the layers declared in each of its ancestors. The
+/// default is the outermost layer, which holds the rules outside of any `@layer`.
+/// <https://www.w3.org/TR/css-cascade-5/#layering>
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default)]
+pub struct Layer(Vec<usize>);
+
+impl Layer {
+    /// Where the rules directly in this layer are in the order of layers
+    pub fn rank(&self) -> LayerRank {
+        LayerRank(self.0.iter().copied().chain([usize::MAX]).collect())
+    }
+}
+
+/// Where rules are in the order of cascade layers. Normal declarations in rules with a higher
+/// rank win, so rules in later layers outrank those in earlier ones, the rules of a layer
+/// outrank those in the layers inside it, and rules outside of any layer outrank all others
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct LayerRank(Vec<usize>);
+
+/// The cascade layers declared by the stylesheets of an origin, in the order they were declared
+#[derive(Clone, Debug, Default)]
+pub struct CascadeLayers {
+    // The names of the layers in each layer, in the order they were first declared. Anonymous
+    // layers are `None`
+    names: HashMap<Layer, Vec<Option<String>>>,
+}
+
+impl CascadeLayers {
+    /// The layer `name` inside `parent`, declaring it if it's new. Names can be dotted like
+    /// `a.b`, and anonymous layers are always new
+    pub fn layer(&mut self, parent: &Layer, name: Option<&str>) -> Layer {
+        let names: Vec<Option<&str>> = match name {
+            Some(name) => name.split('.').map(|n| Some(n.trim())).collect(),
+            None => vec![None],
+        };
+        let mut layer = parent.clone();
+        for name in names {
+            let layers = self.names.entry(layer.clone()).or_default();
+            let index = match layers
+                .iter()
+                .position(|l| name.is_some() && l.as_deref() == name)
+            {
+                Some(index) => index,
+                None => {
+                    layers.push(name.map(str::to_string));
+                    layers.len() - 1
+                }
+            };
+            layer.0.push(index);
+        }
+        layer
+    }
+}
+
+/// An `@import` of the stylesheet at `url`, which only applies when `media` matches
+#[derive(PartialEq, Clone, Debug)]
+pub struct Import<'a> {
+    pub url: Cow<'a, str>,
+    // The layer the stylesheet's rules are put in, if any. This is `Some(None)` for an
+    // anonymous layer
+    pub layer: Option<Option<&'a str>>,
+    pub media: MediaQueryList,
+}
+
+impl<'a> Import<'a> {
+    pub fn new(url: &'a str) -> Self {
+        Self {
+            url: Cow::Borrowed(url),
+            layer: None,
+            media: MediaQueryList::default(),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Rule<'a> {
+    Style(Ruleset<'a>),
+    At(AtRule<'a>),
+}
+
+impl<'a> Rule<'a> {
+    /// Every declaration in this rule, including those in rules nested inside it
+    pub fn declarations(&self) -> Vec<&Declaration<'a>> {
+        let rules: &[Rule<'a>] = match self {
+            Rule::Style(ruleset) => return ruleset.declarations.iter().collect(),
+            Rule::At(AtRule::FontFace(declarations) | AtRule::Page { declarations, .. }) => {
+                return declarations.iter().collect()
+            }
+            Rule::At(AtRule::Keyframes { keyframes, .. }) => {
+                return keyframes.iter().flat_map(|k| &k.declarations).collect()
+            }
+            Rule::At(AtRule::Media { rules, .. } | AtRule::Supports { rules, .. }) => rules,
+            Rule::At(AtRule::Layer { rules, .. }) => rules.as_deref().unwrap_or_default(),
+        };
+        rules.iter().flat_map(Rule::declarations).collect()
+    }
+}
+
+impl<'a> From<Ruleset<'a>> for Rule<'a> {
+    fn from(ruleset: Ruleset<'a>) -> Self {
+        Rule::Style(ruleset)
+    }
+}
+
+/// A rule starting with an `@`.
+/// <https://www.w3.org/TR/css-syntax-3/#at-rule>
+#[derive(PartialEq, Clone, Debug)]
+pub enum AtRule<'a> {
+    /// Rules which apply when the media query matches
+    Media {
+        query: MediaQueryList,
+        rules: Vec<Rule<'a>>,
+    },
+    /// Rules which apply when the browser supports the condition
+    Supports {
+        condition: &'a str,
+        rules: Vec<Rule<'a>>,
+    },
+    /// The descriptors of a font, like `font-family` and `src`
+    FontFace(Vec<Declaration<'a>>),
+    /// Descriptors for printed pages, which `selector` (like `:first`) chooses
+    Page {
+        selector: &'a str,
+        declarations: Vec<Declaration<'a>>,
+    },
+    Keyframes {
+        name: &'a str,
+        keyframes: Vec<Keyframe<'a>>,
+    },
+    /// Either a statement declaring the order of layers, or a block of rules in a layer.
+    /// Anonymous layers have no names
+    Layer {
+        names: Vec<&'a str>,
+        rules: Option<Vec<Rule<'a>>>,
+    },
+}
+
+/// The declarations of a step in an animation
+#[derive(PartialEq, Clone, Debug)]
+pub struct Keyframe<'a> {
+    // How far through the animation the step is, as percentages. `from` is 0 and `to` is 100
+    pub offsets: Vec<f64>,
+    pub declarations: Vec<Declaration<'a>>,
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -30,30 +237,30 @@ pub enum Combinator {
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum SimpleSelector<'a> {
-    Type(&'a str),
+    Type(Cow<'a, str>),
     Universal,
-    Attribute(&'a str),
-    Class(&'a str),
-    PseudoClass(&'a str),
-    ID(&'a str),
+    Attribute(AttributeSelector<'a>),
+    Class(Cow<'a, str>),
+    PseudoClass(Cow<'a, str>),
+    ID(Cow<'a, str>),
 }
 
 #[macro_export]
 macro_rules! simple_selector {
     (#$x:expr) => {
-        SimpleSelector::ID(stringify!($x))
+        SimpleSelector::ID(stringify!($x).into())
     };
     (.$x:expr) => {
-        SimpleSelector::Class(stringify!($x))
+        SimpleSelector::Class(stringify!($x).into())
     };
     (:$x:expr) => {
-        SimpleSelector::PseudoClass(stringify!($x))
+        SimpleSelector::PseudoClass(stringify!($x).into())
     };
     (*) => {
         SimpleSelector::Universal
     };
     ($x:expr) => {
-        SimpleSelector::Type(stringify!($x))
+        SimpleSelector::Type(stringify!($x).into())
     };
 }
 
@@ -69,31 +276,78 @@ macro_rules! combinator_selector {
     };
 }
 
-// [att]
-#[allow(dead_code)]
+/// Selects elements by whether they have an attribute, and what its value is.
+/// <https://www.w3.org/TR/selectors-4/#attribute-selectors>
 #[derive(PartialEq, Clone, Debug)]
-pub enum AttributeSelector {
+pub enum AttributeSelector<'a> {
     // [att]
-    Has(String),
+    Has(Cow<'a, str>),
     // [att=val]
-    Equals(String, String),
-    // [att=val1 val2]
-    EqualsMany(String, Vec<String>),
+    Equals(Cow<'a, str>, Cow<'a, str>),
+    // [att~=val]
+    // `att` is a space seperated list of words, one of which is val
+    Includes(Cow<'a, str>, Cow<'a, str>),
     // [att|=val]
-    // `att` begins with val-
-    Begins(String, String),
+    // `att` is val, or begins with val-
+    DashMatch(Cow<'a, str>, Cow<'a, str>),
+    // [att^=val]
+    Prefix(Cow<'a, str>, Cow<'a, str>),
+    // [att$=val]
+    Suffix(Cow<'a, str>, Cow<'a, str>),
+    // [att*=val]
+    Substring(Cow<'a, str>, Cow<'a, str>),
+}
+
+impl<'a> AttributeSelector<'a> {
+    /// The name of the attribute which is tested
+    pub fn name(&self) -> &str {
+        match self {
+            AttributeSelector::Has(name)
+            | AttributeSelector::Equals(name, _)
+            | AttributeSelector::Includes(name, _)
+            | AttributeSelector::DashMatch(name, _)
+            | AttributeSelector::Prefix(name, _)
+            | AttributeSelector::Suffix(name, _)
+            | AttributeSelector::Substring(name, _) => name,
+        }
+    }
+
+    /// Whether an element whose attribute is `value` is selected. Elements without the
+    /// attribute never are
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            AttributeSelector::Has(_) => true,
+            AttributeSelector::Equals(_, v) => value == v,
+            AttributeSelector::Includes(_, v) => value.split_ascii_whitespace().any(|w| w == v),
+            AttributeSelector::DashMatch(_, v) => {
+                value == v
+                    || value.strip_prefix(&**v).map(|rest| rest.starts_with('-')) == Some(true)
+            }
+            // An empty value would match every element, so it matches none
+            AttributeSelector::Prefix(_, v) => !v.is_empty() && value.starts_with(&**v),
+            AttributeSelector::Suffix(_, v) => !v.is_empty() && value.ends_with(&**v),
+            AttributeSelector::Substring(_, v) => !v.is_empty() && value.contains(&**v),
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Declaration<'a> {
-    pub name: &'a str,
+    // The name of the property, in lowercase
+    pub name: Cow<'a, str>,
     pub value: Value<'a>,
+    // Whether the declaration ends with `!important`, which puts it before others in the cascade
+    pub important: bool,
 }
 
 #[allow(dead_code)]
 impl<'a> Declaration<'a> {
     pub fn new(name: &'a str, value: Value<'a>) -> Self {
-        Self { name, value }
+        Self {
+            name: Cow::Borrowed(name),
+            value,
+            important: false,
+        }
     }
 }
 
@@ -101,8 +355,8 @@ impl<'a> Declaration<'a> {
 #[derive(PartialEq, Clone, Debug)]
 pub enum Value<'a> {
     Keyword(&'a str),
-    String(&'a str),
-    Url(&'a str),
+    String(Cow<'a, str>),
+    Url(Cow<'a, str>),
     Number(f64),
     Percentage(f64),
     Length(f64, Unit),
@@ -152,6 +406,17 @@ impl<'a> Value<'a> {
             false
         }
     }
+    /// The URLs this value refers to, including those inside functions and lists
+    pub fn urls(&self) -> Vec<&str> {
+        match self {
+            Value::Url(url) => vec![url],
+            Value::Function(FunctionValue(_, args)) => args.iter().flat_map(Value::urls).collect(),
+            Value::Multiple(MultiValue(values)) => {
+                values.iter().flat_map(|(_, v)| v.urls()).collect()
+            }
+            _ => vec![],
+        }
+    }
     /// Check if this is a valid color, color-function or color-keyword
     pub fn is_color(&self) -> bool {
         match self {
@@ -267,7 +532,7 @@ pub use keywords::*;
 
 /// Takes a CSS keyword and returns a Value. If the keyword is implemented,
 /// the proper value will be returned. Otherwise, it will be returned as a Value::Keyword
-pub fn keyword_to_value(kw: &str) -> Value {
+pub fn keyword_to_value(kw: &str) -> Value<'_> {
     match kw {
         "black" => Value::Color(BLACK),
         _ => Value::Keyword(kw),
@@ -277,44 +542,35 @@ pub fn keyword_to_value(kw: &str) -> Value {
 /// Takes a CSS function call and returns a Value. If the function is implemented,
 /// the proper value will be returned. Otherwise, it will be returned as a Value::FunctionValue
 pub fn function_to_value(func: FunctionValue) -> Value {
-    match func.0 {
-        "rgb" => {
-            let mut args: Vec<u8> = func
-                .1
-                .iter()
-                .take(3)
-                .map(|v| {
-                    if let Value::Number(val) = v {
-                        *val as u8
-                    } else {
-                        unreachable!()
-                    }
-                })
-                .collect();
-            args.push(255);
-            Value::Color(ColorValue::new(args.as_slice()))
-        }
-        "rgba" => {
-            let args: Vec<u8> = func
-                .1
-                .iter()
-                .take(4)
-                .map(|v| {
-                    if let Value::Number(val) = v {
-                        *val as u8
-                    } else {
-                        unreachable!()
-                    }
-                })
-                .collect();
-            Value::Color(ColorValue::new(args.as_slice()))
-        }
+    let channels: Option<Vec<u8>> = func
+        .1
+        .iter()
+        .map(|v| match v {
+            Value::Number(val) => Some(*val as u8),
+            _ => None,
+        })
+        .collect();
+    match (func.0, channels.as_deref()) {
+        ("rgb", Some(&[r, g, b])) => Value::Color(ColorValue::new(&[r, g, b, 255])),
+        ("rgba", Some(&[r, g, b, a])) => Value::Color(ColorValue::new(&[r, g, b, a])),
         _ => Value::Function(func),
     }
 }
 
+mod condition;
+mod media;
 mod parsing;
+mod supports;
+mod syntax;
 #[cfg(test)]
 mod tests;
+mod tokenizer;
 
-pub use parsing::stylesheet;
+pub use media::{
+    ColorScheme, Comparison, Device, MediaCondition, MediaFeature, MediaQuery, MediaQueryList,
+    MediaType, MediaValue, Qualifier,
+};
+pub use parsing::selectors;
+pub use supports::supports;
+pub use syntax::{declarations, stylesheet};
+pub use tokenizer::{Token, Tokenizer};
@@ -0,0 +1,719 @@
+//! Parsing media queries and evaluating them against a [`Device`]
+//! <https://www.w3.org/TR/mediaqueries-4/>
+
+use crate::condition::{ident, Tokens};
+use crate::tokenizer::{Token, Tokenizer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+/// What a page is being shown on, which media queries are evaluated against
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub media_type: MediaType,
+    // The size of the viewport, in CSS pixels
+    pub width: f64,
+    pub height: f64,
+    // Device pixels per CSS pixel
+    pub resolution: f64,
+    pub color_scheme: ColorScheme,
+}
+
+impl Device {
+    /// A screen with a viewport of `width` by `height` pixels
+    pub fn screen(width: f64, height: f64) -> Self {
+        Self {
+            media_type: MediaType::Screen,
+            width,
+            height,
+            resolution: 1.0,
+            color_scheme: ColorScheme::Light,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaType {
+    All,
+    Screen,
+    Print,
+    // Types from older specs, like `tv`, which never match
+    Other(String),
+}
+
+impl MediaType {
+    /// Returns [`None`] for the keywords which can't be used as a media type
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "all" => MediaType::All,
+            "screen" => MediaType::Screen,
+            "print" => MediaType::Print,
+            "only" | "not" | "and" | "or" | "layer" => return None,
+            name => MediaType::Other(name.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Qualifier {
+    Not,
+    // Only there to hide queries from old browsers, and has no effect
+    Only,
+}
+
+/// A comma-separated list of media queries, which matches if any of them do. An empty list
+/// matches everything
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaQueryList(pub Vec<MediaQuery>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaQuery {
+    pub qualifier: Option<Qualifier>,
+    // A query without a media type applies to all of them
+    pub media_type: Option<MediaType>,
+    pub condition: Option<MediaCondition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaCondition {
+    Feature(MediaFeature),
+    Not(Box<MediaCondition>),
+    And(Vec<MediaCondition>),
+    Or(Vec<MediaCondition>),
+    // Something in parentheses which isn't understood, which is neither true nor false
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaFeature {
+    /// `(color)`, which is true if the feature isn't zero or `none`
+    Boolean(String),
+    /// `(orientation: portrait)` or `(600px <= width < 900px)`. `(min-width: 600px)` is stored
+    /// as `(width >= 600px)`. The comparisons are all of the form `feature op value`
+    Compare {
+        name: String,
+        comparisons: Vec<(Comparison, MediaValue)>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl Comparison {
+    /// The comparison with its sides swapped, so `a < b` becomes `b > a`
+    fn flip(self) -> Self {
+        match self {
+            Comparison::Lt => Comparison::Gt,
+            Comparison::Le => Comparison::Ge,
+            Comparison::Eq => Comparison::Eq,
+            Comparison::Ge => Comparison::Le,
+            Comparison::Gt => Comparison::Lt,
+        }
+    }
+
+    fn compare(self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Lt => a < b,
+            Comparison::Le => a <= b,
+            Comparison::Eq => a == b,
+            Comparison::Ge => a >= b,
+            Comparison::Gt => a > b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaValue {
+    Number(f64),
+    Dimension(f64, String),
+    Ident(String),
+    Ratio(f64, f64),
+}
+
+/// Features which have values that can be compared with `<` and `>`, and so can have `min-` and
+/// `max-` prefixes
+static RANGE_FEATURES: &[&str] = &[
+    "width",
+    "height",
+    "device-width",
+    "device-height",
+    "aspect-ratio",
+    "device-aspect-ratio",
+    "resolution",
+    "color",
+    "color-index",
+    "monochrome",
+];
+
+/// The value of a feature of a [`Device`]
+enum DeviceValue {
+    Length(f64),
+    Ratio(f64),
+    Resolution(f64),
+    Number(f64),
+    Ident(&'static str),
+}
+
+/// Split `tokens` at the commas which aren't in parentheses
+fn split_commas<'t, 'a>(tokens: &'t [Token<'a>]) -> Vec<&'t [Token<'a>]> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::OpenParen | Token::Function(_) | Token::OpenSquare | Token::OpenCurly => {
+                depth += 1
+            }
+            Token::CloseParen | Token::CloseSquare | Token::CloseCurly => depth -= 1,
+            Token::Comma if depth <= 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts
+}
+
+/// Get the comparison at the start of `tokens`, and how many tokens it is
+fn comparison(tokens: &[Token]) -> Option<(Comparison, usize)> {
+    let or_equal = tokens.get(1) == Some(&Token::Delim('='));
+    match tokens.first()? {
+        Token::Delim('<') if or_equal => Some((Comparison::Le, 2)),
+        Token::Delim('<') => Some((Comparison::Lt, 1)),
+        Token::Delim('>') if or_equal => Some((Comparison::Ge, 2)),
+        Token::Delim('>') => Some((Comparison::Gt, 1)),
+        Token::Delim('=') => Some((Comparison::Eq, 1)),
+        _ => None,
+    }
+}
+
+impl MediaQueryList {
+    pub fn parse(input: &str) -> Self {
+        let mut tokens: Vec<Token> = vec![];
+        let mut all = Tokenizer::new(input).map(|(t, _)| t).peekable();
+        while let Some(token) = all.next() {
+            // Whitespace is only kept where it splits a comparison, since `< =` isn't `<=`
+            let splits_comparison = matches!(tokens.last(), Some(Token::Delim('<' | '>')))
+                && all.peek() == Some(&Token::Delim('='));
+            if token != Token::Whitespace || splits_comparison {
+                tokens.push(token);
+            }
+        }
+        if tokens.is_empty() {
+            return Self::default();
+        }
+        // A query which is invalid doesn't stop the others from being used
+        Self(
+            split_commas(&tokens)
+                .into_iter()
+                .map(|query| MediaQuery::parse(query).unwrap_or_else(MediaQuery::never))
+                .collect(),
+        )
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        self.0.is_empty() || self.0.iter().any(|q| q.matches(device))
+    }
+}
+
+impl MediaQuery {
+    /// `not all`, which is what invalid queries become
+    fn never() -> Self {
+        Self {
+            qualifier: Some(Qualifier::Not),
+            media_type: Some(MediaType::All),
+            condition: None,
+        }
+    }
+
+    fn parse(tokens: &[Token]) -> Option<Self> {
+        let (qualifier, start) = match ident(tokens.first()).as_deref() {
+            Some("not") if ident(tokens.get(1)).is_some() => (Some(Qualifier::Not), 1),
+            Some("only") => (Some(Qualifier::Only), 1),
+            _ => (None, 0),
+        };
+        match ident(tokens.get(start)) {
+            // `not` without a media type starts a condition
+            Some(name) if qualifier.is_some() || name != "not" => {
+                let media_type = MediaType::parse(&name)?;
+                let mut parser = ConditionParser::new(&tokens[start + 1..]);
+                let condition = if parser.at_end() {
+                    None
+                } else {
+                    parser.tokens.eat_ident("and").then_some(())?;
+                    Some(parser.condition(false)?)
+                };
+                parser.at_end().then_some(Self {
+                    qualifier,
+                    media_type: Some(media_type),
+                    condition,
+                })
+            }
+            _ if qualifier.is_some() => None,
+            _ => {
+                let mut parser = ConditionParser::new(tokens);
+                let condition = parser.condition(true)?;
+                parser.at_end().then_some(Self {
+                    qualifier: None,
+                    media_type: None,
+                    condition: Some(condition),
+                })
+            }
+        }
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        let media_type = match &self.media_type {
+            None | Some(MediaType::All) => true,
+            Some(MediaType::Other(_)) => false,
+            Some(media_type) => media_type == &device.media_type,
+        };
+        let result = match &self.condition {
+            Some(condition) if media_type => condition.evaluate(device),
+            _ => Some(media_type),
+        };
+        let result = match self.qualifier {
+            Some(Qualifier::Not) => result.map(|r| !r),
+            _ => result,
+        };
+        result.unwrap_or(false)
+    }
+}
+
+/// Parses media conditions from tokens without whitespace
+struct ConditionParser<'t, 'a> {
+    tokens: Tokens<'t, Token<'a>>,
+}
+
+impl<'t, 'a> ConditionParser<'t, 'a> {
+    fn new(tokens: &'t [Token<'a>]) -> Self {
+        Self {
+            tokens: Tokens::new(tokens),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.tokens.at_end()
+    }
+
+    /// `<media-condition>`, or `<media-condition-without-or>` if `allow_or` isn't set
+    fn condition(&mut self, allow_or: bool) -> Option<MediaCondition> {
+        if self.tokens.eat_ident("not") {
+            return Some(MediaCondition::Not(Box::new(self.in_parens()?)));
+        }
+        let first = self.in_parens()?;
+        let operator = match self.tokens.ident().as_deref() {
+            Some("and") => "and",
+            Some("or") if allow_or => "or",
+            _ => return Some(first),
+        };
+        let mut conditions = vec![first];
+        while self.tokens.eat_ident(operator) {
+            conditions.push(self.in_parens()?);
+        }
+        Some(match operator {
+            "and" => MediaCondition::And(conditions),
+            _ => MediaCondition::Or(conditions),
+        })
+    }
+
+    /// `<media-in-parens>`
+    fn in_parens(&mut self) -> Option<MediaCondition> {
+        match self.tokens.peek()? {
+            Token::OpenParen => {
+                let inner = self.tokens.block();
+                let mut parser = ConditionParser::new(inner);
+                if let Some(condition) = parser.condition(true).filter(|_| parser.at_end()) {
+                    return Some(condition);
+                }
+                Some(
+                    MediaFeature::parse(inner)
+                        .map(MediaCondition::Feature)
+                        .unwrap_or(MediaCondition::Unknown),
+                )
+            }
+            Token::Function(_) => {
+                self.tokens.block();
+                Some(MediaCondition::Unknown)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MediaFeature {
+    /// Parse what's inside the parentheses of a feature
+    fn parse(tokens: &[Token]) -> Option<Self> {
+        if let [Token::Ident(name)] = tokens {
+            return Some(MediaFeature::Boolean(name.to_ascii_lowercase()));
+        }
+        if let [Token::Ident(name), Token::Colon, value @ ..] = tokens {
+            let value = MediaValue::parse_all(value)?;
+            let name = name.to_ascii_lowercase();
+            let (name, comparison) = if let Some(name) = name.strip_prefix("min-") {
+                (name.to_string(), Comparison::Ge)
+            } else if let Some(name) = name.strip_prefix("max-") {
+                (name.to_string(), Comparison::Le)
+            } else {
+                (name, Comparison::Eq)
+            };
+            // Features like `orientation` can't be given a minimum or maximum
+            if comparison != Comparison::Eq && !RANGE_FEATURES.contains(&name.as_str()) {
+                return None;
+            }
+            return Some(MediaFeature::Compare {
+                name,
+                comparisons: vec![(comparison, value)],
+            });
+        }
+        // `name op value`
+        if let [Token::Ident(name), rest @ ..] = tokens {
+            if let Some((comparison, length)) = comparison(rest) {
+                return Some(MediaFeature::Compare {
+                    name: name.to_ascii_lowercase(),
+                    comparisons: vec![(comparison, MediaValue::parse_all(&rest[length..])?)],
+                });
+            }
+        }
+        // `value op name`, or `value op name op value`
+        let (first, length) = MediaValue::parse(tokens)?;
+        let (first_comparison, comparison_length) = comparison(&tokens[length..])?;
+        let rest = &tokens[length + comparison_length..];
+        let name = ident(rest.first())?;
+        let mut comparisons = vec![(first_comparison.flip(), first)];
+        let rest = &rest[1..];
+        if !rest.is_empty() {
+            let (second_comparison, length) = comparison(rest)?;
+            // Both comparisons have to go the same way
+            let less = |c| matches!(c, Comparison::Lt | Comparison::Le);
+            let greater = |c| matches!(c, Comparison::Gt | Comparison::Ge);
+            if !(less(first_comparison) && less(second_comparison)
+                || greater(first_comparison) && greater(second_comparison))
+            {
+                return None;
+            }
+            comparisons.push((second_comparison, MediaValue::parse_all(&rest[length..])?));
+        }
+        Some(MediaFeature::Compare { name, comparisons })
+    }
+
+    /// Whether the feature matches the device, or [`None`] if it isn't known
+    fn evaluate(&self, device: &Device) -> Option<bool> {
+        match self {
+            MediaFeature::Boolean(name) => Some(match device_value(name, device)? {
+                DeviceValue::Length(n)
+                | DeviceValue::Ratio(n)
+                | DeviceValue::Resolution(n)
+                | DeviceValue::Number(n) => n != 0.0,
+                DeviceValue::Ident(ident) => !matches!(ident, "none" | "no-preference"),
+            }),
+            MediaFeature::Compare { name, comparisons } => {
+                let actual = device_value(name, device)?;
+                comparisons
+                    .iter()
+                    .try_fold(true, |result, (comparison, value)| {
+                        let matches = match (&actual, value) {
+                            (DeviceValue::Ident(actual), MediaValue::Ident(value)) => {
+                                (*comparison == Comparison::Eq).then(|| actual == value)?
+                            }
+                            (DeviceValue::Length(actual), value) => {
+                                comparison.compare(*actual, value.to_px()?)
+                            }
+                            (DeviceValue::Ratio(actual), MediaValue::Ratio(a, b)) => {
+                                comparison.compare(*actual, a / b)
+                            }
+                            (DeviceValue::Ratio(actual), MediaValue::Number(n)) => {
+                                comparison.compare(*actual, *n)
+                            }
+                            (DeviceValue::Resolution(actual), value) => {
+                                comparison.compare(*actual, value.to_dppx()?)
+                            }
+                            (DeviceValue::Number(actual), MediaValue::Number(n)) => {
+                                comparison.compare(*actual, *n)
+                            }
+                            _ => return None,
+                        };
+                        Some(result && matches)
+                    })
+            }
+        }
+    }
+}
+
+/// The value of the feature `name` for `device`, if it's a feature which is known
+fn device_value(name: &str, device: &Device) -> Option<DeviceValue> {
+    Some(match name {
+        "width" | "device-width" => DeviceValue::Length(device.width),
+        "height" | "device-height" => DeviceValue::Length(device.height),
+        "aspect-ratio" | "device-aspect-ratio" => DeviceValue::Ratio(device.width / device.height),
+        "orientation" if device.height >= device.width => DeviceValue::Ident("portrait"),
+        "orientation" => DeviceValue::Ident("landscape"),
+        "resolution" => DeviceValue::Resolution(device.resolution),
+        "prefers-color-scheme" => DeviceValue::Ident(match device.color_scheme {
+            ColorScheme::Light => "light",
+            ColorScheme::Dark => "dark",
+        }),
+        // Bits per color component
+        "color" => DeviceValue::Number(8.0),
+        "color-index" | "monochrome" | "grid" => DeviceValue::Number(0.0),
+        "hover" | "any-hover" => DeviceValue::Ident("hover"),
+        "pointer" | "any-pointer" => DeviceValue::Ident("fine"),
+        "prefers-reduced-motion" | "prefers-contrast" => DeviceValue::Ident("no-preference"),
+        "scan" => DeviceValue::Ident("progressive"),
+        "update" => DeviceValue::Ident("fast"),
+        _ => return None,
+    })
+}
+
+impl MediaValue {
+    /// Parse the value at the start of `tokens`, returning how many tokens it was
+    fn parse(tokens: &[Token]) -> Option<(Self, usize)> {
+        Some(match tokens {
+            [Token::Number { value: a, .. }, Token::Delim('/'), Token::Number { value: b, .. }, ..] => {
+                (MediaValue::Ratio(*a, *b), 3)
+            }
+            [Token::Number { value, .. }, ..] => (MediaValue::Number(*value), 1),
+            [Token::Dimension { value, unit, .. }, ..] => {
+                (MediaValue::Dimension(*value, unit.to_ascii_lowercase()), 1)
+            }
+            [Token::Ident(ident), ..] => (MediaValue::Ident(ident.to_ascii_lowercase()), 1),
+            _ => return None,
+        })
+    }
+
+    /// Parse a value which is the whole of `tokens`
+    fn parse_all(tokens: &[Token]) -> Option<Self> {
+        match Self::parse(tokens)? {
+            (value, length) if length == tokens.len() => Some(value),
+            _ => None,
+        }
+    }
+
+    /// This as a length in pixels. Font-relative lengths use the initial font size
+    fn to_px(&self) -> Option<f64> {
+        match self {
+            MediaValue::Number(n) if *n == 0.0 => Some(0.0),
+            MediaValue::Dimension(n, unit) => Some(
+                n * match unit.as_str() {
+                    "px" => 1.0,
+                    "em" | "rem" => 16.0,
+                    "in" => 96.0,
+                    "cm" => 96.0 / 2.54,
+                    "mm" => 96.0 / 25.4,
+                    "q" => 96.0 / 101.6,
+                    "pt" => 96.0 / 72.0,
+                    "pc" => 16.0,
+                    _ => return None,
+                },
+            ),
+            _ => None,
+        }
+    }
+
+    /// This as a resolution in dots per pixel
+    fn to_dppx(&self) -> Option<f64> {
+        match self {
+            MediaValue::Dimension(n, unit) => Some(
+                n * match unit.as_str() {
+                    "dppx" | "x" => 1.0,
+                    "dpi" => 1.0 / 96.0,
+                    "dpcm" => 2.54 / 96.0,
+                    _ => return None,
+                },
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl MediaCondition {
+    /// Whether the condition matches the device, or [`None`] if that isn't known
+    pub fn evaluate(&self, device: &Device) -> Option<bool> {
+        match self {
+            MediaCondition::Feature(feature) => feature.evaluate(device),
+            MediaCondition::Not(condition) => condition.evaluate(device).map(|r| !r),
+            MediaCondition::And(conditions) => {
+                let results: Vec<_> = conditions.iter().map(|c| c.evaluate(device)).collect();
+                if results.contains(&Some(false)) {
+                    Some(false)
+                } else if results.contains(&None) {
+                    None
+                } else {
+                    Some(true)
+                }
+            }
+            MediaCondition::Or(conditions) => {
+                let results: Vec<_> = conditions.iter().map(|c| c.evaluate(device)).collect();
+                if results.contains(&Some(true)) {
+                    Some(true)
+                } else if results.contains(&None) {
+                    None
+                } else {
+                    Some(false)
+                }
+            }
+            MediaCondition::Unknown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse() {
+    let query = |i| {
+        let mut list = MediaQueryList::parse(i);
+        assert_eq!(list.0.len(), 1);
+        list.0.remove(0)
+    };
+    let compare = |name: &str, comparisons| {
+        MediaCondition::Feature(MediaFeature::Compare {
+            name: name.to_string(),
+            comparisons,
+        })
+    };
+    assert_eq!(MediaQueryList::parse(" "), MediaQueryList(vec![]));
+    assert_eq!(
+        query("only screen and (min-width: 600px) and (color)"),
+        MediaQuery {
+            qualifier: Some(Qualifier::Only),
+            media_type: Some(MediaType::Screen),
+            condition: Some(MediaCondition::And(vec![
+                compare(
+                    "width",
+                    vec![(
+                        Comparison::Ge,
+                        MediaValue::Dimension(600.0, "px".to_string())
+                    )]
+                ),
+                MediaCondition::Feature(MediaFeature::Boolean("color".to_string()))
+            ]))
+        }
+    );
+    assert_eq!(
+        query("not (400px < WIDTH <= 700px)").condition,
+        Some(MediaCondition::Not(Box::new(compare(
+            "width",
+            vec![
+                (
+                    Comparison::Gt,
+                    MediaValue::Dimension(400.0, "px".to_string())
+                ),
+                (
+                    Comparison::Le,
+                    MediaValue::Dimension(700.0, "px".to_string())
+                )
+            ]
+        ))))
+    );
+    assert_eq!(
+        query("(orientation: landscape) or (prefers-color-scheme: dark)").condition,
+        Some(MediaCondition::Or(vec![
+            compare(
+                "orientation",
+                vec![(Comparison::Eq, MediaValue::Ident("landscape".to_string()))]
+            ),
+            compare(
+                "prefers-color-scheme",
+                vec![(Comparison::Eq, MediaValue::Ident("dark".to_string()))]
+            )
+        ]))
+    );
+    assert_eq!(
+        query("(resolution >= 2dppx) and (hover) and (unknown(x))").condition,
+        Some(MediaCondition::And(vec![
+            compare(
+                "resolution",
+                vec![(
+                    Comparison::Ge,
+                    MediaValue::Dimension(2.0, "dppx".to_string())
+                )]
+            ),
+            MediaCondition::Feature(MediaFeature::Boolean("hover".to_string())),
+            MediaCondition::Unknown
+        ]))
+    );
+    // Invalid queries become `not all`, without affecting the rest of the list
+    for invalid in [
+        "screen and",
+        "(a) and (b) or (c)",
+        "only (color)",
+        "and",
+        "screen (color)",
+        "not (color) or (hover)",
+    ] {
+        assert_eq!(query(invalid), MediaQuery::never(), "{}", invalid);
+    }
+    assert_eq!(MediaQueryList::parse("screen, , print").0.len(), 3);
+    // Prefixes on features which aren't ranges, and comparisons split by whitespace, are
+    // unknown
+    for unknown in [
+        "(min-orientation: portrait)",
+        "(max-hover: hover)",
+        "(width > = 600px)",
+        "(600px < = width)",
+    ] {
+        assert_eq!(
+            query(unknown).condition,
+            Some(MediaCondition::Unknown),
+            "{}",
+            unknown
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_evaluate() {
+    let device = Device::screen(1600.0, 1080.0);
+    let matches = |i| MediaQueryList::parse(i).matches(&device);
+    assert!(matches(""));
+    assert!(matches("screen"));
+    assert!(matches("all and (min-width: 600px)"));
+    assert!(!matches("print"));
+    assert!(matches("print, screen"));
+    assert!(!matches("tv"));
+    assert!(matches("not print"));
+    assert!(!matches("not screen and (min-width: 600px)"));
+    assert!(matches("(width > 1000px) and (height <= 1080px)"));
+    assert!(matches("(1000px < width <= 100em)"));
+    assert!(!matches("(1000px < width < 100em)"));
+    assert!(!matches("(max-width: 50em)"));
+    assert!(matches("(orientation: landscape)"));
+    assert!(!matches("(orientation: portrait)"));
+    assert!(matches("(aspect-ratio > 4/3)"));
+    assert!(matches("(resolution: 96dpi)"));
+    assert!(matches("(prefers-color-scheme: light)"));
+    assert!(matches("(color) and (not (monochrome))"));
+    // Unknown features are neither true nor false, so negating them doesn't match either
+    assert!(!matches("(unknown-feature)"));
+    assert!(!matches("not (unknown-feature)"));
+    assert!(matches("(unknown-feature) or (color)"));
+    assert!(!matches("(width: red)"));
+    assert!(!matches("not (1px < width > 2px)"));
+    assert!(matches("(min-color: 1)"));
+    assert!(!matches("(min-orientation: landscape)"));
+    assert!(!matches("(width > = 600px)"));
+    assert!(matches("(width >= 600px)"));
+
+    let dark = Device {
+        color_scheme: ColorScheme::Dark,
+        media_type: MediaType::Print,
+        ..Device::screen(400.0, 800.0)
+    };
+    let matches = |i| MediaQueryList::parse(i).matches(&dark);
+    assert!(matches(
+        "print and (prefers-color-scheme: dark) and (orientation: portrait)"
+    ));
+    assert!(!matches("screen"));
+}
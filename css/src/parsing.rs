@@ -1,143 +1,200 @@
-//! Implements the CSS spec <https://github.com/antlr/grammars-v4/blob/master/css3/css3.g4>
+//! Builds selectors, values and the preludes of `@import` rules from the tokens of the text they
+//! were written in. Splitting a stylesheet into rules and declarations is done in
+//! [`crate::syntax`]
 
 use super::*;
-use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, tag_no_case, take, take_until};
-use nom::character::complete::{
-    alphanumeric1, char as chr, digit1, multispace0, multispace1, one_of,
-};
-use nom::combinator::{map, opt, peek, recognize, value, verify};
-use nom::multi::{many0, many1};
-use nom::sequence::{delimited, pair, preceded, terminated, tuple};
-use nom::{AsChar, IResult};
-use tracing::{span, Level};
-
-/// Parses a CSS source file to a [`Stylesheet`]
-pub fn stylesheet(input: &str) -> IResult<&str, Stylesheet> {
-    let span = span!(Level::DEBUG, "Parsing Stylesheet");
-    let _enter = span.enter();
-    let (input, _) = ws(input)?;
-    let (input, _) = many0(pair(charset, ws))(input)?;
-    let (input, _) = many0(pair(import, ws))(input)?;
-    let (input, rules) = many0(ruleset)(input)?;
-    // Skip over any rules with empty selectors/bodies - these are either useless or invalid,
-    // and the parser has returned an empty rule
-    let rules = rules
-        .into_iter()
-        .filter(|r| !r.selectors.is_empty() && !r.declarations.is_empty())
-        .collect();
-    let (input, _) = ws(input)?;
-    Ok((input, Stylesheet { rules }))
-}
-
-/// Parse an 'import' statement
-fn import(input: &str) -> IResult<&str, &str> {
-    let (input, (_, _, url, _)) = tuple((
-        tag_no_case("@import"),
-        multispace0,
-        alt((string, uri)),
-        chr(';'),
-    ))(input)?;
-    Ok((input, url))
-}
-#[cfg(test)]
-#[test]
-fn test_import() {
-    let i = r#"@import "navigation.css";"#;
-    let target = Ok(("", "navigation.css"));
-    assert_eq!(import(i), target);
-    let i = r#"@import url("navigation.css");"#;
-    assert_eq!(import(i), target);
-}
-
-/// Parse a 'charset' statement
-fn charset(input: &str) -> IResult<&str, &str> {
-    map(
-        tuple((tag("@charset"), ws, string, ws, chr(';'), ws)),
-        |t| t.2,
-    )(input)
-}
-
-/// Parse quoted string
-fn string(input: &str) -> IResult<&str, &str> {
-    /// Parse double-quoted string
-    fn string1(input: &str) -> IResult<&str, &str> {
-        let (input, (_, content, _)) = tuple((
-            chr('"'),
-            recognize(many0(alt((
-                is_not("\n\r\\\""),
-                alt((tag("\\n"), tag("\\r"))),
-            )))),
-            chr('"'),
-        ))(input)?;
-        Ok((input, content))
+use crate::condition::Tokens;
+use crate::tokenizer::{Token, Tokenizer};
+use std::ops::Range;
+
+/// Parse the prelude of an `@import` rule to the URL it imports, the layer it imports into, the
+/// argument of its `supports()` condition and the media query list which follows them. The layer
+/// is `Some(None)` for `layer`, which imports into an anonymous layer, and [`None`] when the
+/// import isn't into a layer
+pub(crate) fn parse_import(prelude: &str) -> Option<ImportPrelude<'_>> {
+    let tokens: Vec<_> = Tokenizer::new(prelude).collect();
+    let mut tokens = Tokens::new(&tokens);
+    tokens.skip_whitespace();
+    let url = match tokens.peek()? {
+        Token::String(url) | Token::Url(url) => {
+            tokens.bump();
+            url.clone()
+        }
+        Token::Function(name) if name.eq_ignore_ascii_case("url") => {
+            match closed_block(&mut tokens).map(trim_ranged)? {
+                [(Token::String(url), _)] => url.clone(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    tokens.skip_whitespace();
+    let layer = match tokens.peek() {
+        Some(Token::Ident(name)) if name.eq_ignore_ascii_case("layer") => {
+            tokens.bump();
+            Some(None)
+        }
+        Some(Token::Function(name)) if name.eq_ignore_ascii_case("layer") => {
+            // The name of a layer is identifiers joined by `.`s, and `layer()` is invalid
+            let name = closed_block(&mut tokens).map(trim_ranged)?;
+            let valid = name.len() % 2 == 1
+                && name.iter().enumerate().all(|(i, (t, _))| match t {
+                    Token::Ident(_) => i % 2 == 0,
+                    Token::Delim('.') => i % 2 == 1,
+                    _ => false,
+                });
+            if !valid {
+                return None;
+            }
+            Some(Some(source(prelude, name)))
+        }
+        _ => None,
+    };
+    tokens.skip_whitespace();
+    let mut supports = None;
+    if let Some(Token::Function(name)) = tokens.peek() {
+        if name.eq_ignore_ascii_case("supports") {
+            let mut after = Tokens::new(tokens.rest());
+            // A `supports()` which isn't closed is left for the media query list to reject
+            if let Some(argument) = closed_block(&mut after) {
+                supports = Some(source(prelude, trim_ranged(argument)));
+                tokens = after;
+            }
+        }
     }
-    /// Parse single-quoted string
-    fn string2(input: &str) -> IResult<&str, &str> {
-        let (input, (_, content, _)) = tuple((
-            chr('\''),
-            recognize(many0(alt((
-                is_not("\n\r\\'"),
-                alt((tag("\\n"), tag("\\r"))),
-            )))),
-            chr('\''),
-        ))(input)?;
-        Ok((input, content))
+    tokens.skip_whitespace();
+    let media = match tokens.rest().first() {
+        Some((_, range)) => &prelude[range.start..],
+        None => "",
+    };
+    Some(ImportPrelude {
+        url,
+        layer,
+        supports,
+        media,
+    })
+}
+
+/// The parts of an `@import` prelude, before its media query list is parsed
+#[derive(PartialEq, Debug)]
+pub(crate) struct ImportPrelude<'a> {
+    pub url: Cow<'a, str>,
+    pub layer: Option<Option<&'a str>>,
+    pub supports: Option<&'a str>,
+    pub media: &'a str,
+}
+
+type RangedToken<'a> = (Token<'a>, Range<usize>);
+
+/// Skip over the function or parenthesised block which is next, returning the tokens inside of
+/// it, or [`None`] if it isn't closed
+fn closed_block<'t, 'a>(tokens: &mut Tokens<'t, RangedToken<'a>>) -> Option<&'t [RangedToken<'a>]> {
+    let remaining = tokens.rest().len();
+    let block = tokens.block();
+    (remaining - tokens.rest().len() == block.len() + 2).then_some(block)
+}
+
+/// `tokens` without the whitespace at either end
+fn trim_ranged<'t, 'a>(tokens: &'t [RangedToken<'a>]) -> &'t [RangedToken<'a>] {
+    let start = tokens
+        .iter()
+        .position(|(t, _)| t != &Token::Whitespace)
+        .unwrap_or(tokens.len());
+    let end = tokens
+        .iter()
+        .rposition(|(t, _)| t != &Token::Whitespace)
+        .map_or(start, |i| i + 1);
+    &tokens[start..end]
+}
+
+/// The source of `tokens`, which were read from `input`
+fn source<'a>(input: &'a str, tokens: &[RangedToken<'a>]) -> &'a str {
+    match (tokens.first(), tokens.last()) {
+        (Some((_, first)), Some((_, last))) => &input[first.start..last.end],
+        _ => "",
     }
-    alt((string1, string2))(input)
 }
-#[cfg(test)]
-#[test]
-fn test_string() {
-    let i = r#""Hello, world""#;
-    let target = ("", "Hello, world");
-    assert_eq!(string(i).unwrap(), target);
 
-    let i = "'Hello, world'";
-    let target = ("", "Hello, world");
-    assert_eq!(string(i).unwrap(), target);
-
-    let i = r#""Hello\nworld""#;
-    let target = ("", "Hello\\nworld");
-    assert_eq!(string(i).unwrap(), target);
-}
-
-/// Parse ruleset
-fn ruleset(input: &str) -> IResult<&str, Ruleset> {
-    let (input, selectors) = selector_group(input)?;
-    let (input, _) = ws(input)?;
-    let (input, body) = delimited(pair(chr('{'), ws), take_until("}"), pair(chr('}'), ws))(input)?;
-    let (input, _) = ws(input)?;
-    if let Ok((_, declarations)) = declaration_list(body) {
-        Ok((
-            input,
-            Ruleset {
-                selectors,
-                declarations,
-            },
-        ))
-    } else {
-        // Wasn't able to parse the body of this ruleset - return an 'empty' rule and move on
-        Ok((
-            input,
-            Ruleset {
-                selectors: vec![],
-                declarations: vec![],
-            },
-        ))
-    }
-}
 #[cfg(test)]
 #[test]
-fn test_ruleset() {
-    let i = r#"html {
-    box-sizing: border-box
-}"#;
-    let target = Ruleset {
-        selectors: vec![Selector::Simple(simple_selector!(html))],
-        declarations: vec![Declaration::new("box-sizing", Value::Keyword("border-box"))],
+fn test_import() {
+    let import = |url: &'static str, layer, supports, media| {
+        Some(ImportPrelude {
+            url: url.into(),
+            layer,
+            supports,
+            media,
+        })
     };
-    assert_eq!(ruleset(i), Ok(("", target)));
+    assert_eq!(
+        parse_import(r#""navigation.css""#),
+        import("navigation.css", None, None, "")
+    );
+    assert_eq!(
+        parse_import(r#"url("navigation.css")"#),
+        import("navigation.css", None, None, "")
+    );
+    assert_eq!(
+        parse_import("url(navigation.css) screen and (color)"),
+        import("navigation.css", None, None, "screen and (color)")
+    );
+    assert_eq!(
+        parse_import("'a.css' layer( base.theme ) print"),
+        import("a.css", Some(Some("base.theme")), None, "print")
+    );
+    assert_eq!(
+        parse_import("url(a.css) LAYER"),
+        import("a.css", Some(None), None, "")
+    );
+    assert_eq!(
+        parse_import("'a.css' layers"),
+        import("a.css", None, None, "layers")
+    );
+    assert_eq!(
+        parse_import(r#""a.css" layer(x) supports(display:grid) screen"#),
+        import("a.css", Some(Some("x")), Some("display:grid"), "screen")
+    );
+    assert_eq!(
+        parse_import("'a.css' SUPPORTS( (color: red) and (not (display: grid)) )"),
+        import(
+            "a.css",
+            None,
+            Some("(color: red) and (not (display: grid))"),
+            ""
+        )
+    );
+    assert_eq!(
+        parse_import("'a.css' supports((color: red)"),
+        import("a.css", None, None, "supports((color: red)")
+    );
+    assert_eq!(parse_import("navigation.css"), None);
+    // `url()` is matched case-insensitively, and escapes in strings are read
+    assert_eq!(
+        parse_import(r#"URL( "\62 .css" ) Print"#),
+        import("b.css", None, None, "Print")
+    );
+    assert_eq!(
+        parse_import("Url(a.css) layer(a.b)"),
+        import("a.css", Some(Some("a.b")), None, "")
+    );
+    // An empty layer name isn't an anonymous layer, and neither is one that isn't a name
+    assert_eq!(parse_import("'a.css' layer()"), None);
+    assert_eq!(parse_import("'a.css' layer(a b)"), None);
+    assert_eq!(parse_import("'a.css' layer(a..b)"), None);
+    assert_eq!(parse_import("url('a.css' 'b.css')"), None);
+}
+
+/// `tokens` without the whitespace at either end
+pub(crate) fn trim<'t, 'a>(tokens: &'t [Token<'a>]) -> &'t [Token<'a>] {
+    let start = tokens
+        .iter()
+        .position(|t| t != &Token::Whitespace)
+        .unwrap_or(tokens.len());
+    let end = tokens
+        .iter()
+        .rposition(|t| t != &Token::Whitespace)
+        .map_or(start, |i| i + 1);
+    &tokens[start..end]
 }
 
 /*
@@ -147,96 +204,62 @@ a simpleSelectorSequence is a simple selector optionally followed by more simple
 simpleSelectorSequence selectors apply when *all* their constituent selectors apply
 selectors split by combinators are more complex and represent relationships ('h1 h2' applies when h2 is inside of h1, 'div > p' applies when a div is a direct child of a p)
 */
-/// Parse comma seperated groups of selectors
-fn selector_group(input: &str) -> IResult<&str, Vec<Selector>> {
-    let (input, (first, rest)) = pair(
-        selector,
-        map(many0(tuple((chr(','), ws, selector))), |v| {
-            v.into_iter().map(|t| t.2).collect::<Vec<Selector>>()
-        }),
-    )(input)?;
-    Ok((input, [first].into_iter().chain(rest).collect()))
-}
-
-/// Parse selector
-fn selector(input: &str) -> IResult<&str, Selector> {
-    alt((combinator_selector, selector_sequence))(input)
-}
-
-fn selector_sequence(input: &str) -> IResult<&str, Selector> {
-    let (input, seq) = terminated(simple_selector_sequence, ws)(input)?;
-    if seq.len() == 1 {
-        Ok((input, Selector::Simple(seq.into_iter().next().unwrap())))
-    } else {
-        Ok((input, Selector::Compound(seq)))
-    }
-}
-
-fn combinator_selector<'a>(input: &'a str) -> IResult<&str, Selector<'a>> {
-    let (input, (first, combinated)) = pair(
-        simple_selector_sequence,
-        many1(map(
-            tuple((combinator, simple_selector_sequence, ws)),
-            |(com, sels, _)| (com, sels),
-        )),
-    )(input)?;
-
-    // Wrap `combinated` into temporary type so we can make it into a flat array
-    #[derive(Clone)]
-    enum Temp<'a> {
-        Selector(Vec<SimpleSelector<'a>>),
-        Combinator(Combinator),
+/// Parses a comma seperated group of selectors, such as those given to `querySelectorAll` or
+/// the prelude of a qualified rule. Returns [`None`] unless all of `input` is understood
+pub fn selectors(input: &str) -> Option<Vec<Selector<'_>>> {
+    let tokens: Vec<_> = Tokenizer::new(input).map(|(t, _)| t).collect();
+    tokens
+        .split(|t| t == &Token::Comma)
+        .map(|tokens| selector(trim(tokens)))
+        .collect()
+}
+
+/// Parse a selector: compound selectors joined by combinators. The combinators nest to the
+/// right, so `a b > c` is `a` and then `b > c`
+fn selector<'a>(tokens: &[Token<'a>]) -> Option<Selector<'a>> {
+    let mut tokens = Tokens::new(tokens);
+    let mut compounds = vec![simple_selector_sequence(&mut tokens)?];
+    let mut combinators = vec![];
+    while !tokens.at_end() {
+        combinators.push(combinator(&mut tokens)?);
+        compounds.push(simple_selector_sequence(&mut tokens)?);
     }
-    let arr: Vec<Temp> = [Temp::Selector(first)]
-        .into_iter()
-        .chain(
-            combinated
-                .into_iter()
-                .flat_map(|(com, sel)| [Temp::Combinator(com), Temp::Selector(sel)]),
-        )
-        .collect();
-    fn peeler(v: Vec<Temp>) -> Selector {
-        if v.len() == 3 {
-            match &v[..] {
-                [Temp::Selector(s1), Temp::Combinator(c), Temp::Selector(s2)] => {
-                    combinator_selector!(
-                        Selector::Compound(s1.clone()),
-                        *c,
-                        Selector::Compound(s2.clone())
-                    )
-                }
-                _ => unreachable!(),
-            }
+    let mut last = compounds.pop().unwrap();
+    if combinators.is_empty() {
+        return Some(if last.len() == 1 {
+            Selector::Simple(last.pop().unwrap())
         } else {
-            let (start, rest) = v.split_at(2);
-            match start {
-                [Temp::Selector(s1), Temp::Combinator(c)] => {
-                    combinator_selector!(Selector::Compound(s1.clone()), *c, peeler(rest.to_vec()))
-                }
-                _ => unreachable!(),
-            }
-        }
+            Selector::Compound(last)
+        });
     }
-    Ok((input, peeler(arr)))
+    let selector = compounds
+        .into_iter()
+        .zip(combinators)
+        .rev()
+        .fold(Selector::Compound(last), |right, (left, c)| {
+            combinator_selector!(Selector::Compound(left), c, right)
+        });
+    Some(selector)
 }
 
 #[cfg(test)]
 #[test]
 fn test_combinator_selectors() {
+    let selector = |i| selectors(i).unwrap().remove(0);
     let i = "div > p";
     let target = combinator_selector!(
         compound_selector![simple_selector!(div)],
         Combinator::Child,
         compound_selector![simple_selector!(p)]
     );
-    assert_eq!(selector(i), Ok(("", target)));
-    let i = "div + p";
+    assert_eq!(selector(i), target);
+    let i = "div+p";
     let target = combinator_selector!(
         compound_selector![simple_selector!(div)],
         Combinator::NextSibling,
         compound_selector![simple_selector!(p)]
     );
-    assert_eq!(selector(i), Ok(("", target)));
+    assert_eq!(selector(i), target);
 
     let i = "div ~ p";
     let target = combinator_selector!(
@@ -244,15 +267,15 @@ fn test_combinator_selectors() {
         Combinator::SubsequentSibling,
         compound_selector![simple_selector!(p)]
     );
-    assert_eq!(selector(i), Ok(("", target)));
+    assert_eq!(selector(i), target);
 
-    let i = "div p";
+    let i = "div /* comment */ p";
     let target = combinator_selector!(
         compound_selector![simple_selector!(div)],
         Combinator::Descendant,
         compound_selector![simple_selector!(p)]
     );
-    assert_eq!(selector(i), Ok(("", target)));
+    assert_eq!(selector(i), target);
 
     let i = "a b > c";
     let target = combinator_selector!(
@@ -264,364 +287,344 @@ fn test_combinator_selectors() {
             compound_selector![simple_selector!(c)]
         )
     );
-    assert_eq!(selector(i), Ok(("", target)));
-}
-
-fn combinator(input: &str) -> IResult<&str, Combinator> {
-    let (input, val) = alt((
-        terminated(value(' ', ws), peek(simple_selector_sequence)),
-        delimited(opt(ws), one_of("+>~"), opt(ws)),
-    ))(input)?;
-    let c = match val {
-        ' ' => Combinator::Descendant,
-        '>' => Combinator::Child,
-        '+' => Combinator::NextSibling,
-        '~' => Combinator::SubsequentSibling,
-        _ => unreachable!(),
+    assert_eq!(selector(i), target);
+    assert_eq!(selectors("a >"), None);
+    assert_eq!(selectors("a, , b"), None);
+}
+
+fn combinator(tokens: &mut Tokens<Token>) -> Option<Combinator> {
+    let space = tokens.skip_whitespace();
+    let c = match tokens.peek() {
+        Some(Token::Delim('>')) => Combinator::Child,
+        Some(Token::Delim('+')) => Combinator::NextSibling,
+        Some(Token::Delim('~')) => Combinator::SubsequentSibling,
+        _ if space => return Some(Combinator::Descendant),
+        _ => return None,
     };
-    Ok((input, c))
-}
-
-fn make_selector<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
-    recognize(pair(chr(c), name))
-}
-
-fn simple_selector_sequence(input: &str) -> IResult<&str, Vec<SimpleSelector>> {
-    let selectors = alt((
-        make_selector('#'),
-        make_selector('.'), /*attrib*/
-        make_selector(':'),
-    ));
-    let selectors2 = alt((
-        make_selector('#'),
-        make_selector('.'), /*attrib*/
-        make_selector(':'),
-    ));
-
-    let element_or_universal = alt((ident, tag("*")));
-    let (input, (first, rest)) =
-        tuple((alt((element_or_universal, selectors)), many0(selectors2)))(input)?;
-    let mut selectors = vec![first];
-    selectors.extend(rest);
-    Ok((input, selectors.into_iter().map(simple_selector).collect()))
+    tokens.bump();
+    tokens.skip_whitespace();
+    Some(c)
+}
+
+/// Parse a type or universal selector followed by ID, class, attribute and pseudo-class
+/// selectors, with nothing between them
+fn simple_selector_sequence<'a>(tokens: &mut Tokens<Token<'a>>) -> Option<Vec<SimpleSelector<'a>>> {
+    let mut selectors = vec![];
+    match tokens.peek() {
+        Some(Token::Ident(name)) => selectors.push(SimpleSelector::Type(name.clone())),
+        Some(Token::Delim('*')) => selectors.push(SimpleSelector::Universal),
+        _ => {}
+    }
+    if !selectors.is_empty() {
+        tokens.bump();
+    }
+    loop {
+        let selector = match tokens.peek() {
+            Some(Token::Hash { value, id: true }) => SimpleSelector::ID(value.clone()),
+            Some(Token::Delim('.')) => match tokens.bump().and(tokens.peek()) {
+                Some(Token::Ident(name)) => SimpleSelector::Class(name.clone()),
+                _ => return None,
+            },
+            Some(Token::Colon) => match tokens.bump().and(tokens.peek()) {
+                Some(Token::Ident(name)) => SimpleSelector::PseudoClass(name.clone()),
+                _ => return None,
+            },
+            Some(Token::OpenSquare) => {
+                tokens.bump();
+                SimpleSelector::Attribute(attribute_selector(tokens)?)
+            }
+            _ => break,
+        };
+        selectors.push(selector);
+        tokens.bump();
+    }
+    (!selectors.is_empty()).then_some(selectors)
 }
 
-fn simple_selector(input: &str) -> SimpleSelector {
-    let mut it = input.chars();
-    #[allow(unreachable_code)]
-    match it.next().unwrap() {
-        '#' => SimpleSelector::ID(&input[1..]),
-        '.' => SimpleSelector::Class(&input[1..]),
-        '*' => SimpleSelector::Universal,
-        ':' => SimpleSelector::PseudoClass(&input[1..]),
-        '[' => SimpleSelector::Attribute(todo!()),
-        _ => SimpleSelector::Type(input),
+#[cfg(test)]
+#[test]
+fn test_simple_selectors() {
+    use crate::SimpleSelector::*;
+    let selector = |i| match selectors(i)?.remove(0) {
+        Selector::Simple(s) => Some(vec![s]),
+        Selector::Compound(s) => Some(s),
+        _ => None,
+    };
+    assert_eq!(
+        selector("p#a.b:first-child"),
+        Some(vec![
+            simple_selector!(p),
+            simple_selector!(#a),
+            simple_selector!(.b),
+            simple_selector!(:first-child),
+        ])
+    );
+    // Escapes are read as the characters they stand for
+    assert_eq!(selector(r".\31 a"), Some(vec![Class("1a".into())]));
+    assert_eq!(selector(r"#x\:y"), Some(vec![ID("x:y".into())]));
+    assert_eq!(selector("*.a"), Some(vec![Universal, Class("a".into())]));
+    assert_eq!(selector("#1a"), None);
+    assert_eq!(selector("a. b"), None);
+    assert_eq!(selector("a::before"), None);
+    assert_eq!(selector("a:not(b)"), None);
+}
+
+/// Parse an attribute selector, like `[href]` or `[lang|="en"]`, after its `[`. The `]` is
+/// left for the caller to move past
+fn attribute_selector<'a>(tokens: &mut Tokens<Token<'a>>) -> Option<AttributeSelector<'a>> {
+    tokens.skip_whitespace();
+    let name = match tokens.bump()? {
+        Token::Ident(name) => name.clone(),
+        _ => return None,
+    };
+    tokens.skip_whitespace();
+    if tokens.peek()? == &Token::CloseSquare {
+        return Some(AttributeSelector::Has(name));
     }
+    let operator = match tokens.bump()? {
+        Token::Delim('=') => '=',
+        Token::Delim(c @ ('~' | '|' | '^' | '$' | '*')) if tokens.eat(&Token::Delim('=')) => *c,
+        _ => return None,
+    };
+    tokens.skip_whitespace();
+    let value = match tokens.bump()? {
+        Token::Ident(value) | Token::String(value) => value.clone(),
+        _ => return None,
+    };
+    tokens.skip_whitespace();
+    if tokens.peek()? != &Token::CloseSquare {
+        return None;
+    }
+    Some(match operator {
+        '=' => AttributeSelector::Equals(name, value),
+        '~' => AttributeSelector::Includes(name, value),
+        '|' => AttributeSelector::DashMatch(name, value),
+        '^' => AttributeSelector::Prefix(name, value),
+        '$' => AttributeSelector::Suffix(name, value),
+        _ => AttributeSelector::Substring(name, value),
+    })
 }
 
-/// Parse list of declarations
-fn declaration_list(input: &str) -> IResult<&str, Vec<Declaration>> {
-    let (input, (_, first, _, rest)) = tuple((
-        many0(pair(chr(';'), ws)),
-        declaration,
-        ws,
-        many0(map(tuple((chr(';'), ws, opt(declaration))), |t| t.2)),
-    ))(input)?;
-    Ok((
-        input,
-        [first]
-            .into_iter()
-            .chain(rest.into_iter().flatten())
-            .collect(),
-    ))
+#[cfg(test)]
+#[test]
+fn test_attribute_selector() {
+    use crate::AttributeSelector::*;
+    let attribute = |input| match selectors(input)?.remove(0) {
+        Selector::Simple(SimpleSelector::Attribute(attribute)) => Some(attribute),
+        _ => None,
+    };
+    assert_eq!(attribute("[href]"), Some(Has("href".into())));
+    assert_eq!(
+        attribute("[ type = text ]"),
+        Some(Equals("type".into(), "text".into()))
+    );
+    assert_eq!(
+        attribute("[rel~=next]"),
+        Some(Includes("rel".into(), "next".into()))
+    );
+    assert_eq!(
+        attribute(r#"[lang|="en"]"#),
+        Some(DashMatch("lang".into(), "en".into()))
+    );
+    assert_eq!(
+        attribute("[href^='https:']"),
+        Some(Prefix("href".into(), "https:".into()))
+    );
+    assert_eq!(attribute("[src$=.png]"), None);
+    assert_eq!(
+        attribute("[src$='.png']"),
+        Some(Suffix("src".into(), ".png".into()))
+    );
+    assert_eq!(
+        attribute("[title*=\"a \\\"b\\\"\"]"),
+        Some(Substring("title".into(), "a \"b\"".into()))
+    );
+    assert_eq!(attribute("[data-x=]"), None);
+    assert_eq!(attribute("[=a]"), None);
+    assert_eq!(attribute("[a ~ = b]"), None);
+    assert_eq!(attribute("[a"), None);
 }
 
-/// Parse single declaration
-fn declaration(input: &str) -> IResult<&str, Declaration> {
-    let (input, (prop, _, _, value, _)) =
-        tuple((property, chr(':'), ws, expr, opt(priority)))(input)?;
-    Ok((input, Declaration { name: prop, value }))
+/// Parse the value of a declaration
+pub(crate) fn parse_value(input: &str) -> Option<Value<'_>> {
+    let tokens: Vec<_> = Tokenizer::new(input).map(|(t, _)| t).collect();
+    parse_value_tokens(trim(&tokens))
 }
 
-/// Parse property
-fn property(input: &str) -> IResult<&str, &str> {
-    alt((ident, variable))(input)
+/// Build the value of a declaration from its tokens, which have no whitespace around them
+pub(crate) fn parse_value_tokens<'a>(tokens: &[Token<'a>]) -> Option<Value<'a>> {
+    let mut tokens = Tokens::new(tokens);
+    let value = expr(&mut tokens)?;
+    tokens.at_end().then_some(value)
 }
 
 /// Parse expression
-fn expr(input: &str) -> IResult<&str, Value> {
-    let (input, (result, others)) = pair(term, many0(pair(operator, term)))(input)?;
-    if !others.is_empty() {
-        let all = [(None, result)]
-            .into_iter()
-            .chain(others.into_iter().map(|(op, t)| (Some(op), t)))
-            .collect();
-        Ok((input, Value::Multiple(MultiValue(all))))
-    } else {
-        Ok((input, result))
+fn expr<'a>(tokens: &mut Tokens<Token<'a>>) -> Option<Value<'a>> {
+    let mut values = vec![(None, term(tokens)?)];
+    while let Some(operator) = operator(tokens) {
+        values.push((Some(operator), term(tokens)?));
     }
+    Some(if values.len() == 1 {
+        values.pop().unwrap().1
+    } else {
+        Value::Multiple(MultiValue(values))
+    })
 }
 
 #[cfg(test)]
 #[test]
 fn test_expr() {
-    use crate::Value::{Keyword, Length};
-    let i = "5em auto";
-    let target = Ok((
-        "",
-        Value::Multiple(MultiValue(vec![
-            (None, Length(5.0, Unit::Em)),
-            (Some(Operator::Space), Keyword("auto")),
-        ])),
-    ));
-    assert_eq!(expr(i), target);
+    use crate::Value::{Keyword, Length, Number};
+    let target = Value::Multiple(MultiValue(vec![
+        (None, Length(5.0, Unit::Em)),
+        (Some(Operator::Space), Keyword("auto")),
+    ]));
+    assert_eq!(parse_value("5em auto"), Some(target));
+    let target = Value::Multiple(MultiValue(vec![
+        (None, Length(1000.0, Unit::Px)),
+        (Some(Operator::Slash), Number(1.5)),
+        (Some(Operator::Comma), Value::String("\"".into())),
+    ]));
+    assert_eq!(parse_value(r#"1e3px / 15E-1, "\"""#), Some(target));
+    assert_eq!(parse_value("1px !"), None);
+    assert_eq!(parse_value("1s"), None);
+}
+
+/// Parse the operator between two terms. Terms with nothing between them are separated by
+/// spaces, like those with whitespace (or comments) between them
+fn operator(tokens: &mut Tokens<Token>) -> Option<Operator> {
+    tokens.skip_whitespace();
+    let op = match tokens.peek()? {
+        Token::Delim('/') => Operator::Slash,
+        Token::Comma => Operator::Comma,
+        Token::Delim('=') => Operator::Equals,
+        _ => return Some(Operator::Space),
+    };
+    tokens.bump();
+    tokens.skip_whitespace();
+    Some(op)
 }
 
 /// Parse a term
-fn term(input: &str) -> IResult<&str, Value> {
-    alt((
-        map(function, function_to_value),
-        percentage,
-        dimension,
-        number,
-        map(string, Value::String),
-        map(ident, keyword_to_value),
-        map(variable, Value::Keyword),
-        map(uri, Value::Url),
-        hexcolor,
-        // calc,
-    ))(input)
-}
-
-fn number(input: &str) -> IResult<&str, Value> {
-    let (input, (sign, number)) = pair(opt(one_of("+-")), digit1)(input)?;
-    let sign = sign.unwrap_or('+');
-    let val = format!("{}{}", sign, number).parse().unwrap();
-    Ok((input, Value::Number(val)))
-}
-fn percentage(input: &str) -> IResult<&str, Value> {
-    let (input, (sign, number, _)) = tuple((opt(one_of("+-")), digit1, chr('%')))(input)?;
-    let sign = sign.unwrap_or('+');
-    let val = format!("{}{}", sign, number).parse().unwrap();
-    Ok((input, Value::Percentage(val)))
-}
-fn dimension(input: &str) -> IResult<&str, Value> {
-    let (input, (value, unit)) = pair(number, dimension_unit)(input)?;
-    if let Value::Number(v) = value {
-        Ok((input, Value::Length(v, unit)))
-    } else {
-        unreachable!()
+fn term<'a>(tokens: &mut Tokens<Token<'a>>) -> Option<Value<'a>> {
+    if let Some(Token::Function(name)) = tokens.peek() {
+        let args = trim(tokens.block());
+        return function(name, args);
     }
+    let value = match tokens.bump()? {
+        Token::Url(url) => Value::Url(url.clone()),
+        Token::Percentage(n) => Value::Percentage(*n),
+        Token::Dimension { value, unit, .. } => Value::Length(*value, dimension_unit(unit)?),
+        Token::Number { value, .. } => Value::Number(*value),
+        Token::String(s) => Value::String(s.clone()),
+        // Keywords are borrowed from the stylesheet, so ones with escapes aren't understood
+        Token::Ident(Cow::Borrowed(ident)) => keyword_to_value(ident),
+        Token::Hash { value, .. } => hexcolor(value)?,
+        _ => return None,
+    };
+    Some(value)
 }
-fn dimension_unit(input: &str) -> IResult<&str, Unit> {
-    let abs = alt((
-        tag_no_case("px"),
-        tag_no_case("cm"),
-        tag_no_case("mm"),
-        tag_no_case("in"),
-        tag_no_case("pt"),
-        tag_no_case("pc"),
-        tag_no_case("q"),
-    ));
-    let font_rel = alt((
-        tag_no_case("em"),
-        tag_no_case("ex"),
-        tag_no_case("ch"),
-        tag_no_case("rem"),
-    ));
-    let vp_rel = alt((
-        tag_no_case("vw"),
-        tag_no_case("vh"),
-        tag_no_case("vmin"),
-        tag_no_case("vmax"),
-    ));
-    let length = alt((abs, font_rel, vp_rel));
-    let (input, unit): (&str, &str) = alt((
-        length,
-        tag_no_case("ms"),
-        tag_no_case("s"),
-        // Freq
-        tag_no_case("hz"),
-        tag_no_case("khz"),
-        // Resolution
-        tag_no_case("dpi"),
-        tag_no_case("dpcm"),
-        tag_no_case("dppx"),
-        // Angle
-        tag_no_case("deg"),
-        tag_no_case("rad"),
-        tag_no_case("grad"),
-        tag_no_case("turn"),
-    ))(input)?;
-    let unit = match unit.to_lowercase().as_str() {
+
+fn dimension_unit(unit: &str) -> Option<Unit> {
+    Some(match unit.to_ascii_lowercase().as_str() {
         "px" => Unit::Px,
         "cm" => Unit::Cm,
         "mm" => Unit::Mm,
+        "q" => Unit::Q,
+        "in" => Unit::In,
+        "pc" => Unit::Pc,
+        "pt" => Unit::Pt,
         "em" => Unit::Em,
-        _ => todo!("{}", unit),
+        "ex" => Unit::Ex,
+        "ch" => Unit::Ch,
+        "rem" => Unit::Rem,
+        "vw" => Unit::Vw,
+        "vh" => Unit::Vh,
+        "vmin" => Unit::Vmin,
+        "vmax" => Unit::Vmax,
+        // Times, frequencies, resolutions and angles aren't supported yet
+        _ => return None,
+    })
+}
+
+/// Parse the hex digits of a color, after its `#`
+fn hexcolor(hex: &str) -> Option<Value<'static>> {
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    // Colors with 3 digits are promoted to 6
+    let hex: String = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 => hex.to_string(),
+        _ => return None,
     };
-    Ok((input, unit))
-}
-fn hexcolor(input: &str) -> IResult<&str, Value> {
-    let is_hex_str = |c: &str| c.bytes().all(|c| c.is_hex_digit());
-    let long_form = map(verify(take::<usize, &str, _>(6), is_hex_str), |s| {
-        s.to_string()
-    });
-    let short_form = verify(
-        map(take::<usize, &str, _>(3), |val| {
-            val.chars()
-                .flat_map(|c| [c, c].into_iter())
-                .collect::<String>()
-        }),
-        is_hex_str,
-    );
-    let (input, hex_val) = preceded(chr('#'), alt((long_form, short_form)))(input)?;
-    // We should be provided a string of length 3 or 6, with 3 being promoted to 6
-    assert_eq!(hex_val.len(), 6);
-    let hex_val: Vec<char> = hex_val.chars().collect();
-    let mut values: Vec<u8> = hex_val
-        .as_slice()
+    let mut values: Vec<u8> = hex
+        .as_bytes()
         .chunks(2)
-        .map(|v| u8::from_str_radix(v.iter().collect::<String>().as_str(), 16).unwrap())
+        .map(|v| u8::from_str_radix(std::str::from_utf8(v).unwrap(), 16).unwrap())
         .collect();
     values.push(255);
 
     let col = ColorValue::new(values.as_slice());
-    Ok((input, Value::Color(col)))
+    Some(Value::Color(col))
 }
 #[cfg(test)]
 #[test]
 fn test_hexcolor() {
-    let i = "#112233";
     let target = Value::Color(ColorValue::new(&[0x11, 0x22, 0x33, 0xff]));
-    assert_eq!(hexcolor(i), Ok(("", target)));
-
-    let i = "#123";
-    let target = Value::Color(ColorValue::new(&[0x11, 0x22, 0x33, 0xff]));
-    assert_eq!(hexcolor(i), Ok(("", target)));
-
-    let i = "#ggg";
-    assert!(hexcolor(i).is_err());
-}
-
-fn _calc(_input: &str) -> IResult<&str, Value> {
-    todo!()
-}
-fn function(input: &str) -> IResult<&str, FunctionValue> {
-    let (input, (name, _, _, args, _, _)) =
-        tuple((ident, chr('('), ws, expr, chr(')'), ws))(input)?;
-    if let Value::Multiple(v) = args {
-        let value = v.0;
-        if value.len() == 1 {
-            // Just a single value
-            Ok((
-                input,
-                FunctionValue(name, value.into_iter().map(|v| v.1).collect()),
-            ))
-        } else if value[1..]
-            .iter()
-            .all(|t| matches!(t.0, Some(Operator::Comma)))
-        {
-            // A comma-separated argument list
-            Ok((
-                input,
-                FunctionValue(name, value.into_iter().map(|v| v.1).collect()),
-            ))
-        } else {
-            // Not comma separated? Bail lol
-            unimplemented!()
-        }
-    } else {
-        Ok((input, FunctionValue(name, vec![args])))
+    assert_eq!(parse_value("#112233"), Some(target.clone()));
+    assert_eq!(parse_value("#123"), Some(target));
+    assert_eq!(parse_value("#ggg"), None);
+    assert_eq!(parse_value("#1234"), None);
+}
+
+/// Parse a function from the tokens of its arguments. `url("...")` is a URL, as its quoted
+/// argument keeps it from being read as a URL token
+fn function<'a>(name: &Cow<'a, str>, args: &[Token<'a>]) -> Option<Value<'a>> {
+    let name = match name {
+        Cow::Borrowed(name) => *name,
+        Cow::Owned(_) => return None,
+    };
+    if name.eq_ignore_ascii_case("url") {
+        return match args {
+            [Token::String(url)] => Some(Value::Url(url.clone())),
+            _ => None,
+        };
     }
-}
-
-fn operator(input: &str) -> IResult<&str, Operator> {
-    let (input, (op, _)) = pair(alt((chr('/'), chr(','), chr(' '), chr('='))), ws)(input)?;
-    let op = match op {
-        '/' => Operator::Slash,
-        ',' => Operator::Comma,
-        ' ' => Operator::Space,
-        '=' => Operator::Equals,
-        _ => unreachable!(),
+    if args.is_empty() {
+        return Some(function_to_value(FunctionValue(name, vec![])));
+    }
+    let mut tokens = Tokens::new(args);
+    let args = expr(&mut tokens).filter(|_| tokens.at_end())?;
+    let args = match args {
+        Value::Multiple(v) => {
+            // Arguments which aren't comma separated, as in `calc()`, aren't supported yet
+            if !v.0[1..]
+                .iter()
+                .all(|t| matches!(t.0, Some(Operator::Comma)))
+            {
+                return None;
+            }
+            v.0.into_iter().map(|v| v.1).collect()
+        }
+        args => vec![args],
     };
-    Ok((input, op))
-}
-/// Parse priority
-fn priority(input: &str) -> IResult<&str, ()> {
-    value((), pair(tag("important"), ws))(input)
-}
-
-#[cfg(test)]
-#[test]
-fn test_declaration_list() {
-    let i = r#"color: black;
-background-color: rgb(197,93,161)"#;
-    let target = vec![
-        Declaration {
-            name: "color",
-            value: Value::Color(BLACK),
-        },
-        Declaration {
-            name: "background-color",
-            value: Value::Color(ColorValue {
-                r: 197,
-                g: 93,
-                b: 161,
-                a: 255,
-            }),
-        },
-    ];
-    assert_eq!(declaration_list(i), Ok(((""), target)))
-}
-
-/// Another whitespace parse
-fn ws(input: &str) -> IResult<&str, ()> {
-    value(
-        (),
-        many0(alt((
-            delimited(tag("/*"), take_until("*/"), tag("*/")),
-            multispace1,
-        ))),
-    )(input)
-}
-/// Parse URI
-fn uri(input: &str) -> IResult<&str, &str> {
-    let (input, (_, url, _)) = delimited(
-        tag("url("),
-        tuple((multispace0, string, multispace0)),
-        tag(")"),
-    )(input)?;
-    Ok((input, url))
+    Some(function_to_value(FunctionValue(name, args)))
 }
 
-/// Parse name
-fn name(input: &str) -> IResult<&str, &str> {
-    let nmchar = alt((alphanumeric1, alt((tag("_"), tag("-")))));
-    let (input, vals) = recognize(many1(nmchar))(input)?;
-    Ok((input, vals))
-}
-/// Parse ident
-fn ident(input: &str) -> IResult<&str, &str> {
-    let nmstart = alt((alphanumeric1, tag("_")));
-    let nmchar = alt((alphanumeric1, alt((tag("_"), tag("-")))));
-    let (input, identifier) = recognize(tuple((opt(chr('-')), nmstart, many0(nmchar))))(input)?;
-    Ok((input, identifier))
-}
-/// Parse variable
-fn variable(input: &str) -> IResult<&str, &str> {
-    let nmstart = alt((alphanumeric1, tag("_")));
-    let nmchar = alt((alphanumeric1, alt((tag("_"), tag("-")))));
-    let (input, identifier) = recognize(tuple((tag("--"), nmstart, many0(nmchar))))(input)?;
-    Ok((input, identifier))
-}
 #[cfg(test)]
 #[test]
-fn test_name() {
-    let i = "hello";
-    let target = ("", "hello");
-    assert_eq!(name(i).unwrap(), target);
-
-    let i = "~hello";
-    assert!(name(i).is_err());
+fn test_function() {
+    let color = |r, g, b| Some(Value::Color(ColorValue::new(&[r, g, b, 255])));
+    assert_eq!(parse_value("rgb( 1, 2,3 )"), color(1, 2, 3));
+    assert_eq!(
+        parse_value("url( 'a.png' )"),
+        Some(Value::Url("a.png".into()))
+    );
+    assert_eq!(
+        parse_value("url( a\\ b.png )"),
+        Some(Value::Url("a b.png".into()))
+    );
+    assert_eq!(parse_value("calc(1px + 2px)"), None);
+    assert_eq!(parse_value("f(1px"), parse_value("f(1px)"));
 }
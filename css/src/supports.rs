@@ -0,0 +1,178 @@
+//! Evaluating the conditions of `@supports` rules
+//! <https://www.w3.org/TR/css-conditional-3/#at-supports>
+
+use crate::condition::Tokens;
+use crate::parsing::{parse_value, selectors};
+use crate::tokenizer::{Token, Tokenizer};
+use crate::Value;
+use std::ops::Range;
+
+/// The properties which browsah lays out or paints, along with the keywords it understands for
+/// those whose values are keywords. Other values of these are supported if they can be parsed
+static SUPPORTED: &[(&str, Option<&[&str]>)] = &[
+    ("display", Some(&["block", "inline", "none"])),
+    (
+        "white-space",
+        Some(&["normal", "pre", "nowrap", "pre-wrap", "pre-line"]),
+    ),
+    ("width", None),
+    ("height", None),
+    ("margin", None),
+    ("margin-top", None),
+    ("margin-right", None),
+    ("margin-bottom", None),
+    ("margin-left", None),
+    ("padding", None),
+    ("padding-top", None),
+    ("padding-right", None),
+    ("padding-bottom", None),
+    ("padding-left", None),
+    ("border", None),
+    ("border-top", None),
+    ("border-right", None),
+    ("border-bottom", None),
+    ("border-left", None),
+    ("background", None),
+    ("background-color", None),
+    ("color", None),
+    ("font-size", None),
+];
+
+/// Whether the declaration `name: value` is one browsah understands
+fn supports_declaration(name: &str, value: &str) -> bool {
+    let keywords = match SUPPORTED
+        .iter()
+        .find(|(property, _)| property.eq_ignore_ascii_case(name))
+    {
+        Some((_, keywords)) => keywords,
+        None => return false,
+    };
+    match (parse_value(value), keywords) {
+        (Some(Value::Keyword(keyword)), Some(keywords)) => {
+            keywords.iter().any(|k| k.eq_ignore_ascii_case(keyword))
+        }
+        (_, Some(_)) => false,
+        (value, None) => value.is_some(),
+    }
+}
+
+/// Whether the condition of an `@supports` rule holds. A declaration is supported if browsah
+/// uses its property, and understands its value. Conditions which are invalid never hold
+pub fn supports(condition: &str) -> bool {
+    let tokens: Vec<_> = Tokenizer::new(condition)
+        .filter(|(t, _)| t != &Token::Whitespace)
+        .collect();
+    let mut parser = ConditionParser::new(condition, &tokens);
+    parser
+        .condition()
+        .filter(|_| parser.at_end())
+        .unwrap_or(false)
+}
+
+/// Whether the argument of the `supports()` of an `@import` holds. It is either a condition, or
+/// a declaration on its own
+pub(crate) fn supports_import(argument: &str) -> bool {
+    supports(argument) || supports(&format!("({})", argument))
+}
+
+/// Evaluates conditions from tokens without whitespace, which are in `input` at their ranges
+struct ConditionParser<'t, 'a> {
+    input: &'a str,
+    tokens: Tokens<'t, (Token<'a>, Range<usize>)>,
+}
+
+impl<'t, 'a> ConditionParser<'t, 'a> {
+    fn new(input: &'a str, tokens: &'t [(Token<'a>, Range<usize>)]) -> Self {
+        Self {
+            input,
+            tokens: Tokens::new(tokens),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.tokens.at_end()
+    }
+
+    /// The source of `tokens`
+    fn text(&self, tokens: &[(Token<'a>, Range<usize>)]) -> &'a str {
+        match (tokens.first(), tokens.last()) {
+            (Some((_, first)), Some((_, last))) => &self.input[first.start..last.end],
+            _ => "",
+        }
+    }
+
+    /// `<supports-condition>`, or [`None`] if it isn't valid
+    fn condition(&mut self) -> Option<bool> {
+        if self.tokens.eat_ident("not") {
+            return self.in_parens().map(|result| !result);
+        }
+        let mut result = self.in_parens()?;
+        let operator = match self.tokens.ident().as_deref() {
+            Some("and") => "and",
+            Some("or") => "or",
+            _ => return Some(result),
+        };
+        while self.tokens.eat_ident(operator) {
+            let next = self.in_parens()?;
+            result = match operator {
+                "and" => result && next,
+                _ => result || next,
+            };
+        }
+        Some(result)
+    }
+
+    /// `<supports-in-parens>`. Anything in parentheses which isn't understood is false
+    fn in_parens(&mut self) -> Option<bool> {
+        match self.tokens.peek()? {
+            Token::OpenParen => {
+                let inner = self.tokens.block();
+                let mut parser = ConditionParser::new(self.input, inner);
+                if let Some(result) = parser.condition().filter(|_| parser.at_end()) {
+                    return Some(result);
+                }
+                Some(match inner {
+                    [(Token::Ident(name), _), (Token::Colon, _), value @ ..]
+                        if !value.is_empty() =>
+                    {
+                        supports_declaration(name, self.text(value))
+                    }
+                    _ => false,
+                })
+            }
+            Token::Function(name) => {
+                let selector = name.eq_ignore_ascii_case("selector");
+                let inner = self.tokens.block();
+                Some(selector && selectors(self.text(inner)).is_some())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_supports() {
+    assert!(supports("(display: block)"));
+    assert!(supports("(White-Space: PRE)"));
+    assert!(supports("(color: red) and (margin: 1px 2px)"));
+    // Properties and keywords which aren't implemented aren't supported, even when valid
+    assert!(!supports("(display: grid)"));
+    assert!(supports("not (display: grid)"));
+    assert!(!supports("(float: left)"));
+    assert!(!supports("(display: 1px)"));
+    assert!(supports("not (width: {})"));
+    assert!(supports(
+        "(width: {}) or ((color: red) and selector(a > b))"
+    ));
+    assert!(supports("selector(.a .b)"));
+    assert!(!supports("(width: {})"));
+    assert!(!supports("selector(a >)"));
+    assert!(!supports("(color: red) and (width: {})"));
+    assert!(!supports("unknown(x)"));
+    // Invalid conditions
+    assert!(!supports("not (color: red) or (width: 1px)"));
+    assert!(!supports("(color: red) and (width: 1px) or (margin: 0)"));
+    assert!(!supports("color: red"));
+    assert!(!supports(""));
+}
@@ -0,0 +1,526 @@
+//! Implements the CSS parsing stage, which splits a stylesheet into rules and declarations
+//! <https://www.w3.org/TR/css-syntax-3/#parsing>
+//!
+//! Anything which is invalid is skipped over as the spec says, so an error only loses the
+//! declaration or rule it is in, and not the rest of the stylesheet. The preludes of rules are
+//! left as source text and the values of declarations as tokens, for the grammars in
+//! [`crate::parsing`] to build selectors and values from
+
+use crate::parsing::{parse_import, parse_value_tokens, selectors, trim};
+use crate::supports::supports_import;
+use crate::tokenizer::{Token, Tokenizer};
+use crate::{AtRule, Declaration, Import, Keyframe, MediaQueryList, Rule, Ruleset, Stylesheet};
+use std::borrow::Cow;
+use std::ops::Range;
+use tracing::{debug, span, Level};
+
+/// A rule before its prelude and block have been parsed
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RawRule<'a> {
+    Qualified {
+        prelude: &'a str,
+        block: &'a str,
+    },
+    At {
+        name: Cow<'a, str>,
+        prelude: &'a str,
+        // Statements like `@import` end with a `;` rather than a block
+        block: Option<&'a str>,
+    },
+}
+
+/// A declaration before its value has been parsed
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RawDeclaration<'a> {
+    // Lowercased, apart from the names of custom properties which are case-sensitive
+    pub name: Cow<'a, str>,
+    // The tokens of the value, without whitespace around them
+    pub value: Vec<Token<'a>>,
+    pub important: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Curly,
+    Square,
+    Paren,
+}
+
+impl Block {
+    fn opened_by(token: &Token) -> Option<Self> {
+        match token {
+            Token::OpenCurly => Some(Block::Curly),
+            Token::OpenSquare => Some(Block::Square),
+            Token::OpenParen | Token::Function(_) => Some(Block::Paren),
+            _ => None,
+        }
+    }
+
+    fn closed_by(self, token: &Token) -> bool {
+        matches!(
+            (self, token),
+            (Block::Curly, Token::CloseCurly)
+                | (Block::Square, Token::CloseSquare)
+                | (Block::Paren, Token::CloseParen)
+        )
+    }
+}
+
+pub(crate) struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(Token<'a>, Range<usize>)>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            tokens: Tokenizer::new(input).collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    /// Where the next token starts, or the end of the input
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map(|(_, r)| r.start)
+            .unwrap_or(self.input.len())
+    }
+
+    /// The source from `start` up to the next token, without surrounding whitespace
+    fn source(&self, start: usize) -> &'a str {
+        self.input[start..self.offset()].trim()
+    }
+
+    /// Skip a component value: a token, or a whole block or function with what's inside it.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-component-value>
+    fn skip_component(&mut self) {
+        let block = match self.tokens.get(self.pos) {
+            Some((token, _)) => Block::opened_by(token),
+            None => return,
+        };
+        self.pos += 1;
+        if let Some(block) = block {
+            while let Some(token) = self.peek() {
+                if block.closed_by(token) {
+                    self.pos += 1;
+                    return;
+                }
+                self.skip_component();
+            }
+        }
+    }
+
+    /// Skip a `{}` block, returning the source inside of it
+    fn block(&mut self) -> &'a str {
+        self.pos += 1;
+        let start = self.offset();
+        while let Some(token) = self.peek() {
+            if token == &Token::CloseCurly {
+                let block = &self.input[start..self.offset()];
+                self.pos += 1;
+                return block;
+            }
+            self.skip_component();
+        }
+        &self.input[start..]
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-list-of-rules>
+    pub fn rules(&mut self, top_level: bool) -> Vec<RawRule<'a>> {
+        let mut rules = vec![];
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Whitespace => self.pos += 1,
+                // These are only there to hide stylesheets from ancient browsers
+                Token::Cdo | Token::Cdc if top_level => self.pos += 1,
+                Token::AtKeyword(_) => rules.push(self.at_rule()),
+                _ => rules.extend(self.qualified_rule()),
+            }
+        }
+        rules
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-at-rule>
+    fn at_rule(&mut self) -> RawRule<'a> {
+        let name = match self.tokens[self.pos].0.clone() {
+            Token::AtKeyword(name) => name,
+            _ => unreachable!("At-rules start with an at-keyword"),
+        };
+        self.pos += 1;
+        let start = self.offset();
+        let mut block = None;
+        let mut prelude = self.source(start);
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Semicolon => {
+                    prelude = self.source(start);
+                    self.pos += 1;
+                    break;
+                }
+                Token::OpenCurly => {
+                    prelude = self.source(start);
+                    block = Some(self.block());
+                    break;
+                }
+                _ => {
+                    self.skip_component();
+                    prelude = self.source(start);
+                }
+            }
+        }
+        RawRule::At {
+            name,
+            prelude,
+            block,
+        }
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-qualified-rule>
+    fn qualified_rule(&mut self) -> Option<RawRule<'a>> {
+        let start = self.offset();
+        while let Some(token) = self.peek() {
+            if token == &Token::OpenCurly {
+                let prelude = self.source(start);
+                let block = self.block();
+                return Some(RawRule::Qualified { prelude, block });
+            }
+            self.skip_component();
+        }
+        debug!("Dropping rule without a block: {}", self.source(start));
+        None
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-list-of-declarations>
+    pub fn declarations(&mut self) -> Vec<RawDeclaration<'a>> {
+        let mut declarations = vec![];
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Whitespace | Token::Semicolon => self.pos += 1,
+                Token::AtKeyword(_) => {
+                    let rule = self.at_rule();
+                    debug!("Ignoring at-rule in declarations: {:?}", rule);
+                }
+                token => {
+                    let is_ident = matches!(token, Token::Ident(_));
+                    let start = self.pos;
+                    while !matches!(self.peek(), Some(Token::Semicolon) | None) {
+                        self.skip_component();
+                    }
+                    let declaration = is_ident
+                        .then(|| self.declaration(start..self.pos))
+                        .flatten();
+                    match declaration {
+                        Some(declaration) => declarations.push(declaration),
+                        None => debug!(
+                            "Dropping invalid declaration: {}",
+                            self.source(self.tokens[start].1.start)
+                        ),
+                    }
+                }
+            }
+        }
+        declarations
+    }
+
+    /// Read a declaration from a run of tokens, the first of which is its name.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-declaration>
+    fn declaration(&self, tokens: Range<usize>) -> Option<RawDeclaration<'a>> {
+        let tokens = &self.tokens[tokens];
+        let name = match &tokens[0].0 {
+            Token::Ident(name) if name.starts_with("--") => name.clone(),
+            Token::Ident(name) if name.bytes().any(|b| b.is_ascii_uppercase()) => {
+                Cow::Owned(name.to_ascii_lowercase())
+            }
+            Token::Ident(name) => name.clone(),
+            _ => return None,
+        };
+        let mut rest = tokens[1..]
+            .iter()
+            .skip_while(|(t, _)| t == &Token::Whitespace);
+        match rest.next() {
+            Some((Token::Colon, _)) => {}
+            _ => return None,
+        }
+        let mut value: Vec<_> = rest.map(|(t, _)| t.clone()).collect();
+        let important = strip_important(&mut value);
+        let value = trim(&value).to_vec();
+        Some(RawDeclaration {
+            name,
+            value,
+            important,
+        })
+    }
+}
+
+/// Remove `!important` from the end of the tokens of a value, returning whether it was there
+fn strip_important(value: &mut Vec<Token>) -> bool {
+    let mut significant = value
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, t)| *t != &Token::Whitespace);
+    let bang = match (significant.next(), significant.next()) {
+        (Some((_, Token::Ident(i))), Some((bang, Token::Delim('!'))))
+            if i.eq_ignore_ascii_case("important") =>
+        {
+            Some(bang)
+        }
+        _ => None,
+    };
+    if let Some(bang) = bang {
+        value.truncate(bang);
+    }
+    bang.is_some()
+}
+
+/// Turn the declarations of a block into [`Declaration`]s, skipping those with values which
+/// aren't understood
+fn parse_declarations(declarations: Vec<RawDeclaration<'_>>) -> Vec<Declaration<'_>> {
+    declarations
+        .into_iter()
+        .filter_map(|d| match parse_value_tokens(&d.value) {
+            Some(value) => Some(Declaration {
+                name: d.name,
+                value,
+                important: d.important,
+            }),
+            None => {
+                debug!(
+                    "Dropping declaration with invalid value: {}: {:?}",
+                    d.name, d.value
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Turn the prelude and block of a qualified rule into a [`Ruleset`]
+fn parse_ruleset<'a>(prelude: &'a str, block: &'a str) -> Option<Ruleset<'a>> {
+    let selectors = match selectors(prelude) {
+        Some(selectors) => selectors,
+        None => {
+            debug!("Dropping rule with invalid selectors: {}", prelude);
+            return None;
+        }
+    };
+    let declarations = parse_declarations(Parser::new(block).declarations());
+    // Rules without any declarations do nothing
+    (!declarations.is_empty()).then_some(Ruleset {
+        selectors,
+        declarations,
+    })
+}
+
+/// Turn the rules of a block into `@keyframes`, skipping those with invalid offsets
+fn parse_keyframes(block: &str) -> Vec<Keyframe<'_>> {
+    Parser::new(block)
+        .rules(false)
+        .into_iter()
+        .filter_map(|rule| {
+            let (prelude, block) = match rule {
+                RawRule::Qualified { prelude, block } => (prelude, block),
+                RawRule::At { .. } => return None,
+            };
+            let offsets = prelude
+                .split(',')
+                .map(|offset| match offset.trim().to_ascii_lowercase().as_str() {
+                    "from" => Some(0.0),
+                    "to" => Some(100.0),
+                    offset => offset.strip_suffix('%')?.parse().ok(),
+                })
+                .collect::<Option<Vec<f64>>>()?;
+            Some(Keyframe {
+                offsets,
+                declarations: parse_declarations(Parser::new(block).declarations()),
+            })
+        })
+        .collect()
+}
+
+/// Turn an at-rule other than `@charset` or `@import` into an [`AtRule`], if it's one which
+/// is understood and valid. `name` is lowercase
+fn parse_at_rule<'a>(name: &str, prelude: &'a str, block: Option<&'a str>) -> Option<AtRule<'a>> {
+    let nested = |block| parse_rules(Parser::new(block).rules(false), None);
+    let descriptors = |block| parse_declarations(Parser::new(block).declarations());
+    Some(match (name, block) {
+        ("media", Some(block)) => AtRule::Media {
+            query: MediaQueryList::parse(prelude),
+            rules: nested(block),
+        },
+        ("supports", Some(block)) if !prelude.is_empty() => AtRule::Supports {
+            condition: prelude,
+            rules: nested(block),
+        },
+        ("font-face", Some(block)) if prelude.is_empty() => AtRule::FontFace(descriptors(block)),
+        ("page", Some(block)) => AtRule::Page {
+            selector: prelude,
+            declarations: descriptors(block),
+        },
+        ("keyframes" | "-webkit-keyframes", Some(block)) if !prelude.is_empty() => {
+            AtRule::Keyframes {
+                name: prelude.trim_matches(['"', '\'']),
+                keyframes: parse_keyframes(block),
+            }
+        }
+        ("layer", block) => {
+            let names: Vec<&str> = match prelude {
+                "" => vec![],
+                prelude => prelude.split(',').map(str::trim).collect(),
+            };
+            // A block is in at most one layer, and a statement names at least one
+            let valid = match block {
+                Some(_) => names.len() <= 1,
+                None => !names.is_empty(),
+            };
+            if !valid || names.contains(&"") {
+                return None;
+            }
+            AtRule::Layer {
+                names,
+                rules: block.map(nested),
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// Turn raw rules into [`Rule`]s, leaving out those which are invalid or not understood.
+/// `imports` collects `@import`s, which are only allowed at the top level
+fn parse_rules<'a>(
+    raw_rules: Vec<RawRule<'a>>,
+    mut imports: Option<&mut Vec<Import<'a>>>,
+) -> Vec<Rule<'a>> {
+    let mut rules = vec![];
+    for rule in raw_rules {
+        let (name, prelude, block) = match rule {
+            RawRule::Qualified { prelude, block } => {
+                rules.extend(parse_ruleset(prelude, block).map(Rule::Style));
+                continue;
+            }
+            RawRule::At {
+                name,
+                prelude,
+                block,
+            } => (name.to_ascii_lowercase(), prelude, block),
+        };
+        // Imports have to come before any other rules, apart from `@layer` statements
+        let can_import = rules
+            .iter()
+            .all(|r| matches!(r, Rule::At(AtRule::Layer { rules: None, .. })));
+        match (name.as_str(), block, imports.as_mut()) {
+            ("charset", None, Some(_)) => {}
+            ("import", None, Some(imports)) if can_import => match parse_import(prelude) {
+                Some(import) if !import.supports.map(supports_import).unwrap_or(true) => {
+                    debug!("Dropping import whose supports() doesn't hold: {}", prelude)
+                }
+                Some(import) => imports.push(Import {
+                    url: import.url,
+                    layer: import.layer,
+                    media: MediaQueryList::parse(import.media),
+                }),
+                None => debug!("Dropping invalid import: {}", prelude),
+            },
+            _ => match parse_at_rule(&name, prelude, block) {
+                Some(rule) => rules.push(Rule::At(rule)),
+                None => debug!("Ignoring unsupported or invalid @{} rule", name),
+            },
+        }
+    }
+    rules
+}
+
+/// Parses a CSS source file to a [`Stylesheet`]. Rules and declarations which are invalid
+/// or not understood are left out
+pub fn stylesheet(input: &str) -> Stylesheet<'_> {
+    let span = span!(Level::DEBUG, "Parsing Stylesheet");
+    let _enter = span.enter();
+    // A byte order mark is left in when decoding the stylesheet lossily
+    let input = input.strip_prefix('\u{FEFF}').unwrap_or(input);
+    let mut imports = vec![];
+    let rules = parse_rules(Parser::new(input).rules(true), Some(&mut imports));
+    Stylesheet { imports, rules }
+}
+
+/// Parses a list of declarations, such as a `style` attribute
+pub fn declarations(input: &str) -> Vec<Declaration<'_>> {
+    parse_declarations(Parser::new(input).declarations())
+}
+
+#[cfg(test)]
+#[test]
+fn test_rules() {
+    let rules = |i| Parser::new(i).rules(true);
+    assert_eq!(
+        rules("<!-- a { b } @media screen { c { d } } @import 'e' ;"),
+        [
+            RawRule::Qualified {
+                prelude: "a",
+                block: " b "
+            },
+            RawRule::At {
+                name: Cow::Borrowed("media"),
+                prelude: "screen",
+                block: Some(" c { d } ")
+            },
+            RawRule::At {
+                name: Cow::Borrowed("import"),
+                prelude: "'e'",
+                block: None
+            }
+        ]
+    );
+    // Brackets inside the prelude are matched, and a rule without a block is dropped
+    assert_eq!(
+        rules("a[x='}'] { (} ) } b"),
+        [RawRule::Qualified {
+            prelude: "a[x='}']",
+            block: " (} ) "
+        }]
+    );
+    // An unclosed block runs to the end
+    assert_eq!(
+        rules("a { b: c"),
+        [RawRule::Qualified {
+            prelude: "a",
+            block: " b: c"
+        }]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_declarations() {
+    let declarations = |i| Parser::new(i).declarations();
+    let declaration = |name, value, important| RawDeclaration {
+        name: Cow::Borrowed(name),
+        value: Tokenizer::new(value).map(|(t, _)| t).collect(),
+        important,
+    };
+    assert_eq!(
+        declarations("a: b c ; d : e ! IMPORTANT; ; f; 1: 2; g: {h; i}; @j k; l: m(n; o);p:"),
+        [
+            declaration("a", "b c", false),
+            declaration("d", "e", true),
+            declaration("g", "{h; i}", false),
+            declaration("l", "m(n; o)", false),
+            declaration("p", "", false),
+        ]
+    );
+    // Property names are case-insensitive, apart from those of custom properties
+    assert_eq!(
+        declarations(r"COLOR: red; --Var: x; \62 order: 0"),
+        [
+            declaration("color", "red", false),
+            declaration("--Var", "x", false),
+            declaration("border", "0", false),
+        ]
+    );
+}
@@ -1,4 +1,3 @@
-use super::parsing::stylesheet;
 use super::*;
 
 #[cfg(test)]
@@ -26,33 +25,47 @@ body {
 }
 "#;
     let target = Stylesheet {
+        imports: vec![Import::new("test.css")],
         rules: vec![
-            Ruleset {
+            Rule::Style(Ruleset {
                 selectors: vec![Selector::Simple(simple_selector!(html))],
                 declarations: vec![Declaration::new("box-sizing", Value::Keyword("border-box"))],
-            },
-            Ruleset {
+            }),
+            Rule::Style(Ruleset {
                 selectors: vec![
                     Selector::Simple(simple_selector!(*)),
                     Selector::Compound(vec![simple_selector!(*), simple_selector!(:before)]),
                     Selector::Compound(vec![simple_selector!(*), simple_selector!(:after)]),
                 ],
                 declarations: vec![Declaration::new("box-sizing", Value::Keyword("inherit"))],
-            },
-            Ruleset {
+            }),
+            Rule::Style(Ruleset {
                 selectors: vec![Selector::Simple(simple_selector!(html))],
                 declarations: vec![
                     Declaration::new("-ms-text-size-adjust", Value::Percentage(100.0)),
                     Declaration::new("-webkit-text-size-adjust", Value::Percentage(100.0)),
                 ],
-            },
-            Ruleset {
+            }),
+            Rule::Style(Ruleset {
                 selectors: vec![Selector::Simple(simple_selector!(body))],
                 declarations: vec![Declaration::new("margin", Value::Number(0.0))],
-            },
+            }),
         ],
     };
-    assert_eq!(stylesheet(i), Ok(("", target)));
+    assert_eq!(stylesheet(i), target);
+}
+
+#[cfg(test)]
+#[test]
+fn test_byte_order_mark() {
+    let target = Stylesheet {
+        imports: vec![],
+        rules: vec![Rule::Style(Ruleset {
+            selectors: vec![Selector::Simple(simple_selector!(body))],
+            declarations: vec![Declaration::new("margin", Value::Number(0.0))],
+        })],
+    };
+    assert_eq!(stylesheet("\u{FEFF}body { MARGIN: 0 }"), target);
 }
 
 #[cfg(test)]
@@ -65,10 +78,341 @@ h2 {
     color: black;
 }"#;
     let target = Stylesheet {
-        rules: vec![Ruleset {
+        imports: vec![],
+        rules: vec![Rule::Style(Ruleset {
             selectors: vec![Selector::Simple(simple_selector!(h2))],
             declarations: vec![Declaration::new("color", Value::Color(keywords::BLACK))],
-        }],
+        })],
+    };
+    assert_eq!(stylesheet(i), target)
+}
+
+#[cfg(test)]
+#[test]
+fn test_urls() {
+    let i = r#"@import url(a.css); @import "b.css" print, (max-width: 600px);
+div { background: url("c.png") no-repeat, url(d.png) }"#;
+    let sheet = stylesheet(i);
+    assert_eq!(
+        sheet.imports,
+        [
+            Import::new("a.css"),
+            Import {
+                url: "b.css".into(),
+                layer: None,
+                media: MediaQueryList::parse("print, (max-width: 600px)")
+            }
+        ]
+    );
+    assert!(!sheet.imports[1]
+        .media
+        .matches(&Device::screen(1600.0, 1080.0)));
+    assert_eq!(
+        sheet.rules[0].declarations()[0].value.urls(),
+        ["c.png", "d.png"]
+    );
+    let declarations = declarations(" color: red; background: url(e.png)");
+    assert_eq!(declarations[1].value.urls(), ["e.png"]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_supports() {
+    let sheet = stylesheet(
+        r#"@import "a.css" layer(x) supports(display:block) screen;
+        @import "b.css" layer(x) supports(display:grid) screen;
+        @import "c.css" supports(not (display: grid));"#,
+    );
+    assert_eq!(
+        sheet.imports,
+        [
+            Import {
+                url: "a.css".into(),
+                layer: Some(Some("x")),
+                media: MediaQueryList::parse("screen")
+            },
+            Import::new("c.css")
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_ruleset() {
+    let i = r#"html {
+    box-sizing: border-box
+}"#;
+    let target = Ruleset {
+        selectors: vec![Selector::Simple(simple_selector!(html))],
+        declarations: vec![Declaration::new("box-sizing", Value::Keyword("border-box"))],
     };
-    assert_eq!(stylesheet(i), Ok(("", target)))
+    assert_eq!(stylesheet(i).rules, [Rule::Style(target)]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_declaration_list() {
+    let i = r#"color: black;
+background-color: rgb(197,93,161)"#;
+    let target = vec![
+        Declaration {
+            name: "color".into(),
+            value: Value::Color(BLACK),
+            important: false,
+        },
+        Declaration {
+            name: "background-color".into(),
+            value: Value::Color(ColorValue {
+                r: 197,
+                g: 93,
+                b: 161,
+                a: 255,
+            }),
+            important: false,
+        },
+    ];
+    assert_eq!(declarations(i), target)
+}
+
+#[cfg(test)]
+#[test]
+fn test_error_recovery() {
+    let i = r#"
+a { color: black; width: 10 20 }
+b { color: ; margin: calc(1px + 2px); padding: 1.5em !important; transition: 1s; float: left }
+c::before, d[x] { color: black }
+@unknown foo { e { color: black } }
+f { width: "unclosed
+; height: 5px }
+g { color: black; @nested { } margin
+  :
+  0 }
+h }
+i { color: black"#;
+    let rule = |name, declarations| {
+        Rule::Style(Ruleset {
+            selectors: vec![Selector::Simple(SimpleSelector::Type(Cow::Borrowed(name)))],
+            declarations,
+        })
+    };
+    let black = Declaration::new("color", Value::Color(BLACK));
+    assert_eq!(
+        stylesheet(i).rules,
+        [
+            rule(
+                "a",
+                vec![
+                    black.clone(),
+                    Declaration::new(
+                        "width",
+                        Value::Multiple(MultiValue(vec![
+                            (None, Value::Number(10.0)),
+                            (Some(Operator::Space), Value::Number(20.0))
+                        ]))
+                    )
+                ]
+            ),
+            rule(
+                "b",
+                vec![
+                    Declaration {
+                        name: "padding".into(),
+                        value: Value::Length(1.5, Unit::Em),
+                        important: true
+                    },
+                    Declaration::new("float", Value::Keyword("left"))
+                ]
+            ),
+            rule(
+                "f",
+                vec![Declaration::new("height", Value::Length(5.0, Unit::Px))]
+            ),
+            rule(
+                "g",
+                vec![
+                    black.clone(),
+                    Declaration::new("margin", Value::Number(0.0))
+                ]
+            ),
+            // `h }` is a rule prelude up to the next block, so it swallows `i`
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_at_rules() {
+    let i = r#"@charset "utf-8";
+@layer base, theme;
+@import "a.css";
+a { color: black }
+@import "ignored.css";
+@media screen and (min-width: 600px) {
+    b { color: black }
+    @supports (display: block) { c { color: black } }
+}
+@font-face { font-family: "Sans"; src: url(sans.woff2) format("woff2") }
+@page :first { margin: 1in }
+@keyframes spin { from { width: 0 } 50%, to { width: 10px } bogus { width: 1px } }
+@layer theme { d { color: black } }
+@layer { e { color: black } }
+@layer a, b { }
+@supports { }
+"#;
+    let rule = |name| {
+        Rule::Style(Ruleset {
+            selectors: vec![Selector::Simple(SimpleSelector::Type(Cow::Borrowed(name)))],
+            declarations: vec![Declaration::new("color", Value::Color(BLACK))],
+        })
+    };
+    let sheet = stylesheet(i);
+    assert_eq!(sheet.imports, [Import::new("a.css")]);
+    assert_eq!(
+        sheet.rules,
+        [
+            Rule::At(AtRule::Layer {
+                names: vec!["base", "theme"],
+                rules: None
+            }),
+            rule("a"),
+            Rule::At(AtRule::Media {
+                query: MediaQueryList::parse("screen and (min-width: 600px)"),
+                rules: vec![
+                    rule("b"),
+                    Rule::At(AtRule::Supports {
+                        condition: "(display: block)",
+                        rules: vec![rule("c")]
+                    })
+                ]
+            }),
+            Rule::At(AtRule::FontFace(vec![
+                Declaration::new("font-family", Value::String("Sans".into())),
+                Declaration::new(
+                    "src",
+                    Value::Multiple(MultiValue(vec![
+                        (None, Value::Url("sans.woff2".into())),
+                        (
+                            Some(Operator::Space),
+                            Value::Function(FunctionValue(
+                                "format",
+                                vec![Value::String("woff2".into())]
+                            ))
+                        )
+                    ]))
+                )
+            ])),
+            Rule::At(AtRule::Page {
+                selector: ":first",
+                declarations: vec![Declaration::new("margin", Value::Length(1.0, Unit::In))]
+            }),
+            Rule::At(AtRule::Keyframes {
+                name: "spin",
+                keyframes: vec![
+                    Keyframe {
+                        offsets: vec![0.0],
+                        declarations: vec![Declaration::new("width", Value::Number(0.0))]
+                    },
+                    Keyframe {
+                        offsets: vec![50.0, 100.0],
+                        declarations: vec![Declaration::new(
+                            "width",
+                            Value::Length(10.0, Unit::Px)
+                        )]
+                    }
+                ]
+            }),
+            Rule::At(AtRule::Layer {
+                names: vec!["theme"],
+                rules: Some(vec![rule("d")])
+            }),
+            Rule::At(AtRule::Layer {
+                names: vec![],
+                rules: Some(vec![rule("e")])
+            }),
+        ]
+    );
+    // Rules in layers come first, in the order the layers were declared, and rules in `@media`
+    // and `@supports` rules only apply when their conditions hold
+    let selectors = |device| -> Vec<_> {
+        sheet
+            .rulesets(&device, &mut CascadeLayers::default(), &Layer::default())
+            .into_iter()
+            .map(|(_, r)| r.selectors[0].clone())
+            .collect()
+    };
+    let types = |names: &[&'static str]| -> Vec<_> {
+        names
+            .iter()
+            .map(|&n| Selector::Simple(SimpleSelector::Type(n.into())))
+            .collect()
+    };
+    assert_eq!(
+        selectors(Device::screen(1600.0, 1080.0)),
+        types(&["d", "e", "a", "b", "c"])
+    );
+    assert_eq!(
+        selectors(Device::screen(400.0, 800.0)),
+        types(&["d", "e", "a"])
+    );
+    assert_eq!(sheet.rules[2].declarations().len(), 2);
+}
+
+#[test]
+fn test_layer_order() {
+    let i = "@layer b, a; @layer a { x { color: red } } @layer b { y { color: red } }
+        z { color: red } @layer a.c { w { color: red } }
+        @layer a { @layer c { v { color: red } } u { color: red } }";
+    let sheet = stylesheet(i);
+    let selectors: Vec<_> = sheet
+        .rulesets(
+            &Device::screen(1600.0, 1080.0),
+            &mut CascadeLayers::default(),
+            &Layer::default(),
+        )
+        .into_iter()
+        .map(|(_, r)| r.selectors[0].clone())
+        .collect();
+    // Layers in a layer come before its own rules, and unlayered rules come last
+    let expected: Vec<_> = ["y", "w", "v", "x", "u", "z"]
+        .into_iter()
+        .map(|n| Selector::Simple(SimpleSelector::Type(n.into())))
+        .collect();
+    assert_eq!(selectors, expected);
+
+    // Stylesheets of the same origin share their layers
+    let device = Device::screen(1600.0, 1080.0);
+    let mut layers = CascadeLayers::default();
+    let first = stylesheet("@layer a, b; @layer b { x { color: red } }");
+    let second = stylesheet("@layer c { y { color: red } } @layer a { z { color: red } }");
+    let ranks: Vec<LayerRank> = [&first, &second]
+        .into_iter()
+        .flat_map(|sheet| sheet.rulesets(&device, &mut layers, &Layer::default()))
+        .map(|(rank, _)| rank)
+        .collect();
+    // The ranks of `b`, then `a` and `c`, since the second sheet's rules are sorted
+    assert!(ranks[1] < ranks[0] && ranks[0] < ranks[2]);
+    assert!(ranks[2] < Layer::default().rank());
+}
+
+#[cfg(test)]
+#[test]
+fn test_attribute_matches() {
+    use AttributeSelector::*;
+    let b = Cow::Borrowed;
+    assert!(Has(b("href")).matches(""));
+    assert!(Equals(b("type"), b("text")).matches("text"));
+    assert!(!Equals(b("type"), b("text")).matches("Text"));
+    assert!(Includes(b("rel"), b("next")).matches(" prev  next"));
+    assert!(!Includes(b("rel"), b("a b")).matches("a b"));
+    assert!(!Includes(b("rel"), b("")).matches(""));
+    assert!(DashMatch(b("lang"), b("en")).matches("en"));
+    assert!(DashMatch(b("lang"), b("en")).matches("en-GB"));
+    assert!(!DashMatch(b("lang"), b("en")).matches("english"));
+    assert!(Prefix(b("href"), b("#")).matches("#top"));
+    assert!(Suffix(b("src"), b(".png")).matches("a.png"));
+    assert!(Substring(b("title"), b("b")).matches("abc"));
+    // Empty values match nothing, rather than everything
+    assert!(!Prefix(b("href"), b("")).matches("a"));
+    assert!(!Suffix(b("href"), b("")).matches("a"));
+    assert!(!Substring(b("href"), b("")).matches("a"));
 }
@@ -0,0 +1,546 @@
+//! Implements the CSS tokenization stage
+//! <https://www.w3.org/TR/css-syntax-3/#tokenization>
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token<'a> {
+    Ident(Cow<'a, str>),
+    // The name of a function, which has consumed its `(`
+    Function(Cow<'a, str>),
+    AtKeyword(Cow<'a, str>),
+    // `id` is whether the value would be a valid identifier, so the hash can be an ID selector
+    Hash {
+        value: Cow<'a, str>,
+        id: bool,
+    },
+    String(Cow<'a, str>),
+    // A string with an unescaped newline in it
+    BadString,
+    // An unquoted `url(...)`. Quoted URLs are a `url` function with a string argument
+    Url(Cow<'a, str>),
+    BadUrl,
+    Delim(char),
+    Number {
+        value: f64,
+        integer: bool,
+    },
+    Percentage(f64),
+    Dimension {
+        value: f64,
+        integer: bool,
+        unit: Cow<'a, str>,
+    },
+    Whitespace,
+    // `<!--`
+    Cdo,
+    // `-->`
+    Cdc,
+    Colon,
+    Semicolon,
+    Comma,
+    OpenSquare,
+    CloseSquare,
+    OpenParen,
+    CloseParen,
+    OpenCurly,
+    CloseCurly,
+}
+
+/// Splits CSS source into [`Token`]s, along with the range of the source each came from.
+/// Comments are skipped over. Tokenizing never fails: anything invalid becomes a token the
+/// parser will throw away, like [`Token::BadString`]
+pub struct Tokenizer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+fn is_newline(c: char) -> bool {
+    matches!(c, '\n' | '\r' | '\x0C')
+}
+
+fn is_whitespace(c: char) -> bool {
+    is_newline(c) || c == ' ' || c == '\t'
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+fn is_ident_char(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit() || c == '-'
+}
+
+/// <https://www.w3.org/TR/css-syntax-3/#non-printable-code-point>
+fn is_non_printable(c: char) -> bool {
+    matches!(c, '\0'..='\x08' | '\x0B' | '\x0E'..='\x1F' | '\x7F')
+}
+
+/// Whether the two characters are a `\` which starts an escape
+fn is_valid_escape(first: Option<char>, second: Option<char>) -> bool {
+    first == Some('\\') && !second.map(is_newline).unwrap_or(false)
+}
+
+/// <https://www.w3.org/TR/css-syntax-3/#would-start-an-identifier>
+fn would_start_ident(first: Option<char>, second: Option<char>, third: Option<char>) -> bool {
+    match first {
+        Some('-') => {
+            second
+                .map(|c| is_ident_start(c) || c == '-')
+                .unwrap_or(false)
+                || is_valid_escape(second, third)
+        }
+        Some('\\') => is_valid_escape(first, second),
+        Some(c) => is_ident_start(c),
+        None => false,
+    }
+}
+
+/// <https://www.w3.org/TR/css-syntax-3/#starts-with-a-number>
+fn would_start_number(first: Option<char>, second: Option<char>, third: Option<char>) -> bool {
+    let digit = |c: Option<char>| c.map(|c| c.is_ascii_digit()).unwrap_or(false);
+    match first {
+        Some('+' | '-') => digit(second) || (second == Some('.') && digit(third)),
+        Some('.') => digit(second),
+        c => digit(c),
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+
+    /// The `n`th character after the current position
+    fn peek(&self, n: usize) -> Option<char> {
+        self.input[self.pos..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn bump_if(&mut self, c: char) -> bool {
+        let matches = self.peek(0) == Some(c);
+        if matches {
+            self.bump();
+        }
+        matches
+    }
+
+    fn skip_comments(&mut self) {
+        while self.input[self.pos..].starts_with("/*") {
+            self.pos = match self.input[self.pos + 2..].find("*/") {
+                Some(end) => self.pos + 2 + end + 2,
+                // An unclosed comment runs to the end
+                None => self.input.len(),
+            };
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek(0).map(is_whitespace).unwrap_or(false) {
+            self.bump();
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        self.skip_comments();
+        let (first, second, third) = (self.peek(0), self.peek(1), self.peek(2));
+        let c = self.bump()?;
+        Some(match c {
+            c if is_whitespace(c) => {
+                self.skip_whitespace();
+                Token::Whitespace
+            }
+            '"' | '\'' => self.string(c),
+            '#' => {
+                if self.peek(0).map(is_ident_char).unwrap_or(false)
+                    || is_valid_escape(second, third)
+                {
+                    let id = would_start_ident(second, third, self.peek(2));
+                    Token::Hash {
+                        value: self.name(),
+                        id,
+                    }
+                } else {
+                    Token::Delim('#')
+                }
+            }
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '[' => Token::OpenSquare,
+            ']' => Token::CloseSquare,
+            '{' => Token::OpenCurly,
+            '}' => Token::CloseCurly,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            ';' => Token::Semicolon,
+            '+' | '.' if would_start_number(first, second, third) => {
+                self.reconsume(c);
+                self.numeric()
+            }
+            '-' if would_start_number(first, second, third) => {
+                self.reconsume(c);
+                self.numeric()
+            }
+            '-' if second == Some('-') && third == Some('>') => {
+                self.pos += 2;
+                Token::Cdc
+            }
+            '-' | '\\' if would_start_ident(first, second, third) => {
+                self.reconsume(c);
+                self.ident_like()
+            }
+            '<' if self.input[self.pos..].starts_with("!--") => {
+                self.pos += 3;
+                Token::Cdo
+            }
+            '@' if would_start_ident(second, third, self.peek(2)) => Token::AtKeyword(self.name()),
+            c if c.is_ascii_digit() => {
+                self.reconsume(c);
+                self.numeric()
+            }
+            c if is_ident_start(c) => {
+                self.reconsume(c);
+                self.ident_like()
+            }
+            c => Token::Delim(c),
+        })
+    }
+
+    fn reconsume(&mut self, c: char) {
+        self.pos -= c.len_utf8();
+    }
+
+    /// Consume an escape, after its `\`.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-escaped-code-point>
+    fn escape(&mut self) -> char {
+        match self.bump() {
+            Some(c) if c.is_ascii_hexdigit() => {
+                let start = self.pos - 1;
+                while self.pos - start < 6
+                    && self.peek(0).map(|c| c.is_ascii_hexdigit()) == Some(true)
+                {
+                    self.bump();
+                }
+                let value = u32::from_str_radix(&self.input[start..self.pos], 16).unwrap();
+                // A single whitespace after the digits is part of the escape
+                if self.input[self.pos..].starts_with("\r\n") {
+                    self.pos += 2;
+                } else if self.peek(0).map(is_whitespace) == Some(true) {
+                    self.bump();
+                }
+                match value {
+                    0 => char::REPLACEMENT_CHARACTER,
+                    value => char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER),
+                }
+            }
+            Some(c) => c,
+            None => char::REPLACEMENT_CHARACTER,
+        }
+    }
+
+    /// Consume a name, borrowing it from the input unless it has escapes.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-name>
+    fn name(&mut self) -> Cow<'a, str> {
+        let start = self.pos;
+        let mut owned: Option<String> = None;
+        loop {
+            match self.peek(0) {
+                Some(c) if is_ident_char(c) => {
+                    self.bump();
+                    if let Some(owned) = &mut owned {
+                        owned.push(c);
+                    }
+                }
+                first @ Some('\\') if is_valid_escape(first, self.peek(1)) => {
+                    let owned =
+                        owned.get_or_insert_with(|| self.input[start..self.pos].to_string());
+                    self.bump();
+                    owned.push(self.escape());
+                }
+                _ => break,
+            }
+        }
+        match owned {
+            Some(owned) => Cow::Owned(owned),
+            None => Cow::Borrowed(&self.input[start..self.pos]),
+        }
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-a-string-token>
+    fn string(&mut self, quote: char) -> Token<'a> {
+        let start = self.pos;
+        let mut owned: Option<String> = None;
+        loop {
+            match self.peek(0) {
+                // An unclosed string runs to the end
+                None => break,
+                Some(c) if c == quote => {
+                    let end = self.pos;
+                    self.bump();
+                    return Token::String(match owned {
+                        Some(owned) => Cow::Owned(owned),
+                        None => Cow::Borrowed(&self.input[start..end]),
+                    });
+                }
+                // The newline isn't consumed, so what follows is tokenized normally
+                Some(c) if is_newline(c) => return Token::BadString,
+                Some('\\') => {
+                    let owned =
+                        owned.get_or_insert_with(|| self.input[start..self.pos].to_string());
+                    self.bump();
+                    match self.peek(0) {
+                        None => {}
+                        // An escaped newline continues the string onto the next line
+                        Some(c) if is_newline(c) => {
+                            if self.input[self.pos..].starts_with("\r\n") {
+                                self.pos += 2;
+                            } else {
+                                self.bump();
+                            }
+                        }
+                        Some(_) => owned.push(self.escape()),
+                    }
+                }
+                Some(c) => {
+                    self.bump();
+                    if let Some(owned) = &mut owned {
+                        owned.push(c);
+                    }
+                }
+            }
+        }
+        Token::String(match owned {
+            Some(owned) => Cow::Owned(owned),
+            None => Cow::Borrowed(&self.input[start..]),
+        })
+    }
+
+    /// Consume a number, returning its value and whether it's an integer.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-number>
+    fn number(&mut self) -> (f64, bool) {
+        let start = self.pos;
+        let digit = |c: Option<char>| c.map(|c| c.is_ascii_digit()).unwrap_or(false);
+        let mut integer = true;
+        if matches!(self.peek(0), Some('+' | '-')) {
+            self.bump();
+        }
+        while digit(self.peek(0)) {
+            self.bump();
+        }
+        if self.peek(0) == Some('.') && digit(self.peek(1)) {
+            integer = false;
+            self.bump();
+            while digit(self.peek(0)) {
+                self.bump();
+            }
+        }
+        if matches!(self.peek(0), Some('e' | 'E'))
+            && (digit(self.peek(1))
+                || (matches!(self.peek(1), Some('+' | '-')) && digit(self.peek(2))))
+        {
+            integer = false;
+            self.bump();
+            self.bump();
+            while digit(self.peek(0)) {
+                self.bump();
+            }
+        }
+        let value = self.input[start..self.pos].parse().unwrap_or(0.0);
+        (value, integer)
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-numeric-token>
+    fn numeric(&mut self) -> Token<'a> {
+        let (value, integer) = self.number();
+        if would_start_ident(self.peek(0), self.peek(1), self.peek(2)) {
+            Token::Dimension {
+                value,
+                integer,
+                unit: self.name(),
+            }
+        } else if self.bump_if('%') {
+            Token::Percentage(value)
+        } else {
+            Token::Number { value, integer }
+        }
+    }
+
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-ident-like-token>
+    fn ident_like(&mut self) -> Token<'a> {
+        let name = self.name();
+        if self.peek(0) != Some('(') {
+            return Token::Ident(name);
+        }
+        self.bump();
+        if !name.eq_ignore_ascii_case("url") {
+            return Token::Function(name);
+        }
+        // Quoted URLs are left for the parser to read as a function
+        let rest = self.input[self.pos..].trim_start_matches(is_whitespace);
+        if rest.starts_with(['"', '\'']) {
+            return Token::Function(name);
+        }
+        self.url()
+    }
+
+    /// Consume an unquoted URL, after its `url(`.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-url-token>
+    fn url(&mut self) -> Token<'a> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let mut owned: Option<String> = None;
+        loop {
+            let end = self.pos;
+            match self.bump() {
+                Some(')') | None => {
+                    return Token::Url(
+                        owned
+                            .map(Cow::Owned)
+                            .unwrap_or_else(|| Cow::Borrowed(&self.input[start..end])),
+                    )
+                }
+                Some(c) if is_whitespace(c) => {
+                    self.skip_whitespace();
+                    if self.bump_if(')') || self.peek(0).is_none() {
+                        return Token::Url(
+                            owned
+                                .map(Cow::Owned)
+                                .unwrap_or_else(|| Cow::Borrowed(&self.input[start..end])),
+                        );
+                    }
+                    return self.bad_url();
+                }
+                Some('"' | '\'' | '(') => return self.bad_url(),
+                Some(c) if is_non_printable(c) => return self.bad_url(),
+                Some('\\') => {
+                    if !is_valid_escape(Some('\\'), self.peek(0)) {
+                        return self.bad_url();
+                    }
+                    let escaped = self.escape();
+                    owned
+                        .get_or_insert_with(|| self.input[start..end].to_string())
+                        .push(escaped);
+                }
+                Some(c) => {
+                    if let Some(owned) = &mut owned {
+                        owned.push(c);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Skip the rest of an invalid URL.
+    /// <https://www.w3.org/TR/css-syntax-3/#consume-remnants-of-bad-url>
+    fn bad_url(&mut self) -> Token<'a> {
+        loop {
+            match self.bump() {
+                Some(')') | None => return Token::BadUrl,
+                Some('\\') if self.peek(0).is_some() => {
+                    self.escape();
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = (Token<'a>, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_comments();
+        let start = self.pos;
+        let token = self.next_token()?;
+        Some((token, start..self.pos))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_tokenizer() {
+    let tokens = |i| Tokenizer::new(i).map(|(t, _)| t).collect::<Vec<_>>();
+    let ident = |s| Token::Ident(Cow::Borrowed(s));
+    assert_eq!(
+        tokens("a{color:red}"),
+        [
+            ident("a"),
+            Token::OpenCurly,
+            ident("color"),
+            Token::Colon,
+            ident("red"),
+            Token::CloseCurly
+        ]
+    );
+    assert_eq!(
+        tokens("/* comment */ 1.5em -2 +.5% 1e3 #fff #-1"),
+        [
+            Token::Whitespace,
+            Token::Dimension {
+                value: 1.5,
+                integer: false,
+                unit: Cow::Borrowed("em")
+            },
+            Token::Whitespace,
+            Token::Number {
+                value: -2.0,
+                integer: true
+            },
+            Token::Whitespace,
+            Token::Percentage(0.5),
+            Token::Whitespace,
+            Token::Number {
+                value: 1000.0,
+                integer: false
+            },
+            Token::Whitespace,
+            Token::Hash {
+                value: Cow::Borrowed("fff"),
+                id: true
+            },
+            Token::Whitespace,
+            Token::Hash {
+                value: Cow::Borrowed("-1"),
+                id: false
+            },
+        ]
+    );
+    assert_eq!(
+        tokens(r#"url( a.png ) url("b.png") url(a b) "c\"d" 'e"#),
+        [
+            Token::Url(Cow::Borrowed("a.png")),
+            Token::Whitespace,
+            Token::Function(Cow::Borrowed("url")),
+            Token::String(Cow::Borrowed("b.png")),
+            Token::CloseParen,
+            Token::Whitespace,
+            Token::BadUrl,
+            Token::Whitespace,
+            Token::String(Cow::Owned("c\"d".to_string())),
+            Token::Whitespace,
+            Token::String(Cow::Borrowed("e")),
+        ]
+    );
+    assert_eq!(
+        tokens("\\31 0 @media <!-- --> 'a\nb"),
+        [
+            ident("10"),
+            Token::Whitespace,
+            Token::AtKeyword(Cow::Borrowed("media")),
+            Token::Whitespace,
+            Token::Cdo,
+            Token::Whitespace,
+            Token::Cdc,
+            Token::Whitespace,
+            Token::BadString,
+            Token::Whitespace,
+            ident("b"),
+        ]
+    );
+}
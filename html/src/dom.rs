@@ -0,0 +1,451 @@
+//! The document tree, stored as an arena of nodes linked to their parent, siblings and children
+
+use crate::{
+    DOMAttributes, DOMContent, DOMElement, DOMText, DocumentType, Namespace, ParseError,
+    QuirksMode, SourceSpan,
+};
+use css::AttributeSelector;
+use std::iter;
+
+/// Identifies a node in a [`Document`]. It is only meaningful for the document it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeData {
+    /// The document itself, which is the parent of the doctype and root element
+    Document,
+    Doctype(DocumentType),
+    Element(ElementData),
+    Text(String),
+    /// A comment. Processing instructions (`<?...>`) are parsed as comments too
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElementData {
+    pub name: String,
+    pub namespace: Namespace,
+    pub attributes: DOMAttributes,
+}
+
+impl ElementData {
+    pub fn get_attribute(&self, name: &str) -> Option<&String> {
+        self.attributes.0.get(name)
+    }
+
+    /// Check if the space seperated `class` attribute contains `class`
+    pub fn has_class(&self, class: &str) -> bool {
+        self.attributes.has_class(class)
+    }
+
+    /// Check if the element has the attribute `selector` tests, with a value it matches
+    pub fn matches_attribute(&self, selector: &AttributeSelector) -> bool {
+        // Attribute names of HTML elements are case-insensitive
+        let value = match self.namespace {
+            Namespace::Html => self.get_attribute(&selector.name().to_ascii_lowercase()),
+            _ => self.get_attribute(selector.name()),
+        };
+        value.map(|v| selector.matches(v)) == Some(true)
+    }
+}
+
+impl DOMAttributes {
+    /// Check if the space seperated `class` attribute contains `class`
+    pub(crate) fn has_class(&self, class: &str) -> bool {
+        self.0
+            .get("class")
+            .map(|c| c.split_ascii_whitespace().any(|c| c == class))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub data: NodeData,
+    /// Where the node was parsed from. [`None`] if it was not created by the parser
+    pub span: Option<SourceSpan>,
+    parent: Option<NodeId>,
+    previous_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    /// Whether the node or anything below it has been changed
+    changed: bool,
+}
+
+impl Node {
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn previous_sibling(&self) -> Option<NodeId> {
+        self.previous_sibling
+    }
+
+    pub fn next_sibling(&self) -> Option<NodeId> {
+        self.next_sibling
+    }
+
+    pub fn first_child(&self) -> Option<NodeId> {
+        self.first_child
+    }
+
+    pub fn last_child(&self) -> Option<NodeId> {
+        self.last_child
+    }
+
+    pub fn as_element(&self) -> Option<&ElementData> {
+        match &self.data {
+            NodeData::Element(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed HTML document
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// Every node in the document. The document node itself is always first
+    nodes: Vec<Node>,
+    /// The rendering mode derived from the doctype
+    pub quirks_mode: QuirksMode,
+    /// Recoverable errors encountered while parsing, in source order
+    pub errors: Vec<ParseError>,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Document {
+    /// Create a document with no contents
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node {
+                data: NodeData::Document,
+                span: None,
+                parent: None,
+                previous_sibling: None,
+                next_sibling: None,
+                first_child: None,
+                last_child: None,
+                changed: false,
+            }],
+            quirks_mode: QuirksMode::default(),
+            errors: vec![],
+        }
+    }
+
+    /// The document node, which is the parent of the doctype, root element and any comments
+    /// around them
+    pub fn document_node(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    /// The root `<html>` element.
+    ///
+    /// # Panics
+    ///
+    /// If the document has no root element, as with one made by [`Document::new`] which nothing
+    /// has been added to. Parsed documents always have one, and it can't be removed, so use
+    /// [`Document::get_document_element`] for documents built by hand
+    pub fn document_element(&self) -> NodeId {
+        self.get_document_element()
+            .expect("Document has no root element")
+    }
+
+    /// The root element, or [`None`] if the document doesn't have one yet
+    pub fn get_document_element(&self) -> Option<NodeId> {
+        self.children(self.document_node())
+            .find(|&id| self.element(id).is_some())
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    /// The element `id`, or [`None`] if it is not an element
+    pub fn element(&self, id: NodeId) -> Option<&ElementData> {
+        self.node(id).as_element()
+    }
+
+    pub fn doctype(&self) -> Option<&DocumentType> {
+        self.children(self.document_node())
+            .find_map(|id| match &self.node(id).data {
+                NodeData::Doctype(d) => Some(d),
+                _ => None,
+            })
+    }
+
+    /// Iterate over the children of `id`, in order
+    pub fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        iter::successors(self.node(id).first_child, |&child| {
+            self.node(child).next_sibling
+        })
+    }
+
+    /// Iterate over the ancestors of `id`, from its parent up to the document node
+    pub fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        iter::successors(self.node(id).parent, |&parent| self.node(parent).parent)
+    }
+
+    /// Iterate over every node below `id`, in document order
+    pub fn descendants(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        iter::successors(self.node(id).first_child, move |&node| {
+            if let Some(child) = self.node(node).first_child {
+                return Some(child);
+            }
+            // Go back up until there's a following sibling, without leaving `id`
+            iter::once(node)
+                .chain(self.ancestors(node))
+                .take_while(|&n| n != id)
+                .find_map(|n| self.node(n).next_sibling)
+        })
+    }
+
+    /// The element siblings before `id`, nearest first
+    pub fn previous_element_siblings(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        iter::successors(self.node(id).previous_sibling, |&sibling| {
+            self.node(sibling).previous_sibling
+        })
+        .filter(|&sibling| self.element(sibling).is_some())
+    }
+
+    /// The element siblings after `id`, nearest first
+    pub fn next_element_siblings(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        iter::successors(self.node(id).next_sibling, |&sibling| {
+            self.node(sibling).next_sibling
+        })
+        .filter(|&sibling| self.element(sibling).is_some())
+    }
+
+    /// The text of `id` and all of its descendants joined together
+    pub fn text_content(&self, id: NodeId) -> String {
+        iter::once(id)
+            .chain(self.descendants(id))
+            .filter_map(|node| match &self.node(node).data {
+                NodeData::Text(t) => Some(t.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Copy the root `<html>` element out of the document, as a standalone tree
+    pub fn root(&self) -> DOMElement {
+        self.to_element(self.document_element())
+    }
+
+    /// Copy the top level nodes of the document out, in source order
+    pub fn contents(&self) -> Vec<DOMContent> {
+        self.children(self.document_node())
+            .map(|id| self.to_content(id))
+            .collect()
+    }
+
+    /// Copy the element `id` and everything below it out of the document, as a standalone tree
+    pub fn to_element(&self, id: NodeId) -> DOMElement {
+        let node = self.node(id);
+        let element = node.as_element().expect("Node is not an element");
+        DOMElement {
+            name: element.name.clone(),
+            namespace: element.namespace,
+            attributes: element.attributes.clone(),
+            contents: self
+                .children(id)
+                .map(|child| self.to_content(child))
+                .collect(),
+            span: node.span,
+        }
+    }
+
+    fn to_content(&self, id: NodeId) -> DOMContent {
+        let node = self.node(id);
+        match &node.data {
+            NodeData::Document => unreachable!("The document node has no parent"),
+            NodeData::Doctype(d) => DOMContent::Doctype(d.clone()),
+            NodeData::Element(_) => DOMContent::Element(self.to_element(id)),
+            NodeData::Text(t) => DOMContent::Text(DOMText {
+                text: t.clone(),
+                span: node.span,
+            }),
+            NodeData::Comment(c) => DOMContent::Comment(c.clone()),
+        }
+    }
+
+    /// Copy `element` and everything below it into the document as the last child of `parent`.
+    /// This is the reverse of [`Document::to_element`]
+    pub(crate) fn append_element(&mut self, parent: NodeId, element: &DOMElement) -> NodeId {
+        let data = ElementData {
+            name: element.name.clone(),
+            namespace: element.namespace,
+            attributes: element.attributes.clone(),
+        };
+        let id = self.create(NodeData::Element(data), element.span);
+        self.link(parent, id, None);
+        for content in &element.contents {
+            match content {
+                DOMContent::Element(child) => {
+                    self.append_element(id, child);
+                }
+                DOMContent::Text(text) => {
+                    let child = self.create(NodeData::Text(text.text.clone()), text.span);
+                    self.link(id, child, None);
+                }
+                DOMContent::Comment(comment) => {
+                    let child = self.create(NodeData::Comment(comment.clone()), None);
+                    self.link(id, child, None);
+                }
+                DOMContent::Doctype(doctype) => {
+                    let child = self.create(NodeData::Doctype(doctype.clone()), None);
+                    self.link(id, child, None);
+                }
+            }
+        }
+        id
+    }
+
+    /// The roots of the subtrees which have changed since the last call to
+    /// [`Document::clear_changes`], in document order. Changes to nodes which are no longer in
+    /// the document are left out
+    pub fn changed_subtrees(&self) -> Vec<NodeId> {
+        let mut changed = vec![];
+        self.find_changes(self.document_node(), &mut changed);
+        changed
+    }
+
+    fn find_changes(&self, id: NodeId, changed: &mut Vec<NodeId>) {
+        if self.node(id).changed {
+            changed.push(id);
+            return;
+        }
+        for child in self.children(id) {
+            self.find_changes(child, changed);
+        }
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.nodes.iter_mut().for_each(|n| n.changed = false);
+    }
+
+    /// Record that `id` or something below it has changed
+    pub(crate) fn mark_changed(&mut self, id: NodeId) {
+        self.nodes[id.0].changed = true;
+    }
+
+    pub(crate) fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0]
+    }
+
+    /// The id the next node to be created will have. Nodes created later have greater ids
+    pub(crate) fn next_node_id(&self) -> NodeId {
+        NodeId(self.nodes.len())
+    }
+
+    /// Create a node which is not part of the tree yet
+    pub(crate) fn create(&mut self, data: NodeData, span: Option<SourceSpan>) -> NodeId {
+        self.nodes.push(Node {
+            data,
+            span,
+            parent: None,
+            previous_sibling: None,
+            next_sibling: None,
+            first_child: None,
+            last_child: None,
+            changed: false,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Add the detached node `id` to the children of `parent`, before `before` or at the end
+    pub(crate) fn link(&mut self, parent: NodeId, id: NodeId, before: Option<NodeId>) {
+        let previous_sibling = match before {
+            Some(before) => self.node(before).previous_sibling,
+            None => self.node(parent).last_child,
+        };
+        let node = &mut self.nodes[id.0];
+        node.parent = Some(parent);
+        node.previous_sibling = previous_sibling;
+        node.next_sibling = before;
+        match previous_sibling {
+            Some(previous) => self.nodes[previous.0].next_sibling = Some(id),
+            None => self.nodes[parent.0].first_child = Some(id),
+        }
+        match before {
+            Some(before) => self.nodes[before.0].previous_sibling = Some(id),
+            None => self.nodes[parent.0].last_child = Some(id),
+        }
+    }
+
+    /// Remove `id` from its parent, keeping its own children
+    pub(crate) fn unlink(&mut self, id: NodeId) {
+        let node = &mut self.nodes[id.0];
+        let (parent, previous, next) = match node.parent.take() {
+            Some(parent) => (
+                parent,
+                node.previous_sibling.take(),
+                node.next_sibling.take(),
+            ),
+            None => return,
+        };
+        match previous {
+            Some(previous) => self.nodes[previous.0].next_sibling = next,
+            None => self.nodes[parent.0].first_child = next,
+        }
+        match next {
+            Some(next) => self.nodes[next.0].previous_sibling = previous,
+            None => self.nodes[parent.0].last_child = previous,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_navigation() {
+    let (_, doc) = crate::document("<!DOCTYPE html><p>a<b>b</b>c<i></i></p><!--end-->").unwrap();
+    let name = |id| match &doc.node(id).data {
+        NodeData::Element(e) => e.name.clone(),
+        NodeData::Text(t) => format!("'{}'", t),
+        NodeData::Comment(c) => format!("<!--{}-->", c),
+        NodeData::Doctype(d) => format!("<!DOCTYPE {}>", d.name),
+        NodeData::Document => "#document".to_string(),
+    };
+    let names = |ids: Vec<NodeId>| ids.into_iter().map(name).collect::<Vec<_>>();
+
+    let html = doc.document_element();
+    assert_eq!(doc.get_document_element(), Some(html));
+    assert_eq!(Document::new().get_document_element(), None);
+    assert_eq!(
+        names(doc.children(doc.document_node()).collect()),
+        ["<!DOCTYPE html>", "html"]
+    );
+    assert_eq!(
+        names(doc.descendants(html).collect()),
+        [
+            "head",
+            "body",
+            "p",
+            "'a'",
+            "b",
+            "'b'",
+            "'c'",
+            "i",
+            "<!--end-->"
+        ]
+    );
+    let p = doc.descendants(html).nth(2).unwrap();
+    let b = doc.children(p).nth(1).unwrap();
+    assert_eq!(
+        names(doc.ancestors(b).collect()),
+        ["p", "body", "html", "#document"]
+    );
+    assert_eq!(names(doc.descendants(b).collect()), ["'b'"]);
+    assert_eq!(name(doc.node(b).previous_sibling().unwrap()), "'a'");
+    assert_eq!(name(doc.node(b).next_sibling().unwrap()), "'c'");
+    assert_eq!(names(doc.next_element_siblings(b).collect()), ["i"]);
+    assert_eq!(doc.previous_element_siblings(b).next(), None);
+    assert_eq!(name(doc.node(p).last_child().unwrap()), "i");
+    assert_eq!(doc.text_content(p), "abc");
+}
@@ -0,0 +1,302 @@
+//! Determining the character encoding of a document and decoding it, following
+//! <https://html.spec.whatwg.org/multipage/parsing.html#determining-the-character-encoding>
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+use tracing::debug;
+
+/// How many bytes are looked at for a `<meta>` declaring the encoding
+pub(crate) const PRESCAN_LENGTH: usize = 1024;
+
+/// Decode the bytes of an HTML document, returning its text and the encoding it was in.
+/// `content_type` is the `Content-Type` the document was served with, if any.
+///
+/// The encoding is taken from, in order: a byte order mark, the `charset` of the
+/// `Content-Type`, or a `<meta>` near the start of the document. Documents which declare none
+/// are decoded as UTF-8 if they are valid UTF-8, and windows-1252 otherwise. Invalid bytes are
+/// replaced with U+FFFD
+pub fn decode(bytes: &[u8], content_type: Option<&str>) -> (String, &'static Encoding) {
+    let (encoding, bom_length, _) = sniff(bytes, content_type, true);
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+    (text.into_owned(), encoding)
+}
+
+/// How sure the detected encoding of a document is.
+/// <https://html.spec.whatwg.org/multipage/parsing.html#concept-encoding-confidence>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Confidence {
+    // The document declares its encoding, or has been checked as a whole
+    Certain,
+    // The document was guessed to be UTF-8 from its start, and may turn out not to be
+    Tentative,
+}
+
+/// Detect the encoding of a document from its first bytes, as described for [`decode`].
+/// `complete` is whether `bytes` is the whole document. Returns the encoding, the length of
+/// the byte order mark, which should be skipped, and whether the rest of the document could
+/// change the encoding
+pub(crate) fn sniff(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    complete: bool,
+) -> (&'static Encoding, usize, Confidence) {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        debug!("Found byte order mark for {}", encoding.name());
+        return (encoding, bom_length, Confidence::Certain);
+    }
+    let declared = content_type
+        .and_then(charset_parameter)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .or_else(|| prescan(&bytes[..bytes.len().min(PRESCAN_LENGTH)]));
+    let (encoding, confidence) = match declared {
+        Some(encoding) => (encoding, Confidence::Certain),
+        None => match std::str::from_utf8(bytes) {
+            // If there's more to come, the bytes may end part way through a character
+            Err(e) if complete || e.error_len().is_some() => (WINDOWS_1252, Confidence::Certain),
+            _ if complete => (UTF_8, Confidence::Certain),
+            _ => (UTF_8, Confidence::Tentative),
+        },
+    };
+    debug!("Decoding document as {}", encoding.name());
+    (encoding, 0, confidence)
+}
+
+/// Get the `charset` parameter of a MIME type like `text/html; charset="utf-8"`
+fn charset_parameter(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// Look for a `<meta>` declaring the encoding.
+/// <https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding>
+fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        if rest.starts_with(b"<!--") {
+            // The hyphens of the `-->` can overlap the `<!--`
+            let end = rest[2..].windows(3).position(|w| w == b"-->")?;
+            pos += 2 + end + 2;
+        } else if starts_with_ignore_case(rest, b"<meta")
+            && rest
+                .get(5)
+                .map(|&b| is_whitespace(b) || b == b'/')
+                .unwrap_or(false)
+        {
+            pos += 5;
+            if let Some(encoding) = meta_encoding(bytes, &mut pos) {
+                // A document can't really be UTF-16 if its start could be read as ASCII
+                return Some(match encoding {
+                    e if e == UTF_16BE || e == UTF_16LE => UTF_8,
+                    e if e == X_USER_DEFINED => WINDOWS_1252,
+                    e => e,
+                });
+            }
+        } else if rest.len() > 1
+            && rest[0] == b'<'
+            && (rest[1].is_ascii_alphabetic()
+                || (rest[1] == b'/' && rest.get(2).map(u8::is_ascii_alphabetic) == Some(true)))
+        {
+            // Skip over other tags, so attribute values can't be mistaken for markup
+            pos += rest
+                .iter()
+                .position(|&b| is_whitespace(b) || b == b'>')
+                .unwrap_or(rest.len());
+            while attribute(bytes, &mut pos).is_some() {}
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            pos += rest.iter().position(|&b| b == b'>')?;
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// Read the attributes of a `<meta>` tag, returning the encoding it declares if any
+fn meta_encoding(bytes: &[u8], pos: &mut usize) -> Option<&'static Encoding> {
+    let mut seen = vec![];
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+    while let Some((name, value)) = attribute(bytes, pos) {
+        if seen.contains(&name) {
+            continue;
+        }
+        match name.as_slice() {
+            b"http-equiv" => got_pragma |= value == b"content-type",
+            b"content" if charset.is_none() => {
+                if let Some(label) = charset_from_content(&value) {
+                    charset = Encoding::for_label(label);
+                    need_pragma = Some(true);
+                }
+            }
+            b"charset" => {
+                charset = Encoding::for_label(&value);
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+        seen.push(name);
+    }
+    match need_pragma {
+        Some(true) if !got_pragma => None,
+        Some(_) => charset,
+        None => None,
+    }
+}
+
+/// Read an attribute from a tag in the prescan, with its name and value lowercased. Returns
+/// [`None`] at the end of the tag.
+/// <https://html.spec.whatwg.org/multipage/parsing.html#concept-get-attributes-when-sniffing>
+fn attribute(bytes: &[u8], pos: &mut usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    let next = |pos: &usize| bytes.get(*pos).copied();
+    while next(pos).map(|b| is_whitespace(b) || b == b'/')? {
+        *pos += 1;
+    }
+    if next(pos)? == b'>' {
+        return None;
+    }
+    let mut name = vec![];
+    let mut value = vec![];
+    loop {
+        match next(pos)? {
+            b'=' if !name.is_empty() => break,
+            b if is_whitespace(b) => {
+                while is_whitespace(next(pos)?) {
+                    *pos += 1;
+                }
+                if next(pos)? != b'=' {
+                    return Some((name, value));
+                }
+                break;
+            }
+            b'/' | b'>' => return Some((name, value)),
+            b => name.push(b.to_ascii_lowercase()),
+        }
+        *pos += 1;
+    }
+    // Skip the '='
+    *pos += 1;
+    while is_whitespace(next(pos)?) {
+        *pos += 1;
+    }
+    match next(pos)? {
+        quote @ (b'"' | b'\'') => loop {
+            *pos += 1;
+            match next(pos)? {
+                b if b == quote => {
+                    *pos += 1;
+                    return Some((name, value));
+                }
+                b => value.push(b.to_ascii_lowercase()),
+            }
+        },
+        b'>' => Some((name, value)),
+        _ => {
+            while let Some(b) = next(pos).filter(|&b| !is_whitespace(b) && b != b'>') {
+                value.push(b.to_ascii_lowercase());
+                *pos += 1;
+            }
+            Some((name, value))
+        }
+    }
+}
+
+/// Find the encoding label in the `content` of a `<meta http-equiv="Content-Type">`.
+/// <https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element>
+fn charset_from_content(content: &[u8]) -> Option<&[u8]> {
+    let mut pos = 0;
+    loop {
+        pos += content[pos..]
+            .windows(7)
+            .position(|w| w.eq_ignore_ascii_case(b"charset"))?
+            + 7;
+        while content.get(pos).map(|&b| is_whitespace(b)) == Some(true) {
+            pos += 1;
+        }
+        if content.get(pos) != Some(&b'=') {
+            continue;
+        }
+        pos += 1;
+        while content.get(pos).map(|&b| is_whitespace(b)) == Some(true) {
+            pos += 1;
+        }
+        return match *content.get(pos)? {
+            quote @ (b'"' | b'\'') => {
+                let rest = &content[pos + 1..];
+                rest.iter()
+                    .position(|&b| b == quote)
+                    .map(|end| &rest[..end])
+            }
+            _ => {
+                let rest = &content[pos..];
+                let end = rest
+                    .iter()
+                    .position(|&b| is_whitespace(b) || b == b';')
+                    .unwrap_or(rest.len());
+                Some(&rest[..end])
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sniffing() {
+    use encoding_rs::SHIFT_JIS;
+
+    let encoding = |bytes: &[u8], content_type| decode(bytes, content_type).1;
+    assert_eq!(
+        encoding(b"\xEF\xBB\xBF<p>", Some("text/html; charset=windows-1252")),
+        UTF_8
+    );
+    assert_eq!(encoding(b"\xFF\xFE<\0p\0>\0", None), UTF_16LE);
+    assert_eq!(
+        encoding(b"<p>", Some("text/html;charset=\"Shift_JIS\"")),
+        SHIFT_JIS
+    );
+    assert_eq!(
+        encoding(b"<meta charset=sjis>", Some("text/html")),
+        SHIFT_JIS
+    );
+    assert_eq!(encoding(b"<META CHARSET='latin1'/>", None), WINDOWS_1252);
+    assert_eq!(
+        encoding(
+            b"<meta content=\"text/html; charset=shift_jis\" http-equiv=Content-Type>",
+            None
+        ),
+        SHIFT_JIS
+    );
+    // A content-type in `content` needs a matching http-equiv
+    assert_eq!(encoding(b"<meta content='charset=sjis'>", None), UTF_8);
+    // Declarations in comments and attribute values are ignored
+    assert_eq!(encoding(b"<!--<meta charset=sjis>--><p>", None), UTF_8);
+    assert_eq!(encoding(b"<a title='<meta charset=sjis>'>", None), UTF_8);
+    assert_eq!(encoding(b"<!---->\n<meta charset=sjis>", None), SHIFT_JIS);
+    assert_eq!(encoding(b"<meta charset=utf-16le>", None), UTF_8);
+    assert_eq!(encoding(b"<meta charset=bogus>", None), UTF_8);
+    let late = [&[b' '; PRESCAN_LENGTH][..], b"<meta charset=sjis>"].concat();
+    assert_eq!(encoding(&late, None), UTF_8);
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode() {
+    assert_eq!(decode(b"caf\xE9", None).0, "café");
+    assert_eq!(decode("café".as_bytes(), None).0, "café");
+    assert_eq!(
+        decode(b"<meta charset=shift_jis>\x93\xFA\x96\x7B", None).0,
+        "<meta charset=shift_jis>日本"
+    );
+    assert_eq!(decode(b"\xEF\xBB\xBFa\xFF", None).0, "a\u{FFFD}");
+}
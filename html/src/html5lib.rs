@@ -0,0 +1,414 @@
+//! Runs the [html5lib-tests](https://github.com/html5lib/html5lib-tests) conformance suite,
+//! vendored in `tests/html5lib-tests`, against the tokenizer and tree builder. The vendored
+//! files are currently subsets of the upstream ones, see the README there.
+//!
+//! Every file is run and the numbers of its cases which pass and which are skipped, e.g. because
+//! they need scripting, are printed (see them with
+//! `cargo test -p html html5lib -- --nocapture`). The test fails if fewer cases pass than are
+//! recorded in `tests/html5lib-results.txt`. Once more pass, record them by running it again
+//! with `HTML5LIB_BLESS=1` set. Parse errors aren't compared, only tokens and trees. Cases
+//! which panic count as failures
+
+use crate::tokenizer::{State, Token, Tokenizer};
+use crate::{document, parse_fragment, DOMContent, DOMElement, Namespace};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// How many cases of a file passed and how many were skipped, out of all of its cases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Results {
+    passed: usize,
+    skipped: usize,
+    total: usize,
+}
+
+/// The outcome of a single case. Cases which can't be run here are skipped
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip,
+}
+
+/// Run a case, counting it as failed if it panics, so one bad case doesn't stop the rest of
+/// the suite from being run
+fn run_case(case: impl FnOnce() -> Outcome) -> Outcome {
+    panic::catch_unwind(AssertUnwindSafe(case))
+        .unwrap_or_else(|_| Outcome::Fail("panicked".to_string()))
+}
+
+/// Runs the cases in the source of a test file
+type RunCases = fn(&str) -> Vec<(String, Outcome)>;
+
+/// Run every case of a file, printing the failures
+fn run_file(file: &str, cases: Vec<(String, Outcome)>) -> Results {
+    let mut results = Results::default();
+    for (name, outcome) in cases {
+        match outcome {
+            Outcome::Pass => results.passed += 1,
+            Outcome::Fail(reason) => println!("FAIL {}: {}\n{}", file, name, reason),
+            Outcome::Skip => results.skipped += 1,
+        }
+        results.total += 1;
+    }
+    results
+}
+
+/// Undo the extra escaping of `doubleEscaped` tokenizer cases. Returns [`None`] for lone
+/// surrogates, which can't be in a Rust string
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find("\\u") {
+        out.push_str(&rest[..i]);
+        let code = u32::from_str_radix(rest.get(i + 2..i + 6)?, 16).ok()?;
+        out.push(char::from_u32(code)?);
+        rest = &rest[i + 6..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+fn unescape_value(value: &Value) -> Option<Value> {
+    Some(match value {
+        Value::String(s) => Value::String(unescape(s)?),
+        Value::Array(values) => {
+            Value::Array(values.iter().map(unescape_value).collect::<Option<_>>()?)
+        }
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| Some((unescape(k)?, unescape_value(v)?)))
+                .collect::<Option<_>>()?,
+        ),
+        v => v.clone(),
+    })
+}
+
+/// Convert tokens to the format of tokenizer tests, with adjacent characters joined together
+fn tokens_to_json(tokens: Vec<Token>) -> Value {
+    let mut output: Vec<Value> = vec![];
+    for token in tokens {
+        let value = match token {
+            Token::Doctype(d) => {
+                json!(["DOCTYPE", d.name, d.public_id, d.system_id, !d.force_quirks])
+            }
+            Token::StartTag(tag) => {
+                let attributes: serde_json::Map<String, Value> = tag
+                    .attributes
+                    .into_iter()
+                    .map(|a| (a.name, Value::String(a.value)))
+                    .collect();
+                if tag.self_closing {
+                    json!(["StartTag", tag.name, attributes, true])
+                } else {
+                    json!(["StartTag", tag.name, attributes])
+                }
+            }
+            Token::EndTag(tag) => json!(["EndTag", tag.name]),
+            Token::Comment(c) => json!(["Comment", c]),
+            Token::Character(c) => {
+                if let Some(Value::Array(last)) = output.last_mut() {
+                    if let [Value::String(kind), Value::String(text)] = last.as_mut_slice() {
+                        if kind == "Character" {
+                            text.push(c);
+                            continue;
+                        }
+                    }
+                }
+                json!(["Character", c.to_string()])
+            }
+            Token::Eof => break,
+        };
+        output.push(value);
+    }
+    Value::Array(output)
+}
+
+fn initial_state(name: &str) -> Option<State> {
+    Some(match name {
+        "Data state" => State::Data,
+        "PLAINTEXT state" => State::Plaintext,
+        "RCDATA state" => State::Rcdata,
+        "RAWTEXT state" => State::Rawtext,
+        "Script data state" => State::ScriptData,
+        "CDATA section state" => State::CdataSection,
+        _ => return None,
+    })
+}
+
+/// Run the cases of a tokenizer `.test` file. Each is run once for each of its initial states
+fn tokenizer_cases(source: &str) -> Vec<(String, Outcome)> {
+    let file: Value = serde_json::from_str(source).expect("Invalid tokenizer test");
+    let mut cases = vec![];
+    let tests = file["tests"].as_array().cloned().unwrap_or_default();
+    for test in tests {
+        let description = test["description"].as_str().unwrap_or_default();
+        let (input, expected) = if test["doubleEscaped"] == Value::Bool(true) {
+            match (
+                unescape_value(&test["input"]),
+                unescape_value(&test["output"]),
+            ) {
+                (Some(input), Some(output)) => (input, output),
+                _ => {
+                    cases.push((description.to_string(), Outcome::Skip));
+                    continue;
+                }
+            }
+        } else {
+            (test["input"].clone(), test["output"].clone())
+        };
+        let input = input.as_str().unwrap_or_default();
+        let states = match test["initialStates"].as_array() {
+            Some(states) => states.iter().filter_map(Value::as_str).collect(),
+            None => vec!["Data state"],
+        };
+        for state_name in states {
+            let name = format!("{} ({})", description, state_name);
+            let state = match initial_state(state_name) {
+                Some(state) => state,
+                None => {
+                    cases.push((name, Outcome::Skip));
+                    continue;
+                }
+            };
+            let outcome = run_case(|| {
+                let mut tokenizer = Tokenizer::new();
+                tokenizer.set_state(state);
+                if let Some(tag) = test["lastStartTag"].as_str() {
+                    tokenizer.set_last_start_tag(tag);
+                }
+                tokenizer.feed(input);
+                tokenizer.end();
+                let tokens = std::iter::from_fn(|| tokenizer.next_token())
+                    .map(|(t, _)| t)
+                    .collect();
+                let actual = tokens_to_json(tokens);
+                if actual == expected {
+                    Outcome::Pass
+                } else {
+                    Outcome::Fail(format!(
+                        "input:    {:?}\nexpected: {}\nactual:   {}",
+                        input, expected, actual
+                    ))
+                }
+            });
+            cases.push((name, outcome));
+        }
+    }
+    cases
+}
+
+/// A case of a tree construction `.dat` file
+#[derive(Default)]
+struct TreeTest {
+    data: String,
+    fragment: Option<String>,
+    scripting: Option<bool>,
+    document: String,
+}
+
+/// Split a `.dat` file into its cases, each of which is a list of `#sections`
+fn parse_dat(source: &str) -> Vec<TreeTest> {
+    let mut tests = vec![];
+    let mut sections: Vec<(&str, Vec<&str>)> = vec![];
+    let mut finish = |sections: &mut Vec<(&str, Vec<&str>)>| {
+        if sections.is_empty() {
+            return;
+        }
+        let mut test = TreeTest::default();
+        for (name, lines) in sections.drain(..) {
+            match name {
+                "#data" => test.data = lines.join("\n"),
+                "#document-fragment" => test.fragment = Some(lines.join("\n")),
+                "#script-on" => test.scripting = Some(true),
+                "#script-off" => test.scripting = Some(false),
+                // Cases are separated by a blank line
+                "#document" => test.document = lines.join("\n").trim_end_matches('\n').to_string(),
+                _ => {}
+            }
+        }
+        tests.push(test);
+    };
+    for line in source.lines() {
+        match line {
+            "#data" => {
+                finish(&mut sections);
+                sections.push((line, vec![]));
+            }
+            "#errors" | "#new-errors" | "#document-fragment" | "#script-on" | "#script-off"
+            | "#document" => sections.push((line, vec![])),
+            _ => {
+                if let Some((_, lines)) = sections.last_mut() {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+    finish(&mut sections);
+    tests
+}
+
+/// Write a tree in the format of tree construction tests
+fn dump(contents: &[DOMContent], depth: usize, out: &mut String) {
+    for content in contents {
+        let indent = "  ".repeat(depth);
+        match content {
+            DOMContent::Element(e) => {
+                let prefix = match e.namespace {
+                    Namespace::Html => "",
+                    Namespace::Svg => "svg ",
+                    Namespace::MathMl => "math ",
+                };
+                writeln!(out, "| {}<{}{}>", indent, prefix, e.name).unwrap();
+                let attributes: BTreeMap<_, _> = e.attributes.0.iter().collect();
+                for (name, value) in attributes {
+                    writeln!(out, "| {}  {}=\"{}\"", indent, name, value).unwrap();
+                }
+                if e.namespace == Namespace::Html && e.name == "template" {
+                    writeln!(out, "| {}  content", indent).unwrap();
+                    dump(&e.contents, depth + 2, out);
+                } else {
+                    dump(&e.contents, depth + 1, out);
+                }
+            }
+            DOMContent::Text(t) => writeln!(out, "| {}\"{}\"", indent, t.text).unwrap(),
+            DOMContent::Comment(c) => writeln!(out, "| {}<!-- {} -->", indent, c).unwrap(),
+            DOMContent::Doctype(d) if d.public_id.is_none() && d.system_id.is_none() => {
+                writeln!(out, "| {}<!DOCTYPE {}>", indent, d.name).unwrap()
+            }
+            DOMContent::Doctype(d) => writeln!(
+                out,
+                "| {}<!DOCTYPE {} \"{}\" \"{}\">",
+                indent,
+                d.name,
+                d.public_id.as_deref().unwrap_or_default(),
+                d.system_id.as_deref().unwrap_or_default()
+            )
+            .unwrap(),
+        }
+    }
+}
+
+/// Make the context element of a fragment case, e.g. `td` or `svg path`
+fn context_element(context: &str) -> DOMElement {
+    match context.split_once(' ') {
+        Some(("svg", name)) => DOMElement::new_foreign(Namespace::Svg, name, None, vec![]),
+        Some(("math", name)) => DOMElement::new_foreign(Namespace::MathMl, name, None, vec![]),
+        _ => DOMElement::new(context, None, vec![]),
+    }
+}
+
+/// Run the cases of a tree construction `.dat` file. The parser doesn't run scripts, so cases
+/// which need scripting are skipped
+fn tree_construction_cases(source: &str) -> Vec<(String, Outcome)> {
+    parse_dat(source)
+        .into_iter()
+        .map(|test| {
+            if test.scripting == Some(true) {
+                return (test.data, Outcome::Skip);
+            }
+            let outcome = run_case(|| {
+                let contents = match &test.fragment {
+                    Some(context) => parse_fragment(&context_element(context), &test.data),
+                    None => document(&test.data).unwrap().1.contents(),
+                };
+                let mut actual = String::new();
+                dump(&contents, 0, &mut actual);
+                let actual = actual.trim_end_matches('\n');
+                if actual == test.document {
+                    Outcome::Pass
+                } else {
+                    Outcome::Fail(format!("expected:\n{}\nactual:\n{}", test.document, actual))
+                }
+            });
+            (test.data, outcome)
+        })
+        .collect()
+}
+
+/// The test files in a directory of the suite, in order
+fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|e| Some(e.ok()?.path())).collect())
+        .unwrap_or_default();
+    files.retain(|f| f.extension().and_then(|e| e.to_str()) == Some(extension));
+    files.sort();
+    files
+}
+
+/// Read the results recorded in the results file, by path relative to the suite. Each line is
+/// e.g. `tree-construction/tests1.dat 21/22 passed, 1 skipped`
+fn read_results(path: &Path) -> BTreeMap<String, Results> {
+    let source = fs::read_to_string(path).unwrap_or_default();
+    source
+        .lines()
+        .filter_map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [file, counts, "passed,", skipped, "skipped"] = words[..] else {
+                return None;
+            };
+            let (passed, total) = counts.split_once('/')?;
+            let results = Results {
+                passed: passed.parse().ok()?,
+                skipped: skipped.parse().ok()?,
+                total: total.parse().ok()?,
+            };
+            Some((file.to_string(), results))
+        })
+        .collect()
+}
+
+#[test]
+fn test_html5lib() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let suite = root.join("html5lib-tests");
+    let results_path = root.join("html5lib-results.txt");
+
+    // Results by the path of the file within the suite
+    let mut results = BTreeMap::new();
+    let kinds: [(&str, &str, RunCases); 2] = [
+        ("tokenizer", "test", tokenizer_cases),
+        ("tree-construction", "dat", tree_construction_cases),
+    ];
+    for (dir, extension, cases) in kinds {
+        for file in files(&suite.join(dir), extension) {
+            let name = format!("{}/{}", dir, file.file_name().unwrap().to_string_lossy());
+            let source = fs::read_to_string(&file).unwrap();
+            let r = run_file(&name, cases(&source));
+            results.insert(name, r);
+        }
+    }
+
+    let mut report = String::new();
+    for (file, r) in &results {
+        writeln!(
+            report,
+            "{} {}/{} passed, {} skipped",
+            file, r.passed, r.total, r.skipped
+        )
+        .unwrap();
+    }
+    println!("{}", report);
+    if std::env::var_os("HTML5LIB_BLESS").is_some() {
+        fs::write(&results_path, report).unwrap();
+        return;
+    }
+
+    let expected = read_results(&results_path);
+    let regressions: Vec<String> = results
+        .iter()
+        .filter_map(|(file, r)| {
+            let passed = expected.get(file).map(|e| e.passed).unwrap_or(0);
+            (r.passed < passed)
+                .then(|| format!("{}: {} passed, expected {}", file, r.passed, passed))
+        })
+        .collect();
+    assert!(
+        regressions.is_empty(),
+        "Fewer html5lib-tests cases pass:\n{}",
+        regressions.join("\n")
+    );
+}
@@ -1,20 +1,113 @@
-#![feature(iter_intersperse)]
-
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
+/// A location in the source of a document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourcePosition {
+    /// Byte offset into the input
+    pub offset: usize,
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Byte offset into the line, starting at 1
+    pub column: usize,
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The range of the source a node was parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceSpan {
+    pub start: SourcePosition,
+    /// The position just after the end of the node
+    pub end: SourcePosition,
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// The `<!DOCTYPE>` of a document
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DocumentType {
+    pub name: String,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+}
+
+/// <https://dom.spec.whatwg.org/#concept-document-quirks>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuirksMode {
+    #[default]
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
+/// A recoverable error encountered while parsing a document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Where in the input the error occurred
+    pub position: SourcePosition,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.position)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct DOMElement {
     pub name: String,
+    pub namespace: Namespace,
     pub attributes: DOMAttributes,
     pub contents: Vec<DOMContent>,
+    /// Where the element was parsed from, running from its start tag to its end tag or
+    /// wherever it was implicitly closed. [`None`] if it was not created by the parser
+    pub span: Option<SourceSpan>,
+}
+
+/// Source positions are not compared, so parsed trees can be compared with constructed ones
+impl PartialEq for DOMElement {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.namespace == other.namespace
+            && self.attributes == other.attributes
+            && self.contents == other.contents
+    }
 }
 
 impl DOMElement {
     pub fn new(name: &str, attributes: Option<DOMAttributes>, contents: Vec<DOMContent>) -> Self {
         Self {
             name: name.to_lowercase(),
+            namespace: Namespace::Html,
             attributes: attributes.unwrap_or_default(),
             contents,
+            span: None,
+        }
+    }
+
+    /// Create an SVG or MathML element. Unlike HTML elements, their names are case-sensitive
+    pub fn new_foreign(
+        namespace: Namespace,
+        name: &str,
+        attributes: Option<DOMAttributes>,
+        contents: Vec<DOMContent>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            namespace,
+            attributes: attributes.unwrap_or_default(),
+            contents,
+            span: None,
         }
     }
 
@@ -40,7 +133,7 @@ impl DOMElement {
                 })
                 .collect()
         } else {
-            todo!()
+            self.descendants().filter(|e| e.name == name).collect()
         }
     }
 
@@ -50,6 +143,15 @@ impl DOMElement {
     }
 }
 
+/// The namespace an element belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Namespace {
+    #[default]
+    Html,
+    Svg,
+    MathMl,
+}
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct DOMAttributes(pub HashMap<String, String>);
 
@@ -62,15 +164,37 @@ macro_rules! attributes {
     }
 }
 
+/// A text node
+#[derive(Debug, Clone)]
+pub struct DOMText {
+    pub text: String,
+    /// Where the text was parsed from. [`None`] if it was not created by the parser
+    pub span: Option<SourceSpan>,
+}
+
+/// Like [`DOMElement`], source positions are not compared
+impl PartialEq for DOMText {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum DOMContent {
-    Text(String),
+    Text(DOMText),
     Element(DOMElement),
+    /// A comment. Processing instructions (`<?...>`) are parsed as comments too
+    Comment(String),
+    /// Only found at the top level of a [`Document`]
+    Doctype(DocumentType),
 }
 
 impl From<&str> for DOMContent {
     fn from(s: &str) -> Self {
-        DOMContent::Text(s.to_string())
+        DOMContent::Text(DOMText {
+            text: s.to_string(),
+            span: None,
+        })
     }
 }
 
@@ -80,8 +204,22 @@ impl From<DOMElement> for DOMContent {
     }
 }
 
+mod dom;
+mod encoding;
+mod entities;
+#[cfg(test)]
+mod html5lib;
+mod mutation;
 mod parsing;
+mod query;
+mod serialize;
 #[cfg(test)]
 mod tests;
+mod tokenizer;
+mod tree_builder;
 
-pub use parsing::document;
+pub use dom::{Document, ElementData, Node, NodeData, NodeId};
+pub use encoding::decode;
+pub use mutation::MutationError;
+pub use parsing::{document, parse_fragment, Parser};
+pub use query::Descendants;
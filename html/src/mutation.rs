@@ -0,0 +1,409 @@
+//! Changing the tree of a [`Document`], following <https://dom.spec.whatwg.org/#mutation-algorithms>
+//!
+//! Every change marks the node it was made to, so [`Document::changed_subtrees`] can report
+//! which parts of the document need to be looked at again
+
+use crate::{DOMAttributes, Document, ElementData, Namespace, NodeData, NodeId};
+use std::{fmt, iter};
+
+/// Why a change to a [`Document`] could not be made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MutationError {
+    /// The node can't be put there, e.g. because it would become its own ancestor, the parent
+    /// is not an element, or the document would have a second doctype or one after its element
+    HierarchyRequest,
+    /// The node the change was relative to is not a child of the parent
+    NotFound,
+    /// Only elements have attributes
+    NotAnElement,
+    /// The document must always have a root element, so it can't be removed or moved elsewhere
+    RootElement,
+}
+
+impl fmt::Display for MutationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MutationError::HierarchyRequest => "The node can't be inserted there",
+            MutationError::NotFound => "The node is not a child of the parent",
+            MutationError::NotAnElement => "The node is not an element",
+            MutationError::RootElement => "The root element can't be taken out of the document",
+        })
+    }
+}
+
+impl Document {
+    /// Create an HTML element, which is not in the document until it's inserted
+    pub fn create_element(&mut self, name: &str) -> NodeId {
+        self.create(
+            NodeData::Element(ElementData {
+                name: name.to_lowercase(),
+                namespace: Namespace::Html,
+                attributes: DOMAttributes::default(),
+            }),
+            None,
+        )
+    }
+
+    /// Create a text node, which is not in the document until it's inserted
+    pub fn create_text_node(&mut self, text: &str) -> NodeId {
+        self.create(NodeData::Text(text.to_string()), None)
+    }
+
+    /// Add `child` as the last child of `parent`. If `child` is already in the tree, it is
+    /// moved
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), MutationError> {
+        self.insert_before(parent, child, None)
+    }
+
+    /// Add `child` to `parent` before its child `before`, or at the end if it's [`None`]. If
+    /// `child` is already in the tree, it is moved
+    pub fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        before: Option<NodeId>,
+    ) -> Result<(), MutationError> {
+        if let Some(before) = before {
+            if self.node(before).parent() != Some(parent) {
+                return Err(MutationError::NotFound);
+            }
+        }
+        self.check_insert(parent, child, before, false)?;
+        if parent != self.document_node() && self.is_root_element(child) {
+            return Err(MutationError::RootElement);
+        }
+        // Inserting a node before itself leaves it where it is
+        let before = if before == Some(child) {
+            self.node(child).next_sibling()
+        } else {
+            before
+        };
+        if let Some(old_parent) = self.node(child).parent() {
+            self.unlink(child);
+            self.mark_changed(old_parent);
+        }
+        self.link(parent, child, before);
+        self.mark_changed(parent);
+        Ok(())
+    }
+
+    /// Remove `child` from `parent`. It can be inserted again afterwards
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), MutationError> {
+        if self.node(child).parent() != Some(parent) {
+            return Err(MutationError::NotFound);
+        }
+        if self.is_root_element(child) {
+            return Err(MutationError::RootElement);
+        }
+        self.unlink(child);
+        self.mark_changed(parent);
+        Ok(())
+    }
+
+    /// Put `child` in the place of `parent`'s child `old`, which is removed
+    pub fn replace_child(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        old: NodeId,
+    ) -> Result<(), MutationError> {
+        if self.node(old).parent() != Some(parent) {
+            return Err(MutationError::NotFound);
+        }
+        if child == old {
+            return Ok(());
+        }
+        // Everything is checked before the tree is touched, so a failed replacement leaves it
+        // as it was
+        self.check_insert(parent, child, Some(old), true)?;
+        if parent != self.document_node() && self.is_root_element(child) {
+            return Err(MutationError::RootElement);
+        }
+        if self.is_root_element(old) && self.element(child).is_none() {
+            return Err(MutationError::RootElement);
+        }
+        let before = match self.node(old).next_sibling() {
+            Some(next) if next == child => self.node(child).next_sibling(),
+            next => next,
+        };
+        if let Some(old_parent) = self.node(child).parent() {
+            self.unlink(child);
+            self.mark_changed(old_parent);
+        }
+        self.unlink(old);
+        self.link(parent, child, before);
+        self.mark_changed(parent);
+        Ok(())
+    }
+
+    /// Set the attribute `name` of the element `id` to `value`
+    pub fn set_attribute(
+        &mut self,
+        id: NodeId,
+        name: &str,
+        value: &str,
+    ) -> Result<(), MutationError> {
+        let element = match &mut self.node_mut(id).data {
+            NodeData::Element(e) => e,
+            _ => return Err(MutationError::NotAnElement),
+        };
+        // Attribute names of HTML elements are case-insensitive
+        let name = match element.namespace {
+            Namespace::Html => name.to_ascii_lowercase(),
+            _ => name.to_string(),
+        };
+        element.attributes.0.insert(name, value.to_string());
+        self.mark_changed(id);
+        Ok(())
+    }
+
+    /// Remove the attribute `name` from `id`, returning its value if it had one
+    pub fn remove_attribute(&mut self, id: NodeId, name: &str) -> Option<String> {
+        let element = match &mut self.node_mut(id).data {
+            NodeData::Element(e) => e,
+            _ => return None,
+        };
+        let name = match element.namespace {
+            Namespace::Html => name.to_ascii_lowercase(),
+            _ => name.to_string(),
+        };
+        let value = element.attributes.0.remove(&name)?;
+        self.mark_changed(id);
+        Some(value)
+    }
+
+    /// Replace the contents of `id` with `text`. For elements, this removes all of their
+    /// children, and adds a single text node unless `text` is empty
+    pub fn set_text_content(&mut self, id: NodeId, text: &str) {
+        match &mut self.node_mut(id).data {
+            NodeData::Text(t) | NodeData::Comment(t) => *t = text.to_string(),
+            NodeData::Element(_) => {
+                let children: Vec<NodeId> = self.children(id).collect();
+                children.into_iter().for_each(|child| self.unlink(child));
+                if !text.is_empty() {
+                    let text = self.create_text_node(text);
+                    self.link(id, text, None);
+                }
+            }
+            NodeData::Document | NodeData::Doctype(_) => return,
+        }
+        self.mark_changed(id);
+    }
+
+    /// Whether `id` is the root element of the document
+    fn is_root_element(&self, id: NodeId) -> bool {
+        self.element(id).is_some() && self.node(id).parent() == Some(self.document_node())
+    }
+
+    /// Check that `child` can be inserted into `parent` before `before`, or in its place if
+    /// `replacing`. A document has at most one doctype and one element, with the doctype first.
+    /// <https://dom.spec.whatwg.org/#concept-node-ensure-pre-insertion-validity>
+    fn check_insert(
+        &self,
+        parent: NodeId,
+        child: NodeId,
+        before: Option<NodeId>,
+        replacing: bool,
+    ) -> Result<(), MutationError> {
+        let parent_is_document = match &self.node(parent).data {
+            NodeData::Document => true,
+            NodeData::Element(_) => false,
+            _ => return Err(MutationError::HierarchyRequest),
+        };
+        if child == parent || self.ancestors(parent).any(|a| a == child) {
+            return Err(MutationError::HierarchyRequest);
+        }
+        let is_doctype = |id| matches!(self.node(id).data, NodeData::Doctype(_));
+        let is_element = |id| self.element(id).is_some();
+        // The node being replaced doesn't count
+        let others = || {
+            self.children(parent)
+                .filter(move |&c| !(replacing && Some(c) == before))
+        };
+        let preceding = || {
+            let start = match before {
+                Some(before) => self.node(before).previous_sibling(),
+                None => self.node(parent).last_child(),
+            };
+            iter::successors(start, |&s| self.node(s).previous_sibling())
+        };
+        let following = || {
+            let start = match (before, replacing) {
+                (Some(before), true) => self.node(before).next_sibling(),
+                (before, _) => before,
+            };
+            iter::successors(start, |&s| self.node(s).next_sibling())
+        };
+        let allowed = match &self.node(child).data {
+            NodeData::Document => false,
+            NodeData::Doctype(_) => {
+                parent_is_document && !others().any(is_doctype) && !preceding().any(is_element)
+            }
+            NodeData::Text(_) => !parent_is_document,
+            // The document can only have one element
+            NodeData::Element(_) if parent_is_document => {
+                others().all(|c| c == child || !is_element(c)) && !following().any(is_doctype)
+            }
+            NodeData::Element(_) | NodeData::Comment(_) => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(MutationError::HierarchyRequest)
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_mutation() {
+    let (_, mut doc) =
+        crate::document("<ul id=list><li>1<li id=two>2<li>3</ul><p id=p>Text</p>").unwrap();
+    let list = doc.get_element_by_id("list").unwrap();
+    let two = doc.get_element_by_id("two").unwrap();
+    let p = doc.get_element_by_id("p").unwrap();
+    let body = |doc: &Document| {
+        let root = doc.root();
+        root.get_elements_by_name("body", false)[0].inner_html()
+    };
+
+    let item = doc.create_element("LI");
+    doc.set_text_content(item, "0");
+    doc.insert_before(list, item, doc.node(list).first_child())
+        .unwrap();
+    doc.remove_child(list, two).unwrap();
+    assert_eq!(
+        body(&doc),
+        r#"<ul id="list"><li>0</li><li>1</li><li>3</li></ul><p id="p">Text</p>"#
+    );
+
+    // Moving a node takes it out of its old parent
+    doc.append_child(p, item).unwrap();
+    doc.replace_child(list, two, doc.node(list).last_child().unwrap())
+        .unwrap();
+    doc.set_attribute(p, "CLASS", "x").unwrap();
+    assert_eq!(doc.remove_attribute(p, "id"), Some("p".to_string()));
+    assert_eq!(doc.remove_attribute(p, "id"), None);
+    assert_eq!(
+        body(&doc),
+        r#"<ul id="list"><li>1</li><li id="two">2</li></ul><p class="x">Text<li>0</li></p>"#
+    );
+
+    doc.set_text_content(p, "");
+    assert_eq!(doc.node(p).first_child(), None);
+    let text = doc.node(two).first_child().unwrap();
+    doc.set_text_content(text, "Two");
+    assert_eq!(doc.text_content(list), "1Two");
+}
+
+#[cfg(test)]
+#[test]
+fn test_mutation_errors() {
+    let (_, mut doc) = crate::document("<div><p>a</p></div>").unwrap();
+    let div = doc.get_elements_by_name(doc.document_element(), "div", true)[0];
+    let p = doc.node(div).first_child().unwrap();
+    let text = doc.node(p).first_child().unwrap();
+    let root = doc.document_node();
+    let item = doc.create_element("li");
+
+    let hierarchy = Err(MutationError::HierarchyRequest);
+    assert_eq!(doc.append_child(p, div), hierarchy);
+    assert_eq!(doc.append_child(div, div), hierarchy);
+    assert_eq!(doc.append_child(text, item), hierarchy);
+    assert_eq!(doc.append_child(div, root), hierarchy);
+    assert_eq!(doc.append_child(root, item), hierarchy);
+    let text = doc.create_text_node("a");
+    assert_eq!(doc.append_child(root, text), hierarchy);
+
+    let not_found = Err(MutationError::NotFound);
+    assert_eq!(doc.remove_child(div, item), not_found);
+    assert_eq!(doc.insert_before(div, item, Some(text)), not_found);
+    assert_eq!(doc.replace_child(p, item, div), not_found);
+    assert_eq!(
+        doc.set_attribute(text, "a", "b"),
+        Err(MutationError::NotAnElement)
+    );
+    // The root element can't be removed, or moved out of the document
+    let html = doc.document_element();
+    let root_element = Err(MutationError::RootElement);
+    assert_eq!(doc.remove_child(root, html), root_element);
+    assert_eq!(doc.append_child(item, html), root_element);
+    assert_eq!(doc.document_element(), html);
+    assert!(doc.changed_subtrees().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_replace_root_element() {
+    let (_, mut doc) = crate::document("<!--a--><p>b</p>").unwrap();
+    let root = doc.document_node();
+    let html = doc.document_element();
+    let comment = doc.node(root).first_child().unwrap();
+    // The document would be left without a root element
+    assert_eq!(
+        doc.replace_child(root, comment, html),
+        Err(MutationError::RootElement)
+    );
+    assert_eq!(doc.children(root).collect::<Vec<_>>(), [comment, html]);
+    assert!(doc.changed_subtrees().is_empty());
+
+    let div = doc.create_element("div");
+    doc.replace_child(root, div, html).unwrap();
+    assert_eq!(doc.children(root).collect::<Vec<_>>(), [comment, div]);
+    assert_eq!(doc.document_element(), div);
+    assert_eq!(doc.node(html).parent(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_changed_subtrees() {
+    let (_, mut doc) =
+        crate::document("<div id=a><p id=b>1</p></div><div id=c><p id=d>2</p></div>").unwrap();
+    let [a, b, c, d] = ["a", "b", "c", "d"].map(|id| doc.get_element_by_id(id).unwrap());
+    assert!(doc.changed_subtrees().is_empty());
+
+    doc.set_attribute(d, "class", "x").unwrap();
+    doc.set_attribute(b, "class", "x").unwrap();
+    assert_eq!(doc.changed_subtrees(), [b, d]);
+    // Changes below a changed node are part of its subtree
+    doc.set_attribute(a, "class", "x").unwrap();
+    assert_eq!(doc.changed_subtrees(), [a, d]);
+
+    doc.clear_changes();
+    doc.remove_child(c, d).unwrap();
+    doc.set_text_content(d, "Removed");
+    assert_eq!(doc.changed_subtrees(), [c]);
+
+    doc.clear_changes();
+    doc.append_child(b, d).unwrap();
+    assert_eq!(doc.changed_subtrees(), [b]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_doctype_insertion() {
+    let (_, mut doc) = crate::document("<!DOCTYPE html><!--a--><p>b</p>").unwrap();
+    let root = doc.document_node();
+    let html = doc.document_element();
+    let doctype = doc.node(root).first_child().unwrap();
+    let comment = doc.node(doctype).next_sibling().unwrap();
+    let hierarchy = Err(MutationError::HierarchyRequest);
+    // The document already has a doctype
+    assert_eq!(doc.insert_before(root, doctype, Some(comment)), hierarchy);
+    assert_eq!(doc.replace_child(root, doctype, comment), hierarchy);
+    assert_eq!(
+        doc.children(root).collect::<Vec<_>>(),
+        [doctype, comment, html]
+    );
+
+    doc.replace_child(root, comment, doctype).unwrap();
+    // The doctype has to come before the element
+    assert_eq!(doc.append_child(root, doctype), hierarchy);
+    assert_eq!(doc.children(root).collect::<Vec<_>>(), [comment, html]);
+    doc.insert_before(root, doctype, Some(html)).unwrap();
+    assert_eq!(
+        doc.children(root).collect::<Vec<_>>(),
+        [comment, doctype, html]
+    );
+    assert_eq!(doc.doctype().map(|d| d.name.as_str()), Some("html"));
+}
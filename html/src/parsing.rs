@@ -1,387 +1,683 @@
-use nom::{
-    branch::alt,
-    bytes::complete::{tag, tag_no_case, take_until},
-    character::complete::{alphanumeric1, anychar, char, multispace0, none_of, one_of, space1},
-    combinator::{map, opt, value, verify},
-    multi::{many0, many1, many_till},
-    sequence::{delimited, preceded, terminated, tuple},
-    IResult,
-};
-use tracing::{span, Level};
+use encoding_rs::{Decoder, Encoding, WINDOWS_1252};
+use nom::IResult;
+use std::io::{self, Read};
+use std::mem;
+use tracing::{debug, span, Level};
 
 use super::*;
+use crate::encoding::{sniff, Confidence, PRESCAN_LENGTH};
+use crate::tokenizer::Tokenizer;
+use crate::tree_builder::TreeBuilder;
 
-static VOID_ELEMENTS: &[&str] = &[
-    "area", "base", "br", "col", "command", "embed", "hr", "img", "input", "keygen", "link",
-    "meta", "param", "source", "track", "wbr",
-];
-static RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
-
-#[derive(Debug, PartialEq, Clone)]
-struct Tag<'a> {
-    pub opening: bool,
-    pub name: &'a str,
-    pub attributes: DOMAttributes,
+/// Parses an HTML document. Following the HTML parsing algorithm, this never fails: malformed
+/// markup is recovered from in the same way as browsers do, and recorded in [`Document::errors`]
+pub fn document(input: &str) -> IResult<&str, Document> {
+    let span = span!(Level::DEBUG, "Parsing HTML");
+    let _enter = span.enter();
+    let mut parser = Parser::new();
+    parser.feed(input);
+    Ok(("", parser.finish()))
+}
+
+/// Parses `input` as the contents of `context`, in the same way as setting its `innerHTML`. The
+/// context decides how the input is parsed: e.g. text in a `<textarea>` is never markup, and
+/// table rows need to be in a table
+pub fn parse_fragment(context: &DOMElement, input: &str) -> Vec<DOMContent> {
+    let span = span!(Level::DEBUG, "Parsing HTML fragment");
+    let _enter = span.enter();
+    let mut parser = Parser::with_builder(TreeBuilder::for_fragment(context));
+    parser.feed(input);
+    parser.finish().root().contents
+}
+
+/// How many bytes of a document guessed to be UTF-8 are kept, in case it has to be parsed again.
+/// A document which is still valid UTF-8 after these is taken to be UTF-8, so memory stays bounded
+const TENTATIVE_LENGTH: usize = 64 * 1024;
+
+/// A push parser, which builds a document from input as it arrives. Input is parsed as soon as
+/// it is fed in, and dropped once it has been parsed, except for the start of a document whose
+/// encoding is only a guess (see [`Parser::feed_bytes`])
+pub struct Parser {
+    tokenizer: Tokenizer,
+    builder: TreeBuilder,
+    // The `Content-Type` bytes were served with
+    content_type: Option<String>,
+    // Bytes held back until there are enough to detect their encoding
+    sniff_buffer: Vec<u8>,
+    decoder: Option<Decoder>,
+    // Every byte decoded so far while the encoding is a guess of UTF-8, so the document can be
+    // parsed again if it turns out not to be. Dropped after `TENTATIVE_LENGTH` bytes
+    tentative: Option<Vec<u8>>,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<'a> Tag<'a> {
-    pub fn opening(name: &'a str, attributes: Option<impl Into<DOMAttributes>>) -> Self {
-        let attributes = match attributes {
-            Some(a) => a.into(),
-            None => Default::default(),
-        };
+impl Parser {
+    pub fn new() -> Self {
+        Self::with_builder(TreeBuilder::new())
+    }
+
+    fn with_builder(mut builder: TreeBuilder) -> Self {
+        let mut tokenizer = Tokenizer::new();
+        if let Some(state) = builder.take_tokenizer_state() {
+            tokenizer.set_state(state);
+        }
+        tokenizer.set_cdata_allowed(builder.in_foreign_content());
         Self {
-            name,
-            attributes,
-            opening: true,
+            tokenizer,
+            builder,
+            content_type: None,
+            sniff_buffer: vec![],
+            decoder: None,
+            tentative: None,
         }
     }
-    pub fn closing(name: &'a str) -> Self {
-        Self {
-            name,
-            attributes: Default::default(),
-            opening: false,
+
+    /// Set the `Content-Type` the document was served with, whose `charset` is used to decode
+    /// the input to [`Parser::feed_bytes`]. This has no effect once bytes have been decoded
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.content_type = Some(content_type.to_string());
+    }
+
+    /// The encoding input bytes are being decoded from, once it has been detected
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        self.decoder.as_ref().map(Decoder::encoding)
+    }
+
+    /// Parse the next chunk of the document
+    pub fn feed(&mut self, chunk: &str) {
+        self.tokenizer.feed(chunk);
+        self.run();
+    }
+
+    /// Parse the next chunk of the document from bytes. The encoding is detected as with
+    /// [`decode`], from the first kilobyte of input. A document which doesn't declare its
+    /// encoding is parsed as UTF-8 while it is valid UTF-8, but if it stops being so within the
+    /// first 64KiB, the whole document is parsed again as windows-1252. Until then, its bytes
+    /// are kept in memory. Invalid bytes after that are replaced, as in any UTF-8 document
+    pub fn feed_bytes(&mut self, chunk: &[u8]) {
+        if self.decoder.is_some() {
+            self.decode(chunk, false);
+        } else {
+            self.sniff_buffer.extend_from_slice(chunk);
+            if self.sniff_buffer.len() >= PRESCAN_LENGTH {
+                self.start_decoding(false);
+            }
         }
     }
-}
 
-// TODO: `Document` type holding doctype
-pub fn document(input: &str) -> IResult<&str, DOMElement> {
-    let span = span!(Level::DEBUG, "Parsing HTML");
-    let _enter = span.enter();
-    let (input, _) = ws(input)?;
-    let (input, _) = opt(doctype)(input)?;
-    let (input, _) = ws(input)?;
-    let (input, root) = dom_element(input)?;
-    let (input, _) = ws(input)?;
-    Ok((input, root))
-}
+    /// Parse everything from `reader` as bytes
+    pub fn read_from(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut buffer = [0; 8192];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(len) => self.feed_bytes(&buffer[..len]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-fn doctype(input: &str) -> IResult<&str, &str> {
-    let (input, (_, _, t, _, _, _)) = tuple((
-        tag_no_case("<!DOCTYPE"),
-        many1(space),
-        tag_no_case("html"),
-        many0(space),
-        opt(many0(none_of(">"))),
-        char('>'),
-    ))(input)?;
-    Ok((input, t))
-}
+    /// The document parsed so far, so it can be used before the rest arrives. Elements at the
+    /// end of the input may not have been closed yet, and only the errors found while building
+    /// the tree are in [`Document::errors`] until the input is finished
+    pub fn document(&self) -> &Document {
+        self.builder.document()
+    }
+
+    /// Parse the rest of the input, which has all been fed in
+    pub fn finish(mut self) -> Document {
+        if self.decoder.is_some() {
+            self.decode(&[], true);
+        } else if !self.sniff_buffer.is_empty() {
+            self.start_decoding(true);
+        }
+        self.tokenizer.end();
+        self.run();
+        let mut document = self.builder.into_document();
+        document.errors.extend_from_slice(self.tokenizer.errors());
+        document.errors.sort_by_key(|e| e.position.offset);
+        document
+    }
+
+    /// Process every token which can be made from the input so far
+    fn run(&mut self) {
+        while let Some((token, span)) = self.tokenizer.next_token() {
+            self.builder.process(token, span);
+            if let Some(state) = self.builder.take_tokenizer_state() {
+                self.tokenizer.set_state(state);
+            }
+            self.tokenizer
+                .set_cdata_allowed(self.builder.in_foreign_content());
+        }
+    }
+
+    /// Detect the encoding of the bytes held back so far, and parse them. `last` is whether
+    /// they are the whole document
+    fn start_decoding(&mut self, last: bool) {
+        let bytes = mem::take(&mut self.sniff_buffer);
+        let (encoding, bom_length, confidence) = sniff(&bytes, self.content_type.as_deref(), last);
+        self.decoder = Some(encoding.new_decoder_without_bom_handling());
+        if confidence == Confidence::Tentative {
+            self.tentative = Some(vec![]);
+        }
+        self.decode(&bytes[bom_length..], last);
+    }
+
+    fn decode(&mut self, bytes: &[u8], last: bool) {
+        let decoder = self.decoder.as_mut().expect("Encoding has been detected");
+        let length = decoder
+            .max_utf8_buffer_length(bytes.len())
+            .expect("Chunk is too large to decode");
+        let mut text = String::with_capacity(length);
+        // The buffer is large enough for the whole chunk, and invalid bytes are replaced
+        let (_, _, replaced) = decoder.decode_to_string(bytes, &mut text, last);
+        if let Some(mut seen) = self.tentative.take() {
+            seen.extend_from_slice(bytes);
+            if replaced {
+                debug!("Document isn't UTF-8, parsing it again as windows-1252");
+                self.restart(WINDOWS_1252, &seen, last);
+                return;
+            }
+            if seen.len() >= TENTATIVE_LENGTH {
+                debug!("Document is valid UTF-8 so far, no longer keeping it to parse again");
+            } else if !last {
+                self.tentative = Some(seen);
+            }
+        }
+        self.feed(&text);
+    }
 
-fn dom_element(input: &str) -> IResult<&str, DOMElement> {
-    alt((
-        void_element,
-        raw_text_element,
-        normal_element, /* rcdata_element, foreign_element*/
-    ))(input)
+    /// Throw away everything parsed so far, and parse `bytes` again as `encoding`
+    fn restart(&mut self, encoding: &'static Encoding, bytes: &[u8], last: bool) {
+        let content_type = self.content_type.take();
+        *self = Self::new();
+        self.content_type = content_type;
+        self.decoder = Some(encoding.new_decoder_without_bom_handling());
+        self.decode(bytes, last);
+    }
 }
 
-fn void_element(input: &str) -> IResult<&str, DOMElement> {
-    let (input, tag) = verify(alt((start_tag, start_void_tag)), |t| {
-        VOID_ELEMENTS.contains(&t.name.to_lowercase().as_str())
-    })(input)?;
-    Ok((
-        input,
-        DOMElement::new(tag.name, Some(tag.attributes), vec![]),
-    ))
+/// Parse a document and return the contents of its `<body>`
+#[cfg(test)]
+fn body(input: &str) -> Vec<DOMContent> {
+    let (_, document) = document(input).unwrap();
+    document.root().get_elements_by_name("body", false)[0]
+        .contents
+        .clone()
 }
 
 #[cfg(test)]
 #[test]
 fn test_void() {
-    let i = "<br>";
-    let target = Ok((
-        "",
-        DOMElement {
-            name: "br".to_string(),
-            attributes: DOMAttributes(HashMap::new()),
-            contents: vec![],
-        },
-    ));
-    assert_eq!(void_element(i), target);
-    let i = "<br/>";
-    assert_eq!(void_element(i), target);
-    let i = "<p/>";
-    assert!(void_element(i).is_err());
-}
-
-fn normal_element(input: &str) -> IResult<&str, DOMElement> {
-    if let Ok((input, (start, contents, _))) =
-        verify(tuple((start_tag, normal_contents, end_tag)), |(s, _, e)| {
-            s.name == e.name
-        })(input)
-    {
-        return Ok((
-            input,
-            DOMElement::new(start.name, Some(start.attributes), contents),
-        ));
-    }
-    // Most browsers will correct <b>...<b> -> <b>...</b>
-    let (input, (start, contents, _)) = verify(
-        tuple((start_tag, normal_contents, start_tag)),
-        |(s, _, e)| s.name == e.name,
-    )(input)?;
-    Ok((
-        input,
-        DOMElement::new(start.name, Some(start.attributes), contents),
-    ))
+    let target = vec![DOMElement::new("br", None, vec![]).into()];
+    assert_eq!(body("<br>"), target);
+    assert_eq!(body("<br/>"), target);
+    assert_eq!(body("</br>"), target);
+    // The self-closing flag is ignored on non-void elements
+    let target = vec![DOMElement::new("p", None, vec!["a".into()]).into()];
+    assert_eq!(body("<p/>a"), target);
 }
 
 #[cfg(test)]
 #[test]
 fn test_normal_element() {
     let i = "<div>Test</div>";
-    let target = DOMElement::new("div", None, vec!["Test".into()]);
-    assert_eq!(normal_element(i), Ok(("", target)));
+    let target = vec![DOMElement::new("div", None, vec!["Test".into()]).into()];
+    assert_eq!(body(i), target);
 
     let i = "<div><h1>Head</h1><h2>Head 2</h2></div>";
-    let target = DOMElement::new(
+    let target = vec![DOMElement::new(
         "div",
         None,
         vec![
             DOMElement::new("h1", None, vec!["Head".into()]).into(),
             DOMElement::new("h2", None, vec!["Head 2".into()]).into(),
         ],
-    );
-    assert_eq!(normal_element(i), Ok(("", target)));
+    )
+    .into()];
+    assert_eq!(body(i), target);
 
+    // An unclosed formatting element is closed at the end of the document
     let i = "<b>Uh-oh<b>";
-    let target = DOMElement::new("b", None, vec!["Uh-oh".into()]);
-    assert_eq!(normal_element(i), Ok(("", target)));
-}
-
-// Text, character references, elements, comments
-fn normal_contents(input: &str) -> IResult<&str, Vec<DOMContent>> {
-    let content_dom_element = map(dom_element, |el| el.into());
-    let possible = delimited(
-        opt(comment),
-        alt((content_dom_element, text_content)),
-        opt(comment),
-    );
-    let (input, result) = many0(possible)(input)?;
-    Ok((
-        input,
-        result
-            .into_iter()
-            .filter(|dc| {
-                if let DOMContent::Text(s) = dc {
-                    !s.is_empty()
-                } else {
-                    true
-                }
-            })
-            .collect(),
-    ))
+    let target = vec![DOMElement::new(
+        "b",
+        None,
+        vec!["Uh-oh".into(), DOMElement::new("b", None, vec![]).into()],
+    )
+    .into()];
+    assert_eq!(body(i), target);
 }
 
-fn text_content(input: &str) -> IResult<&str, DOMContent> {
-    let (input, result): (&str, String) = map(
-        verify(take_until("<"), |s: &str| !s.is_empty()),
-        |el: &str| {
-            // Truncate whitespace
-            el.to_string()
-                .split_whitespace()
-                .intersperse(" ")
-                .collect::<String>()
-        },
-    )(input)?;
-    Ok((input, result.as_str().into()))
+#[cfg(test)]
+#[test]
+fn test_implied_end_tags() {
+    let i = "<p>One<p>Two<ul><li>A<li>B</ul>";
+    let target = vec![
+        DOMElement::new("p", None, vec!["One".into()]).into(),
+        DOMElement::new("p", None, vec!["Two".into()]).into(),
+        DOMElement::new(
+            "ul",
+            None,
+            vec![
+                DOMElement::new("li", None, vec!["A".into()]).into(),
+                DOMElement::new("li", None, vec!["B".into()]).into(),
+            ],
+        )
+        .into(),
+    ];
+    assert_eq!(body(i), target);
+    // Stray end tags are ignored
+    let i = "<div>a</span></div>";
+    let target = vec![DOMElement::new("div", None, vec!["a".into()]).into()];
+    assert_eq!(body(i), target);
 }
 
-// <script> and <style>
-fn raw_text_element(input: &str) -> IResult<&str, DOMElement> {
-    let (input, start) = verify(start_tag, |t| {
-        RAW_TEXT_ELEMENTS.contains(&t.name.to_lowercase().as_str())
-    })(input)?;
-    let (input, content) = map(many_till(anychar, named_end_tag(start.name)), |(v, _)| {
-        v.into_iter().collect::<String>()
-    })(input)?;
-    Ok((
-        input,
+#[cfg(test)]
+#[test]
+fn test_adoption_agency() {
+    let i = "<b>1<p>2</b>3</p>";
+    let target = vec![
+        DOMElement::new("b", None, vec!["1".into()]).into(),
         DOMElement::new(
-            start.name,
-            Some(start.attributes),
-            vec![content.as_str().into()],
-        ),
-    ))
+            "p",
+            None,
+            vec![
+                DOMElement::new("b", None, vec!["2".into()]).into(),
+                "3".into(),
+            ],
+        )
+        .into(),
+    ];
+    assert_eq!(body(i), target);
 }
 
-// Create a parser that searches  for a named closing tag
-// Anonymous lifetime to capture 'name'
-fn named_end_tag(name: &str) -> impl FnMut(&str) -> IResult<&str, &str> + '_ {
-    move |input: &str| {
-        delimited(
-            tag("</"),
-            terminated(tag_no_case(name), many0(space)),
-            char('>'),
-        )(input)
-    }
+#[cfg(test)]
+#[test]
+fn test_foster_parenting() {
+    let i = "<table>a<tr><td>b</table>";
+    let target = vec![
+        "a".into(),
+        DOMElement::new(
+            "table",
+            None,
+            vec![DOMElement::new(
+                "tbody",
+                None,
+                vec![DOMElement::new(
+                    "tr",
+                    None,
+                    vec![DOMElement::new("td", None, vec!["b".into()]).into()],
+                )
+                .into()],
+            )
+            .into()],
+        )
+        .into(),
+    ];
+    assert_eq!(body(i), target);
 }
 
 #[cfg(test)]
 #[test]
 fn test_raw_text() {
     let i = r#"<script>let one = 2;</script>"#;
+    let html = document(i).unwrap().1.root();
+    let head = html.get_elements_by_name("head", false)[0];
     let target = DOMElement::new("script", None, vec!["let one = 2;".into()]);
-    assert_eq!(raw_text_element(i), Ok(("", target)));
+    assert_eq!(head.contents, vec![target.into()]);
 
     let i = r#"<script>let one = "</two>";</script>"#;
+    let html = document(i).unwrap().1.root();
+    let head = html.get_elements_by_name("head", false)[0];
     let target = DOMElement::new("script", None, vec![r#"let one = "</two>";"#.into()]);
-    assert_eq!(raw_text_element(i), Ok(("", target)));
+    assert_eq!(head.contents, vec![target.into()]);
 
+    // Raw text only ends at the matching end tag
     let i = r#"<style>html {}</script>"#;
-    assert!(raw_text_element(i).is_err());
-}
-
-// An opening tag <elem>
-fn start_tag(input: &str) -> IResult<&str, Tag> {
-    let (input, (name, attributes, _)) = delimited(
-        char('<'),
-        tuple((tag_name, opt(attributes), many0(space))),
-        char('>'),
-    )(input)?;
-    let attributes = attributes.unwrap_or_default();
-    Ok((input, Tag::opening(name, Some(attributes))))
-}
-
-// An opening tag with terminator <elem/> (Only for void elements)
-fn start_void_tag(input: &str) -> IResult<&str, Tag> {
-    let (input, (name, attributes, _)) = delimited(
-        char('<'),
-        tuple((tag_name, opt(attributes), many0(space))),
-        tag("/>"),
-    )(input)?;
-    let attributes = attributes.unwrap_or_default();
-    Ok((input, Tag::opening(name, Some(attributes))))
-}
-
-// A closing tag </elem>
-fn end_tag(input: &str) -> IResult<&str, Tag> {
-    let (input, (name, _)) =
-        delimited(tag("</"), tuple((tag_name, many0(space))), char('>'))(input)?;
-    Ok((input, Tag::closing(name)))
+    let html = document(i).unwrap().1.root();
+    let head = html.get_elements_by_name("head", false)[0];
+    let target = DOMElement::new("style", None, vec!["html {}</script>".into()]);
+    assert_eq!(head.contents, vec![target.into()]);
 }
 
 #[cfg(test)]
 #[test]
-fn test_end_tag() {
-    let target = Ok((
-        "",
-        Tag {
-            name: "elem",
-            opening: false,
-            attributes: Default::default(),
-        },
-    ));
-    let i = "</elem>";
-    assert_eq!(end_tag(i), target);
-    let i = "</elem   >";
-    assert_eq!(end_tag(i), target);
-    let i = "</elem disabled>";
-    assert!(end_tag(i).is_err());
+fn test_quirks_mode() {
+    let quirks_mode = |i| document(i).unwrap().1.quirks_mode;
+    assert_eq!(quirks_mode("<!DOCTYPE html>"), QuirksMode::NoQuirks);
+    assert_eq!(quirks_mode("<p>No doctype"), QuirksMode::Quirks);
+    assert_eq!(
+        quirks_mode(r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01//EN">"#),
+        QuirksMode::NoQuirks
+    );
+    assert_eq!(
+        quirks_mode(r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01 Transitional//EN">"#),
+        QuirksMode::Quirks
+    );
+    assert_eq!(
+        quirks_mode(
+            r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01 Transitional//EN" "http://www.w3.org/TR/html4/loose.dtd">"#
+        ),
+        QuirksMode::LimitedQuirks
+    );
+    assert_eq!(quirks_mode("<!DOCTYPE svg>"), QuirksMode::Quirks);
+
+    // In quirks mode a table may be nested in a paragraph
+    let root = document("<p><table></table>").unwrap().1.root();
+    let body = root.get_elements_by_name("body", false)[0];
+    assert_eq!(body.get_elements_by_name("p", false).len(), 1);
+    let root = document("<!DOCTYPE html><p><table></table>")
+        .unwrap()
+        .1
+        .root();
+    let body = root.get_elements_by_name("body", false)[0];
+    assert_eq!(body.get_elements_by_name("table", false).len(), 1);
 }
 
-// The name of an HTML element
-fn tag_name(input: &str) -> IResult<&str, &str> {
-    alphanumeric1(input)
+#[cfg(test)]
+#[test]
+fn test_doctype_and_errors() {
+    let (_, doc) =
+        document(r#"<!doctype html SYSTEM "about:legacy-compat"><p a=1 a=2>&bogus</i>"#).unwrap();
+    assert_eq!(
+        doc.doctype(),
+        Some(&DocumentType {
+            name: "html".to_string(),
+            public_id: None,
+            system_id: Some("about:legacy-compat".to_string()),
+        })
+    );
+    let errors: Vec<(usize, &str)> = doc
+        .errors
+        .iter()
+        .map(|e| (e.position.offset, e.message))
+        .collect();
+    assert_eq!(
+        errors,
+        vec![(54, "duplicate-attribute"), (61, "Unexpected end tag")]
+    );
 }
 
-// A list of attributes
-fn attributes(input: &str) -> IResult<&str, DOMAttributes> {
-    let (input, attrs) = many0(preceded(space1, attribute))(input)?;
-    let attrs: HashMap<String, String> = attrs.into_iter().collect();
-    Ok((input, DOMAttributes(attrs)))
+#[cfg(test)]
+#[test]
+fn test_comments() {
+    let i = "<!--before--><!DOCTYPE html><html><!--[if IE]><p>x<![endif]--><body><?php x ?></body></html><!--after-->";
+    let (_, doc) = document(i).unwrap();
+    let doctype = DocumentType {
+        name: "html".to_string(),
+        public_id: None,
+        system_id: None,
+    };
+    assert_eq!(
+        doc.contents(),
+        vec![
+            DOMContent::Comment("before".to_string()),
+            DOMContent::Doctype(doctype),
+            DOMContent::Element(doc.root()),
+            DOMContent::Comment("after".to_string()),
+        ]
+    );
+    assert_eq!(
+        doc.root().contents[0],
+        DOMContent::Comment("[if IE]><p>x<![endif]".to_string())
+    );
+    assert_eq!(body(i), vec![DOMContent::Comment("?php x ?".to_string())]);
+    assert_eq!(
+        doc.to_string(),
+        "<!--before-->\n<!DOCTYPE html>\n<html><!--[if IE]><p>x<![endif]--><head></head><body><!--?php x ?--></body></html>\n<!--after-->"
+    );
 }
 
 #[cfg(test)]
 #[test]
-fn test_attributes() {
-    let i = r#" disabled attr=value attr2='value' attr3="multiple values""#;
-    let attrs = DOMAttributes(HashMap::from([
-        ("disabled".to_string(), "".to_string()),
-        ("attr".to_string(), "value".to_string()),
-        ("attr2".to_string(), "value".to_string()),
-        ("attr3".to_string(), "multiple values".to_string()),
-    ]));
-    assert_eq!(attributes(i), Ok(("", attrs)));
+fn test_rcdata() {
+    let i = "<title>a <b>&amp; c</title><textarea>\n<p>x</p>&lt;</textareax></textarea>";
+    let html = document(i).unwrap().1.root();
+    let head = html.get_elements_by_name("head", false)[0];
+    let target = DOMElement::new("title", None, vec!["a <b>& c".into()]);
+    assert_eq!(head.contents, vec![target.into()]);
+    let target = DOMElement::new("textarea", None, vec!["<p>x</p><</textareax>".into()]);
+    assert_eq!(body(i), vec![target.into()]);
 }
 
-// A single attribute
-fn attribute(input: &str) -> IResult<&str, (String, String)> {
-    let empty = map(attribute_name, |n| (n, "".to_string()));
-    let unquoted = map(
-        tuple((
-            attribute_name,
-            multispace0,
-            char('='),
-            multispace0,
-            many1(none_of(" \t\r\n\0\"'>=")),
-        )),
-        |(name, .., value)| (name, value.into_iter().collect()),
-    );
-    let single_quoted = map(
-        tuple((
-            attribute_name,
-            multispace0,
-            char('='),
-            multispace0,
-            delimited(char('\''), take_until("'"), char('\'')),
-        )),
-        |(name, .., value)| (name, value.to_string()),
+#[cfg(test)]
+#[test]
+fn test_foreign_content() {
+    use crate::Namespace;
+
+    let i = r#"<p><svg viewBox="0 0 10 10"><clipPath/><foreignObject><b>x</b></foreignObject><path d="M0"/><![CDATA[<a>]]></svg><math><mi>1</mi><annotation-xml definitionURL="u"></math><![CDATA[y]]>"#;
+    let svg = DOMElement::new_foreign(
+        Namespace::Svg,
+        "svg",
+        Some(DOMAttributes(HashMap::from([(
+            "viewBox".to_string(),
+            "0 0 10 10".to_string(),
+        )]))),
+        vec![
+            DOMElement::new_foreign(Namespace::Svg, "clipPath", None, vec![]).into(),
+            DOMElement::new_foreign(
+                Namespace::Svg,
+                "foreignObject",
+                None,
+                vec![DOMElement::new("b", None, vec!["x".into()]).into()],
+            )
+            .into(),
+            DOMElement::new_foreign(
+                Namespace::Svg,
+                "path",
+                Some(DOMAttributes(HashMap::from([(
+                    "d".to_string(),
+                    "M0".to_string(),
+                )]))),
+                vec![],
+            )
+            .into(),
+            "<a>".into(),
+        ],
     );
-    let double_quoted = map(
-        tuple((
-            attribute_name,
-            multispace0,
-            char('='),
-            multispace0,
-            delimited(char('"'), take_until("\""), char('"')),
-        )),
-        |(name, .., value)| (name, value.to_string()),
+    let math = DOMElement::new_foreign(
+        Namespace::MathMl,
+        "math",
+        None,
+        vec![
+            DOMElement::new_foreign(Namespace::MathMl, "mi", None, vec!["1".into()]).into(),
+            DOMElement::new_foreign(
+                Namespace::MathMl,
+                "annotation-xml",
+                Some(DOMAttributes(HashMap::from([(
+                    "definitionURL".to_string(),
+                    "u".to_string(),
+                )]))),
+                vec![],
+            )
+            .into(),
+        ],
     );
-    alt((single_quoted, double_quoted, unquoted, empty))(input)
+    let target = vec![DOMElement::new(
+        "p",
+        None,
+        vec![
+            svg.into(),
+            math.into(),
+            DOMContent::Comment("[CDATA[y]]".to_string()),
+        ],
+    )
+    .into()];
+    assert_eq!(body(i), target);
+
+    // HTML elements break out of foreign content
+    let i = "<svg><g><div>a</div></g></svg>";
+    let target = vec![
+        DOMElement::new_foreign(
+            Namespace::Svg,
+            "svg",
+            None,
+            vec![DOMElement::new_foreign(Namespace::Svg, "g", None, vec![]).into()],
+        )
+        .into(),
+        DOMElement::new("div", None, vec!["a".into()]).into(),
+    ];
+    assert_eq!(body(i), target);
 }
 
-fn attribute_name(input: &str) -> IResult<&str, String> {
-    map(many1(none_of(" \t\r\n\0\"'>=")), |x| {
-        x.into_iter().collect()
-    })(input)
+#[cfg(test)]
+#[test]
+fn test_source_spans() {
+    let (_, doc) = document("<p>a\n<b>bold</b>c\n<p>x").unwrap();
+    let root = doc.root();
+    let span = |e: &DOMElement| e.span.unwrap().to_string();
+    let spans: Vec<String> = root.descendants().map(span).collect();
+    // Implied elements are empty spans where they were inserted, and elements closed without
+    // an end tag end where the tag closing them starts
+    assert_eq!(
+        spans,
+        ["1:1-1:1", "1:1-3:5", "1:1-3:1", "2:1-2:12", "3:1-3:5"]
+    );
+    let p = root.get_elements_by_name("p", true)[0];
+    let texts: Vec<String> = p
+        .contents
+        .iter()
+        .filter_map(|c| match c {
+            DOMContent::Text(t) => Some(t.span.unwrap().to_string()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(texts, ["1:4-2:1", "2:12-3:1"]);
 }
 
 #[cfg(test)]
 #[test]
-fn test_attribute() {
-    let i = r#"disabled"#;
+fn test_deep_nesting() {
+    // Finding where elements end used to take time proportional to the square of the depth for
+    // every token, which made deeply nested documents take minutes
+    let depth = 3000;
+    let input = "<div>text ".repeat(depth) + &"</div>".repeat(depth);
+    let (_, doc) = document(&input).unwrap();
+    let divs = doc.get_elements_by_name(doc.document_node(), "div", true);
+    assert_eq!(divs.len(), depth);
+    let span = |id| doc.node(id).span.unwrap();
+    assert_eq!(span(divs[0]).end.offset, input.len());
+    assert_eq!(span(divs[depth - 1]).start.offset, 10 * (depth - 1));
     assert_eq!(
-        attribute(i),
-        Ok(("", ("disabled".to_string(), "".to_string())))
+        span(divs[depth - 1]).end.offset,
+        10 * depth + "</div>".len()
     );
-    let i = r#"attr=value"#;
+}
+
+#[cfg(test)]
+#[test]
+fn test_fragment() {
+    let fragment = |context, input| {
+        let context = DOMElement::new(context, None, vec![]);
+        parse_fragment(&context, input)
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+    };
     assert_eq!(
-        attribute(i),
-        Ok(("", ("attr".to_string(), "value".to_string())))
+        fragment("ul", "<li>a</li><li>b"),
+        ["<li>a</li>", "<li>b</li>"]
     );
-    let i = r#"attr='value'"#;
+    // Only in a table context can rows be parsed without a table
+    assert_eq!(fragment("tbody", "<tr><td>1"), ["<tr><td>1</td></tr>"]);
+    assert_eq!(fragment("div", "<tr><td>1"), ["1"]);
     assert_eq!(
-        attribute(i),
-        Ok(("", ("attr".to_string(), "value".to_string())))
+        fragment("textarea", "<b>a  b</b></textarea>"),
+        ["&lt;b&gt;a  b&lt;/b&gt;&lt;/textarea&gt;"]
     );
-    let i = r#"attr="multiple words""#;
+    assert_eq!(fragment("p", "<p>a"), ["<p>a</p>"]);
     assert_eq!(
-        attribute(i),
-        Ok(("", ("attr".to_string(), "multiple words".to_string())))
+        fragment("html", "<title>T</title>"),
+        ["<head><title>T</title></head>", "<body></body>"]
     );
-}
 
-fn comment(input: &str) -> IResult<&str, ()> {
-    value((), delimited(tag("<!--"), take_until("-->"), tag("-->")))(input)
+    let svg = DOMElement::new_foreign(Namespace::Svg, "svg", None, vec![]);
+    let contents = parse_fragment(&svg, "<foreignObject><p>a</p></foreignObject><![CDATA[<]]>");
+    match &contents[0] {
+        DOMContent::Element(e) => {
+            assert_eq!(
+                (e.namespace, e.name.as_str()),
+                (Namespace::Svg, "foreignObject")
+            );
+            assert_eq!(
+                e.contents,
+                vec![DOMElement::new("p", None, vec!["a".into()]).into()]
+            );
+        }
+        c => panic!("Expected an element, not {:?}", c),
+    }
+    assert_eq!(contents[1], "<".into());
 }
 
-fn space(input: &str) -> IResult<&str, ()> {
-    value((), one_of(" \t\r\n"))(input)
-}
+#[cfg(test)]
+#[test]
+fn test_streaming() {
+    let input =
+        "<!DOCTYPE html><title>Caf\u{E9}</title><p id=a>Fish &amp; chips\r\n<!-- c --><b>b</p>";
+    let (_, whole) = document(input).unwrap();
+    // Split the input at every point, including part way through characters
+    for i in 0..input.len() {
+        let mut parser = Parser::new();
+        parser.feed_bytes(&input.as_bytes()[..i]);
+        parser.feed_bytes(&input.as_bytes()[i..]);
+        let streamed = parser.finish();
+        assert_eq!(streamed.root(), whole.root());
+        assert_eq!(streamed.errors, whole.errors);
+    }
 
-fn ws(input: &str) -> IResult<&str, ()> {
-    alt((comment, value((), many0(space))))(input)
+    // A document guessed to be UTF-8 is parsed again if a later byte shows it isn't, giving the
+    // same result as decoding it all at once
+    for input in [
+        [&[b'a'; 2000][..], b"<p>caf\xE9"].concat(),
+        [&[b'a'; 2000][..], "<p>\u{E9}".as_bytes(), b"caf\xE9"].concat(),
+        [&[b'a'; 2000][..], b"<p>caf\xC3"].concat(),
+    ] {
+        let (text, encoding) = crate::decode(&input, None);
+        assert_eq!(encoding, WINDOWS_1252);
+        let (_, whole) = document(&text).unwrap();
+        let mut parser = Parser::new();
+        for chunk in input.chunks(100) {
+            parser.feed_bytes(chunk);
+        }
+        assert_eq!(parser.finish().root(), whole.root());
+    }
+    // Only the start of the document is kept to be parsed again, after which it's UTF-8
+    let mut parser = Parser::new();
+    parser.feed_bytes(&[b'a'; TENTATIVE_LENGTH]);
+    assert!(parser.tentative.is_none());
+    parser.feed_bytes(b"<p>caf\xE9");
+    assert_eq!(parser.encoding(), Some(encoding_rs::UTF_8));
+    let document = parser.finish();
+    assert!(document
+        .text_content(document.document_node())
+        .ends_with("caf\u{FFFD}"));
+
+    // Bytes are held back until their encoding can be detected
+    let mut parser = Parser::new();
+    parser.set_content_type("text/html; charset=windows-1252");
+    parser.read_from(&b"<p>caf\xE9"[..]).unwrap();
+    assert_eq!(parser.encoding(), None);
+    assert_eq!(
+        parser.finish().root().to_html(),
+        "<html><head></head><body><p>caf\u{E9}</p></body></html>"
+    );
+
+    // The document so far can be used before the rest arrives
+    let mut parser = Parser::new();
+    parser.feed("<ul><li>One<li>T");
+    assert_eq!(
+        parser.document().root().to_html(),
+        "<html><head></head><body><ul><li>One</li><li>T</li></ul></body></html>"
+    );
+    parser.feed("wo</ul>");
+    assert_eq!(
+        parser.finish().root().to_html(),
+        "<html><head></head><body><ul><li>One</li><li>Two</li></ul></body></html>"
+    );
 }
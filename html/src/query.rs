@@ -0,0 +1,327 @@
+//! Querying a DOM tree by id, class and CSS selectors
+
+use crate::{DOMContent, DOMElement, Document, Namespace, NodeData, NodeId};
+use css::{Combinator, Selector, SimpleSelector};
+use std::slice;
+
+/// Depth-first, pre-order iterator over the descendant elements of a [`DOMElement`]
+pub struct Descendants<'a> {
+    stack: Vec<slice::Iter<'a, DOMContent>>,
+}
+
+impl<'a> Iterator for Descendants<'a> {
+    type Item = &'a DOMElement;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let iter = self.stack.last_mut()?;
+            match iter.next() {
+                Some(DOMContent::Element(elt)) => {
+                    self.stack.push(elt.contents.iter());
+                    return Some(elt);
+                }
+                Some(_) => {}
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl DOMElement {
+    /// Iterate over every element below this one, in document order
+    pub fn descendants(&self) -> Descendants<'_> {
+        Descendants {
+            stack: vec![self.contents.iter()],
+        }
+    }
+
+    /// Find the first descendant element with the given `id`
+    pub fn get_element_by_id(&self, id: &str) -> Option<&DOMElement> {
+        self.descendants()
+            .find(|e| e.get_attribute("id").map(|i| i == id).unwrap_or(false))
+    }
+
+    /// Find all descendant elements which have every one of the space seperated `classes`
+    pub fn get_elements_by_class_name(&self, classes: &str) -> Vec<&DOMElement> {
+        let classes: Vec<&str> = classes.split_ascii_whitespace().collect();
+        if classes.is_empty() {
+            return vec![];
+        }
+        self.descendants()
+            .filter(|e| classes.iter().all(|c| e.attributes.has_class(c)))
+            .collect()
+    }
+
+    /// Find the first descendant element matched by any of the `selectors`. The element is
+    /// matched as if it were the root of a document, so combinators can't reach above it
+    pub fn query_selector(&self, selectors: &[Selector]) -> Option<&DOMElement> {
+        self.query_selector_all(selectors).into_iter().next()
+    }
+
+    /// Find all descendant elements matched by any of the `selectors`, in document order
+    pub fn query_selector_all(&self, selectors: &[Selector]) -> Vec<&DOMElement> {
+        // Selectors are matched in a copy of the tree, which has the links between nodes that
+        // combinators follow. Its elements are visited in the same order as the descendants
+        let mut document = Document::new();
+        let root = document.append_element(document.document_node(), self);
+        let matched: Vec<bool> = document
+            .descendants(root)
+            .filter(|&node| document.element(node).is_some())
+            .map(|node| selectors.iter().any(|s| document.matches(node, s)))
+            .collect();
+        self.descendants()
+            .zip(matched)
+            .filter_map(|(element, matched)| matched.then_some(element))
+            .collect()
+    }
+}
+
+impl Document {
+    /// Find the elements called `name` which are children of `id`, or descendants if
+    /// `recursive`
+    pub fn get_elements_by_name(&self, id: NodeId, name: &str, recursive: bool) -> Vec<NodeId> {
+        let is_match = |&node: &NodeId| self.element(node).map(|e| e.name == name) == Some(true);
+        if recursive {
+            self.descendants(id).filter(is_match).collect()
+        } else {
+            self.children(id).filter(is_match).collect()
+        }
+    }
+
+    /// Find the first element in the document with the given `id`
+    pub fn get_element_by_id(&self, id: &str) -> Option<NodeId> {
+        self.descendants(self.document_node()).find(|&node| {
+            self.element(node)
+                .and_then(|e| e.get_attribute("id"))
+                .map(|i| i == id)
+                .unwrap_or(false)
+        })
+    }
+
+    /// Find the first element below `id` matched by any of the `selectors`
+    pub fn query_selector(&self, id: NodeId, selectors: &[Selector]) -> Option<NodeId> {
+        self.descendants(id)
+            .find(|&node| selectors.iter().any(|s| self.matches(node, s)))
+    }
+
+    /// Find all elements below `id` matched by any of the `selectors`, in document order
+    pub fn query_selector_all(&self, id: NodeId, selectors: &[Selector]) -> Vec<NodeId> {
+        self.descendants(id)
+            .filter(|&node| selectors.iter().any(|s| self.matches(node, s)))
+            .collect()
+    }
+
+    /// Check if `selector` matches the element `id`
+    pub fn matches(&self, id: NodeId, selector: &Selector) -> bool {
+        let (mut compounds, mut combinators) = (vec![], vec![]);
+        flatten(selector, &mut compounds, &mut combinators);
+        self.matches_from(&compounds, &combinators, compounds.len() - 1, id)
+    }
+
+    /// Check if the compound selectors up to and including `i` match `id`
+    fn matches_from(
+        &self,
+        compounds: &[&[SimpleSelector]],
+        combinators: &[Combinator],
+        i: usize,
+        id: NodeId,
+    ) -> bool {
+        if self.element(id).is_none() || !compounds[i].iter().all(|s| self.matches_simple(s, id)) {
+            return false;
+        }
+        if i == 0 {
+            return true;
+        }
+        let next = |node| self.matches_from(compounds, combinators, i - 1, node);
+        match combinators[i - 1] {
+            Combinator::Child => self.node(id).parent().map(next).unwrap_or(false),
+            Combinator::Descendant => self.ancestors(id).any(next),
+            Combinator::NextSibling => self
+                .previous_element_siblings(id)
+                .next()
+                .map(next)
+                .unwrap_or(false),
+            Combinator::SubsequentSibling => self.previous_element_siblings(id).any(next),
+        }
+    }
+
+    fn matches_simple(&self, selector: &SimpleSelector, id: NodeId) -> bool {
+        let element = match self.element(id) {
+            Some(element) => element,
+            None => return false,
+        };
+        match selector {
+            // Only the names of HTML elements are case-insensitive
+            SimpleSelector::Type(name) => match element.namespace {
+                Namespace::Html => element.name.eq_ignore_ascii_case(name),
+                _ => element.name == *name,
+            },
+            SimpleSelector::Universal => true,
+            SimpleSelector::Class(class) => element.has_class(class),
+            SimpleSelector::ID(i) => element.get_attribute("id").map(|e| e == i) == Some(true),
+            SimpleSelector::PseudoClass(class) => {
+                let first = self.previous_element_siblings(id).next().is_none();
+                let last = self.next_element_siblings(id).next().is_none();
+                match class.as_ref() {
+                    "first-child" => first,
+                    "last-child" => last,
+                    "only-child" => first && last,
+                    // Comments don't count as content, and neither does text with nothing in it
+                    "empty" => self.children(id).all(|child| match &self.node(child).data {
+                        NodeData::Comment(_) => true,
+                        NodeData::Text(text) => text.is_empty(),
+                        _ => false,
+                    }),
+                    _ => false,
+                }
+            }
+            SimpleSelector::Attribute(attribute) => element.matches_attribute(attribute),
+        }
+    }
+}
+
+/// Flatten a selector into its compound selectors and the combinators between them
+fn flatten<'s, 'a>(
+    selector: &'s Selector<'a>,
+    compounds: &mut Vec<&'s [SimpleSelector<'a>]>,
+    combinators: &mut Vec<Combinator>,
+) {
+    match selector {
+        Selector::Simple(s) => compounds.push(slice::from_ref(s)),
+        Selector::Compound(sels) => compounds.push(sels),
+        Selector::Combinator(left, c, right) => {
+            flatten(left, compounds, combinators);
+            combinators.push(*c);
+            flatten(right, compounds, combinators);
+        }
+    }
+}
+
+#[cfg(test)]
+fn sample() -> Document {
+    crate::document(
+        r#"<div id="main" class="a b">
+            <p class="a">One</p>
+            <p id="two">Two <span class="b">Span</span></p>
+            <ul><li>1<li class="a b c">2<li>3</ul>
+        </div>"#,
+    )
+    .unwrap()
+    .1
+}
+
+#[cfg(test)]
+#[test]
+fn test_get_elements() {
+    let root = sample().root();
+    let names: Vec<&str> = root.descendants().map(|e| e.name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["head", "body", "div", "p", "p", "span", "ul", "li", "li", "li"]
+    );
+    assert_eq!(root.get_elements_by_name("li", true).len(), 3);
+    assert_eq!(root.get_elements_by_name("li", false).len(), 0);
+    assert_eq!(root.get_element_by_id("two").unwrap().name, "p");
+    assert_eq!(root.get_element_by_id("three"), None);
+    let names: Vec<&str> = root
+        .get_elements_by_class_name(" b  a")
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect();
+    assert_eq!(names, ["div", "li"]);
+    assert!(root.get_elements_by_class_name(" ").is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_query_selector() {
+    let doc = sample();
+    let root = doc.document_element();
+    let name = |id| doc.element(id).unwrap().name.as_str();
+    let query = |s| {
+        let selectors = css::selectors(s).unwrap();
+        doc.query_selector_all(root, &selectors)
+            .into_iter()
+            .map(name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(query("p"), ["p", "p"]);
+    assert_eq!(query("div .b"), ["span", "li"]);
+    assert_eq!(query("#main > .a"), ["p"]);
+    assert_eq!(query("div > p span"), ["span"]);
+    assert_eq!(query("p + p"), ["p"]);
+    assert_eq!(query("p ~ ul li.c"), ["li"]);
+    assert_eq!(query("li:first-child, span"), ["span", "li"]);
+    assert_eq!(query("body > li"), Vec::<&str>::new());
+    assert_eq!(query("[id]"), ["div", "p"]);
+    assert_eq!(query("p[ID=two]"), ["p"]);
+    assert_eq!(query("[class~=b]"), ["div", "span", "li"]);
+    assert_eq!(query("[class|=a]"), ["p"]);
+    assert_eq!(query("[class='a b']"), ["div"]);
+    assert_eq!(query(r#"[class^="a "]"#), ["div", "li"]);
+    assert_eq!(query("li[class$=c]"), ["li"]);
+    assert_eq!(query("[id*=ai]"), ["div"]);
+    assert_eq!(query("[id^='']"), Vec::<&str>::new());
+
+    let selectors = css::selectors("li.a").unwrap();
+    let li = doc.query_selector(root, &selectors).unwrap();
+    assert_eq!(doc.to_element(li).contents, vec!["2".into()]);
+    // Selectors which aren't supported are rejected rather than partly matched
+    assert_eq!(css::selectors("li[class=a b]"), None);
+    assert_eq!(css::selectors("li.a )"), None);
+
+    // Queries can be made on an element on its own too
+    let div = doc.to_element(doc.get_element_by_id("main").unwrap());
+    let names = |s| {
+        let selectors = css::selectors(s).unwrap();
+        div.query_selector_all(&selectors)
+            .into_iter()
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("p + p, li:last-child"), ["p", "li"]);
+    assert_eq!(names(".a"), ["p", "li"]);
+    assert_eq!(names("body div p"), Vec::<&str>::new());
+    let selectors = css::selectors("#main > p span").unwrap();
+    assert_eq!(div.query_selector(&selectors).unwrap().name, "span");
+
+    let two = doc.get_element_by_id("two").unwrap();
+    assert_eq!(name(two), "p");
+    assert_eq!(doc.get_elements_by_name(two, "span", false).len(), 1);
+    assert_eq!(doc.get_elements_by_name(root, "li", false).len(), 0);
+    assert_eq!(doc.get_elements_by_name(root, "li", true).len(), 3);
+
+    let mut doc = crate::document("<p id=a><!-- note --></p><p id=b> </p><p id=c></p>")
+        .unwrap()
+        .1;
+    let c = doc.get_element_by_id("c").unwrap();
+    let text = doc.create_text_node("");
+    doc.append_child(c, text).unwrap();
+    let empty = css::selectors("p:empty").unwrap();
+    let ids: Vec<_> = doc
+        .query_selector_all(doc.document_element(), &empty)
+        .into_iter()
+        .map(|id| {
+            doc.element(id)
+                .unwrap()
+                .get_attribute("id")
+                .unwrap()
+                .as_str()
+        })
+        .collect();
+    assert_eq!(ids, ["a", "c"]);
+
+    let doc = crate::document("<div><svg><foreignObject></foreignObject></svg></div>")
+        .unwrap()
+        .1;
+    let count = |s| {
+        let selectors = css::selectors(s).unwrap();
+        doc.query_selector_all(doc.document_element(), &selectors)
+            .len()
+    };
+    assert_eq!(count("DIV"), 1);
+    assert_eq!(count("foreignObject"), 1);
+    assert_eq!(count("foreignobject"), 0);
+}
@@ -0,0 +1,233 @@
+//! Serializes DOM trees back to markup, following
+//! <https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments>
+
+use crate::{DOMContent, DOMElement, Document, Namespace};
+use std::fmt::{self, Display, Formatter, Write};
+
+/// Elements which never have contents or an end tag
+static VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "frame", "hr", "img", "input",
+    "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// Elements whose text contents are written out without escaping. `noscript` is left out since
+/// the parser runs with scripting disabled, and so parses its contents as markup
+static RAW_TEXT_ELEMENTS: &[&str] = &[
+    "iframe",
+    "noembed",
+    "noframes",
+    "plaintext",
+    "script",
+    "style",
+    "xmp",
+];
+
+/// Elements whose whitespace is significant, and so are never pretty-printed
+static PREFORMATTED_ELEMENTS: &[&str] = &["listing", "pre", "textarea"];
+
+fn escape(s: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\u{A0}' => escaped.push_str("&nbsp;"),
+            '"' if attribute => escaped.push_str("&quot;"),
+            '<' if !attribute => escaped.push_str("&lt;"),
+            '>' if !attribute => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl DOMElement {
+    /// Serialize this element and its contents to HTML
+    pub fn to_html(&self) -> String {
+        self.to_string()
+    }
+
+    /// Serialize this element to HTML, with each child element on its own indented line. This
+    /// adds whitespace, so the result may not render exactly the same as the original
+    pub fn to_pretty_html(&self) -> String {
+        format!("{:#}", self)
+    }
+
+    /// Serialize the contents of this element to HTML
+    pub fn inner_html(&self) -> String {
+        let mut html = String::new();
+        self.write_contents(&mut html, None)
+            .expect("Writing to a String cannot fail");
+        html
+    }
+
+    fn write_start_tag(&self, f: &mut impl Write) -> fmt::Result {
+        write!(f, "<{}", self.name)?;
+        // Attributes are stored unordered, so sort them to give consistent output
+        let mut attributes: Vec<(&String, &String)> = self.attributes.0.iter().collect();
+        attributes.sort();
+        for (name, value) in attributes {
+            write!(f, " {}=\"{}\"", name, escape(value, true))?;
+        }
+        f.write_char('>')
+    }
+
+    /// Write this element, indented to `indent` levels if pretty-printing
+    fn write_html(&self, f: &mut impl Write, indent: Option<usize>) -> fmt::Result {
+        self.write_start_tag(f)?;
+        if self.namespace == Namespace::Html && VOID_ELEMENTS.contains(&self.name.as_str()) {
+            return Ok(());
+        }
+        self.write_contents(f, indent)?;
+        write!(f, "</{}>", self.name)
+    }
+
+    fn write_contents(&self, f: &mut impl Write, indent: Option<usize>) -> fmt::Result {
+        // Only HTML elements have special rules for their contents
+        let name = match self.namespace {
+            Namespace::Html => self.name.as_str(),
+            _ => "",
+        };
+        if PREFORMATTED_ELEMENTS.contains(&name) {
+            // The parser drops a newline directly after the start tag, so add one to keep it
+            if let Some(DOMContent::Text(t)) = self.contents.first() {
+                if t.text.starts_with('\n') {
+                    f.write_char('\n')?;
+                }
+            }
+        }
+        let block = indent.filter(|_| {
+            !PREFORMATTED_ELEMENTS.contains(&name)
+                && !RAW_TEXT_ELEMENTS.contains(&name)
+                && self
+                    .contents
+                    .iter()
+                    .any(|c| matches!(c, DOMContent::Element(_)))
+        });
+        for content in &self.contents {
+            let text = match content {
+                // Whitespace around text is replaced by the indentation
+                DOMContent::Text(t) if block.is_some() => {
+                    let text = t.text.trim_matches(|c: char| c.is_ascii_whitespace());
+                    if text.is_empty() {
+                        continue;
+                    }
+                    text
+                }
+                DOMContent::Text(t) => &t.text,
+                _ => "",
+            };
+            if let Some(indent) = block {
+                write!(f, "\n{:1$}", "", (indent + 1) * 2)?;
+            }
+            match content {
+                DOMContent::Text(_) if RAW_TEXT_ELEMENTS.contains(&name) => f.write_str(text)?,
+                DOMContent::Text(_) => f.write_str(&escape(text, false))?,
+                DOMContent::Element(e) => e.write_html(f, block.map(|i| i + 1))?,
+                content => write!(f, "{}", content)?,
+            }
+        }
+        if let Some(indent) = block {
+            write!(f, "\n{:1$}", "", indent * 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the element as HTML. The alternate flag (`{:#}`) pretty-prints it
+impl Display for DOMElement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.write_html(f, f.alternate().then_some(0))
+    }
+}
+
+impl Display for DOMContent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DOMContent::Text(t) => f.write_str(&escape(&t.text, false)),
+            DOMContent::Element(e) => e.fmt(f),
+            DOMContent::Comment(c) => write!(f, "<!--{}-->", c),
+            DOMContent::Doctype(d) => write!(f, "<!DOCTYPE {}>", d.name),
+        }
+    }
+}
+
+impl Display for Document {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, content) in self.contents().iter().enumerate() {
+            if i > 0 {
+                f.write_char('\n')?;
+            }
+            content.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_serialize() {
+    use crate::{document, DOMAttributes};
+    use std::collections::HashMap;
+
+    let elt = DOMElement::new(
+        "p",
+        Some(DOMAttributes(HashMap::from([
+            ("title".to_string(), "\"Fish\" & Chips".to_string()),
+            ("class".to_string(), "a<b".to_string()),
+        ]))),
+        vec![
+            "1 < 2 & 3\u{A0}> 2".into(),
+            DOMElement::new("br", None, vec![]).into(),
+            DOMElement::new("script", None, vec!["a < b && c".into()]).into(),
+        ],
+    );
+    assert_eq!(
+        elt.to_html(),
+        r#"<p class="a<b" title="&quot;Fish&quot; &amp; Chips">1 &lt; 2 &amp; 3&nbsp;&gt; 2<br><script>a < b && c</script></p>"#
+    );
+    assert_eq!(
+        elt.inner_html(),
+        "1 &lt; 2 &amp; 3&nbsp;&gt; 2<br><script>a < b && c</script>"
+    );
+
+    let (_, doc) = document("<!DOCTYPE html><title>T</title><div><p>One<p>Two</div>").unwrap();
+    assert_eq!(
+        doc.to_string(),
+        "<!DOCTYPE html>\n<html><head><title>T</title></head><body><div><p>One</p><p>Two</p></div></body></html>"
+    );
+    assert_eq!(
+        doc.root().to_pretty_html(),
+        "<html>
+  <head>
+    <title>T</title>
+  </head>
+  <body>
+    <div>
+      <p>One</p>
+      <p>Two</p>
+    </div>
+  </body>
+</html>"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_round_trip() {
+    let i = r#"<!DOCTYPE html><html lang="en"><head><style>a > b { color: red }</style></head><body><p class="x">a &amp; b<img alt="&quot;"><table><tbody><tr><td>1</td></tr></tbody></table></p></body></html>"#;
+    let (_, doc) = crate::document(i).unwrap();
+    let (_, reparsed) = crate::document(&doc.to_string()).unwrap();
+    assert_eq!(doc.root(), reparsed.root());
+    // Escaped markup in `<noscript>` stays as text
+    let i = "<noscript>&lt;script&gt;alert(1)&lt;/script&gt;</noscript>";
+    let (_, doc) = crate::document(&format!("<p>a</p>{}", i)).unwrap();
+    assert!(doc.to_string().contains(i));
+    let (_, reparsed) = crate::document(&doc.to_string()).unwrap();
+    assert_eq!(doc.root(), reparsed.root());
+    // Pretty-printing only changes whitespace, which it replaces with its own
+    let (_, reparsed) = crate::document(&doc.root().to_pretty_html()).unwrap();
+    assert_eq!(
+        doc.root().to_pretty_html(),
+        reparsed.root().to_pretty_html()
+    );
+}
@@ -14,6 +14,7 @@ fn test_document() {
         Some untagged text
     </body>
 </html>"#;
+    // Whitespace is kept as it is, apart from where it's ignored before `<head>`
     let target = DOMElement::new(
         "html",
         Some(attributes!(lang=>en)),
@@ -22,26 +23,52 @@ fn test_document() {
                 "head",
                 None,
                 vec![
+                    "\n        ".into(),
                     DOMElement::new("meta", Some(attributes!(charset=>utf-8)), vec![]).into(),
+                    "\n        ".into(),
                     DOMElement::new(
                         "title",
                         None,
                         vec!["The minimal, valid HTML5 document".into()],
                     )
                     .into(),
+                    "\n    ".into(),
                 ],
             )
             .into(),
+            "\n    ".into(),
             DOMElement::new(
                 "body",
                 None,
                 vec![
+                    "\n        ".into(),
+                    DOMContent::Comment(" User-visible content goes in the body ".to_string()),
+                    "\n        ".into(),
                     DOMElement::new("p", None, vec!["Some paragraph".into()]).into(),
-                    "Some untagged text".into(),
+                    "\n        Some untagged text\n    \n".into(),
                 ],
             )
             .into(),
         ],
     );
-    assert_eq!(document(i), Ok(("", target)));
+    let (_, document) = document(i).unwrap();
+    assert_eq!(document.root(), target);
+    assert_eq!(document.quirks_mode, QuirksMode::NoQuirks);
+    assert_eq!(document.errors, vec![]);
+}
+
+#[test]
+fn test_character_references() {
+    let i = r#"<p title="Fish &amp; Chips">Fish&nbsp;&amp;&nbsp;Chips &mdash; &#163;5</p>"#;
+    let html = document(i).unwrap().1.root();
+    let body = html.get_elements_by_name("body", false)[0];
+    let target = DOMElement::new(
+        "p",
+        Some(DOMAttributes(HashMap::from([(
+            "title".to_string(),
+            "Fish & Chips".to_string(),
+        )]))),
+        vec!["Fish\u{A0}&\u{A0}Chips \u{2014} \u{A3}5".into()],
+    );
+    assert_eq!(body.contents, vec![target.into()]);
 }
@@ -0,0 +1,1995 @@
+//! Implements the HTML tokenization stage
+//! <https://html.spec.whatwg.org/multipage/parsing.html#tokenization>
+
+use crate::entities::{self, LONGEST_ENTITY};
+use crate::{ParseError, SourcePosition, SourceSpan};
+use std::collections::VecDeque;
+use std::mem;
+use tracing::debug;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Doctype(Doctype),
+    StartTag(Tag),
+    EndTag(Tag),
+    Comment(String),
+    Character(char),
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Doctype {
+    pub name: Option<String>,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    pub force_quirks: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Tag {
+    pub name: String,
+    pub attributes: Vec<Attribute>,
+    pub self_closing: bool,
+    // Where the start tag was parsed from. Set by the tree builder
+    pub span: Option<SourceSpan>,
+}
+
+impl Tag {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| a.value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Attribute {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    Data,
+    Rcdata,
+    Rawtext,
+    ScriptData,
+    Plaintext,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    RcdataLessThanSign,
+    RcdataEndTagOpen,
+    RcdataEndTagName,
+    RawtextLessThanSign,
+    RawtextEndTagOpen,
+    RawtextEndTagName,
+    ScriptDataLessThanSign,
+    ScriptDataEndTagOpen,
+    ScriptDataEndTagName,
+    ScriptDataEscapeStart,
+    ScriptDataEscapeStartDash,
+    ScriptDataEscaped,
+    ScriptDataEscapedDash,
+    ScriptDataEscapedDashDash,
+    ScriptDataEscapedLessThanSign,
+    ScriptDataEscapedEndTagOpen,
+    ScriptDataEscapedEndTagName,
+    ScriptDataDoubleEscapeStart,
+    ScriptDataDoubleEscaped,
+    ScriptDataDoubleEscapedDash,
+    ScriptDataDoubleEscapedDashDash,
+    ScriptDataDoubleEscapedLessThanSign,
+    ScriptDataDoubleEscapeEnd,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueDoubleQuoted,
+    AttributeValueSingleQuoted,
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    BogusComment,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentLessThanSign,
+    CommentLessThanSignBang,
+    CommentLessThanSignBangDash,
+    CommentLessThanSignBangDashDash,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    Doctype,
+    BeforeDoctypeName,
+    DoctypeName,
+    AfterDoctypeName,
+    AfterDoctypePublicKeyword,
+    BeforeDoctypePublicIdentifier,
+    DoctypePublicIdentifierDoubleQuoted,
+    DoctypePublicIdentifierSingleQuoted,
+    AfterDoctypePublicIdentifier,
+    BetweenDoctypePublicAndSystemIdentifiers,
+    AfterDoctypeSystemKeyword,
+    BeforeDoctypeSystemIdentifier,
+    DoctypeSystemIdentifierDoubleQuoted,
+    DoctypeSystemIdentifierSingleQuoted,
+    AfterDoctypeSystemIdentifier,
+    BogusDoctype,
+    CdataSection,
+    CdataSectionBracket,
+    CdataSectionEnd,
+    CharacterReference,
+    NamedCharacterReference,
+    AmbiguousAmpersand,
+    NumericCharacterReference,
+    HexadecimalCharacterReferenceStart,
+    DecimalCharacterReferenceStart,
+    HexadecimalCharacterReference,
+    DecimalCharacterReference,
+    NumericCharacterReferenceEnd,
+}
+
+/// The result of consuming the next input character
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Next {
+    Char(char),
+    Eof,
+}
+
+use Next::{Char, Eof};
+
+/// Replacements for numeric references to C1 control characters, which are interpreted as
+/// windows-1252. Entries of `'\0'` have no replacement
+static C1_REPLACEMENTS: [char; 32] = [
+    '\u{20AC}', '\0', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\0', '\u{017D}', '\0', '\0',
+    '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}', '\u{02DC}',
+    '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\0', '\u{017E}', '\u{0178}',
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TagKind {
+    Start,
+    End,
+}
+
+/// A state machine turning HTML source text into [`Token`]s. Input may be provided in several
+/// pieces with [`Tokenizer::feed`]; tokens are only produced once enough input is available to
+/// decide on them.
+pub(crate) struct Tokenizer {
+    // The input which has not been consumed yet
+    input: String,
+    // How many bytes of input have been consumed and dropped from `input`
+    discarded: usize,
+    // Byte offset of the next character to be consumed
+    pos: usize,
+    // Length in bytes of the last consumed character, so it may be reconsumed
+    last_len: usize,
+    // Set once the end of the input stream has been reached
+    eof: bool,
+    // Set once the EOF token has been emitted
+    done: bool,
+    state: State,
+    // The state to return to after a character reference
+    return_state: State,
+    // Tokens along with the span of input they were parsed from
+    tokens: VecDeque<(Token, SourceSpan)>,
+    // Byte offsets of the start of each line consumed so far
+    line_starts: Vec<usize>,
+    // Start offsets of the character being consumed, the current tag, comment or doctype, and
+    // the current character reference
+    step_start: usize,
+    token_start: usize,
+    reference_start: usize,
+    errors: Vec<ParseError>,
+    tag_kind: TagKind,
+    tag: Tag,
+    attribute: Option<Attribute>,
+    comment: String,
+    doctype: Doctype,
+    temp_buffer: String,
+    character_reference_code: u32,
+    last_start_tag: Option<String>,
+    // Whether CDATA sections are allowed, which is only the case in foreign content
+    cdata_allowed: bool,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | ' ')
+}
+
+impl Tokenizer {
+    pub fn new() -> Self {
+        Self {
+            input: String::new(),
+            discarded: 0,
+            pos: 0,
+            last_len: 0,
+            eof: false,
+            done: false,
+            state: State::Data,
+            return_state: State::Data,
+            tokens: VecDeque::new(),
+            line_starts: vec![0],
+            step_start: 0,
+            token_start: 0,
+            reference_start: 0,
+            errors: vec![],
+            tag_kind: TagKind::Start,
+            tag: Tag::default(),
+            attribute: None,
+            comment: String::new(),
+            doctype: Doctype::default(),
+            temp_buffer: String::new(),
+            character_reference_code: 0,
+            last_start_tag: None,
+            cdata_allowed: false,
+        }
+    }
+
+    /// Append a chunk of source text to the input stream
+    pub fn feed(&mut self, input: &str) {
+        // Input is never looked at again once consumed, so drop it to keep memory use down
+        self.input.drain(..self.pos - self.discarded);
+        self.discarded = self.pos;
+        self.input.push_str(input);
+    }
+
+    /// Mark the end of the input stream
+    pub fn end(&mut self) {
+        self.eof = true;
+    }
+
+    /// Switch the tokenizer state. Used by the tree builder for elements such as `<script>`
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Set the name of the last start tag emitted, which decides whether an end tag in RCDATA,
+    /// RAWTEXT or script data is appropriate
+    #[cfg(test)]
+    pub fn set_last_start_tag(&mut self, name: &str) {
+        self.last_start_tag = Some(name.to_string());
+    }
+
+    /// Set whether `<![CDATA[` starts a CDATA section, rather than a bogus comment. The tree
+    /// builder allows this while the adjusted current node is not an HTML element
+    pub fn set_cdata_allowed(&mut self, allowed: bool) {
+        self.cdata_allowed = allowed;
+    }
+
+    /// Returns the next token and the span of input it was parsed from, or [`None`] if more
+    /// input is needed (or the EOF token has already been returned)
+    pub fn next_token(&mut self) -> Option<(Token, SourceSpan)> {
+        loop {
+            if let Some(token) = self.tokens.pop_front() {
+                return Some(token);
+            }
+            if self.done || !self.step() {
+                return None;
+            }
+        }
+    }
+
+    /// The parse errors encountered so far
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    fn error(&mut self, code: &'static str) {
+        let position = self.position(self.step_start);
+        debug!("Parse error at {}: {}", position, code);
+        self.errors.push(ParseError {
+            position,
+            message: code,
+        });
+    }
+
+    /// Consume the next input character, normalizing newlines
+    fn consume(&mut self) -> Option<Next> {
+        let mut chars = self.rest().chars();
+        let (next, len) = match chars.next() {
+            None if self.eof => (Eof, 0),
+            None => return None,
+            Some('\r') => match chars.next() {
+                Some('\n') => (Char('\n'), 2),
+                None if !self.eof => return None,
+                _ => (Char('\n'), 1),
+            },
+            Some(c) => (Char(c), c.len_utf8()),
+        };
+        self.pos += len;
+        self.last_len = len;
+        if next == Char('\n') && self.pos > *self.line_starts.last().unwrap() {
+            self.line_starts.push(self.pos);
+        }
+        Some(next)
+    }
+
+    /// The input from the next character to be consumed
+    fn rest(&self) -> &str {
+        &self.input[self.pos - self.discarded..]
+    }
+
+    /// Switch to `state` and consume the current input character again in it
+    fn reconsume(&mut self, state: State) {
+        self.pos -= self.last_len;
+        self.last_len = 0;
+        self.state = state;
+    }
+
+    /// Check whether the upcoming input starts with `s`. Returns [`None`] if this cannot yet be
+    /// decided
+    fn lookahead(&self, s: &str, ignore_case: bool) -> Option<bool> {
+        let rest = self.rest();
+        let eq = |a: &str, b: &str| {
+            if ignore_case {
+                a.eq_ignore_ascii_case(b)
+            } else {
+                a == b
+            }
+        };
+        if rest.len() >= s.len() {
+            Some(rest.is_char_boundary(s.len()) && eq(&rest[..s.len()], s))
+        } else if self.eof || !(s.is_char_boundary(rest.len()) && eq(&s[..rest.len()], rest)) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Find the line and column of a byte offset which has already been consumed
+    fn position(&self, offset: usize) -> SourcePosition {
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        SourcePosition {
+            offset,
+            line,
+            column: offset - self.line_starts[line - 1] + 1,
+        }
+    }
+
+    fn emit_at(&mut self, token: Token, start: usize, end: usize) {
+        if token == Token::Eof {
+            self.done = true;
+        }
+        let span = SourceSpan {
+            start: self.position(start),
+            end: self.position(end),
+        };
+        self.tokens.push_back((token, span));
+    }
+
+    fn emit(&mut self, token: Token) {
+        let start = match token {
+            Token::Character(_) | Token::Eof => self.step_start,
+            _ => self.token_start,
+        };
+        self.emit_at(token, start, self.pos);
+    }
+
+    fn emit_char(&mut self, c: char) {
+        self.emit(Token::Character(c));
+    }
+
+    /// Emit characters which were held back from the start of the current tag, such as `</`
+    fn emit_str(&mut self, s: &str) {
+        for (i, c) in s.char_indices() {
+            let start = self.token_start + i;
+            self.emit_at(Token::Character(c), start, start + c.len_utf8());
+        }
+    }
+
+    /// Switch to a state following a `<` which may begin a tag
+    fn begin_markup(&mut self, state: State) {
+        self.token_start = self.step_start;
+        self.state = state;
+    }
+
+    fn new_tag(&mut self, kind: TagKind) {
+        self.tag_kind = kind;
+        self.tag = Tag::default();
+        self.attribute = None;
+    }
+
+    fn new_attribute(&mut self, name: &str) {
+        self.finish_attribute();
+        self.attribute = Some(Attribute {
+            name: name.to_string(),
+            value: String::new(),
+        });
+    }
+
+    fn finish_attribute(&mut self) {
+        if let Some(attr) = self.attribute.take() {
+            if self.tag.attributes.iter().any(|a| a.name == attr.name) {
+                self.error("duplicate-attribute");
+            } else {
+                self.tag.attributes.push(attr);
+            }
+        }
+    }
+
+    fn attribute_name(&mut self) -> &mut String {
+        &mut self.attribute.get_or_insert_with(Default::default).name
+    }
+
+    fn attribute_value(&mut self) -> &mut String {
+        &mut self.attribute.get_or_insert_with(Default::default).value
+    }
+
+    fn emit_tag(&mut self) {
+        self.finish_attribute();
+        let tag = mem::take(&mut self.tag);
+        match self.tag_kind {
+            TagKind::Start => {
+                self.last_start_tag = Some(tag.name.clone());
+                self.emit(Token::StartTag(tag));
+            }
+            TagKind::End => {
+                if !tag.attributes.is_empty() {
+                    self.error("end-tag-with-attributes");
+                }
+                if tag.self_closing {
+                    self.error("end-tag-with-trailing-solidus");
+                }
+                self.emit(Token::EndTag(tag));
+            }
+        }
+    }
+
+    fn emit_comment(&mut self) {
+        let comment = mem::take(&mut self.comment);
+        self.emit(Token::Comment(comment));
+    }
+
+    fn emit_doctype(&mut self) {
+        let doctype = mem::take(&mut self.doctype);
+        self.emit(Token::Doctype(doctype));
+    }
+
+    fn is_appropriate_end_tag(&self) -> bool {
+        self.tag_kind == TagKind::End && self.last_start_tag.as_ref() == Some(&self.tag.name)
+    }
+
+    /// Performs a single step of the state machine. Returns `false` if more input is needed
+    fn step(&mut self) -> bool {
+        self.step_start = self.pos;
+        // These states look ahead at the input before consuming anything
+        match self.state {
+            State::MarkupDeclarationOpen => return self.markup_declaration_open(),
+            State::AfterDoctypeName => return self.after_doctype_name(),
+            State::NamedCharacterReference => return self.named_character_reference(),
+            State::NumericCharacterReferenceEnd => {
+                self.numeric_character_reference_end();
+                return true;
+            }
+            _ => {}
+        }
+        let c = match self.consume() {
+            Some(c) => c,
+            None => return false,
+        };
+        match self.state {
+            State::Data => match c {
+                Char('&') => self.begin_character_reference(),
+                Char('<') => self.begin_markup(State::TagOpen),
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\0');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => self.emit(Token::Eof),
+            },
+            State::Rcdata => match c {
+                Char('&') => self.begin_character_reference(),
+                Char('<') => self.begin_markup(State::RcdataLessThanSign),
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => self.emit(Token::Eof),
+            },
+            State::Rawtext => match c {
+                Char('<') => self.begin_markup(State::RawtextLessThanSign),
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => self.emit(Token::Eof),
+            },
+            State::ScriptData => match c {
+                Char('<') => self.begin_markup(State::ScriptDataLessThanSign),
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => self.emit(Token::Eof),
+            },
+            State::Plaintext => match c {
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => self.emit(Token::Eof),
+            },
+            State::TagOpen => match c {
+                Char('!') => self.state = State::MarkupDeclarationOpen,
+                Char('/') => self.state = State::EndTagOpen,
+                Char(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(TagKind::Start);
+                    self.reconsume(State::TagName);
+                }
+                Char('?') => {
+                    self.error("unexpected-question-mark-instead-of-tag-name");
+                    self.comment.clear();
+                    self.reconsume(State::BogusComment);
+                }
+                Char(_) => {
+                    self.error("invalid-first-character-of-tag-name");
+                    self.emit_str("<");
+                    self.reconsume(State::Data);
+                }
+                Eof => {
+                    self.error("eof-before-tag-name");
+                    self.emit_str("<");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::EndTagOpen => match c {
+                Char(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(TagKind::End);
+                    self.reconsume(State::TagName);
+                }
+                Char('>') => {
+                    self.error("missing-end-tag-name");
+                    self.state = State::Data;
+                }
+                Char(_) => {
+                    self.error("invalid-first-character-of-tag-name");
+                    self.comment.clear();
+                    self.reconsume(State::BogusComment);
+                }
+                Eof => {
+                    self.error("eof-before-tag-name");
+                    self.emit_str("</");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::TagName => match c {
+                Char(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Char('/') => self.state = State::SelfClosingStartTag,
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.tag.name.push('\u{FFFD}');
+                }
+                Char(c) => self.tag.name.push(c.to_ascii_lowercase()),
+                Eof => {
+                    self.error("eof-in-tag");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::RcdataLessThanSign => self.text_less_than_sign(c, State::Rcdata),
+            State::RcdataEndTagOpen => self.text_end_tag_open(c, State::Rcdata),
+            State::RcdataEndTagName => self.text_end_tag_name(c, State::Rcdata),
+            State::RawtextLessThanSign => self.text_less_than_sign(c, State::Rawtext),
+            State::RawtextEndTagOpen => self.text_end_tag_open(c, State::Rawtext),
+            State::RawtextEndTagName => self.text_end_tag_name(c, State::Rawtext),
+            State::ScriptDataLessThanSign => match c {
+                Char('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::ScriptDataEndTagOpen;
+                }
+                Char('!') => {
+                    self.state = State::ScriptDataEscapeStart;
+                    self.emit_str("<!");
+                }
+                _ => {
+                    self.emit_str("<");
+                    self.reconsume(State::ScriptData);
+                }
+            },
+            State::ScriptDataEndTagOpen => self.text_end_tag_open(c, State::ScriptData),
+            State::ScriptDataEndTagName => self.text_end_tag_name(c, State::ScriptData),
+            State::ScriptDataEscapeStart => match c {
+                Char('-') => {
+                    self.state = State::ScriptDataEscapeStartDash;
+                    self.emit_char('-');
+                }
+                _ => self.reconsume(State::ScriptData),
+            },
+            State::ScriptDataEscapeStartDash => match c {
+                Char('-') => {
+                    self.state = State::ScriptDataEscapedDashDash;
+                    self.emit_char('-');
+                }
+                _ => self.reconsume(State::ScriptData),
+            },
+            State::ScriptDataEscaped => match c {
+                Char('-') => {
+                    self.state = State::ScriptDataEscapedDash;
+                    self.emit_char('-');
+                }
+                Char('<') => self.begin_markup(State::ScriptDataEscapedLessThanSign),
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => {
+                    self.error("eof-in-script-html-comment-like-text");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::ScriptDataEscapedDash => match c {
+                Char('-') => {
+                    self.state = State::ScriptDataEscapedDashDash;
+                    self.emit_char('-');
+                }
+                Char('<') => self.begin_markup(State::ScriptDataEscapedLessThanSign),
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => {
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char(c);
+                }
+                Eof => {
+                    self.error("eof-in-script-html-comment-like-text");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::ScriptDataEscapedDashDash => match c {
+                Char('-') => self.emit_char('-'),
+                Char('<') => self.begin_markup(State::ScriptDataEscapedLessThanSign),
+                Char('>') => {
+                    self.state = State::ScriptData;
+                    self.emit_char('>');
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => {
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char(c);
+                }
+                Eof => {
+                    self.error("eof-in-script-html-comment-like-text");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::ScriptDataEscapedLessThanSign => match c {
+                Char('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::ScriptDataEscapedEndTagOpen;
+                }
+                Char(c) if c.is_ascii_alphabetic() => {
+                    self.temp_buffer.clear();
+                    self.emit_str("<");
+                    self.reconsume(State::ScriptDataDoubleEscapeStart);
+                }
+                _ => {
+                    self.emit_str("<");
+                    self.reconsume(State::ScriptDataEscaped);
+                }
+            },
+            State::ScriptDataEscapedEndTagOpen => {
+                self.text_end_tag_open(c, State::ScriptDataEscaped)
+            }
+            State::ScriptDataEscapedEndTagName => {
+                self.text_end_tag_name(c, State::ScriptDataEscaped)
+            }
+            State::ScriptDataDoubleEscapeStart => self.script_data_double_escape(
+                c,
+                State::ScriptDataDoubleEscaped,
+                State::ScriptDataEscaped,
+            ),
+            State::ScriptDataDoubleEscaped => match c {
+                Char('-') => {
+                    self.state = State::ScriptDataDoubleEscapedDash;
+                    self.emit_char('-');
+                }
+                Char('<') => {
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<');
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => self.emit_char(c),
+                Eof => {
+                    self.error("eof-in-script-html-comment-like-text");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::ScriptDataDoubleEscapedDash => match c {
+                Char('-') => {
+                    self.state = State::ScriptDataDoubleEscapedDashDash;
+                    self.emit_char('-');
+                }
+                Char('<') => {
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<');
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => {
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char(c);
+                }
+                Eof => {
+                    self.error("eof-in-script-html-comment-like-text");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::ScriptDataDoubleEscapedDashDash => match c {
+                Char('-') => self.emit_char('-'),
+                Char('<') => {
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<');
+                }
+                Char('>') => {
+                    self.state = State::ScriptData;
+                    self.emit_char('>');
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Char(c) => {
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char(c);
+                }
+                Eof => {
+                    self.error("eof-in-script-html-comment-like-text");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::ScriptDataDoubleEscapedLessThanSign => match c {
+                Char('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::ScriptDataDoubleEscapeEnd;
+                    self.emit_char('/');
+                }
+                _ => self.reconsume(State::ScriptDataDoubleEscaped),
+            },
+            State::ScriptDataDoubleEscapeEnd => self.script_data_double_escape(
+                c,
+                State::ScriptDataEscaped,
+                State::ScriptDataDoubleEscaped,
+            ),
+            State::BeforeAttributeName => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('/') | Char('>') | Eof => self.reconsume(State::AfterAttributeName),
+                Char('=') => {
+                    self.error("unexpected-equals-sign-before-attribute-name");
+                    self.new_attribute("=");
+                    self.state = State::AttributeName;
+                }
+                Char(_) => {
+                    self.new_attribute("");
+                    self.reconsume(State::AttributeName);
+                }
+            },
+            State::AttributeName => match c {
+                Char(c) if is_whitespace(c) => self.reconsume(State::AfterAttributeName),
+                Char('/') | Char('>') | Eof => self.reconsume(State::AfterAttributeName),
+                Char('=') => self.state = State::BeforeAttributeValue,
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.attribute_name().push('\u{FFFD}');
+                }
+                Char(c) => {
+                    if matches!(c, '"' | '\'' | '<') {
+                        self.error("unexpected-character-in-attribute-name");
+                    }
+                    self.attribute_name().push(c.to_ascii_lowercase());
+                }
+            },
+            State::AfterAttributeName => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('/') => self.state = State::SelfClosingStartTag,
+                Char('=') => self.state = State::BeforeAttributeValue,
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Char(_) => {
+                    self.new_attribute("");
+                    self.reconsume(State::AttributeName);
+                }
+                Eof => {
+                    self.error("eof-in-tag");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::BeforeAttributeValue => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('"') => self.state = State::AttributeValueDoubleQuoted,
+                Char('\'') => self.state = State::AttributeValueSingleQuoted,
+                Char('>') => {
+                    self.error("missing-attribute-value");
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                _ => self.reconsume(State::AttributeValueUnquoted),
+            },
+            State::AttributeValueDoubleQuoted => self.quoted_attribute_value(c, '"'),
+            State::AttributeValueSingleQuoted => self.quoted_attribute_value(c, '\''),
+            State::AttributeValueUnquoted => match c {
+                Char(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Char('&') => self.begin_character_reference(),
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.attribute_value().push('\u{FFFD}');
+                }
+                Char(c) => {
+                    if matches!(c, '"' | '\'' | '<' | '=' | '`') {
+                        self.error("unexpected-character-in-unquoted-attribute-value");
+                    }
+                    self.attribute_value().push(c);
+                }
+                Eof => {
+                    self.error("eof-in-tag");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::AfterAttributeValueQuoted => match c {
+                Char(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Char('/') => self.state = State::SelfClosingStartTag,
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Char(_) => {
+                    self.error("missing-whitespace-between-attributes");
+                    self.reconsume(State::BeforeAttributeName);
+                }
+                Eof => {
+                    self.error("eof-in-tag");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::SelfClosingStartTag => match c {
+                Char('>') => {
+                    self.tag.self_closing = true;
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Char(_) => {
+                    self.error("unexpected-solidus-in-tag");
+                    self.reconsume(State::BeforeAttributeName);
+                }
+                Eof => {
+                    self.error("eof-in-tag");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::BogusComment => match c {
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.comment.push('\u{FFFD}');
+                }
+                Char(c) => self.comment.push(c),
+                Eof => {
+                    self.emit_comment();
+                    self.emit(Token::Eof);
+                }
+            },
+            State::CdataSection => match c {
+                Char(']') => self.state = State::CdataSectionBracket,
+                Char(c) => self.emit_char(c),
+                Eof => {
+                    self.error("eof-in-cdata");
+                    self.emit(Token::Eof);
+                }
+            },
+            State::CdataSectionBracket => match c {
+                Char(']') => self.state = State::CdataSectionEnd,
+                _ => {
+                    self.emit_char(']');
+                    self.reconsume(State::CdataSection);
+                }
+            },
+            State::CdataSectionEnd => match c {
+                Char(']') => self.emit_char(']'),
+                Char('>') => self.state = State::Data,
+                _ => {
+                    self.emit_char(']');
+                    self.emit_char(']');
+                    self.reconsume(State::CdataSection);
+                }
+            },
+            State::CommentStart => match c {
+                Char('-') => self.state = State::CommentStartDash,
+                Char('>') => {
+                    self.error("abrupt-closing-of-empty-comment");
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                _ => self.reconsume(State::Comment),
+            },
+            State::CommentStartDash => match c {
+                Char('-') => self.state = State::CommentEnd,
+                Char('>') => {
+                    self.error("abrupt-closing-of-empty-comment");
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Char(_) => {
+                    self.comment.push('-');
+                    self.reconsume(State::Comment);
+                }
+                Eof => self.eof_in_comment(),
+            },
+            State::Comment => match c {
+                Char('<') => {
+                    self.comment.push('<');
+                    self.state = State::CommentLessThanSign;
+                }
+                Char('-') => self.state = State::CommentEndDash,
+                Char('\0') => {
+                    self.error("unexpected-null-character");
+                    self.comment.push('\u{FFFD}');
+                }
+                Char(c) => self.comment.push(c),
+                Eof => self.eof_in_comment(),
+            },
+            State::CommentLessThanSign => match c {
+                Char('!') => {
+                    self.comment.push('!');
+                    self.state = State::CommentLessThanSignBang;
+                }
+                Char('<') => self.comment.push('<'),
+                _ => self.reconsume(State::Comment),
+            },
+            State::CommentLessThanSignBang => match c {
+                Char('-') => self.state = State::CommentLessThanSignBangDash,
+                _ => self.reconsume(State::Comment),
+            },
+            State::CommentLessThanSignBangDash => match c {
+                Char('-') => self.state = State::CommentLessThanSignBangDashDash,
+                _ => self.reconsume(State::CommentEndDash),
+            },
+            State::CommentLessThanSignBangDashDash => match c {
+                Char('>') | Eof => self.reconsume(State::CommentEnd),
+                Char(_) => {
+                    self.error("nested-comment");
+                    self.reconsume(State::CommentEnd);
+                }
+            },
+            State::CommentEndDash => match c {
+                Char('-') => self.state = State::CommentEnd,
+                Char(_) => {
+                    self.comment.push('-');
+                    self.reconsume(State::Comment);
+                }
+                Eof => self.eof_in_comment(),
+            },
+            State::CommentEnd => match c {
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Char('!') => self.state = State::CommentEndBang,
+                Char('-') => self.comment.push('-'),
+                Char(_) => {
+                    self.comment.push_str("--");
+                    self.reconsume(State::Comment);
+                }
+                Eof => self.eof_in_comment(),
+            },
+            State::CommentEndBang => match c {
+                Char('-') => {
+                    self.comment.push_str("--!");
+                    self.state = State::CommentEndDash;
+                }
+                Char('>') => {
+                    self.error("incorrectly-closed-comment");
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Char(_) => {
+                    self.comment.push_str("--!");
+                    self.reconsume(State::Comment);
+                }
+                Eof => self.eof_in_comment(),
+            },
+            State::Doctype => match c {
+                Char(c) if is_whitespace(c) => self.state = State::BeforeDoctypeName,
+                Char('>') => self.reconsume(State::BeforeDoctypeName),
+                Char(_) => {
+                    self.error("missing-whitespace-before-doctype-name");
+                    self.reconsume(State::BeforeDoctypeName);
+                }
+                Eof => {
+                    self.doctype = Doctype::default();
+                    self.eof_in_doctype();
+                }
+            },
+            State::BeforeDoctypeName => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('>') => {
+                    self.error("missing-doctype-name");
+                    self.doctype = Doctype {
+                        force_quirks: true,
+                        ..Default::default()
+                    };
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Char(c) => {
+                    let c = if c == '\0' {
+                        self.error("unexpected-null-character");
+                        '\u{FFFD}'
+                    } else {
+                        c.to_ascii_lowercase()
+                    };
+                    self.doctype = Doctype {
+                        name: Some(c.to_string()),
+                        ..Default::default()
+                    };
+                    self.state = State::DoctypeName;
+                }
+                Eof => {
+                    self.doctype = Doctype::default();
+                    self.eof_in_doctype();
+                }
+            },
+            State::DoctypeName => match c {
+                Char(c) if is_whitespace(c) => self.state = State::AfterDoctypeName,
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Char(c) => {
+                    let c = if c == '\0' {
+                        self.error("unexpected-null-character");
+                        '\u{FFFD}'
+                    } else {
+                        c.to_ascii_lowercase()
+                    };
+                    self.doctype.name.get_or_insert_with(String::new).push(c);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::AfterDoctypePublicKeyword => match c {
+                Char(c) if is_whitespace(c) => self.state = State::BeforeDoctypePublicIdentifier,
+                Char(q @ ('"' | '\'')) => {
+                    self.error("missing-whitespace-after-doctype-public-keyword");
+                    self.doctype.public_id = Some(String::new());
+                    self.state = if q == '"' {
+                        State::DoctypePublicIdentifierDoubleQuoted
+                    } else {
+                        State::DoctypePublicIdentifierSingleQuoted
+                    };
+                }
+                Char('>') => self.missing_doctype_identifier("missing-doctype-public-identifier"),
+                Char(_) => {
+                    self.error("missing-quote-before-doctype-public-identifier");
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::BeforeDoctypePublicIdentifier => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('"') => {
+                    self.doctype.public_id = Some(String::new());
+                    self.state = State::DoctypePublicIdentifierDoubleQuoted;
+                }
+                Char('\'') => {
+                    self.doctype.public_id = Some(String::new());
+                    self.state = State::DoctypePublicIdentifierSingleQuoted;
+                }
+                Char('>') => self.missing_doctype_identifier("missing-doctype-public-identifier"),
+                Char(_) => {
+                    self.error("missing-quote-before-doctype-public-identifier");
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::DoctypePublicIdentifierDoubleQuoted => self.doctype_identifier(c, '"', true),
+            State::DoctypePublicIdentifierSingleQuoted => self.doctype_identifier(c, '\'', true),
+            State::AfterDoctypePublicIdentifier => match c {
+                Char(c) if is_whitespace(c) => {
+                    self.state = State::BetweenDoctypePublicAndSystemIdentifiers
+                }
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Char(q @ ('"' | '\'')) => {
+                    self.error("missing-whitespace-between-doctype-public-and-system-identifiers");
+                    self.start_system_identifier(q);
+                }
+                Char(_) => {
+                    self.error("missing-quote-before-doctype-system-identifier");
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::BetweenDoctypePublicAndSystemIdentifiers => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Char(q @ ('"' | '\'')) => self.start_system_identifier(q),
+                Char(_) => {
+                    self.error("missing-quote-before-doctype-system-identifier");
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::AfterDoctypeSystemKeyword => match c {
+                Char(c) if is_whitespace(c) => self.state = State::BeforeDoctypeSystemIdentifier,
+                Char(q @ ('"' | '\'')) => {
+                    self.error("missing-whitespace-after-doctype-system-keyword");
+                    self.start_system_identifier(q);
+                }
+                Char('>') => self.missing_doctype_identifier("missing-doctype-system-identifier"),
+                Char(_) => {
+                    self.error("missing-quote-before-doctype-system-identifier");
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::BeforeDoctypeSystemIdentifier => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char(q @ ('"' | '\'')) => self.start_system_identifier(q),
+                Char('>') => self.missing_doctype_identifier("missing-doctype-system-identifier"),
+                Char(_) => {
+                    self.error("missing-quote-before-doctype-system-identifier");
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::DoctypeSystemIdentifierDoubleQuoted => self.doctype_identifier(c, '"', false),
+            State::DoctypeSystemIdentifierSingleQuoted => self.doctype_identifier(c, '\'', false),
+            State::AfterDoctypeSystemIdentifier => match c {
+                Char(c) if is_whitespace(c) => {}
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Char(_) => {
+                    self.error("unexpected-character-after-doctype-system-identifier");
+                    self.reconsume(State::BogusDoctype);
+                }
+                Eof => self.eof_in_doctype(),
+            },
+            State::BogusDoctype => match c {
+                Char('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Char('\0') => self.error("unexpected-null-character"),
+                Char(_) => {}
+                Eof => {
+                    self.emit_doctype();
+                    self.emit(Token::Eof);
+                }
+            },
+            State::CharacterReference => match c {
+                Char(c) if c.is_ascii_alphanumeric() => {
+                    self.reconsume(State::NamedCharacterReference)
+                }
+                Char('#') => {
+                    self.temp_buffer.push('#');
+                    self.state = State::NumericCharacterReference;
+                }
+                _ => {
+                    self.flush_character_reference();
+                    self.reconsume(self.return_state);
+                }
+            },
+            State::AmbiguousAmpersand => match c {
+                Char(c) if c.is_ascii_alphanumeric() => {
+                    if self.in_attribute_value() {
+                        self.attribute_value().push(c);
+                    } else {
+                        self.emit_char(c);
+                    }
+                }
+                Char(';') => {
+                    self.error("unknown-named-character-reference");
+                    self.reconsume(self.return_state);
+                }
+                _ => self.reconsume(self.return_state),
+            },
+            State::NumericCharacterReference => {
+                self.character_reference_code = 0;
+                match c {
+                    Char(c @ ('x' | 'X')) => {
+                        self.temp_buffer.push(c);
+                        self.state = State::HexadecimalCharacterReferenceStart;
+                    }
+                    _ => self.reconsume(State::DecimalCharacterReferenceStart),
+                }
+            }
+            State::HexadecimalCharacterReferenceStart => match c {
+                Char(c) if c.is_ascii_hexdigit() => {
+                    self.reconsume(State::HexadecimalCharacterReference)
+                }
+                _ => {
+                    self.error("absence-of-digits-in-numeric-character-reference");
+                    self.flush_character_reference();
+                    self.reconsume(self.return_state);
+                }
+            },
+            State::DecimalCharacterReferenceStart => match c {
+                Char(c) if c.is_ascii_digit() => self.reconsume(State::DecimalCharacterReference),
+                _ => {
+                    self.error("absence-of-digits-in-numeric-character-reference");
+                    self.flush_character_reference();
+                    self.reconsume(self.return_state);
+                }
+            },
+            State::HexadecimalCharacterReference => self.numeric_character_reference(c, 16),
+            State::DecimalCharacterReference => self.numeric_character_reference(c, 10),
+            State::MarkupDeclarationOpen
+            | State::AfterDoctypeName
+            | State::NamedCharacterReference
+            | State::NumericCharacterReferenceEnd => unreachable!(),
+        }
+        true
+    }
+
+    fn begin_character_reference(&mut self) {
+        self.reference_start = self.step_start;
+        self.return_state = self.state;
+        self.temp_buffer.clear();
+        self.temp_buffer.push('&');
+        self.state = State::CharacterReference;
+    }
+
+    fn in_attribute_value(&self) -> bool {
+        matches!(
+            self.return_state,
+            State::AttributeValueDoubleQuoted
+                | State::AttributeValueSingleQuoted
+                | State::AttributeValueUnquoted
+        )
+    }
+
+    /// Output the contents of the temporary buffer, either to the current attribute value or as
+    /// character tokens
+    fn flush_character_reference(&mut self) {
+        let buffer = mem::take(&mut self.temp_buffer);
+        if self.in_attribute_value() {
+            self.attribute_value().push_str(&buffer);
+        } else {
+            buffer
+                .chars()
+                .for_each(|c| self.emit_at(Token::Character(c), self.reference_start, self.pos));
+        }
+    }
+
+    fn named_character_reference(&mut self) -> bool {
+        let rest = &self.input[self.pos - self.discarded..];
+        let run = rest.bytes().take_while(u8::is_ascii_alphanumeric).count();
+        // The name may continue in input which has not arrived yet
+        if !self.eof && run == rest.len() && run < LONGEST_ENTITY {
+            return false;
+        }
+        let candidate = if rest[run..].starts_with(';') {
+            &rest[..=run]
+        } else {
+            &rest[..run]
+        };
+        let found = (1..=candidate.len().min(LONGEST_ENTITY))
+            .rev()
+            .find_map(|len| entities::lookup(&candidate[..len]).map(|value| (len, value)));
+        let (len, value) = match found {
+            Some(found) => found,
+            None => {
+                self.flush_character_reference();
+                self.state = State::AmbiguousAmpersand;
+                return true;
+            }
+        };
+        let name = &rest[..len];
+        let next = rest[len..].chars().next();
+        self.temp_buffer.push_str(name);
+        self.pos += len;
+        self.state = self.return_state;
+        if !name.ends_with(';') {
+            // For historical reasons, references in attributes followed by these are left as-is
+            if self.in_attribute_value()
+                && matches!(next, Some(c) if c == '=' || c.is_ascii_alphanumeric())
+            {
+                self.flush_character_reference();
+                return true;
+            }
+            self.error("missing-semicolon-after-character-reference");
+        }
+        self.temp_buffer = value.to_string();
+        self.flush_character_reference();
+        true
+    }
+
+    fn numeric_character_reference(&mut self, c: Next, radix: u32) {
+        match c {
+            Char(c) if c.is_digit(radix) => {
+                self.character_reference_code = self
+                    .character_reference_code
+                    .saturating_mul(radix)
+                    .saturating_add(c.to_digit(radix).unwrap());
+            }
+            Char(';') => self.state = State::NumericCharacterReferenceEnd,
+            _ => {
+                self.error("missing-semicolon-after-character-reference");
+                self.reconsume(State::NumericCharacterReferenceEnd);
+            }
+        }
+    }
+
+    fn numeric_character_reference_end(&mut self) {
+        let code = self.character_reference_code;
+        let c = match code {
+            0 => {
+                self.error("null-character-reference");
+                '\u{FFFD}'
+            }
+            0x11_0000.. => {
+                self.error("character-reference-outside-unicode-range");
+                '\u{FFFD}'
+            }
+            0xD800..=0xDFFF => {
+                self.error("surrogate-character-reference");
+                '\u{FFFD}'
+            }
+            0x80..=0x9F if C1_REPLACEMENTS[code as usize - 0x80] != '\0' => {
+                self.error("control-character-reference");
+                C1_REPLACEMENTS[code as usize - 0x80]
+            }
+            _ => {
+                if (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE {
+                    self.error("noncharacter-character-reference");
+                } else if code == 0x0D
+                    || ((code < 0x20 || (0x7F..=0x9F).contains(&code))
+                        && !matches!(code, 0x09 | 0x0A | 0x0C))
+                {
+                    self.error("control-character-reference");
+                }
+                char::from_u32(code).unwrap()
+            }
+        };
+        self.temp_buffer.clear();
+        self.temp_buffer.push(c);
+        self.flush_character_reference();
+        self.state = self.return_state;
+    }
+
+    fn markup_declaration_open(&mut self) -> bool {
+        let (dashes, doctype, cdata) = match (
+            self.lookahead("--", false),
+            self.lookahead("DOCTYPE", true),
+            self.lookahead("[CDATA[", false),
+        ) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => return false,
+        };
+        self.comment.clear();
+        if dashes {
+            self.pos += 2;
+            self.state = State::CommentStart;
+        } else if doctype {
+            self.pos += 7;
+            self.state = State::Doctype;
+        } else if cdata && self.cdata_allowed {
+            self.pos += 7;
+            self.state = State::CdataSection;
+        } else if cdata {
+            self.pos += 7;
+            self.error("cdata-in-html-content");
+            self.comment.push_str("[CDATA[");
+            self.state = State::BogusComment;
+        } else {
+            self.error("incorrectly-opened-comment");
+            self.state = State::BogusComment;
+        }
+        true
+    }
+
+    fn after_doctype_name(&mut self) -> bool {
+        let (public, system) = match (
+            self.lookahead("PUBLIC", true),
+            self.lookahead("SYSTEM", true),
+        ) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return false,
+        };
+        if public {
+            self.pos += 6;
+            self.state = State::AfterDoctypePublicKeyword;
+            return true;
+        } else if system {
+            self.pos += 6;
+            self.state = State::AfterDoctypeSystemKeyword;
+            return true;
+        }
+        let c = match self.consume() {
+            Some(c) => c,
+            None => return false,
+        };
+        match c {
+            Char(c) if is_whitespace(c) => {}
+            Char('>') => {
+                self.state = State::Data;
+                self.emit_doctype();
+            }
+            Char(_) => {
+                self.error("invalid-character-sequence-after-doctype-name");
+                self.doctype.force_quirks = true;
+                self.reconsume(State::BogusDoctype);
+            }
+            Eof => self.eof_in_doctype(),
+        }
+        true
+    }
+
+    /// The less-than sign states of RCDATA and RAWTEXT
+    fn text_less_than_sign(&mut self, c: Next, text_state: State) {
+        match c {
+            Char('/') => {
+                self.temp_buffer.clear();
+                self.state = match text_state {
+                    State::Rcdata => State::RcdataEndTagOpen,
+                    _ => State::RawtextEndTagOpen,
+                };
+            }
+            _ => {
+                self.emit_str("<");
+                self.reconsume(text_state);
+            }
+        }
+    }
+
+    /// The end tag open states of RCDATA, RAWTEXT and script data
+    fn text_end_tag_open(&mut self, c: Next, text_state: State) {
+        match c {
+            Char(c) if c.is_ascii_alphabetic() => {
+                self.new_tag(TagKind::End);
+                self.reconsume(match text_state {
+                    State::Rcdata => State::RcdataEndTagName,
+                    State::Rawtext => State::RawtextEndTagName,
+                    State::ScriptData => State::ScriptDataEndTagName,
+                    _ => State::ScriptDataEscapedEndTagName,
+                });
+            }
+            _ => {
+                self.emit_str("</");
+                self.reconsume(text_state);
+            }
+        }
+    }
+
+    /// The end tag name states of RCDATA, RAWTEXT and script data. Only an appropriate end tag
+    /// will end the text, anything else is emitted as characters
+    fn text_end_tag_name(&mut self, c: Next, text_state: State) {
+        match c {
+            Char(c) if is_whitespace(c) && self.is_appropriate_end_tag() => {
+                self.state = State::BeforeAttributeName;
+                return;
+            }
+            Char('/') if self.is_appropriate_end_tag() => {
+                self.state = State::SelfClosingStartTag;
+                return;
+            }
+            Char('>') if self.is_appropriate_end_tag() => {
+                self.state = State::Data;
+                self.emit_tag();
+                return;
+            }
+            Char(c) if c.is_ascii_alphabetic() => {
+                self.tag.name.push(c.to_ascii_lowercase());
+                self.temp_buffer.push(c);
+                return;
+            }
+            _ => {}
+        }
+        self.emit_str("</");
+        let buffer = mem::take(&mut self.temp_buffer);
+        self.emit_str(&buffer);
+        self.reconsume(text_state);
+    }
+
+    /// The script data double escape start and end states, which differ only in the states
+    /// they switch between
+    fn script_data_double_escape(&mut self, c: Next, if_script: State, otherwise: State) {
+        match c {
+            Char(c) if is_whitespace(c) || c == '/' || c == '>' => {
+                self.state = if self.temp_buffer == "script" {
+                    if_script
+                } else {
+                    otherwise
+                };
+                self.emit_char(c);
+            }
+            Char(c) if c.is_ascii_alphabetic() => {
+                self.temp_buffer.push(c.to_ascii_lowercase());
+                self.emit_char(c);
+            }
+            _ => self.reconsume(otherwise),
+        }
+    }
+
+    fn quoted_attribute_value(&mut self, c: Next, quote: char) {
+        match c {
+            Char(c) if c == quote => self.state = State::AfterAttributeValueQuoted,
+            Char('&') => self.begin_character_reference(),
+            Char('\0') => {
+                self.error("unexpected-null-character");
+                self.attribute_value().push('\u{FFFD}');
+            }
+            Char(c) => self.attribute_value().push(c),
+            Eof => {
+                self.error("eof-in-tag");
+                self.emit(Token::Eof);
+            }
+        }
+    }
+
+    fn eof_in_comment(&mut self) {
+        self.error("eof-in-comment");
+        self.emit_comment();
+        self.emit(Token::Eof);
+    }
+
+    fn eof_in_doctype(&mut self) {
+        self.error("eof-in-doctype");
+        self.doctype.force_quirks = true;
+        self.emit_doctype();
+        self.emit(Token::Eof);
+    }
+
+    fn missing_doctype_identifier(&mut self, code: &'static str) {
+        self.error(code);
+        self.doctype.force_quirks = true;
+        self.state = State::Data;
+        self.emit_doctype();
+    }
+
+    fn start_system_identifier(&mut self, quote: char) {
+        self.doctype.system_id = Some(String::new());
+        self.state = if quote == '"' {
+            State::DoctypeSystemIdentifierDoubleQuoted
+        } else {
+            State::DoctypeSystemIdentifierSingleQuoted
+        };
+    }
+
+    /// The quoted public and system identifier states
+    fn doctype_identifier(&mut self, c: Next, quote: char, public: bool) {
+        let (after, abrupt) = if public {
+            (
+                State::AfterDoctypePublicIdentifier,
+                "abrupt-doctype-public-identifier",
+            )
+        } else {
+            (
+                State::AfterDoctypeSystemIdentifier,
+                "abrupt-doctype-system-identifier",
+            )
+        };
+        let push = |d: &mut Doctype, c: char| {
+            let id = if public {
+                &mut d.public_id
+            } else {
+                &mut d.system_id
+            };
+            id.get_or_insert_with(String::new).push(c);
+        };
+        match c {
+            Char(c) if c == quote => self.state = after,
+            Char('\0') => {
+                self.error("unexpected-null-character");
+                push(&mut self.doctype, '\u{FFFD}');
+            }
+            Char('>') => {
+                self.error(abrupt);
+                self.doctype.force_quirks = true;
+                self.state = State::Data;
+                self.emit_doctype();
+            }
+            Char(c) => push(&mut self.doctype, c),
+            Eof => self.eof_in_doctype(),
+        }
+    }
+}
+
+#[cfg(test)]
+fn tokenize(input: &str) -> Vec<Token> {
+    tokenize_with_errors(input).0
+}
+
+/// Tokenize `input`, along with the messages of the parse errors it has
+#[cfg(test)]
+fn tokenize_with_errors(input: &str) -> (Vec<Token>, Vec<&'static str>) {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.feed(input);
+    tokenizer.end();
+    let tokens = std::iter::from_fn(|| tokenizer.next_token().map(|(t, _)| t)).collect();
+    let errors = tokenizer.errors().iter().map(|e| e.message).collect();
+    (tokens, errors)
+}
+
+#[cfg(test)]
+fn start_tag(name: &str, attributes: &[(&str, &str)], self_closing: bool) -> Token {
+    Token::StartTag(Tag {
+        name: name.to_string(),
+        attributes: attributes
+            .iter()
+            .map(|(name, value)| Attribute {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+        self_closing,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_void() {
+    let i = "<br>";
+    assert_eq!(tokenize(i), vec![start_tag("br", &[], false), Token::Eof]);
+    let i = "<br/>";
+    assert_eq!(tokenize(i), vec![start_tag("br", &[], true), Token::Eof]);
+    let i = "<BR />";
+    assert_eq!(tokenize(i), vec![start_tag("br", &[], true), Token::Eof]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_end_tag() {
+    let target = vec![Token::EndTag(Tag::new("elem")), Token::Eof];
+    let i = "</elem>";
+    assert_eq!(tokenize(i), target);
+    let i = "</elem   >";
+    assert_eq!(tokenize_with_errors(i), (target, vec![]));
+    let i = "</elem/>";
+    let end_tag = Tag {
+        self_closing: true,
+        ..Tag::new("elem")
+    };
+    assert_eq!(
+        tokenize_with_errors(i),
+        (
+            vec![Token::EndTag(end_tag), Token::Eof],
+            vec!["end-tag-with-trailing-solidus"]
+        )
+    );
+    // Attributes on end tags are a parse error, but the tag is still emitted
+    let i = "</elem disabled>";
+    let (tokens, errors) = tokenize_with_errors(i);
+    assert_eq!(errors, ["end-tag-with-attributes"]);
+    assert_eq!(
+        tokens,
+        vec![
+            Token::EndTag(Tag {
+                name: "elem".to_string(),
+                attributes: vec![Attribute {
+                    name: "disabled".to_string(),
+                    value: "".to_string()
+                }],
+                ..Default::default()
+            }),
+            Token::Eof
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_attributes() {
+    let i = r#"<a disabled attr=value attr2='value' attr3="multiple values">"#;
+    let target = start_tag(
+        "a",
+        &[
+            ("disabled", ""),
+            ("attr", "value"),
+            ("attr2", "value"),
+            ("attr3", "multiple values"),
+        ],
+        false,
+    );
+    assert_eq!(tokenize_with_errors(i), (vec![target, Token::Eof], vec![]));
+    // Each kind of quote can be used inside the other, and names are lowercased
+    let i = r#"<a B = "x'y" c='x"y' d=x/y>"#;
+    let target = start_tag("a", &[("b", "x'y"), ("c", r#"x"y"#), ("d", "x/y")], false);
+    assert_eq!(tokenize_with_errors(i), (vec![target, Token::Eof], vec![]));
+    // Quotes in unquoted values are kept, but are a parse error
+    let i = r#"<a b=x"y c='1'd e=>"#;
+    let (tokens, errors) = tokenize_with_errors(i);
+    let target = start_tag(
+        "a",
+        &[("b", r#"x"y"#), ("c", "1"), ("d", ""), ("e", "")],
+        false,
+    );
+    assert_eq!(tokens, vec![target, Token::Eof]);
+    assert_eq!(
+        errors,
+        [
+            "unexpected-character-in-unquoted-attribute-value",
+            "missing-whitespace-between-attributes",
+            "missing-attribute-value",
+        ]
+    );
+    // Only the first of duplicated attributes is kept
+    let i = r#"<a x=1 X=2 y x='3'>"#;
+    assert_eq!(
+        tokenize_with_errors(i),
+        (
+            vec![start_tag("a", &[("x", "1"), ("y", "")], false), Token::Eof],
+            vec!["duplicate-attribute", "duplicate-attribute"]
+        )
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_comment_and_doctype() {
+    let i = "<!DOCTYPE html><!-- a -- b -->";
+    assert_eq!(
+        tokenize(i),
+        vec![
+            Token::Doctype(Doctype {
+                name: Some("html".to_string()),
+                ..Default::default()
+            }),
+            Token::Comment(" a -- b ".to_string()),
+            Token::Eof
+        ]
+    );
+    let i = r#"<!doctype HTML PUBLIC "-//W3C//DTD HTML 4.01//EN" 'about:legacy-compat'>"#;
+    assert_eq!(
+        tokenize(i),
+        vec![
+            Token::Doctype(Doctype {
+                name: Some("html".to_string()),
+                public_id: Some("-//W3C//DTD HTML 4.01//EN".to_string()),
+                system_id: Some("about:legacy-compat".to_string()),
+                force_quirks: false,
+            }),
+            Token::Eof
+        ]
+    );
+    let i = "<?xml?>";
+    assert_eq!(
+        tokenize(i),
+        vec![Token::Comment("?xml?".to_string()), Token::Eof]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_raw_text() {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.feed(r#"<script>let one = "</two>";</script>"#);
+    tokenizer.end();
+    assert_eq!(
+        tokenizer.next_token().map(|(t, _)| t),
+        Some(start_tag("script", &[], false))
+    );
+    tokenizer.set_state(State::ScriptData);
+    let text: String = std::iter::from_fn(|| match tokenizer.next_token().map(|(t, _)| t) {
+        Some(Token::Character(c)) => Some(c),
+        _ => None,
+    })
+    .collect();
+    assert_eq!(text, r#"let one = "</two>";"#);
+}
+
+#[cfg(test)]
+#[test]
+fn test_incremental() {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.feed("<di");
+    assert_eq!(tokenizer.next_token().map(|(t, _)| t), None);
+    tokenizer.feed("v class='a'>\r");
+    assert_eq!(
+        tokenizer.next_token().map(|(t, _)| t),
+        Some(start_tag("div", &[("class", "a")], false))
+    );
+    // A carriage return may be followed by a line feed in the next chunk
+    assert_eq!(tokenizer.next_token().map(|(t, _)| t), None);
+    tokenizer.feed("\n<!-");
+    assert_eq!(
+        tokenizer.next_token().map(|(t, _)| t),
+        Some(Token::Character('\n'))
+    );
+    assert_eq!(tokenizer.next_token().map(|(t, _)| t), None);
+    tokenizer.feed("->");
+    tokenizer.end();
+    assert_eq!(
+        tokenizer.next_token().map(|(t, _)| t),
+        Some(Token::Comment("".to_string()))
+    );
+    assert_eq!(tokenizer.next_token().map(|(t, _)| t), Some(Token::Eof));
+    assert_eq!(tokenizer.next_token().map(|(t, _)| t), None);
+}
+
+#[cfg(test)]
+fn text(input: &str) -> String {
+    tokenize(input)
+        .into_iter()
+        .filter_map(|t| match t {
+            Token::Character(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_character_references() {
+    assert_eq!(text("a &amp; b"), "a & b");
+    assert_eq!(text("&nbsp;&lt;&GT;"), "\u{A0}<>");
+    assert_eq!(text("&#x2014;&#8212;&#X41"), "\u{2014}\u{2014}A");
+    assert_eq!(text("&NotEqualTilde;"), "\u{2242}\u{338}");
+    // Legacy references are recognised without a semicolon
+    assert_eq!(text("&copy 2022 &notit;"), "\u{A9} 2022 \u{AC}it;");
+    // Unknown references are left as they are
+    assert_eq!(text("&bogus; & &#; &#xZ"), "&bogus; & &#; &#xZ");
+    assert_eq!(text("&#0;&#x110000;&#xD800;"), "\u{FFFD}\u{FFFD}\u{FFFD}");
+    assert_eq!(text("&#x80;&#150;"), "\u{20AC}\u{2013}");
+    assert_eq!(text("&amp"), "&");
+}
+
+#[cfg(test)]
+#[test]
+fn test_attribute_character_references() {
+    let i = r#"<a href="?a=1&amp;b=2&copy=3" title='&lt;&#x3E;' alt=x&quot;y>"#;
+    assert_eq!(
+        tokenize(i),
+        vec![
+            start_tag(
+                "a",
+                &[
+                    ("href", "?a=1&b=2&copy=3"),
+                    ("title", "<>"),
+                    ("alt", "x\"y")
+                ],
+                false
+            ),
+            Token::Eof
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_incremental_character_reference() {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.feed("&no");
+    assert_eq!(tokenizer.next_token().map(|(t, _)| t), None);
+    tokenizer.feed("tin;");
+    tokenizer.end();
+    assert_eq!(
+        tokenizer.next_token().map(|(t, _)| t),
+        Some(Token::Character('\u{2209}'))
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_offsets() {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.feed("a<b c>&amp;</b><!--d--></x y");
+    tokenizer.end();
+    let offsets: Vec<(Token, usize)> = std::iter::from_fn(|| tokenizer.next_token())
+        .map(|(t, span)| (t, span.start.offset))
+        .collect();
+    assert_eq!(
+        offsets,
+        vec![
+            (Token::Character('a'), 0),
+            (start_tag("b", &[("c", "")], false), 1),
+            (Token::Character('&'), 6),
+            (Token::EndTag(Tag::new("b")), 11),
+            (Token::Comment("d".to_string()), 15),
+            (Token::Eof, 28),
+        ]
+    );
+    let errors: Vec<usize> = tokenizer
+        .errors()
+        .iter()
+        .map(|e| e.position.offset)
+        .collect();
+    // The unfinished end tag is dropped with an `eof-in-tag` error
+    assert_eq!(errors, vec![28]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_cdata() {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.set_cdata_allowed(true);
+    tokenizer.feed("<![CDATA[a<b>]]]]>");
+    tokenizer.end();
+    let text: String = std::iter::from_fn(|| tokenizer.next_token())
+        .filter_map(|(t, _)| match t {
+            Token::Character(c) => Some(c),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(text, "a<b>]]");
+    assert_eq!(
+        tokenize("<![CDATA[x]]>"),
+        vec![Token::Comment("[CDATA[x]]".to_string()), Token::Eof]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_positions() {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.feed("a\r\n<p>\n &amp;</p>");
+    tokenizer.end();
+    let spans: Vec<String> = std::iter::from_fn(|| tokenizer.next_token())
+        .map(|(_, span)| span.to_string())
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            "1:1-1:2",
+            "1:2-2:1",
+            "2:1-2:4",
+            "2:4-3:1",
+            "3:1-3:2",
+            "3:2-3:7",
+            "3:7-3:11",
+            "3:11-3:11"
+        ]
+    );
+}
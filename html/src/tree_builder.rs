@@ -0,0 +1,2591 @@
+//! Implements the HTML tree construction stage
+//! <https://html.spec.whatwg.org/multipage/parsing.html#tree-construction>
+
+use crate::dom::{ElementData, NodeData, NodeId};
+use crate::tokenizer::{Doctype, State, Tag, Token};
+use crate::{
+    DOMAttributes, DOMElement, Document, DocumentType, Namespace, ParseError, QuirksMode,
+    SourceSpan,
+};
+use std::mem;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    InHeadNoscript,
+    AfterHead,
+    InBody,
+    Text,
+    InTable,
+    InTableText,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    InSelect,
+    InSelectInTable,
+    InTemplate,
+    AfterBody,
+    InFrameset,
+    AfterFrameset,
+    AfterAfterBody,
+    AfterAfterFrameset,
+}
+
+/// An entry in the list of active formatting elements
+#[derive(Debug, Clone)]
+enum FormattingEntry {
+    Marker,
+    // The element along with the token it was created for
+    Element(NodeId, Tag),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Scope {
+    Default,
+    ListItem,
+    Button,
+    Table,
+    Select,
+}
+
+static SPECIAL_ELEMENTS: &[&str] = &[
+    "address",
+    "applet",
+    "area",
+    "article",
+    "aside",
+    "base",
+    "basefont",
+    "bgsound",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "caption",
+    "center",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "embed",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "frame",
+    "frameset",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "iframe",
+    "img",
+    "input",
+    "keygen",
+    "li",
+    "link",
+    "listing",
+    "main",
+    "marquee",
+    "menu",
+    "meta",
+    "nav",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "ol",
+    "p",
+    "param",
+    "plaintext",
+    "pre",
+    "script",
+    "search",
+    "section",
+    "select",
+    "source",
+    "style",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "track",
+    "ul",
+    "wbr",
+    "xmp",
+];
+
+static HEADINGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+static FORMATTING_ELEMENTS: &[&str] = &[
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+];
+
+static IMPLIED_END_TAGS: &[&str] = &[
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+static IMPLIED_END_TAGS_THOROUGH: &[&str] = &[
+    "caption", "colgroup", "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+    "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+/// HTML start tags which close any open foreign elements
+static BREAKOUT_ELEMENTS: &[&str] = &[
+    "b",
+    "big",
+    "blockquote",
+    "body",
+    "br",
+    "center",
+    "code",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "embed",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "listing",
+    "menu",
+    "meta",
+    "nobr",
+    "ol",
+    "p",
+    "pre",
+    "ruby",
+    "s",
+    "small",
+    "span",
+    "strong",
+    "strike",
+    "sub",
+    "sup",
+    "table",
+    "tt",
+    "u",
+    "ul",
+    "var",
+];
+
+/// The tokenizer lowercases names, so restore the case of camel-cased SVG element names
+static SVG_TAG_NAMES: &[(&str, &str)] = &[
+    ("altglyph", "altGlyph"),
+    ("altglyphdef", "altGlyphDef"),
+    ("altglyphitem", "altGlyphItem"),
+    ("animatecolor", "animateColor"),
+    ("animatemotion", "animateMotion"),
+    ("animatetransform", "animateTransform"),
+    ("clippath", "clipPath"),
+    ("feblend", "feBlend"),
+    ("fecolormatrix", "feColorMatrix"),
+    ("fecomponenttransfer", "feComponentTransfer"),
+    ("fecomposite", "feComposite"),
+    ("feconvolvematrix", "feConvolveMatrix"),
+    ("fediffuselighting", "feDiffuseLighting"),
+    ("fedisplacementmap", "feDisplacementMap"),
+    ("fedistantlight", "feDistantLight"),
+    ("fedropshadow", "feDropShadow"),
+    ("feflood", "feFlood"),
+    ("fefunca", "feFuncA"),
+    ("fefuncb", "feFuncB"),
+    ("fefuncg", "feFuncG"),
+    ("fefuncr", "feFuncR"),
+    ("fegaussianblur", "feGaussianBlur"),
+    ("feimage", "feImage"),
+    ("femerge", "feMerge"),
+    ("femergenode", "feMergeNode"),
+    ("femorphology", "feMorphology"),
+    ("feoffset", "feOffset"),
+    ("fepointlight", "fePointLight"),
+    ("fespecularlighting", "feSpecularLighting"),
+    ("fespotlight", "feSpotLight"),
+    ("fetile", "feTile"),
+    ("feturbulence", "feTurbulence"),
+    ("foreignobject", "foreignObject"),
+    ("glyphref", "glyphRef"),
+    ("lineargradient", "linearGradient"),
+    ("radialgradient", "radialGradient"),
+    ("textpath", "textPath"),
+];
+
+static SVG_ATTRIBUTES: &[(&str, &str)] = &[
+    ("attributename", "attributeName"),
+    ("attributetype", "attributeType"),
+    ("basefrequency", "baseFrequency"),
+    ("baseprofile", "baseProfile"),
+    ("calcmode", "calcMode"),
+    ("clippathunits", "clipPathUnits"),
+    ("diffuseconstant", "diffuseConstant"),
+    ("edgemode", "edgeMode"),
+    ("filterunits", "filterUnits"),
+    ("glyphref", "glyphRef"),
+    ("gradienttransform", "gradientTransform"),
+    ("gradientunits", "gradientUnits"),
+    ("kernelmatrix", "kernelMatrix"),
+    ("kernelunitlength", "kernelUnitLength"),
+    ("keypoints", "keyPoints"),
+    ("keysplines", "keySplines"),
+    ("keytimes", "keyTimes"),
+    ("lengthadjust", "lengthAdjust"),
+    ("limitingconeangle", "limitingConeAngle"),
+    ("markerheight", "markerHeight"),
+    ("markerunits", "markerUnits"),
+    ("markerwidth", "markerWidth"),
+    ("maskcontentunits", "maskContentUnits"),
+    ("maskunits", "maskUnits"),
+    ("numoctaves", "numOctaves"),
+    ("pathlength", "pathLength"),
+    ("patterncontentunits", "patternContentUnits"),
+    ("patterntransform", "patternTransform"),
+    ("patternunits", "patternUnits"),
+    ("pointsatx", "pointsAtX"),
+    ("pointsaty", "pointsAtY"),
+    ("pointsatz", "pointsAtZ"),
+    ("preservealpha", "preserveAlpha"),
+    ("preserveaspectratio", "preserveAspectRatio"),
+    ("primitiveunits", "primitiveUnits"),
+    ("refx", "refX"),
+    ("refy", "refY"),
+    ("repeatcount", "repeatCount"),
+    ("repeatdur", "repeatDur"),
+    ("requiredextensions", "requiredExtensions"),
+    ("requiredfeatures", "requiredFeatures"),
+    ("specularconstant", "specularConstant"),
+    ("specularexponent", "specularExponent"),
+    ("spreadmethod", "spreadMethod"),
+    ("startoffset", "startOffset"),
+    ("stddeviation", "stdDeviation"),
+    ("stitchtiles", "stitchTiles"),
+    ("surfacescale", "surfaceScale"),
+    ("systemlanguage", "systemLanguage"),
+    ("tablevalues", "tableValues"),
+    ("targetx", "targetX"),
+    ("targety", "targetY"),
+    ("textlength", "textLength"),
+    ("viewbox", "viewBox"),
+    ("viewtarget", "viewTarget"),
+    ("xchannelselector", "xChannelSelector"),
+    ("ychannelselector", "yChannelSelector"),
+    ("zoomandpan", "zoomAndPan"),
+];
+
+static MATHML_ATTRIBUTES: &[(&str, &str)] = &[("definitionurl", "definitionURL")];
+
+fn adjust_case(name: &mut String, table: &[(&str, &str)]) {
+    if let Some((_, adjusted)) = table.iter().find(|(lower, _)| lower == name) {
+        *name = adjusted.to_string();
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
+/// Public identifier prefixes which put a document in quirks mode
+static QUIRKY_PUBLIC_PREFIXES: &[&str] = &[
+    "+//silmaril//dtd html pro v0r11 19970101//",
+    "-//as//dtd html 3.0 aswedit + extensions//",
+    "-//advasoft ltd//dtd html 3.0 aswedit + extensions//",
+    "-//ietf//dtd html 2.0 level 1//",
+    "-//ietf//dtd html 2.0 level 2//",
+    "-//ietf//dtd html 2.0 strict level 1//",
+    "-//ietf//dtd html 2.0 strict level 2//",
+    "-//ietf//dtd html 2.0 strict//",
+    "-//ietf//dtd html 2.0//",
+    "-//ietf//dtd html 2.1e//",
+    "-//ietf//dtd html 3.0//",
+    "-//ietf//dtd html 3.2 final//",
+    "-//ietf//dtd html 3.2//",
+    "-//ietf//dtd html 3//",
+    "-//ietf//dtd html level 0//",
+    "-//ietf//dtd html level 1//",
+    "-//ietf//dtd html level 2//",
+    "-//ietf//dtd html level 3//",
+    "-//ietf//dtd html strict level 0//",
+    "-//ietf//dtd html strict level 1//",
+    "-//ietf//dtd html strict level 2//",
+    "-//ietf//dtd html strict level 3//",
+    "-//ietf//dtd html strict//",
+    "-//ietf//dtd html//",
+    "-//metrius//dtd metrius presentational//",
+    "-//microsoft//dtd internet explorer 2.0 html strict//",
+    "-//microsoft//dtd internet explorer 2.0 html//",
+    "-//microsoft//dtd internet explorer 2.0 tables//",
+    "-//microsoft//dtd internet explorer 3.0 html strict//",
+    "-//microsoft//dtd internet explorer 3.0 html//",
+    "-//microsoft//dtd internet explorer 3.0 tables//",
+    "-//netscape comm. corp.//dtd html//",
+    "-//netscape comm. corp.//dtd strict html//",
+    "-//o'reilly and associates//dtd html 2.0//",
+    "-//o'reilly and associates//dtd html extended 1.0//",
+    "-//o'reilly and associates//dtd html extended relaxed 1.0//",
+    "-//sq//dtd html 2.0 hotmetal + extensions//",
+    "-//softquad software//dtd hotmetal pro 6.0::19990601::extensions to html 4.0//",
+    "-//softquad//dtd hotmetal pro 4.0::19970916::extensions to html 4.0//",
+    "-//spyglass//dtd html 2.0 extended//",
+    "-//sun microsystems corp.//dtd hotjava html//",
+    "-//sun microsystems corp.//dtd hotjava strict html//",
+    "-//w3c//dtd html 3 1995-03-24//",
+    "-//w3c//dtd html 3.2 draft//",
+    "-//w3c//dtd html 3.2 final//",
+    "-//w3c//dtd html 3.2//",
+    "-//w3c//dtd html 3.2s draft//",
+    "-//w3c//dtd html 4.0 frameset//",
+    "-//w3c//dtd html 4.0 transitional//",
+    "-//w3c//dtd html experimental 19960712//",
+    "-//w3c//dtd html experimental 970421//",
+    "-//w3c//dtd w3 html//",
+    "-//w3o//dtd w3 html 3.0//",
+    "-//webtechs//dtd mozilla html 2.0//",
+    "-//webtechs//dtd mozilla html//",
+];
+
+/// Determine the mode a document should be rendered in from its doctype
+/// <https://html.spec.whatwg.org/multipage/parsing.html#the-initial-insertion-mode>
+fn quirks_mode(doctype: &Doctype) -> QuirksMode {
+    let public_id = doctype.public_id.as_deref().map(str::to_ascii_lowercase);
+    let system_id = doctype.system_id.as_deref().map(str::to_ascii_lowercase);
+    let public_starts_with = |prefixes: &[&str]| {
+        public_id
+            .as_deref()
+            .map(|id| prefixes.iter().any(|p| id.starts_with(p)))
+            .unwrap_or(false)
+    };
+    let html_401 = [
+        "-//w3c//dtd html 4.01 frameset//",
+        "-//w3c//dtd html 4.01 transitional//",
+    ];
+    if doctype.force_quirks
+        || doctype.name.as_deref() != Some("html")
+        || matches!(
+            public_id.as_deref(),
+            Some(
+                "-//w3o//dtd w3 html strict 3.0//en//"
+                    | "-/w3c/dtd html 4.0 transitional/en"
+                    | "html"
+            )
+        )
+        || system_id.as_deref()
+            == Some("http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+        || public_starts_with(QUIRKY_PUBLIC_PREFIXES)
+        || (system_id.is_none() && public_starts_with(&html_401))
+    {
+        QuirksMode::Quirks
+    } else if public_starts_with(&[
+        "-//w3c//dtd xhtml 1.0 frameset//",
+        "-//w3c//dtd xhtml 1.0 transitional//",
+    ]) || (system_id.is_some() && public_starts_with(&html_401))
+    {
+        QuirksMode::LimitedQuirks
+    } else {
+        QuirksMode::NoQuirks
+    }
+}
+
+/// Builds a document tree from a stream of [`Token`]s. The tree is built in place, so the
+/// document can be looked at while it is being parsed
+pub(crate) struct TreeBuilder {
+    document: Document,
+    mode: InsertionMode,
+    original_mode: InsertionMode,
+    template_modes: Vec<InsertionMode>,
+    open_elements: Vec<NodeId>,
+    active_formatting: Vec<FormattingEntry>,
+    head: Option<NodeId>,
+    form: Option<NodeId>,
+    frameset_ok: bool,
+    foster_parenting: bool,
+    pending_table_text: String,
+    ignore_lf: bool,
+    // A tokenizer state switch requested by the last processed token
+    tokenizer_state: Option<State>,
+    stopped: bool,
+    // The element a fragment is being parsed in. It is not part of the tree
+    context: Option<NodeId>,
+    // Where the token being processed was parsed from
+    span: SourceSpan,
+    // Elements taken off the stack of open elements by the token being processed
+    closed: Vec<NodeId>,
+}
+
+impl Default for TreeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TreeBuilder {
+    pub fn new() -> Self {
+        Self {
+            document: Document::new(),
+            mode: InsertionMode::Initial,
+            original_mode: InsertionMode::Initial,
+            template_modes: vec![],
+            open_elements: vec![],
+            active_formatting: vec![],
+            head: None,
+            form: None,
+            frameset_ok: true,
+            foster_parenting: false,
+            pending_table_text: String::new(),
+            ignore_lf: false,
+            tokenizer_state: None,
+            stopped: false,
+            context: None,
+            span: SourceSpan::default(),
+            closed: vec![],
+        }
+    }
+
+    /// Create a builder for parsing the contents of `context`, as with `innerHTML`. The
+    /// contents end up in the root element of the finished document.
+    /// <https://html.spec.whatwg.org/multipage/parsing.html#parsing-html-fragments>
+    pub fn for_fragment(context: &DOMElement) -> Self {
+        let mut builder = Self::new();
+        let is_html = |names: &[&str]| {
+            context.namespace == Namespace::Html && names.contains(&context.name.as_str())
+        };
+        builder.tokenizer_state = if is_html(&["title", "textarea"]) {
+            Some(State::Rcdata)
+        } else if is_html(&["style", "xmp", "iframe", "noembed", "noframes"]) {
+            Some(State::Rawtext)
+        } else if is_html(&["script"]) {
+            Some(State::ScriptData)
+        } else if is_html(&["plaintext"]) {
+            Some(State::Plaintext)
+        } else {
+            None
+        };
+        let element = ElementData {
+            name: context.name.clone(),
+            namespace: context.namespace,
+            attributes: context.attributes.clone(),
+        };
+        let context_node = builder.document.create(NodeData::Element(element), None);
+        builder.context = Some(context_node);
+        let root = builder.create_element(&Tag::new("html"), Namespace::Html, None);
+        builder.append(builder.document.document_node(), root);
+        builder.open_elements.push(root);
+        if is_html(&["template"]) {
+            builder.template_modes.push(InsertionMode::InTemplate);
+        }
+        if is_html(&["form"]) {
+            builder.form = Some(context_node);
+        }
+        builder.reset_insertion_mode();
+        builder
+    }
+
+    /// Returns the tokenizer state requested by the last token, if any
+    pub fn take_tokenizer_state(&mut self) -> Option<State> {
+        self.tokenizer_state.take()
+    }
+
+    /// The tree constructed so far
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    /// The finished tree
+    pub fn into_document(self) -> Document {
+        self.document
+    }
+
+    fn error(&mut self, message: &'static str) {
+        debug!(
+            "Tree construction error at {}: {}",
+            self.span.start, message
+        );
+        self.document.errors.push(ParseError {
+            position: self.span.start,
+            message,
+        });
+    }
+
+    /// Process a single token, parsed from `span` of the input, in the current insertion mode
+    pub fn process(&mut self, mut token: Token, span: SourceSpan) {
+        if self.stopped {
+            return;
+        }
+        self.span = span;
+        let end_tag = match &mut token {
+            Token::StartTag(tag) => {
+                tag.span = Some(span);
+                None
+            }
+            Token::EndTag(tag) => Some(tag.name.clone()),
+            _ => None,
+        };
+        let first_new_node = self.document.next_node_id();
+        self.process_token(token);
+        // Elements closed by this token end after it if it was their end tag, and before it if
+        // they were closed implicitly. Those it created, like void elements, span just their tag
+        for id in mem::take(&mut self.closed) {
+            if id >= first_new_node {
+                continue;
+            }
+            let closed_by_end_tag = end_tag
+                .as_ref()
+                .map(|name| self.element(id).name.eq_ignore_ascii_case(name))
+                .unwrap_or(false);
+            let end = if closed_by_end_tag {
+                span.end
+            } else {
+                span.start
+            };
+            if let Some(span) = &mut self.document.node_mut(id).span {
+                span.end = end;
+            }
+        }
+    }
+
+    fn process_token(&mut self, token: Token) {
+        if mem::take(&mut self.ignore_lf) && token == Token::Character('\n') {
+            return;
+        }
+        if self.use_foreign_rules(&token) {
+            self.foreign_content(token);
+        } else {
+            self.process_in(self.mode, token);
+        }
+    }
+
+    /// Whether the current node is an SVG or MathML element, where CDATA sections are allowed
+    pub fn in_foreign_content(&self) -> bool {
+        self.adjusted_current_node()
+            .map(|id| self.namespace(id) != Namespace::Html)
+            .unwrap_or(false)
+    }
+
+    /// Whether a token should be processed by the rules for foreign content rather than those
+    /// of the current insertion mode
+    fn use_foreign_rules(&self, token: &Token) -> bool {
+        let node = match self.adjusted_current_node() {
+            Some(node) => node,
+            None => return false,
+        };
+        let namespace = self.namespace(node);
+        match token {
+            _ if namespace == Namespace::Html => false,
+            Token::Eof => false,
+            Token::StartTag(tag)
+                if self.is_mathml_text_integration_point(node)
+                    && tag.name != "mglyph"
+                    && tag.name != "malignmark" =>
+            {
+                false
+            }
+            Token::Character(_) if self.is_mathml_text_integration_point(node) => false,
+            Token::StartTag(tag)
+                if namespace == Namespace::MathMl
+                    && self.element(node).name == "annotation-xml"
+                    && tag.name == "svg" =>
+            {
+                false
+            }
+            Token::StartTag(_) | Token::Character(_) => !self.is_html_integration_point(node),
+            _ => true,
+        }
+    }
+
+    fn process_in(&mut self, mode: InsertionMode, token: Token) {
+        match mode {
+            InsertionMode::Initial => self.initial(token),
+            InsertionMode::BeforeHtml => self.before_html(token),
+            InsertionMode::BeforeHead => self.before_head(token),
+            InsertionMode::InHead => self.in_head(token),
+            InsertionMode::InHeadNoscript => self.in_head_noscript(token),
+            InsertionMode::AfterHead => self.after_head(token),
+            InsertionMode::InBody => self.in_body(token),
+            InsertionMode::Text => self.text(token),
+            InsertionMode::InTable => self.in_table(token),
+            InsertionMode::InTableText => self.in_table_text(token),
+            InsertionMode::InCaption => self.in_caption(token),
+            InsertionMode::InColumnGroup => self.in_column_group(token),
+            InsertionMode::InTableBody => self.in_table_body(token),
+            InsertionMode::InRow => self.in_row(token),
+            InsertionMode::InCell => self.in_cell(token),
+            InsertionMode::InSelect => self.in_select(token),
+            InsertionMode::InSelectInTable => self.in_select_in_table(token),
+            InsertionMode::InTemplate => self.in_template(token),
+            InsertionMode::AfterBody => self.after_body(token),
+            InsertionMode::InFrameset => self.in_frameset(token),
+            InsertionMode::AfterFrameset => self.after_frameset(token),
+            InsertionMode::AfterAfterBody => self.after_after_body(token),
+            InsertionMode::AfterAfterFrameset => self.after_after_frameset(token),
+        }
+    }
+
+    /// Switch to `mode` and reprocess the token in it
+    fn reprocess(&mut self, mode: InsertionMode, token: Token) {
+        self.mode = mode;
+        self.process_in(mode, token);
+    }
+
+    // ---- Tree manipulation ----
+
+    fn element(&self, id: NodeId) -> &ElementData {
+        match self.document.element(id) {
+            Some(element) => element,
+            None => unreachable!("Node {:?} is not an element", id),
+        }
+    }
+
+    fn namespace(&self, id: NodeId) -> Namespace {
+        match self.document.element(id) {
+            Some(element) => element.namespace,
+            None => Namespace::Html,
+        }
+    }
+
+    /// The name of an HTML element, or an empty string for any other node. Almost all of the
+    /// tree construction rules only apply to HTML elements
+    fn name(&self, id: NodeId) -> &str {
+        match self.document.element(id) {
+            Some(element) if element.namespace == Namespace::Html => element.name.as_str(),
+            _ => "",
+        }
+    }
+
+    fn is_foreign(&self, id: NodeId, namespace: Namespace, names: &[&str]) -> bool {
+        match self.document.element(id) {
+            Some(element) => {
+                element.namespace == namespace && names.contains(&element.name.as_str())
+            }
+            None => false,
+        }
+    }
+
+    fn is_special(&self, id: NodeId) -> bool {
+        self.is(id, SPECIAL_ELEMENTS)
+            || self.is_mathml_text_integration_point(id)
+            || self.is_foreign(id, Namespace::MathMl, &["annotation-xml"])
+            || self.is_foreign(id, Namespace::Svg, &["foreignObject", "desc", "title"])
+    }
+
+    fn is_mathml_text_integration_point(&self, id: NodeId) -> bool {
+        self.is_foreign(id, Namespace::MathMl, &["mi", "mo", "mn", "ms", "mtext"])
+    }
+
+    fn is_html_integration_point(&self, id: NodeId) -> bool {
+        if self.is_foreign(id, Namespace::MathMl, &["annotation-xml"]) {
+            self.element(id)
+                .get_attribute("encoding")
+                .map(|e| {
+                    e.eq_ignore_ascii_case("text/html")
+                        || e.eq_ignore_ascii_case("application/xhtml+xml")
+                })
+                .unwrap_or(false)
+        } else {
+            self.is_foreign(id, Namespace::Svg, &["foreignObject", "desc", "title"])
+        }
+    }
+
+    fn is(&self, id: NodeId, names: &[&str]) -> bool {
+        names.contains(&self.name(id))
+    }
+
+    /// Create an element for `tag`, which isn't in the tree until it's inserted
+    fn create_element(
+        &mut self,
+        tag: &Tag,
+        namespace: Namespace,
+        span: Option<SourceSpan>,
+    ) -> NodeId {
+        let element = ElementData {
+            name: tag.name.clone(),
+            namespace,
+            attributes: DOMAttributes(
+                tag.attributes
+                    .iter()
+                    .map(|a| (a.name.clone(), a.value.clone()))
+                    .collect(),
+            ),
+        };
+        self.document.create(NodeData::Element(element), span)
+    }
+
+    /// Insert `child` into `parent`, before `before` if it is given or at the end otherwise
+    fn insert_at(&mut self, parent: NodeId, before: Option<NodeId>, child: NodeId) {
+        self.document.unlink(child);
+        self.document.link(parent, child, before);
+    }
+
+    fn append(&mut self, parent: NodeId, child: NodeId) {
+        self.insert_at(parent, None, child);
+    }
+
+    fn current_node(&self) -> NodeId {
+        *self
+            .open_elements
+            .last()
+            .expect("Stack of open elements is empty")
+    }
+
+    /// The current node, except when parsing a fragment with only the root element open, where
+    /// it's the context element
+    fn adjusted_current_node(&self) -> Option<NodeId> {
+        match self.context {
+            Some(context) if self.open_elements.len() == 1 => Some(context),
+            _ => self.open_elements.last().copied(),
+        }
+    }
+
+    fn current_is(&self, names: &[&str]) -> bool {
+        self.open_elements
+            .last()
+            .map(|&id| self.is(id, names))
+            .unwrap_or(false)
+    }
+
+    /// Find the appropriate place for inserting a node, as a parent and a node to insert before
+    fn appropriate_place(&self, override_target: Option<NodeId>) -> (NodeId, Option<NodeId>) {
+        let target = override_target.unwrap_or_else(|| self.current_node());
+        if self.foster_parenting && self.is(target, &["table", "tbody", "tfoot", "thead", "tr"]) {
+            let last_template = self
+                .open_elements
+                .iter()
+                .rposition(|&id| self.is(id, &["template"]));
+            let last_table = self
+                .open_elements
+                .iter()
+                .rposition(|&id| self.is(id, &["table"]));
+            match (last_template, last_table) {
+                (Some(template), table) if table.map(|t| template > t).unwrap_or(true) => {
+                    (self.open_elements[template], None)
+                }
+                (_, None) => (self.open_elements[0], None),
+                (_, Some(table)) => {
+                    let table_id = self.open_elements[table];
+                    match self.document.node(table_id).parent() {
+                        Some(parent) => (parent, Some(table_id)),
+                        None => (self.open_elements[table - 1], None),
+                    }
+                }
+            }
+        } else {
+            (target, None)
+        }
+    }
+
+    fn insert_character(&mut self, c: char) {
+        let (parent, before) = self.appropriate_place(None);
+        if let NodeData::Document = self.document.node(parent).data {
+            return;
+        }
+        let previous = match before {
+            Some(b) => self.document.node(b).previous_sibling(),
+            None => self.document.node(parent).last_child(),
+        };
+        if let Some(previous) = previous {
+            let end = self.span.end;
+            let node = self.document.node_mut(previous);
+            if let NodeData::Text(text) = &mut node.data {
+                text.push(c);
+                if let Some(span) = &mut node.span {
+                    span.end = end;
+                }
+                return;
+            }
+        }
+        let text = self
+            .document
+            .create(NodeData::Text(c.to_string()), Some(self.span));
+        self.insert_at(parent, before, text);
+    }
+
+    fn insert_comment(&mut self, comment: String, parent: Option<NodeId>) {
+        let (parent, before) = match parent {
+            Some(parent) => (parent, None),
+            None => self.appropriate_place(None),
+        };
+        let node = self.document.create(NodeData::Comment(comment), None);
+        self.insert_at(parent, before, node);
+    }
+
+    /// Create an element for a token and insert it at the appropriate place, pushing it onto
+    /// the stack of open elements
+    fn insert_element(&mut self, tag: Tag) -> NodeId {
+        self.insert_element_in(tag, Namespace::Html)
+    }
+
+    fn insert_element_in(&mut self, tag: Tag, namespace: Namespace) -> NodeId {
+        let (parent, before) = self.appropriate_place(None);
+        // Elements implied by other tags start where those tags do
+        let span = tag.span.unwrap_or(SourceSpan {
+            start: self.span.start,
+            end: self.span.start,
+        });
+        let node = self.create_element(&tag, namespace, Some(span));
+        self.insert_at(parent, before, node);
+        self.open_elements.push(node);
+        node
+    }
+
+    /// Insert an SVG or MathML element, fixing the case of its name and attributes
+    fn insert_foreign_element(&mut self, mut tag: Tag, namespace: Namespace) {
+        match namespace {
+            Namespace::Svg => {
+                adjust_case(&mut tag.name, SVG_TAG_NAMES);
+                tag.attributes
+                    .iter_mut()
+                    .for_each(|a| adjust_case(&mut a.name, SVG_ATTRIBUTES));
+            }
+            Namespace::MathMl => tag
+                .attributes
+                .iter_mut()
+                .for_each(|a| adjust_case(&mut a.name, MATHML_ATTRIBUTES)),
+            Namespace::Html => {}
+        }
+        let self_closing = tag.self_closing;
+        self.insert_element_in(tag, namespace);
+        if self_closing {
+            self.pop_element();
+        }
+    }
+
+    /// Insert an element which is immediately popped from the stack of open elements
+    fn insert_void_element(&mut self, tag: Tag) {
+        self.insert_element(tag);
+        self.pop_element();
+    }
+
+    fn pop_until(&mut self, names: &[&str]) {
+        while let Some(id) = self.pop_element() {
+            if self.is(id, names) {
+                break;
+            }
+        }
+    }
+
+    fn pop_until_node(&mut self, node: NodeId) {
+        while let Some(id) = self.pop_element() {
+            if id == node {
+                break;
+            }
+        }
+    }
+
+    fn remove_from_stack(&mut self, node: NodeId) {
+        if let Some(index) = self.open_elements.iter().position(|&id| id == node) {
+            self.open_elements.remove(index);
+            self.closed.push(node);
+        }
+    }
+
+    /// Pop the current node from the stack of open elements
+    fn pop_element(&mut self) -> Option<NodeId> {
+        let id = self.open_elements.pop();
+        self.closed.extend(id);
+        id
+    }
+
+    /// Pop every element above the first `len` of the stack of open elements
+    fn truncate_stack(&mut self, len: usize) {
+        self.closed.extend(
+            self.open_elements
+                .drain(len.min(self.open_elements.len())..),
+        );
+    }
+
+    fn stack_contains(&self, name: &str) -> bool {
+        self.open_elements.iter().any(|&id| self.name(id) == name)
+    }
+
+    // ---- Scopes and implied end tags ----
+
+    fn is_scope_boundary(&self, id: NodeId, scope: Scope) -> bool {
+        let default = [
+            "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+        ];
+        let default = self.is(id, &default)
+            || self.is_mathml_text_integration_point(id)
+            || self.is_foreign(id, Namespace::MathMl, &["annotation-xml"])
+            || self.is_foreign(id, Namespace::Svg, &["foreignObject", "desc", "title"]);
+        match scope {
+            Scope::Default => default,
+            Scope::ListItem => default || self.is(id, &["ol", "ul"]),
+            Scope::Button => default || self.is(id, &["button"]),
+            Scope::Table => self.is(id, &["html", "table", "template"]),
+            Scope::Select => !self.is(id, &["optgroup", "option"]),
+        }
+    }
+
+    /// Check whether any element with one of `names` is in the given scope
+    fn in_scope(&self, names: &[&str], scope: Scope) -> bool {
+        for &id in self.open_elements.iter().rev() {
+            if self.is(id, names) {
+                return true;
+            }
+            if self.is_scope_boundary(id, scope) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn node_in_scope(&self, node: NodeId, scope: Scope) -> bool {
+        for &id in self.open_elements.iter().rev() {
+            if id == node {
+                return true;
+            }
+            if self.is_scope_boundary(id, scope) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn generate_implied_end_tags(&mut self, except: Option<&str>) {
+        while self.current_is(IMPLIED_END_TAGS) && Some(self.name(self.current_node())) != except {
+            self.pop_element();
+        }
+    }
+
+    fn generate_implied_end_tags_thoroughly(&mut self) {
+        while self.current_is(IMPLIED_END_TAGS_THOROUGH) {
+            self.pop_element();
+        }
+    }
+
+    fn close_p_element(&mut self) {
+        self.generate_implied_end_tags(Some("p"));
+        if !self.current_is(&["p"]) {
+            self.error("Unclosed elements inside <p>");
+        }
+        self.pop_until(&["p"]);
+    }
+
+    fn close_p_if_in_button_scope(&mut self) {
+        if self.in_scope(&["p"], Scope::Button) {
+            self.close_p_element();
+        }
+    }
+
+    // ---- Active formatting elements ----
+
+    fn formatting_position(&self, node: NodeId) -> Option<usize> {
+        self.active_formatting
+            .iter()
+            .position(|e| matches!(e, FormattingEntry::Element(id, _) if *id == node))
+    }
+
+    /// Add `node`, which was created for `tag`, to the list of active formatting elements
+    fn push_formatting_element(&mut self, node: NodeId, tag: Tag) {
+        // The "Noah's Ark" clause: at most three identical entries after the last marker
+        let mut matching = self
+            .active_formatting
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(_, e)| !matches!(e, FormattingEntry::Marker))
+            .filter(|(_, e)| match e {
+                FormattingEntry::Element(_, t) => {
+                    t.name == tag.name
+                        && t.attributes.len() == tag.attributes.len()
+                        && t.attributes.iter().all(|a| tag.attributes.contains(a))
+                }
+                FormattingEntry::Marker => false,
+            })
+            .map(|(i, _)| i);
+        if let (Some(_), Some(_), Some(earliest)) =
+            (matching.next(), matching.next(), matching.next())
+        {
+            self.active_formatting.remove(earliest);
+        }
+        self.active_formatting
+            .push(FormattingEntry::Element(node, tag));
+    }
+
+    /// The tag of the element at `pos` in the list of active formatting elements
+    fn formatting_tag(&self, pos: usize) -> Tag {
+        match &self.active_formatting[pos] {
+            FormattingEntry::Element(_, tag) => tag.clone(),
+            FormattingEntry::Marker => unreachable!("Entry {} is a marker", pos),
+        }
+    }
+
+    fn clear_formatting_to_last_marker(&mut self) {
+        while let Some(entry) = self.active_formatting.pop() {
+            if let FormattingEntry::Marker = entry {
+                break;
+            }
+        }
+    }
+
+    /// Find the last element with the given name in the list of active formatting elements,
+    /// after the last marker
+    fn formatting_element_named(&self, name: &str) -> Option<NodeId> {
+        self.active_formatting
+            .iter()
+            .rev()
+            .take_while(|e| !matches!(e, FormattingEntry::Marker))
+            .find_map(|e| match e {
+                FormattingEntry::Element(id, tag) if tag.name == name => Some(*id),
+                _ => None,
+            })
+    }
+
+    fn reconstruct_active_formatting_elements(&mut self) {
+        let is_open = |b: &Self, e: &FormattingEntry| match e {
+            FormattingEntry::Marker => true,
+            FormattingEntry::Element(id, _) => b.open_elements.contains(id),
+        };
+        let last = match self.active_formatting.last() {
+            Some(last) => last,
+            None => return,
+        };
+        if is_open(self, last) {
+            return;
+        }
+        let mut i = self.active_formatting.len() - 1;
+        while i > 0 && !is_open(self, &self.active_formatting[i - 1]) {
+            i -= 1;
+        }
+        for j in i..self.active_formatting.len() {
+            let tag = match &self.active_formatting[j] {
+                FormattingEntry::Element(_, tag) => tag.clone(),
+                FormattingEntry::Marker => unreachable!(),
+            };
+            let node = self.insert_element(tag.clone());
+            self.active_formatting[j] = FormattingEntry::Element(node, tag);
+        }
+    }
+
+    /// The adoption agency algorithm. Returns `false` if the token should instead be handled
+    /// like any other end tag
+    fn adoption_agency(&mut self, subject: &str) -> bool {
+        let current = self.current_node();
+        if self.name(current) == subject && self.formatting_position(current).is_none() {
+            self.pop_element();
+            return true;
+        }
+        for _ in 0..8 {
+            let formatting_element = match self.formatting_element_named(subject) {
+                Some(id) => id,
+                None => return false,
+            };
+            let fe_stack_index = match self
+                .open_elements
+                .iter()
+                .position(|&id| id == formatting_element)
+            {
+                Some(i) => i,
+                None => {
+                    self.error("Formatting element not open");
+                    let pos = self.formatting_position(formatting_element).unwrap();
+                    self.active_formatting.remove(pos);
+                    return true;
+                }
+            };
+            if !self.node_in_scope(formatting_element, Scope::Default) {
+                self.error("Formatting element not in scope");
+                return true;
+            }
+            if formatting_element != self.current_node() {
+                self.error("Formatting element is not the current node");
+            }
+            let furthest_block = self.open_elements[fe_stack_index + 1..]
+                .iter()
+                .copied()
+                .find(|&id| self.is_special(id));
+            let furthest_block = match furthest_block {
+                Some(id) => id,
+                None => {
+                    self.pop_until_node(formatting_element);
+                    let pos = self.formatting_position(formatting_element).unwrap();
+                    self.active_formatting.remove(pos);
+                    return true;
+                }
+            };
+            let common_ancestor = self.open_elements[fe_stack_index - 1];
+            let mut bookmark = self.formatting_position(formatting_element).unwrap();
+            let mut node_index = self
+                .open_elements
+                .iter()
+                .position(|&id| id == furthest_block)
+                .unwrap();
+            let mut last_node = furthest_block;
+            let mut inner = 0;
+            loop {
+                inner += 1;
+                node_index -= 1;
+                let node = self.open_elements[node_index];
+                if node == formatting_element {
+                    break;
+                }
+                let mut formatting_pos = self.formatting_position(node);
+                if let (true, Some(pos)) = (inner > 3, formatting_pos) {
+                    self.active_formatting.remove(pos);
+                    if pos < bookmark {
+                        bookmark -= 1;
+                    }
+                    formatting_pos = None;
+                }
+                let pos = match formatting_pos {
+                    Some(pos) => pos,
+                    None => {
+                        self.open_elements.remove(node_index);
+                        self.closed.push(node);
+                        continue;
+                    }
+                };
+                let tag = self.formatting_tag(pos);
+                let new_node = self.create_element(&tag, Namespace::Html, None);
+                self.active_formatting[pos] = FormattingEntry::Element(new_node, tag);
+                self.open_elements[node_index] = new_node;
+                self.closed.push(node);
+                if last_node == furthest_block {
+                    bookmark = pos + 1;
+                }
+                self.append(new_node, last_node);
+                last_node = new_node;
+            }
+            let (parent, before) = self.appropriate_place(Some(common_ancestor));
+            self.insert_at(parent, before, last_node);
+            let tag = self.formatting_tag(self.formatting_position(formatting_element).unwrap());
+            let new_element = self.create_element(&tag, Namespace::Html, None);
+            let children: Vec<NodeId> = self.document.children(furthest_block).collect();
+            for child in children {
+                self.append(new_element, child);
+            }
+            self.append(furthest_block, new_element);
+            let pos = self.formatting_position(formatting_element).unwrap();
+            self.active_formatting.remove(pos);
+            if pos < bookmark {
+                bookmark -= 1;
+            }
+            self.active_formatting
+                .insert(bookmark, FormattingEntry::Element(new_element, tag));
+            self.remove_from_stack(formatting_element);
+            let fb_index = self
+                .open_elements
+                .iter()
+                .position(|&id| id == furthest_block)
+                .unwrap();
+            self.open_elements.insert(fb_index + 1, new_element);
+        }
+        true
+    }
+
+    fn reset_insertion_mode(&mut self) {
+        for (i, &node) in self.open_elements.iter().enumerate().rev() {
+            let last = i == 0;
+            // Fragments are parsed as if they are inside the context element
+            let node = if last {
+                self.context.unwrap_or(node)
+            } else {
+                node
+            };
+            self.mode = match self.name(node) {
+                "select" => {
+                    let in_table = !last
+                        && self.open_elements[..i]
+                            .iter()
+                            .rev()
+                            .take_while(|&&id| !self.is(id, &["template"]))
+                            .any(|&id| self.is(id, &["table"]));
+                    if in_table {
+                        InsertionMode::InSelectInTable
+                    } else {
+                        InsertionMode::InSelect
+                    }
+                }
+                "td" | "th" if !last => InsertionMode::InCell,
+                "tr" => InsertionMode::InRow,
+                "tbody" | "thead" | "tfoot" => InsertionMode::InTableBody,
+                "caption" => InsertionMode::InCaption,
+                "colgroup" => InsertionMode::InColumnGroup,
+                "table" => InsertionMode::InTable,
+                "template" => *self.template_modes.last().unwrap(),
+                "head" if !last => InsertionMode::InHead,
+                "body" => InsertionMode::InBody,
+                "frameset" => InsertionMode::InFrameset,
+                "html" if self.head.is_none() => InsertionMode::BeforeHead,
+                "html" => InsertionMode::AfterHead,
+                _ if last => InsertionMode::InBody,
+                _ => continue,
+            };
+            return;
+        }
+    }
+
+    /// Parse the contents of an element as RAWTEXT or RCDATA
+    fn generic_text_element(&mut self, tag: Tag, state: State) {
+        self.insert_element(tag);
+        self.tokenizer_state = Some(state);
+        self.original_mode = self.mode;
+        self.mode = InsertionMode::Text;
+    }
+
+    fn stop_parsing(&mut self) {
+        self.truncate_stack(0);
+        self.stopped = true;
+    }
+
+    // ---- Insertion modes ----
+
+    fn initial(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(comment) => {
+                self.insert_comment(comment, Some(self.document.document_node()))
+            }
+            Token::Doctype(doctype) => {
+                if doctype.name.as_deref() != Some("html")
+                    || doctype.public_id.is_some()
+                    || doctype
+                        .system_id
+                        .as_ref()
+                        .map(|s| s != "about:legacy-compat")
+                        .unwrap_or(false)
+                {
+                    self.error("Bad doctype");
+                }
+                self.document.quirks_mode = quirks_mode(&doctype);
+                let doctype = DocumentType {
+                    name: doctype.name.unwrap_or_default(),
+                    public_id: doctype.public_id,
+                    system_id: doctype.system_id,
+                };
+                let node = self.document.create(NodeData::Doctype(doctype), None);
+                self.append(self.document.document_node(), node);
+                self.mode = InsertionMode::BeforeHtml;
+            }
+            token => {
+                self.error("Missing doctype");
+                self.document.quirks_mode = QuirksMode::Quirks;
+                self.reprocess(InsertionMode::BeforeHtml, token);
+            }
+        }
+    }
+
+    fn before_html(&mut self, token: Token) {
+        match token {
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::Comment(comment) => {
+                self.insert_comment(comment, Some(self.document.document_node()))
+            }
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::StartTag(tag) if tag.name == "html" => {
+                let node = self.create_element(&tag, Namespace::Html, None);
+                self.append(self.document.document_node(), node);
+                self.open_elements.push(node);
+                self.mode = InsertionMode::BeforeHead;
+            }
+            Token::EndTag(tag) if !["head", "body", "html", "br"].contains(&tag.name.as_str()) => {
+                self.error("Unexpected end tag")
+            }
+            token => {
+                let node = self.create_element(&Tag::new("html"), Namespace::Html, None);
+                self.append(self.document.document_node(), node);
+                self.open_elements.push(node);
+                self.reprocess(InsertionMode::BeforeHead, token);
+            }
+        }
+    }
+
+    fn before_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "head" => {
+                self.head = Some(self.insert_element(tag));
+                self.mode = InsertionMode::InHead;
+            }
+            Token::EndTag(tag) if !["head", "body", "html", "br"].contains(&tag.name.as_str()) => {
+                self.error("Unexpected end tag")
+            }
+            token => {
+                self.head = Some(self.insert_element(Tag::new("head")));
+                self.reprocess(InsertionMode::InHead, token);
+            }
+        }
+    }
+
+    fn in_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag)
+                if ["base", "basefont", "bgsound", "link", "meta"].contains(&tag.name.as_str()) =>
+            {
+                self.insert_void_element(tag)
+            }
+            Token::StartTag(tag) if tag.name == "title" => {
+                self.generic_text_element(tag, State::Rcdata)
+            }
+            Token::StartTag(tag) if ["noframes", "style"].contains(&tag.name.as_str()) => {
+                self.generic_text_element(tag, State::Rawtext)
+            }
+            Token::StartTag(tag) if tag.name == "noscript" => {
+                self.insert_element(tag);
+                self.mode = InsertionMode::InHeadNoscript;
+            }
+            Token::StartTag(tag) if tag.name == "script" => {
+                self.generic_text_element(tag, State::ScriptData)
+            }
+            Token::EndTag(tag) if tag.name == "head" => {
+                self.pop_element();
+                self.mode = InsertionMode::AfterHead;
+            }
+            Token::StartTag(tag) if tag.name == "template" => {
+                self.insert_element(tag);
+                self.active_formatting.push(FormattingEntry::Marker);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InTemplate;
+                self.template_modes.push(InsertionMode::InTemplate);
+            }
+            Token::EndTag(tag) if tag.name == "template" => {
+                if !self.stack_contains("template") {
+                    self.error("Unexpected </template>");
+                    return;
+                }
+                self.generate_implied_end_tags_thoroughly();
+                if !self.current_is(&["template"]) {
+                    self.error("Unclosed elements inside <template>");
+                }
+                self.pop_until(&["template"]);
+                self.clear_formatting_to_last_marker();
+                self.template_modes.pop();
+                self.reset_insertion_mode();
+            }
+            Token::StartTag(tag) if tag.name == "head" => self.error("Unexpected <head>"),
+            Token::EndTag(tag) if !["body", "html", "br"].contains(&tag.name.as_str()) => {
+                self.error("Unexpected end tag")
+            }
+            token => {
+                self.pop_element();
+                self.reprocess(InsertionMode::AfterHead, token);
+            }
+        }
+    }
+
+    fn in_head_noscript(&mut self, token: Token) {
+        match token {
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::EndTag(tag) if tag.name == "noscript" => {
+                self.pop_element();
+                self.mode = InsertionMode::InHead;
+            }
+            Token::Character(c) if is_whitespace(c) => self.in_head(token),
+            Token::Comment(_) => self.in_head(token),
+            Token::StartTag(ref tag)
+                if ["basefont", "bgsound", "link", "meta", "noframes", "style"]
+                    .contains(&tag.name.as_str()) =>
+            {
+                self.in_head(token)
+            }
+            Token::StartTag(tag) if ["head", "noscript"].contains(&tag.name.as_str()) => {
+                self.error("Unexpected start tag")
+            }
+            Token::EndTag(tag) if tag.name != "br" => self.error("Unexpected end tag"),
+            token => {
+                self.error("Unexpected token in <noscript>");
+                self.pop_element();
+                self.reprocess(InsertionMode::InHead, token);
+            }
+        }
+    }
+
+    fn after_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "body" => {
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InBody;
+            }
+            Token::StartTag(tag) if tag.name == "frameset" => {
+                self.insert_element(tag);
+                self.mode = InsertionMode::InFrameset;
+            }
+            Token::StartTag(ref tag)
+                if [
+                    "base", "basefont", "bgsound", "link", "meta", "noframes", "script", "style",
+                    "template", "title",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected start tag after <head>");
+                let head = self.head.expect("No <head> element");
+                self.open_elements.push(head);
+                self.in_head(token);
+                self.remove_from_stack(head);
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::StartTag(tag) if tag.name == "head" => self.error("Unexpected <head>"),
+            Token::EndTag(tag) if !["body", "html", "br"].contains(&tag.name.as_str()) => {
+                self.error("Unexpected end tag")
+            }
+            token => {
+                self.insert_element(Tag::new("body"));
+                self.reprocess(InsertionMode::InBody, token);
+            }
+        }
+    }
+
+    fn in_body(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => self.error("Unexpected null character"),
+            Token::Character(c) => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_character(c);
+                if !is_whitespace(c) {
+                    self.frameset_ok = false;
+                }
+            }
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) => self.in_body_start_tag(tag),
+            Token::EndTag(tag) => self.in_body_end_tag(tag),
+            Token::Eof => {
+                if !self.template_modes.is_empty() {
+                    self.in_template(Token::Eof);
+                } else {
+                    self.stop_parsing();
+                }
+            }
+        }
+    }
+
+    fn in_body_start_tag(&mut self, mut tag: Tag) {
+        match tag.name.as_str() {
+            "html" => {
+                self.error("Unexpected <html>");
+                if !self.stack_contains("template") {
+                    self.merge_attributes(self.open_elements[0], tag);
+                }
+            }
+            "base" | "basefont" | "bgsound" | "link" | "meta" | "noframes" | "script" | "style"
+            | "template" | "title" => self.in_head(Token::StartTag(tag)),
+            "body" => {
+                self.error("Unexpected <body>");
+                if self.open_elements.len() > 1
+                    && self.is(self.open_elements[1], &["body"])
+                    && !self.stack_contains("template")
+                {
+                    self.frameset_ok = false;
+                    self.merge_attributes(self.open_elements[1], tag);
+                }
+            }
+            "frameset" => {
+                self.error("Unexpected <frameset>");
+                if self.open_elements.len() > 1
+                    && self.is(self.open_elements[1], &["body"])
+                    && self.frameset_ok
+                {
+                    self.document.unlink(self.open_elements[1]);
+                    self.truncate_stack(1);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InFrameset;
+                }
+            }
+            "address" | "article" | "aside" | "blockquote" | "center" | "details" | "dialog"
+            | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure" | "footer" | "header"
+            | "hgroup" | "main" | "menu" | "nav" | "ol" | "p" | "search" | "section"
+            | "summary" | "ul" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(tag);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.close_p_if_in_button_scope();
+                if self.current_is(HEADINGS) {
+                    self.error("Nested heading");
+                    self.pop_element();
+                }
+                self.insert_element(tag);
+            }
+            "pre" | "listing" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(tag);
+                self.ignore_lf = true;
+                self.frameset_ok = false;
+            }
+            "form" => {
+                let in_template = self.stack_contains("template");
+                if self.form.is_some() && !in_template {
+                    self.error("Nested <form>");
+                    return;
+                }
+                self.close_p_if_in_button_scope();
+                let node = self.insert_element(tag);
+                if !in_template {
+                    self.form = Some(node);
+                }
+            }
+            "li" | "dd" | "dt" => {
+                self.frameset_ok = false;
+                let closes: &[&str] = if tag.name == "li" {
+                    &["li"]
+                } else {
+                    &["dd", "dt"]
+                };
+                for i in (0..self.open_elements.len()).rev() {
+                    let node = self.open_elements[i];
+                    if self.is(node, closes) {
+                        let name = self.name(node).to_string();
+                        self.generate_implied_end_tags(Some(&name));
+                        if !self.current_is(&[name.as_str()]) {
+                            self.error("Unclosed elements inside list item");
+                        }
+                        self.pop_until(&[name.as_str()]);
+                        break;
+                    }
+                    if self.is_special(node) && !self.is(node, &["address", "div", "p"]) {
+                        break;
+                    }
+                }
+                self.close_p_if_in_button_scope();
+                self.insert_element(tag);
+            }
+            "plaintext" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(tag);
+                self.tokenizer_state = Some(State::Plaintext);
+            }
+            "button" => {
+                if self.in_scope(&["button"], Scope::Default) {
+                    self.error("Nested <button>");
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&["button"]);
+                }
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(tag);
+                self.frameset_ok = false;
+            }
+            "a" => {
+                if let Some(a) = self.formatting_element_named("a") {
+                    self.error("Nested <a>");
+                    self.adoption_agency("a");
+                    if let Some(pos) = self.formatting_position(a) {
+                        self.active_formatting.remove(pos);
+                    }
+                    self.remove_from_stack(a);
+                }
+                self.reconstruct_active_formatting_elements();
+                let node = self.insert_element(tag.clone());
+                self.push_formatting_element(node, tag);
+            }
+            "b" | "big" | "code" | "em" | "font" | "i" | "s" | "small" | "strike" | "strong"
+            | "tt" | "u" => {
+                self.reconstruct_active_formatting_elements();
+                let node = self.insert_element(tag.clone());
+                self.push_formatting_element(node, tag);
+            }
+            "nobr" => {
+                self.reconstruct_active_formatting_elements();
+                if self.in_scope(&["nobr"], Scope::Default) {
+                    self.error("Nested <nobr>");
+                    self.adoption_agency("nobr");
+                    self.reconstruct_active_formatting_elements();
+                }
+                let node = self.insert_element(tag.clone());
+                self.push_formatting_element(node, tag);
+            }
+            "applet" | "marquee" | "object" => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(tag);
+                self.active_formatting.push(FormattingEntry::Marker);
+                self.frameset_ok = false;
+            }
+            "table" => {
+                if self.document.quirks_mode != QuirksMode::Quirks {
+                    self.close_p_if_in_button_scope();
+                }
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InTable;
+            }
+            "area" | "br" | "embed" | "img" | "keygen" | "wbr" => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_void_element(tag);
+                self.frameset_ok = false;
+            }
+            "input" => {
+                self.reconstruct_active_formatting_elements();
+                let hidden = tag
+                    .get_attribute("type")
+                    .map(|t| t.eq_ignore_ascii_case("hidden"))
+                    .unwrap_or(false);
+                self.insert_void_element(tag);
+                if !hidden {
+                    self.frameset_ok = false;
+                }
+            }
+            "param" | "source" | "track" => self.insert_void_element(tag),
+            "hr" => {
+                self.close_p_if_in_button_scope();
+                self.insert_void_element(tag);
+                self.frameset_ok = false;
+            }
+            "image" => {
+                self.error("<image> should be <img>");
+                tag.name = "img".to_string();
+                self.in_body_start_tag(tag);
+            }
+            "textarea" => {
+                self.insert_element(tag);
+                self.ignore_lf = true;
+                self.tokenizer_state = Some(State::Rcdata);
+                self.original_mode = self.mode;
+                self.frameset_ok = false;
+                self.mode = InsertionMode::Text;
+            }
+            "xmp" => {
+                self.close_p_if_in_button_scope();
+                self.reconstruct_active_formatting_elements();
+                self.frameset_ok = false;
+                self.generic_text_element(tag, State::Rawtext);
+            }
+            "iframe" => {
+                self.frameset_ok = false;
+                self.generic_text_element(tag, State::Rawtext);
+            }
+            "noembed" => self.generic_text_element(tag, State::Rawtext),
+            "select" => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = match self.mode {
+                    InsertionMode::InTable
+                    | InsertionMode::InCaption
+                    | InsertionMode::InTableBody
+                    | InsertionMode::InRow
+                    | InsertionMode::InCell => InsertionMode::InSelectInTable,
+                    _ => InsertionMode::InSelect,
+                };
+            }
+            "optgroup" | "option" => {
+                if self.current_is(&["option"]) {
+                    self.pop_element();
+                }
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(tag);
+            }
+            "rb" | "rtc" => {
+                if self.in_scope(&["ruby"], Scope::Default) {
+                    self.generate_implied_end_tags(None);
+                    if !self.current_is(&["ruby"]) {
+                        self.error("Unclosed elements inside <ruby>");
+                    }
+                }
+                self.insert_element(tag);
+            }
+            "rp" | "rt" => {
+                if self.in_scope(&["ruby"], Scope::Default) {
+                    self.generate_implied_end_tags(Some("rtc"));
+                    if !self.current_is(&["ruby", "rtc"]) {
+                        self.error("Unclosed elements inside <ruby>");
+                    }
+                }
+                self.insert_element(tag);
+            }
+            "math" => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_foreign_element(tag, Namespace::MathMl);
+            }
+            "svg" => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_foreign_element(tag, Namespace::Svg);
+            }
+            "caption" | "col" | "colgroup" | "frame" | "head" | "tbody" | "td" | "tfoot" | "th"
+            | "thead" | "tr" => self.error("Unexpected start tag"),
+            _ => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(tag);
+            }
+        }
+    }
+
+    fn merge_attributes(&mut self, node: NodeId, tag: Tag) {
+        if let NodeData::Element(existing) = &mut self.document.node_mut(node).data {
+            for attr in tag.attributes {
+                existing.attributes.0.entry(attr.name).or_insert(attr.value);
+            }
+        }
+    }
+
+    fn in_body_end_tag(&mut self, tag: Tag) {
+        match tag.name.as_str() {
+            "template" => self.in_head(Token::EndTag(tag)),
+            "body" | "html" => {
+                if !self.in_scope(&["body"], Scope::Default) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.mode = InsertionMode::AfterBody;
+                if tag.name == "html" {
+                    self.after_body(Token::EndTag(tag));
+                }
+            }
+            "address" | "article" | "aside" | "blockquote" | "button" | "center" | "details"
+            | "dialog" | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure" | "footer"
+            | "header" | "hgroup" | "listing" | "main" | "menu" | "nav" | "ol" | "pre"
+            | "search" | "section" | "summary" | "ul" => {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Default) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.generate_implied_end_tags(None);
+                if !self.current_is(&[tag.name.as_str()]) {
+                    self.error("Unclosed elements");
+                }
+                self.pop_until(&[tag.name.as_str()]);
+            }
+            "form" => {
+                if !self.stack_contains("template") {
+                    let node = self.form.take();
+                    match node {
+                        Some(node) if self.node_in_scope(node, Scope::Default) => {
+                            self.generate_implied_end_tags(None);
+                            if self.current_node() != node {
+                                self.error("Unclosed elements inside <form>");
+                            }
+                            self.remove_from_stack(node);
+                        }
+                        _ => self.error("Unexpected </form>"),
+                    }
+                } else {
+                    if !self.in_scope(&["form"], Scope::Default) {
+                        self.error("Unexpected </form>");
+                        return;
+                    }
+                    self.generate_implied_end_tags(None);
+                    if !self.current_is(&["form"]) {
+                        self.error("Unclosed elements inside <form>");
+                    }
+                    self.pop_until(&["form"]);
+                }
+            }
+            "p" => {
+                if !self.in_scope(&["p"], Scope::Button) {
+                    self.error("Unexpected </p>");
+                    self.insert_element(Tag::new("p"));
+                }
+                self.close_p_element();
+            }
+            "li" => {
+                if !self.in_scope(&["li"], Scope::ListItem) {
+                    self.error("Unexpected </li>");
+                    return;
+                }
+                self.generate_implied_end_tags(Some("li"));
+                if !self.current_is(&["li"]) {
+                    self.error("Unclosed elements inside <li>");
+                }
+                self.pop_until(&["li"]);
+            }
+            "dd" | "dt" => {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Default) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.generate_implied_end_tags(Some(&tag.name));
+                if !self.current_is(&[tag.name.as_str()]) {
+                    self.error("Unclosed elements");
+                }
+                self.pop_until(&[tag.name.as_str()]);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                if !self.in_scope(HEADINGS, Scope::Default) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.generate_implied_end_tags(None);
+                if !self.current_is(&[tag.name.as_str()]) {
+                    self.error("Unclosed elements inside heading");
+                }
+                self.pop_until(HEADINGS);
+            }
+            name if FORMATTING_ELEMENTS.contains(&name) => {
+                if !self.adoption_agency(name) {
+                    self.any_other_end_tag(&tag.name);
+                }
+            }
+            "applet" | "marquee" | "object" => {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Default) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.generate_implied_end_tags(None);
+                if !self.current_is(&[tag.name.as_str()]) {
+                    self.error("Unclosed elements");
+                }
+                self.pop_until(&[tag.name.as_str()]);
+                self.clear_formatting_to_last_marker();
+            }
+            "br" => {
+                self.error("Unexpected </br>");
+                self.in_body_start_tag(Tag::new("br"));
+            }
+            name => self.any_other_end_tag(name),
+        }
+    }
+
+    fn any_other_end_tag(&mut self, name: &str) {
+        for i in (0..self.open_elements.len()).rev() {
+            let node = self.open_elements[i];
+            if self.name(node) == name {
+                self.generate_implied_end_tags(Some(name));
+                if self.current_node() != node {
+                    self.error("Unclosed elements");
+                }
+                self.truncate_stack(i);
+                return;
+            }
+            if self.is_special(node) {
+                self.error("Unexpected end tag");
+                return;
+            }
+        }
+    }
+
+    fn text(&mut self, token: Token) {
+        match token {
+            Token::Character(c) => self.insert_character(c),
+            Token::Eof => {
+                self.error("Unexpected end of file");
+                self.pop_element();
+                self.reprocess(self.original_mode, token);
+            }
+            _ => {
+                self.pop_element();
+                self.mode = self.original_mode;
+            }
+        }
+    }
+
+    fn clear_stack_to_context(&mut self, names: &[&str]) {
+        while !self.current_is(names) {
+            self.pop_element();
+        }
+    }
+
+    fn clear_stack_to_table_context(&mut self) {
+        self.clear_stack_to_context(&["table", "template", "html"]);
+    }
+
+    fn clear_stack_to_table_body_context(&mut self) {
+        self.clear_stack_to_context(&["tbody", "tfoot", "thead", "template", "html"]);
+    }
+
+    fn clear_stack_to_table_row_context(&mut self) {
+        self.clear_stack_to_context(&["tr", "template", "html"]);
+    }
+
+    fn in_table(&mut self, token: Token) {
+        match token {
+            Token::Character(_)
+                if self.current_is(&["table", "tbody", "template", "tfoot", "thead", "tr"]) =>
+            {
+                self.pending_table_text.clear();
+                self.original_mode = self.mode;
+                self.reprocess(InsertionMode::InTableText, token);
+            }
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "caption" => {
+                self.clear_stack_to_table_context();
+                self.active_formatting.push(FormattingEntry::Marker);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InCaption;
+            }
+            Token::StartTag(tag) if tag.name == "colgroup" => {
+                self.clear_stack_to_table_context();
+                self.insert_element(tag);
+                self.mode = InsertionMode::InColumnGroup;
+            }
+            Token::StartTag(ref tag) if tag.name == "col" => {
+                self.clear_stack_to_table_context();
+                self.insert_element(Tag::new("colgroup"));
+                self.reprocess(InsertionMode::InColumnGroup, token);
+            }
+            Token::StartTag(tag) if ["tbody", "tfoot", "thead"].contains(&tag.name.as_str()) => {
+                self.clear_stack_to_table_context();
+                self.insert_element(tag);
+                self.mode = InsertionMode::InTableBody;
+            }
+            Token::StartTag(ref tag) if ["td", "th", "tr"].contains(&tag.name.as_str()) => {
+                self.clear_stack_to_table_context();
+                self.insert_element(Tag::new("tbody"));
+                self.reprocess(InsertionMode::InTableBody, token);
+            }
+            Token::StartTag(ref tag) if tag.name == "table" => {
+                self.error("Nested <table>");
+                if self.in_scope(&["table"], Scope::Table) {
+                    self.pop_until(&["table"]);
+                    self.reset_insertion_mode();
+                    self.process_token(token);
+                }
+            }
+            Token::EndTag(tag) if tag.name == "table" => {
+                if !self.in_scope(&["table"], Scope::Table) {
+                    self.error("Unexpected </table>");
+                    return;
+                }
+                self.pop_until(&["table"]);
+                self.reset_insertion_mode();
+            }
+            Token::EndTag(tag)
+                if [
+                    "body", "caption", "col", "colgroup", "html", "tbody", "td", "tfoot", "th",
+                    "thead", "tr",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected end tag in table")
+            }
+            Token::StartTag(ref tag)
+                if ["style", "script", "template"].contains(&tag.name.as_str()) =>
+            {
+                self.in_head(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::StartTag(tag)
+                if tag.name == "input"
+                    && tag
+                        .get_attribute("type")
+                        .map(|t| t.eq_ignore_ascii_case("hidden"))
+                        .unwrap_or(false) =>
+            {
+                self.error("Hidden <input> in table");
+                self.insert_void_element(tag);
+            }
+            Token::StartTag(tag) if tag.name == "form" => {
+                self.error("<form> in table");
+                if self.stack_contains("template") || self.form.is_some() {
+                    return;
+                }
+                let node = self.insert_element(tag);
+                self.form = Some(node);
+                self.pop_element();
+            }
+            Token::Eof => self.in_body(token),
+            token => {
+                self.error("Unexpected token in table");
+                self.foster_parenting = true;
+                self.in_body(token);
+                self.foster_parenting = false;
+            }
+        }
+    }
+
+    fn in_table_text(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => self.error("Unexpected null character"),
+            Token::Character(c) => self.pending_table_text.push(c),
+            token => {
+                let text = mem::take(&mut self.pending_table_text);
+                if text.chars().any(|c| !is_whitespace(c)) {
+                    self.error("Text in table");
+                    self.foster_parenting = true;
+                    text.chars().for_each(|c| self.in_body(Token::Character(c)));
+                    self.foster_parenting = false;
+                } else {
+                    text.chars().for_each(|c| self.insert_character(c));
+                }
+                self.reprocess(self.original_mode, token);
+            }
+        }
+    }
+
+    /// Close the caption element, returning `false` if there is no caption in table scope
+    fn close_caption(&mut self) -> bool {
+        if !self.in_scope(&["caption"], Scope::Table) {
+            self.error("No <caption> to close");
+            return false;
+        }
+        self.generate_implied_end_tags(None);
+        if !self.current_is(&["caption"]) {
+            self.error("Unclosed elements inside <caption>");
+        }
+        self.pop_until(&["caption"]);
+        self.clear_formatting_to_last_marker();
+        self.mode = InsertionMode::InTable;
+        true
+    }
+
+    fn in_caption(&mut self, token: Token) {
+        match token {
+            Token::EndTag(tag) if tag.name == "caption" => {
+                self.close_caption();
+            }
+            Token::StartTag(ref tag)
+                if [
+                    "caption", "col", "colgroup", "tbody", "td", "tfoot", "th", "thead", "tr",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                if self.close_caption() {
+                    self.in_table(token);
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => {
+                if self.close_caption() {
+                    self.in_table(token);
+                }
+            }
+            Token::EndTag(tag)
+                if [
+                    "body", "col", "colgroup", "html", "tbody", "td", "tfoot", "th", "thead", "tr",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected end tag in <caption>")
+            }
+            token => self.in_body(token),
+        }
+    }
+
+    fn in_column_group(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "col" => self.insert_void_element(tag),
+            Token::EndTag(tag) if tag.name == "colgroup" => {
+                if !self.current_is(&["colgroup"]) {
+                    self.error("Unexpected </colgroup>");
+                    return;
+                }
+                self.pop_element();
+                self.mode = InsertionMode::InTable;
+            }
+            Token::EndTag(tag) if tag.name == "col" => self.error("Unexpected </col>"),
+            Token::StartTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::Eof => self.in_body(token),
+            token => {
+                if !self.current_is(&["colgroup"]) {
+                    self.error("Unexpected token in <colgroup>");
+                    return;
+                }
+                self.pop_element();
+                self.reprocess(InsertionMode::InTable, token);
+            }
+        }
+    }
+
+    fn in_table_body(&mut self, token: Token) {
+        match token {
+            Token::StartTag(tag) if tag.name == "tr" => {
+                self.clear_stack_to_table_body_context();
+                self.insert_element(tag);
+                self.mode = InsertionMode::InRow;
+            }
+            Token::StartTag(ref tag) if ["th", "td"].contains(&tag.name.as_str()) => {
+                self.error("Table cell outside of row");
+                self.clear_stack_to_table_body_context();
+                self.insert_element(Tag::new("tr"));
+                self.reprocess(InsertionMode::InRow, token);
+            }
+            Token::EndTag(tag) if ["tbody", "tfoot", "thead"].contains(&tag.name.as_str()) => {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Table) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.clear_stack_to_table_body_context();
+                self.pop_element();
+                self.mode = InsertionMode::InTable;
+            }
+            Token::StartTag(ref tag)
+                if ["caption", "col", "colgroup", "tbody", "tfoot", "thead"]
+                    .contains(&tag.name.as_str()) =>
+            {
+                self.close_table_body(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => self.close_table_body(token),
+            Token::EndTag(tag)
+                if [
+                    "body", "caption", "col", "colgroup", "html", "td", "th", "tr",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected end tag in table body")
+            }
+            token => self.in_table(token),
+        }
+    }
+
+    fn close_table_body(&mut self, token: Token) {
+        if !self.in_scope(&["tbody", "thead", "tfoot"], Scope::Table) {
+            self.error("No table body to close");
+            return;
+        }
+        self.clear_stack_to_table_body_context();
+        self.pop_element();
+        self.reprocess(InsertionMode::InTable, token);
+    }
+
+    /// Close the current table row, returning `false` if there is no row in table scope
+    fn close_row(&mut self) -> bool {
+        if !self.in_scope(&["tr"], Scope::Table) {
+            self.error("No <tr> to close");
+            return false;
+        }
+        self.clear_stack_to_table_row_context();
+        self.pop_element();
+        self.mode = InsertionMode::InTableBody;
+        true
+    }
+
+    fn in_row(&mut self, token: Token) {
+        match token {
+            Token::StartTag(tag) if ["th", "td"].contains(&tag.name.as_str()) => {
+                self.clear_stack_to_table_row_context();
+                self.insert_element(tag);
+                self.mode = InsertionMode::InCell;
+                self.active_formatting.push(FormattingEntry::Marker);
+            }
+            Token::EndTag(tag) if tag.name == "tr" => {
+                self.close_row();
+            }
+            Token::StartTag(ref tag)
+                if [
+                    "caption", "col", "colgroup", "tbody", "tfoot", "thead", "tr",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                if self.close_row() {
+                    self.in_table_body(token);
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => {
+                if self.close_row() {
+                    self.in_table_body(token);
+                }
+            }
+            Token::EndTag(ref tag) if ["tbody", "tfoot", "thead"].contains(&tag.name.as_str()) => {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Table) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                if self.close_row() {
+                    self.in_table_body(token);
+                }
+            }
+            Token::EndTag(tag)
+                if ["body", "caption", "col", "colgroup", "html", "td", "th"]
+                    .contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected end tag in table row")
+            }
+            token => self.in_table(token),
+        }
+    }
+
+    fn close_cell(&mut self) {
+        self.generate_implied_end_tags(None);
+        if !self.current_is(&["td", "th"]) {
+            self.error("Unclosed elements inside table cell");
+        }
+        self.pop_until(&["td", "th"]);
+        self.clear_formatting_to_last_marker();
+        self.mode = InsertionMode::InRow;
+    }
+
+    fn in_cell(&mut self, token: Token) {
+        match token {
+            Token::EndTag(tag) if ["td", "th"].contains(&tag.name.as_str()) => {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Table) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.generate_implied_end_tags(None);
+                if !self.current_is(&[tag.name.as_str()]) {
+                    self.error("Unclosed elements inside table cell");
+                }
+                self.pop_until(&[tag.name.as_str()]);
+                self.clear_formatting_to_last_marker();
+                self.mode = InsertionMode::InRow;
+            }
+            Token::StartTag(ref tag)
+                if [
+                    "caption", "col", "colgroup", "tbody", "td", "tfoot", "th", "thead", "tr",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                if !self.in_scope(&["td", "th"], Scope::Table) {
+                    self.error("Unexpected start tag");
+                    return;
+                }
+                self.close_cell();
+                self.in_row(token);
+            }
+            Token::EndTag(tag)
+                if ["body", "caption", "col", "colgroup", "html"].contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected end tag in table cell")
+            }
+            Token::EndTag(ref tag)
+                if ["table", "tbody", "tfoot", "thead", "tr"].contains(&tag.name.as_str()) =>
+            {
+                if !self.in_scope(&[tag.name.as_str()], Scope::Table) {
+                    self.error("Unexpected end tag");
+                    return;
+                }
+                self.close_cell();
+                self.in_row(token);
+            }
+            token => self.in_body(token),
+        }
+    }
+
+    fn in_select(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => self.error("Unexpected null character"),
+            Token::Character(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "option" => {
+                if self.current_is(&["option"]) {
+                    self.pop_element();
+                }
+                self.insert_element(tag);
+            }
+            Token::StartTag(tag) if tag.name == "optgroup" => {
+                if self.current_is(&["option"]) {
+                    self.pop_element();
+                }
+                if self.current_is(&["optgroup"]) {
+                    self.pop_element();
+                }
+                self.insert_element(tag);
+            }
+            Token::StartTag(tag) if tag.name == "hr" => {
+                if self.current_is(&["option"]) {
+                    self.pop_element();
+                }
+                if self.current_is(&["optgroup"]) {
+                    self.pop_element();
+                }
+                self.insert_void_element(tag);
+            }
+            Token::EndTag(tag) if tag.name == "optgroup" => {
+                let len = self.open_elements.len();
+                if self.current_is(&["option"])
+                    && len > 1
+                    && self.is(self.open_elements[len - 2], &["optgroup"])
+                {
+                    self.pop_element();
+                }
+                if self.current_is(&["optgroup"]) {
+                    self.pop_element();
+                } else {
+                    self.error("Unexpected </optgroup>");
+                }
+            }
+            Token::EndTag(tag) if tag.name == "option" => {
+                if self.current_is(&["option"]) {
+                    self.pop_element();
+                } else {
+                    self.error("Unexpected </option>");
+                }
+            }
+            Token::EndTag(tag) if tag.name == "select" => {
+                if !self.in_scope(&["select"], Scope::Select) {
+                    self.error("Unexpected </select>");
+                    return;
+                }
+                self.pop_until(&["select"]);
+                self.reset_insertion_mode();
+            }
+            Token::StartTag(tag) if tag.name == "select" => {
+                self.error("Nested <select>");
+                if self.in_scope(&["select"], Scope::Select) {
+                    self.pop_until(&["select"]);
+                    self.reset_insertion_mode();
+                }
+            }
+            Token::StartTag(ref tag)
+                if ["input", "keygen", "textarea"].contains(&tag.name.as_str()) =>
+            {
+                self.error("Unexpected start tag in <select>");
+                if self.in_scope(&["select"], Scope::Select) {
+                    self.pop_until(&["select"]);
+                    self.reset_insertion_mode();
+                    self.process_token(token);
+                }
+            }
+            Token::StartTag(ref tag) if ["script", "template"].contains(&tag.name.as_str()) => {
+                self.in_head(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::Eof => self.in_body(token),
+            _ => self.error("Unexpected token in <select>"),
+        }
+    }
+
+    fn in_select_in_table(&mut self, token: Token) {
+        let table_elements = [
+            "caption", "table", "tbody", "tfoot", "thead", "tr", "td", "th",
+        ];
+        match token {
+            Token::StartTag(ref tag) if table_elements.contains(&tag.name.as_str()) => {
+                self.error("Table element in <select>");
+                self.pop_until(&["select"]);
+                self.reset_insertion_mode();
+                self.process_token(token);
+            }
+            Token::EndTag(ref tag) if table_elements.contains(&tag.name.as_str()) => {
+                self.error("Table element in <select>");
+                if self.in_scope(&[tag.name.as_str()], Scope::Table) {
+                    self.pop_until(&["select"]);
+                    self.reset_insertion_mode();
+                    self.process_token(token);
+                }
+            }
+            token => self.in_select(token),
+        }
+    }
+
+    fn in_template(&mut self, token: Token) {
+        let mode = match token {
+            Token::Character(_) | Token::Comment(_) | Token::Doctype(_) => {
+                return self.in_body(token)
+            }
+            Token::StartTag(ref tag)
+                if [
+                    "base", "basefont", "bgsound", "link", "meta", "noframes", "script", "style",
+                    "template", "title",
+                ]
+                .contains(&tag.name.as_str()) =>
+            {
+                return self.in_head(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => return self.in_head(token),
+            Token::StartTag(ref tag) => match tag.name.as_str() {
+                "caption" | "colgroup" | "tbody" | "tfoot" | "thead" => InsertionMode::InTable,
+                "col" => InsertionMode::InColumnGroup,
+                "tr" => InsertionMode::InTableBody,
+                "td" | "th" => InsertionMode::InRow,
+                _ => InsertionMode::InBody,
+            },
+            Token::EndTag(_) => return self.error("Unexpected end tag in <template>"),
+            Token::Eof => {
+                if !self.stack_contains("template") {
+                    return self.stop_parsing();
+                }
+                self.error("Unclosed <template>");
+                self.pop_until(&["template"]);
+                self.clear_formatting_to_last_marker();
+                self.template_modes.pop();
+                self.reset_insertion_mode();
+                return self.process_token(token);
+            }
+        };
+        self.template_modes.pop();
+        self.template_modes.push(mode);
+        self.reprocess(mode, token);
+    }
+
+    fn after_body(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::Comment(comment) => {
+                let html = self.open_elements[0];
+                self.insert_comment(comment, Some(html));
+            }
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(tag) if tag.name == "html" => self.mode = InsertionMode::AfterAfterBody,
+            Token::Eof => self.stop_parsing(),
+            token => {
+                self.error("Unexpected token after </body>");
+                self.reprocess(InsertionMode::InBody, token);
+            }
+        }
+    }
+
+    fn in_frameset(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag) if tag.name == "frameset" => {
+                self.insert_element(tag);
+            }
+            Token::EndTag(tag) if tag.name == "frameset" => {
+                if self.open_elements.len() == 1 {
+                    self.error("Unexpected </frameset>");
+                    return;
+                }
+                self.pop_element();
+                if !self.current_is(&["frameset"]) {
+                    self.mode = InsertionMode::AfterFrameset;
+                }
+            }
+            Token::StartTag(tag) if tag.name == "frame" => self.insert_void_element(tag),
+            Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+            Token::Eof => self.stop_parsing(),
+            _ => self.error("Unexpected token in <frameset>"),
+        }
+    }
+
+    fn after_frameset(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(tag) if tag.name == "html" => {
+                self.mode = InsertionMode::AfterAfterFrameset
+            }
+            Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+            Token::Eof => self.stop_parsing(),
+            _ => self.error("Unexpected token after <frameset>"),
+        }
+    }
+
+    fn after_after_body(&mut self, token: Token) {
+        match token {
+            Token::Comment(comment) => {
+                self.insert_comment(comment, Some(self.document.document_node()))
+            }
+            Token::Doctype(_) => self.in_body(token),
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::Eof => self.stop_parsing(),
+            token => {
+                self.error("Unexpected token after </html>");
+                self.reprocess(InsertionMode::InBody, token);
+            }
+        }
+    }
+
+    fn after_after_frameset(&mut self, token: Token) {
+        match token {
+            Token::Comment(comment) => {
+                self.insert_comment(comment, Some(self.document.document_node()))
+            }
+            Token::Doctype(_) => self.in_body(token),
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::Eof => self.stop_parsing(),
+            Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+            _ => self.error("Unexpected token after </html>"),
+        }
+    }
+
+    /// <https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign>
+    fn foreign_content(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {
+                self.error("Unexpected null character");
+                self.insert_character('\u{FFFD}');
+            }
+            Token::Character(c) => {
+                self.insert_character(c);
+                if !is_whitespace(c) {
+                    self.frameset_ok = false;
+                }
+            }
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => self.error("Unexpected doctype"),
+            Token::StartTag(ref tag)
+                if BREAKOUT_ELEMENTS.contains(&tag.name.as_str())
+                    || (tag.name == "font"
+                        && ["color", "face", "size"]
+                            .iter()
+                            .any(|a| tag.get_attribute(a).is_some())) =>
+            {
+                self.break_out_of_foreign_content(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "br" || tag.name == "p" => {
+                self.break_out_of_foreign_content(token)
+            }
+            Token::StartTag(tag) => {
+                let node = self
+                    .adjusted_current_node()
+                    .expect("Foreign content is inside an element");
+                self.insert_foreign_element(tag, self.namespace(node));
+            }
+            Token::EndTag(tag) => {
+                let mut i = self.open_elements.len() - 1;
+                if !self
+                    .element(self.open_elements[i])
+                    .name
+                    .eq_ignore_ascii_case(&tag.name)
+                {
+                    self.error("Unexpected end tag");
+                }
+                while i > 0 {
+                    let node = self.open_elements[i];
+                    if self.element(node).name.eq_ignore_ascii_case(&tag.name) {
+                        self.truncate_stack(i);
+                        return;
+                    }
+                    i -= 1;
+                    if self.namespace(self.open_elements[i]) == Namespace::Html {
+                        return self.process_in(self.mode, Token::EndTag(tag));
+                    }
+                }
+            }
+            Token::Eof => self.process_in(self.mode, token),
+        }
+    }
+
+    /// Close foreign elements up to the nearest HTML element or integration point, then
+    /// process the token as HTML
+    fn break_out_of_foreign_content(&mut self, token: Token) {
+        self.error("HTML element in foreign content");
+        while let Some(&node) = self.open_elements.last() {
+            if self.namespace(node) == Namespace::Html
+                || self.is_mathml_text_integration_point(node)
+                || self.is_html_integration_point(node)
+            {
+                break;
+            }
+            self.pop_element();
+        }
+        self.process_in(self.mode, token);
+    }
+}
@@ -0,0 +1,20 @@
+#!/bin/sh
+# Replace the test files here with the unmodified ones from html5lib-tests at a commit, record
+# the commit in COMMIT, and record how many cases now pass in ../html5lib-results.txt
+#
+# Usage: html/tests/html5lib-tests/update.sh <commit>
+set -eu
+
+commit=$1
+dir=$(cd "$(dirname "$0")" && pwd)
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+curl -fsSL "https://github.com/html5lib/html5lib-tests/archive/$commit.tar.gz" |
+    tar -xz -C "$tmp" --strip-components=1
+rm -rf "$dir/tokenizer" "$dir/tree-construction"
+cp -R "$tmp/tokenizer" "$tmp/tree-construction" "$tmp/LICENSE" "$dir/"
+echo "$commit" >"$dir/COMMIT"
+
+cd "$dir/../.."
+HTML5LIB_BLESS=1 cargo test -p html html5lib
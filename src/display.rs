@@ -47,7 +47,7 @@ fn render_borders<'a>(root: &'a LayoutBox) -> Option<Vec<DisplayCommand<'a>>> {
         Rect {
             x: border.x,
             y: border.y,
-            width: dim.border.left as f64,
+            width: dim.border.left,
             height: border.height,
         },
     ));
@@ -1,13 +1,16 @@
 mod properties;
 
 use crate::layout::properties::{get_border, get_margins, get_padding, Border, Margin, Padding};
+pub use crate::layout::properties::{get_white_space, WhiteSpace};
 use crate::layout::BoxContentType::Text;
 use crate::paint::get_rasterized_layout;
-use crate::style::{StyleMap, StyledContent, StyledElement};
+use crate::style::{source_location, StyleMap, StyledContent, StyledElement};
 use css::{Unit, Value};
 use fontdue::layout::LayoutSettings;
+use html::SourceSpan;
+use std::borrow::Cow;
 use std::str::FromStr;
-use tracing::{span, Level};
+use tracing::{span, trace, Level};
 
 pub struct LayoutBox<'a> {
     pub dimensions: Dimensions,
@@ -17,13 +20,15 @@ pub struct LayoutBox<'a> {
     pub box_content_type: BoxContentType<'a>,
     pub font_size: f64,
     pub border: Option<Border<'a>>,
+    // Where the element or text the box was generated for was parsed from
+    pub span: Option<SourceSpan>,
 }
 
 #[allow(dead_code)]
 pub enum BoxContentType<'a> {
     Normal,
     Image,
-    Text(&'a str),
+    Text(Cow<'a, str>),
 }
 
 #[derive(Copy, Clone, Default)]
@@ -46,8 +51,9 @@ impl Dimensions {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 enum BoxType {
+    #[default]
     Block,
     Inline,
     Anonymous,
@@ -63,12 +69,6 @@ impl<'a> From<&Value<'a>> for BoxType {
     }
 }
 
-impl Default for BoxType {
-    fn default() -> Self {
-        Self::Block
-    }
-}
-
 impl FromStr for BoxType {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -146,9 +146,13 @@ fn build_layout_tree<'a>(root: &'a StyledElement) -> LayoutBox<'a> {
         box_content_type: BoxContentType::Normal,
         font_size,
         border: None,
+        span: root.span,
     };
     for child in &root.contents {
         match child {
+            StyledContent::Element(elt) if elt.is_hidden() => {
+                trace!("Hiding element at {}", source_location(elt.span));
+            }
             StyledContent::Element(elt) => {
                 match elt
                     .styles
@@ -162,6 +166,11 @@ fn build_layout_tree<'a>(root: &'a StyledElement) -> LayoutBox<'a> {
                 }
             }
             StyledContent::Text(text) => {
+                // Text keeps its whitespace until here, where its element's styles are known
+                let contents = get_white_space(&root.styles).process(&text.contents);
+                if contents.is_empty() {
+                    continue;
+                }
                 let box_type = text
                     .styles
                     .get("display")
@@ -172,9 +181,10 @@ fn build_layout_tree<'a>(root: &'a StyledElement) -> LayoutBox<'a> {
                     box_type,
                     contents: vec![],
                     style: root.styles.clone(),
-                    box_content_type: Text(&text.contents),
+                    box_content_type: Text(contents),
                     font_size,
                     border: None,
+                    span: text.span,
                 };
                 match box_type {
                     BoxType::Block => root_box.contents.push(the_box),
@@ -212,9 +222,16 @@ impl<'a> LayoutBox<'a> {
             box_content_type: BoxContentType::Normal,
             font_size,
             border: None,
+            span: None,
         }
     }
     fn layout(&mut self, container: Dimensions) {
+        let span = span!(
+            Level::TRACE,
+            "Laying out box",
+            source = %source_location(self.span)
+        );
+        let _enter = span.enter();
         match self.box_type {
             BoxType::Block => self.layout_block(container),
             _ => todo!(),
@@ -328,8 +345,11 @@ impl<'a> LayoutBox<'a> {
     }
 
     fn calculate_text_block_width(&mut self, container: Dimensions) {
+        // Text which can't wrap is as wide as its longest line
         let settings = LayoutSettings {
-            max_width: Some(container.border_box().width as f32),
+            max_width: get_white_space(&self.style)
+                .wraps()
+                .then_some(container.border_box().width as f32),
             ..Default::default()
         };
         if let Text(s) = &self.box_content_type {
@@ -411,3 +431,28 @@ impl<'a> LayoutBox<'a> {
         }
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_preformatted_text() {
+    let (_, doc) = html::document("<pre>a  b\n  c</pre><p>a  b\n  c</p>").unwrap();
+    let mut root = StyledElement::new(&doc, doc.document_element());
+    root.apply_styles(
+        &doc,
+        &crate::style::USER_AGENT_CSS,
+        crate::style::Origin::UserAgent,
+        &css::Device::screen(1600.0, 1080.0),
+        &mut css::CascadeLayers::default(),
+        &css::Layer::default(),
+    );
+    fn texts<'b>(layout_box: &'b LayoutBox, found: &mut Vec<&'b str>) {
+        if let Text(text) = &layout_box.box_content_type {
+            found.push(text);
+        }
+        layout_box.contents.iter().for_each(|c| texts(c, found));
+    }
+    let layout = build_layout_tree(&root);
+    let mut found = vec![];
+    texts(&layout, &mut found);
+    assert_eq!(found, ["a  b\n  c", "a b c"]);
+}
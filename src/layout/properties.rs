@@ -1,5 +1,6 @@
 use crate::style::StyleMap;
 use css::{ColorValue, Value};
+use std::borrow::Cow;
 
 /// Takes a `padding`, and converts it to
 /// (`padding-top`, `padding-right`, `padding-bottom`, `padding-left`)
@@ -246,11 +247,92 @@ fn process_border<'a>(val: &'a Value) -> (Option<Value<'a>>, Option<Value<'a>>,
         }
         let values: Vec<&Value> = mv.0.iter().map(|(_, val)| val).collect();
 
-        let width = { values.iter().cloned().cloned().find(Value::is_width) };
-        let style = { values.iter().cloned().cloned().find(Value::is_border_style) };
-        let color = { values.iter().cloned().cloned().find(Value::is_color) };
+        let width = { values.iter().copied().find(|v| v.is_width()).cloned() };
+        let style = {
+            values
+                .iter()
+                .copied()
+                .find(|v| v.is_border_style())
+                .cloned()
+        };
+        let color = { values.iter().copied().find(|v| v.is_color()).cloned() };
         (width, style, color)
     } else {
         (None, None, None)
     }
 }
+
+/// How whitespace in text is handled, from the `white-space` property
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WhiteSpace {
+    #[default]
+    Normal,
+    Pre,
+    Nowrap,
+    PreWrap,
+    PreLine,
+}
+
+impl WhiteSpace {
+    /// Whether lines of text can be broken to fit their container
+    pub fn wraps(self) -> bool {
+        matches!(self, Self::Normal | Self::PreWrap | Self::PreLine)
+    }
+
+    /// Collapse the whitespace in a run of text, as far as this allows. Each run of text is laid
+    /// out on lines of its own, so whitespace at the start and end of it is removed too.
+    /// <https://drafts.csswg.org/css-text-3/#white-space-processing>
+    pub fn process(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Pre | Self::PreWrap => Cow::Borrowed(text),
+            Self::Normal | Self::Nowrap => {
+                Cow::Owned(text.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+            }
+            // Line breaks are kept, but the spaces around them are not
+            Self::PreLine => Cow::Owned(
+                text.trim_matches(|c: char| c.is_ascii_whitespace())
+                    .split('\n')
+                    .map(|line| line.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+}
+
+pub fn get_white_space(style: &StyleMap) -> WhiteSpace {
+    match style.get("white-space") {
+        Some(Value::Keyword("pre")) => WhiteSpace::Pre,
+        Some(Value::Keyword("nowrap")) => WhiteSpace::Nowrap,
+        Some(Value::Keyword("pre-wrap")) => WhiteSpace::PreWrap,
+        Some(Value::Keyword("pre-line")) => WhiteSpace::PreLine,
+        _ => WhiteSpace::Normal,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_white_space() {
+    let text = "  One  two\t\n   three \n";
+    assert_eq!(WhiteSpace::Normal.process(text), "One two three");
+    assert_eq!(WhiteSpace::Nowrap.process(text), "One two three");
+    assert_eq!(WhiteSpace::PreLine.process(text), "One two\nthree");
+    assert_eq!(WhiteSpace::Pre.process(text), text);
+    assert_eq!(WhiteSpace::PreWrap.process(text), text);
+    assert_eq!(WhiteSpace::Normal.process(" \n "), "");
+
+    let sheet = css::stylesheet("p { white-space: pre-line }");
+    let (_, doc) = html::document("<p>a").unwrap();
+    let p = doc.get_elements_by_name(doc.document_element(), "p", true)[0];
+    let mut p = crate::style::StyledElement::new(&doc, p);
+    assert_eq!(get_white_space(&p.styles), WhiteSpace::Normal);
+    p.apply_styles(
+        &doc,
+        &sheet,
+        crate::style::Origin::Author,
+        &css::Device::screen(1600.0, 1080.0),
+        &mut css::CascadeLayers::default(),
+        &css::Layer::default(),
+    );
+    assert_eq!(get_white_space(&p.styles), WhiteSpace::PreLine);
+}
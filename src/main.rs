@@ -6,17 +6,36 @@ use tracing::{info, span, Level};
 mod display;
 /// Translation of a [`style::StyledElement`] tree into a tree of boxes
 mod layout;
+/// Typed page metadata, such as robots directives and Open Graph properties
+mod metadata;
 /// Painting [`display::DisplayCommand`]s onto a [`paint::Canvas`]
 mod paint;
 /// Application of CSS styles to HTML
 mod style;
+/// Extraction of the text a page displays
+mod text;
 /// Fetching of resources from the web
 mod web;
 
+/// The size of the viewport pages are rendered in, in pixels
+const VIEWPORT: (usize, usize) = (1600, 1080);
+
+/// What is written for a page
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    // An image of the rendered page
+    Render,
+    // The text the page displays
+    Text,
+    // The page's metadata, like its description and Open Graph properties
+    Metadata,
+}
+
 struct Args {
     pub input: String,
     pub output: String,
     pub trace: bool,
+    pub mode: Mode,
 }
 
 fn main() {
@@ -25,38 +44,75 @@ fn main() {
         tracing_subscriber::fmt::init();
         info!("Initialized");
     }
-    render_from_url(args.input.as_str(), args.output);
+    render_from_url(args.input.as_str(), args.output, args.mode);
 }
 
 fn parse_args() -> Result<Args, pico_args::Error> {
     let mut pargs = pico_args::Arguments::from_env();
+    let trace = pargs.contains(["-t", "--trace"]);
+    let mode = match (pargs.contains("--text"), pargs.contains("--metadata")) {
+        (true, true) => {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "--text and --metadata can't be used together".to_string(),
+            })
+        }
+        (true, false) => Mode::Text,
+        (false, true) => Mode::Metadata,
+        (false, false) => Mode::Render,
+    };
     let args = Args {
         input: pargs.free_from_str()?,
         output: pargs.free_from_str()?,
-        trace: pargs.contains(["-t", "--trace"]),
+        trace,
+        mode,
     };
     Ok(args)
 }
 
-fn render_from_url(url: &str, output: String) {
-    let page = web::Page::browse(url);
+/// Write text to `output`, or to stdout if it's `-`
+fn write_text(output: String, text: String) {
+    if output == "-" {
+        println!("{}", text);
+    } else {
+        std::fs::write(output, text + "\n").expect("Could not save to file");
+    }
+}
+
+/// Render the page at `url` to an image at `output`. In the text and metadata modes, write
+/// those there instead, or to stdout if `output` is `-`
+fn render_from_url(url: &str, output: String, mode: Mode) {
+    let device = css::Device::screen(VIEWPORT.0 as f64, VIEWPORT.1 as f64);
+    let page = web::Page::browse(url, device);
+    if let Some(title) = page.title() {
+        info!("Page title: {}", title);
+    }
+    if mode == Mode::Metadata {
+        info!("Extracting metadata");
+        write_text(output, page.metadata());
+        return;
+    }
     let stylesheets = page.get_stylesheet_text();
     let styles = page.get_styles(&stylesheets);
     info!("Applying stylesheets");
-    for sheet in styles {
-        page.style_tree.borrow_mut().apply_styles(&sheet.rules);
+    for (sheet, (_, layer)) in styles.iter().zip(&stylesheets) {
+        page.apply_styles(sheet, style::Origin::Author, layer.as_deref());
+    }
+    if mode == Mode::Text {
+        info!("Extracting text");
+        write_text(output, page.text());
+        return;
     }
     let style = page.style_tree.borrow();
     info!("Performing layout");
-    let layout = create_layout(&style, (1600, 1080));
+    let layout = create_layout(&style, VIEWPORT);
     info!("Painting canvas");
     let canvas = paint(
         &layout,
         Rect {
             x: 0.0,
             y: 0.0,
-            width: 1600.0,
-            height: 1080.0,
+            width: VIEWPORT.0 as f64,
+            height: VIEWPORT.1 as f64,
         },
     );
     info!("Rendering to image");
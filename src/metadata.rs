@@ -0,0 +1,224 @@
+use html::NodeId;
+use std::fmt;
+use url::Url;
+
+/// Whether a page may be indexed and its links followed, from `<meta name="robots">`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Robots {
+    pub index: bool,
+    pub follow: bool,
+}
+
+impl Default for Robots {
+    fn default() -> Self {
+        Self {
+            index: true,
+            follow: true,
+        }
+    }
+}
+
+impl Robots {
+    /// Parse a comma-separated list of directives like `noindex, follow`. Unknown directives are
+    /// ignored
+    pub fn parse(content: &str) -> Self {
+        let mut robots = Self::default();
+        for directive in content.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            match directive.as_str() {
+                "noindex" => robots.index = false,
+                "nofollow" => robots.follow = false,
+                "none" => {
+                    robots = Self {
+                        index: false,
+                        follow: false,
+                    }
+                }
+                _ => {}
+            }
+        }
+        robots
+    }
+}
+
+/// The viewport a page asks for with `<meta name="viewport">`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Viewport {
+    // Either a number of pixels, or `device-width`/`device-height`
+    pub width: Option<String>,
+    pub height: Option<String>,
+    pub initial_scale: Option<f64>,
+    pub minimum_scale: Option<f64>,
+    pub maximum_scale: Option<f64>,
+    pub user_scalable: Option<bool>,
+}
+
+impl Viewport {
+    /// Parse a list of properties like `width=device-width, initial-scale=1`
+    pub fn parse(content: &str) -> Self {
+        let mut viewport = Self::default();
+        for property in content.split([',', ';']) {
+            let (name, value) = match property.split_once('=') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+            match name.as_str() {
+                "width" => viewport.width = Some(value.to_string()),
+                "height" => viewport.height = Some(value.to_string()),
+                "initial-scale" => viewport.initial_scale = value.parse().ok(),
+                "minimum-scale" => viewport.minimum_scale = value.parse().ok(),
+                "maximum-scale" => viewport.maximum_scale = value.parse().ok(),
+                "user-scalable" => {
+                    viewport.user_scalable = match value.to_ascii_lowercase().as_str() {
+                        "yes" | "1" => Some(true),
+                        "no" | "0" => Some(false),
+                        _ => None,
+                    }
+                }
+                _ => {}
+            }
+        }
+        viewport
+    }
+}
+
+/// The Open Graph properties of a page, from `<meta property="og:...">`.
+/// <https://ogp.me/>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenGraph {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    // `og:type`, e.g. `website` or `article`
+    pub kind: Option<String>,
+    pub url: Option<String>,
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+}
+
+/// Another version of a page, from `<link rel="alternate">`, e.g. a translation or a feed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alternate {
+    pub url: Url,
+    pub hreflang: Option<String>,
+    pub media_type: Option<String>,
+}
+
+/// What kind of reference a [`Resource`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// The `href` of an `<a>` or `<area>`
+    Hyperlink,
+    /// The `src` or a `srcset` candidate of an `<img>`, or a `srcset` candidate of a `<source>`
+    Image,
+    /// The `src` of a `<script>`
+    Script,
+    /// The `src` of an `<audio>`, `<video>` or `<source>`
+    Media,
+    /// The `href` of a `<link>`, whatever its `rel`
+    Link,
+    /// A `url()` in a stylesheet of the page or a `style` attribute
+    CssUrl,
+    /// An `@import` in a stylesheet of the page
+    Import,
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResourceKind::Hyperlink => "hyperlink",
+            ResourceKind::Image => "image",
+            ResourceKind::Script => "script",
+            ResourceKind::Media => "media",
+            ResourceKind::Link => "link",
+            ResourceKind::CssUrl => "css-url",
+            ResourceKind::Import => "import",
+        })
+    }
+}
+
+/// A URL a page refers to, and the element it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub url: Url,
+    pub kind: ResourceKind,
+    pub element: NodeId,
+}
+
+/// Get the URLs of the candidates of a `srcset` attribute, like `a.png 1x, b.png 2x`.
+/// <https://html.spec.whatwg.org/multipage/images.html#parsing-a-srcset-attribute>
+pub fn srcset_urls(srcset: &str) -> Vec<&str> {
+    let mut urls = vec![];
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            return urls;
+        }
+        let end = rest
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let url = &rest[..end];
+        rest = &rest[end..];
+        // A URL ending in commas has no descriptors
+        if url.ends_with(',') {
+            urls.push(url.trim_end_matches(','));
+            continue;
+        }
+        urls.push(url);
+        // Skip the descriptors, which may contain commas inside parentheses
+        let mut depth = 0;
+        let end = rest
+            .find(|c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    ',' if depth <= 0 => return true,
+                    _ => {}
+                }
+                false
+            })
+            .unwrap_or(rest.len());
+        rest = &rest[end..];
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse() {
+    assert_eq!(Robots::parse(""), Robots::default());
+    assert_eq!(
+        Robots::parse("NOINDEX, follow"),
+        Robots {
+            index: false,
+            follow: true
+        }
+    );
+    assert_eq!(
+        Robots::parse("none"),
+        Robots {
+            index: false,
+            follow: false
+        }
+    );
+    assert_eq!(
+        Viewport::parse("width=device-width, initial-scale=1.5; user-scalable=no, bogus"),
+        Viewport {
+            width: Some("device-width".to_string()),
+            initial_scale: Some(1.5),
+            user_scalable: Some(false),
+            ..Default::default()
+        }
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_srcset_urls() {
+    assert_eq!(srcset_urls(""), Vec::<&str>::new());
+    assert_eq!(srcset_urls("a.png"), ["a.png"]);
+    assert_eq!(
+        srcset_urls(" a.png 1x,b.png 2x , c,d.png,"),
+        ["a.png", "b.png", "c,d.png"]
+    );
+    assert_eq!(srcset_urls("a.png,, b.png 100w"), ["a.png", "b.png"]);
+    assert_eq!(srcset_urls("a.png (x, y), b.png"), ["a.png", "b.png"]);
+}
@@ -1,36 +1,55 @@
-use css::{stylesheet, Declaration, Ruleset, Selector, SimpleSelector, Stylesheet, Value};
-use html::{DOMAttributes, DOMContent, DOMElement};
+use css::{
+    stylesheet, CascadeLayers, Device, Layer, LayerRank, Ruleset, Selector, SimpleSelector,
+    Stylesheet, Value,
+};
+use html::{Document, ElementData, NodeData, NodeId, SourceSpan};
 use once_cell::sync::Lazy;
 use std::borrow::Cow;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
 use std::collections::HashMap;
 use std::iter::Sum;
 use std::ops::{Add, Deref};
 
-static USER_AGENT_STYLESHEET: &str = include_str!("../resources/html.css");
-pub static USER_AGENT_CSS: Lazy<Stylesheet> =
-    Lazy::new(|| stylesheet(USER_AGENT_STYLESHEET).unwrap().1);
-
+// Layout and text extraction depend on the `white-space` of preformatted elements, so those
+// rules are always part of the stylesheet, whichever one is in `resources`
+static USER_AGENT_STYLESHEET: &str = concat!(
+    "pre, listing, xmp, plaintext { white-space: pre }
+textarea { white-space: pre-wrap }
+",
+    include_str!("../resources/html.css")
+);
+pub static USER_AGENT_CSS: Lazy<Stylesheet> = Lazy::new(|| stylesheet(USER_AGENT_STYLESHEET));
+
+/// Property name -> the value along with the precedence of the declaration it came from, or
+/// [`None`] if it was inherited, which loses to any declaration
 #[derive(Default, Clone)]
-pub struct StyleMap<'a>(HashMap<&'a str, (Value<'a>, Specificity)>);
+pub struct StyleMap<'a>(HashMap<Cow<'a, str>, (Value<'a>, Option<Precedence>)>);
 
 impl<'a> StyleMap<'a> {
-    pub fn get(&self, value: &str) -> Option<&Value> {
+    pub fn get(&self, value: &str) -> Option<&Value<'a>> {
         self.0.get(value).map(|v| &v.0)
     }
 }
 
 pub struct StyledElement<'a> {
-    pub name: String,
-    pub attributes: DOMAttributes,
+    // The element in the document this was created from
+    pub node: NodeId,
     pub contents: Vec<StyledContent<'a>>,
-    // The attribute name -> the value along with the specificity of the selector
     pub styles: StyleMap<'a>,
+    // Where the element was parsed from, if it came from a document
+    pub span: Option<SourceSpan>,
 }
 
 pub struct StyledString<'a> {
     pub contents: Cow<'a, str>,
     pub styles: StyleMap<'a>,
+    pub span: Option<SourceSpan>,
+}
+
+/// Describe where a node was parsed from, for logging
+pub fn source_location(span: Option<SourceSpan>) -> String {
+    span.map(|s| s.to_string())
+        .unwrap_or_else(|| "unknown location".to_string())
 }
 
 impl<'a> From<&'a str> for StyledString<'a> {
@@ -38,6 +57,7 @@ impl<'a> From<&'a str> for StyledString<'a> {
         StyledString {
             contents: Cow::Borrowed(s),
             styles: Default::default(),
+            span: None,
         }
     }
 }
@@ -47,6 +67,7 @@ impl<'a> From<String> for StyledString<'a> {
         StyledString {
             contents: Cow::Owned(s),
             styles: Default::default(),
+            span: None,
         }
     }
 }
@@ -57,24 +78,119 @@ pub enum StyledContent<'a> {
 }
 
 impl<'a> StyledElement<'a> {
-    /// Insert a CSS declaration (key/[`Value`]) only if the [`Specificity`] of the
-    /// existing rule for that key is lower (or does not exist)
-    pub fn insert<'b>(&'b mut self, key: &'a str, value: Value<'a>, spec: Specificity)
+    /// Insert a CSS declaration (key/[`Value`]) only if the [`Precedence`] of the
+    /// existing declaration for that key is lower (or does not exist)
+    pub fn insert<'b>(&'b mut self, key: Cow<'a, str>, value: Value<'a>, precedence: Precedence)
     where
         'a: 'b,
     {
         // Insert the new declaration only if the attribute is not specified *or*
-        // the specificity is lower
-        if let Some(&(_, existing)) = self.styles.0.get(&key) {
-            if spec >= existing {
-                self.styles.0.insert(key, (value, spec));
+        // the precedence is lower
+        if let Some((_, existing)) = self.styles.0.get(key.as_ref()) {
+            if Some(&precedence) >= existing.as_ref() {
+                self.styles.0.insert(key, (value, Some(precedence)));
             };
         } else {
-            self.styles.0.insert(key, (value, spec));
+            self.styles.0.insert(key, (value, Some(precedence)));
+        }
+    }
+
+    /// Give this element and its descendants the values of their parents' [value@INHERITED]
+    /// properties, where no declaration sets them. Values inherited before are replaced, so this
+    /// can be done again after more styles are applied
+    fn inherit(&mut self, parent: Option<&StyleMap<'a>>) {
+        let Self {
+            styles, contents, ..
+        } = self;
+        styles.0.retain(|_, (_, precedence)| precedence.is_some());
+        if let Some(parent) = parent {
+            for &name in INHERITED {
+                if let (None, Some((value, _))) = (styles.0.get(name), parent.0.get(name)) {
+                    styles.0.insert(Cow::Borrowed(name), (value.clone(), None));
+                }
+            }
+        }
+        for content in contents.iter_mut() {
+            if let StyledContent::Element(child) = content {
+                child.inherit(Some(styles));
+            }
         }
     }
 }
 
+/// Where a stylesheet comes from
+/// <https://www.w3.org/TR/css-cascade-4/#cascading-origins>
+#[derive(PartialEq, Copy, Clone, Eq, Hash, Debug)]
+pub enum Origin {
+    UserAgent,
+    // There's no way to give a user stylesheet yet
+    #[allow(dead_code)]
+    User,
+    Author,
+}
+
+/// How a declaration is ordered in the cascade: first by its origin and importance, then by its
+/// cascade layer, and then by the specificity of its selector. Later declarations win ties
+#[derive(PartialEq, Clone, Eq, PartialOrd, Ord, Debug)]
+pub struct Precedence {
+    level: usize,
+    layer: LayerPrecedence,
+    specificity: Specificity,
+}
+
+/// How a declaration is ordered by its cascade layer. Important declarations in earlier layers
+/// win, the other way around from normal ones
+#[derive(PartialEq, Clone, Eq, PartialOrd, Ord, Debug)]
+enum LayerPrecedence {
+    Normal(LayerRank),
+    Important(Reverse<LayerRank>),
+}
+
+impl Precedence {
+    pub fn new(
+        origin: Origin,
+        important: bool,
+        layer: LayerRank,
+        specificity: Specificity,
+    ) -> Self {
+        // Normal declarations go user-agent, user, author, and important ones the other way
+        let level = match (important, origin) {
+            (false, Origin::UserAgent) => 0,
+            (false, Origin::User) => 1,
+            (false, Origin::Author) => 2,
+            (true, Origin::Author) => 3,
+            (true, Origin::User) => 4,
+            (true, Origin::UserAgent) => 5,
+        };
+        let layer = if important {
+            LayerPrecedence::Important(Reverse(layer))
+        } else {
+            LayerPrecedence::Normal(layer)
+        };
+        Self {
+            level,
+            layer,
+            specificity,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_precedence() {
+    let low = Specificity(0, 0, 0, 1);
+    let high = Specificity(0, 1, 0, 0);
+    let precedence = |origin, important, specificity| {
+        Precedence::new(origin, important, Layer::default().rank(), specificity)
+    };
+    assert!(precedence(Origin::Author, false, high) > precedence(Origin::Author, false, low));
+    assert!(precedence(Origin::Author, false, low) > precedence(Origin::User, false, high));
+    assert!(precedence(Origin::User, false, low) > precedence(Origin::UserAgent, false, high));
+    assert!(precedence(Origin::Author, true, low) > precedence(Origin::Author, false, high));
+    assert!(precedence(Origin::User, true, low) > precedence(Origin::Author, true, high));
+    assert!(precedence(Origin::UserAgent, true, low) > precedence(Origin::User, true, high));
+}
+
 // Attrs, IDs, Classes, Elements
 #[derive(PartialEq, Copy, Clone, Default, Eq, Debug)]
 pub struct Specificity(usize, usize, usize, usize);
@@ -87,11 +203,7 @@ impl From<(usize, usize, usize, usize)> for Specificity {
 
 impl PartialOrd for Specificity {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let Specificity(attrs, ids, cls, elts) = self;
-        let value = attrs * 1000 + ids * 100 + cls * 10 + elts;
-        let Specificity(attrs, ids, cls, elts) = other;
-        let o_value = attrs * 1000 + ids * 100 + cls * 10 + elts;
-        std::cmp::PartialOrd::partial_cmp(&value, &o_value)
+        Some(self.cmp(other))
     }
 }
 
@@ -151,7 +263,7 @@ impl<'a> From<&SimpleSelector<'a>> for Specificity {
         match sel {
             SimpleSelector::Type(_) => (0, 0, 0, 1),
             SimpleSelector::Universal => (0, 0, 0, 0),
-            SimpleSelector::Attribute(_) => (1, 0, 0, 0),
+            SimpleSelector::Attribute(_) => (0, 0, 1, 0),
             SimpleSelector::Class(_) => (0, 0, 1, 0),
             SimpleSelector::PseudoClass(_) => (0, 0, 1, 0),
             SimpleSelector::ID(_) => (0, 1, 0, 0),
@@ -160,188 +272,351 @@ impl<'a> From<&SimpleSelector<'a>> for Specificity {
     }
 }
 
-impl<'a> From<DOMContent> for StyledContent<'a> {
-    fn from(content: DOMContent) -> Self {
-        match content {
-            DOMContent::Text(s) => StyledContent::Text(s.into()),
-            DOMContent::Element(e) => StyledContent::Element(e.into()),
-        }
-    }
-}
-
-impl<'a> From<DOMElement> for StyledElement<'a> {
-    fn from(element: DOMElement) -> Self {
+impl<'a> StyledElement<'a> {
+    /// Build the style tree for the element `id` of `document`
+    pub fn new(document: &Document, id: NodeId) -> Self {
+        let contents = document
+            .children(id)
+            .filter_map(|child| {
+                let node = document.node(child);
+                match &node.data {
+                    NodeData::Element(elt) if !element_is_excluded(elt) => {
+                        Some(StyledContent::Element(StyledElement::new(document, child)))
+                    }
+                    NodeData::Text(t) => Some(StyledContent::Text(StyledString {
+                        contents: Cow::Owned(t.clone()),
+                        styles: Default::default(),
+                        span: node.span,
+                    })),
+                    _ => None,
+                }
+            })
+            .collect();
         Self {
-            name: element.name,
+            node: id,
+            contents,
             styles: Default::default(),
-            contents: element
-                .contents
-                .into_iter()
-                .filter(|c| {
-                    if let DOMContent::Element(elt) = c {
-                        !element_is_excluded(elt)
-                    } else {
-                        true
-                    }
-                })
-                .map(|e| e.into())
-                .collect(),
-            attributes: element.attributes,
+            span: document.node(id).span,
         }
     }
 }
 
 /// Certain elements should not be copied into the style tree, i.e. do not produce a 'box'
-fn element_is_excluded(elt: &DOMElement) -> bool {
+fn element_is_excluded(elt: &ElementData) -> bool {
     EXCLUDED.contains(&elt.name.as_str())
 }
 
 impl<'a> StyledElement<'a> {
-    /// Iterate over each ruleset in a stylesheet and apply it to the DOM
-    pub fn apply_styles<'b>(&'b mut self, styles: &[Ruleset<'a>])
-    where
+    /// Iterate over each ruleset in a stylesheet from `origin` which applies on `device` and
+    /// apply it to the DOM. `layers` are the cascade layers of the origin's stylesheets, and
+    /// `layer` is the one the stylesheet was imported into
+    pub fn apply_styles<'b>(
+        &'b mut self,
+        document: &Document,
+        sheet: &Stylesheet<'a>,
+        origin: Origin,
+        device: &Device,
+        layers: &mut CascadeLayers,
+        layer: &Layer,
+    ) where
         'a: 'b,
     {
-        for r in styles {
-            self.apply_rule(r);
+        for (layer, r) in sheet.rulesets(device, layers, layer) {
+            self.apply_rule(document, r, origin, &layer);
         }
+        self.inherit(None);
     }
 
-    /// Find the highest specificity (if any) selector for a given node and apply it
-    /// If this function returns `true`, then the element is not being displayed and should
-    /// be deleted by the parent
-    fn apply_rule<'b>(&'b mut self, style: &Ruleset<'a>) -> bool
-    where
+    /// Find the highest specificity (if any) selector for a given node and apply it, then do
+    /// the same for its descendants
+    fn apply_rule<'b>(
+        &'b mut self,
+        document: &Document,
+        style: &Ruleset<'a>,
+        origin: Origin,
+        layer: &LayerRank,
+    ) where
         'a: 'b,
     {
         if let Some(spec) = style
             .selectors
             .iter()
-            .filter(|r| self.does_rule_apply(r))
+            .filter(|r| self.does_rule_apply(document, r))
             .map(Specificity::from)
             .max()
         {
-            if style
-                .declarations
-                .iter()
-                .any(|decl| decl.name == "display" && decl.value == Value::Keyword("none"))
-            {
-                return true;
+            for decl in &style.declarations {
+                let precedence = Precedence::new(origin, decl.important, layer.clone(), spec);
+                self.insert(decl.name.clone(), decl.value.clone(), precedence);
             }
-            // Styles will be inherited by children
-            self.apply_rule_unconditionally(&style.declarations, spec, false);
         }
-        // Didn't apply to the parent, so we need to check each child recursively
-        let mut remove = Vec::new();
-        for (i, content) in self.contents.iter_mut().enumerate() {
+        for content in self.contents.iter_mut() {
             if let StyledContent::Element(elt) = content {
-                if elt.apply_rule(style) {
-                    remove.push(i);
-                }
+                elt.apply_rule(document, style, origin, layer);
             }
         }
-        // Iterate backwards so we can remove elements from the array
-        remove.into_iter().rev().for_each(|i| {
-            self.contents.remove(i);
-        });
-        false
     }
 
-    /// Check if the provided [`Selector`] selects this element
-    fn does_rule_apply(&self, selector: &Selector) -> bool {
-        match selector {
-            Selector::Simple(s) => self.does_simple_selector_apply(s),
-            Selector::Compound(sels) => sels.iter().any(|s| self.does_simple_selector_apply(s)),
-            // TODO: Implement combinators
-            Selector::Combinator(_, _, _) => false,
-        }
+    /// Whether the cascade gave this element `display: none`, so it and its descendants
+    /// shouldn't be shown
+    pub fn is_hidden(&self) -> bool {
+        self.styles.get("display") == Some(&Value::Keyword("none"))
     }
 
-    /// Check if the provided [`SimpleSelector`] selects this element
-    fn does_simple_selector_apply(&self, selector: &SimpleSelector) -> bool {
-        match selector {
-            SimpleSelector::Type(name) => &self.name == name,
-            SimpleSelector::Universal => true,
-            // TODO: Implement
-            SimpleSelector::Attribute(_) => false,
-            SimpleSelector::Class(name) => self.has_class(name),
-            // TODO: Implement
-            SimpleSelector::PseudoClass(_) => false,
-            SimpleSelector::ID(id) => self.id_is(id),
-        }
+    /// Check if the provided [`Selector`] selects this element
+    fn does_rule_apply(&self, document: &Document, selector: &Selector) -> bool {
+        document.matches(self.node, selector)
     }
+}
 
-    /// Check if the `class` attribute is present and contains the specified class
-    fn has_class(&self, class: &str) -> bool {
-        let class: String = class.into();
-        self.attributes
-            .0
-            .get("class")
-            .map(|c: &String| c.split_whitespace().any(|c| *c == class))
-            .unwrap_or(false)
-    }
+#[cfg(test)]
+#[test]
+fn test_does_apply() {
+    use css::{compound_selector, simple_selector};
+    let element = |input, name| {
+        let (_, doc) = html::document(input).unwrap();
+        let id = doc.get_elements_by_name(doc.document_element(), name, true)[0];
+        let elt = StyledElement::new(&doc, id);
+        (doc, elt)
+    };
+    let (doc, dom) = element("<div>", "div");
+    let style: Selector = Selector::Simple(simple_selector!(div));
+    assert!(dom.does_rule_apply(&doc, &style));
+
+    let (doc, dom) = element("<p>", "p");
+    assert!(!dom.does_rule_apply(&doc, &style));
+
+    let style: Selector = compound_selector!(simple_selector!(div), simple_selector!(.wide));
+    let (doc, dom) = element("<p class=wide>", "p");
+    assert!(!dom.does_rule_apply(&doc, &style));
+    let (doc, dom) = element("<div class=wide>", "div");
+    assert!(dom.does_rule_apply(&doc, &style));
+
+    // Type selectors are case-insensitive, and pseudo-classes match outside of combinators
+    let style = &css::selectors("DIV:first-child").unwrap()[0];
+    let (doc, dom) = element("<div>", "div");
+    assert!(dom.does_rule_apply(&doc, style));
+    let (doc, dom) = element("<p></p><div>", "div");
+    assert!(!dom.does_rule_apply(&doc, style));
+}
 
-    /// Check if the `id` attribute exists and is an exact match for the provided ID
-    fn id_is(&self, id: &str) -> bool {
-        let id: String = id.into();
-        self.attributes
-            .0
-            .get("id")
-            .map(|c| c == &id)
-            .unwrap_or(false)
+/// Style the document `input` with `sheets` as it's shown on `device`, and return the styles of
+/// the element whose id is `name`, or else of the first element named `name`
+#[cfg(test)]
+fn styles_of<'a>(
+    input: &str,
+    sheets: &[(&Stylesheet<'a>, Origin)],
+    device: &Device,
+    name: &str,
+) -> StyleMap<'a> {
+    let (_, doc) = html::document(input).unwrap();
+    let id = doc
+        .get_element_by_id(name)
+        .unwrap_or_else(|| doc.get_elements_by_name(doc.document_element(), name, true)[0]);
+    let mut root = StyledElement::new(&doc, doc.document_element());
+    let layers = &mut CascadeLayers::default();
+    for &(sheet, origin) in sheets {
+        root.apply_styles(&doc, sheet, origin, device, layers, &Layer::default());
     }
+    find_styled(&root, id)
+        .expect("Element is styled")
+        .styles
+        .clone()
+}
 
-    /// Apply a list of [`Declaration`]s to a node and all its children recursively
-    /// `inherit_all`: Whether to apply *every* declaration to children. If this is `false`, only
-    /// properties specified in [value@INHERITED] will be applied recursively
-    fn apply_rule_unconditionally<'b>(
-        &'b mut self,
-        declarations: &[Declaration<'a>],
-        spec: Specificity,
-        inherit_all: bool,
-    ) where
-        'a: 'b,
-    {
-        for decl in declarations {
-            let (name, value) = (decl.name, decl.value.clone());
-            self.insert(name, value, spec);
-        }
-        let inherited: Vec<Declaration> = if inherit_all {
-            // If this is true, we aren't in the top level and our declarations have already been
-            // filtered
-            declarations.to_vec()
-        } else {
-            declarations
-                .iter()
-                .cloned()
-                .filter(|d| INHERITED.contains(&d.name))
-                .collect()
-        };
-        self.contents.iter_mut().for_each(|content| {
-            if let StyledContent::Element(elt) = content {
-                elt.apply_rule_unconditionally(inherited.as_slice(), spec, true)
-            }
-        });
+/// The styled element for the node `id` in the tree under `elt`
+#[cfg(test)]
+fn find_styled<'e, 'a>(elt: &'e StyledElement<'a>, id: NodeId) -> Option<&'e StyledElement<'a>> {
+    if elt.node == id {
+        return Some(elt);
     }
+    elt.contents.iter().find_map(|c| match c {
+        StyledContent::Element(e) => find_styled(e, id),
+        _ => None,
+    })
 }
 
 #[cfg(test)]
 #[test]
-fn test_does_apply() {
-    use {
-        css::{compound_selector, simple_selector},
-        html::attributes,
+fn test_combinators() {
+    let input =
+        "<div><p id=a>a</p><p id=b class=wide>b</p></div><p id=c>c</p><p id=d class=wide>d</p>";
+    let sheet = stylesheet("div > p + .wide { color: red }");
+    let device = Device::screen(1600.0, 1080.0);
+    let colored = |id| {
+        styles_of(input, &[(&sheet, Origin::Author)], &device, id)
+            .get("color")
+            .is_some()
     };
-    let dom: StyledElement = DOMElement::new("div", None, vec![]).into();
-    let style: Selector = Selector::Simple(simple_selector!(div));
-    assert!(dom.does_rule_apply(&style));
+    assert_eq!(
+        ["a", "b", "c", "d"].map(colored),
+        [false, true, false, false]
+    );
+}
 
-    let dom: StyledElement = DOMElement::new("p", None, vec![]).into();
-    assert!(!dom.does_rule_apply(&style));
+#[cfg(test)]
+#[test]
+fn test_important() {
+    let user_agent = stylesheet("p { margin: 1px !important; color: red }");
+    let author = stylesheet(
+        "p { color: blue !important; margin: 2px !important }
+        .b { color: green }
+        #a { color: black !important }",
+    );
+    let p = styles_of(
+        "<div id=a><p class=b>a</p></div>",
+        &[(&user_agent, Origin::UserAgent), (&author, Origin::Author)],
+        &Device::screen(1600.0, 1080.0),
+        "p",
+    );
+    // Important user agent declarations beat important author ones
+    assert_eq!(p.get("margin"), Some(&Value::Length(1.0, css::Unit::Px)));
+    // An important author declaration beats one with a more specific selector, and the
+    // importance of the `div`'s color isn't inherited
+    assert_eq!(p.get("color"), Some(&Value::Keyword("blue")));
+}
 
-    let style: Selector = compound_selector!(simple_selector!(div), simple_selector!(.wide));
-    let dom: StyledElement = DOMElement::new("p", Some(attributes! {class=>wide}), vec![]).into();
-    assert!(dom.does_rule_apply(&style));
+#[cfg(test)]
+#[test]
+fn test_partial_document() {
+    // The document can be styled while it is still being parsed
+    let sheet = stylesheet("p { color: red }");
+    let mut parser = html::Parser::new();
+    parser.feed("<div><p>One");
+    let doc = parser.document();
+    let mut root = StyledElement::new(doc, doc.document_element());
+    root.apply_styles(
+        doc,
+        &sheet,
+        Origin::Author,
+        &Device::screen(1600.0, 1080.0),
+        &mut CascadeLayers::default(),
+        &Layer::default(),
+    );
+    let p = doc.get_elements_by_name(doc.document_element(), "p", true)[0];
+    let styled = find_styled(&root, p).expect("Element is styled");
+    assert_eq!(styled.styles.get("color"), Some(&Value::Keyword("red")));
+    parser.feed("</p><p>Two</div>");
+    let doc = parser.finish();
+    assert_eq!(
+        doc.get_elements_by_name(doc.document_element(), "p", true)
+            .len(),
+        2
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_important_display() {
+    let (_, doc) = html::document("<p id=a>a</p><p>b</p>").unwrap();
+    let sheet =
+        stylesheet("#a { display: none } p { display: block !important } p { display: none }");
+    let mut root = StyledElement::new(&doc, doc.document_element());
+    root.apply_styles(
+        &doc,
+        &sheet,
+        Origin::Author,
+        &Device::screen(1600.0, 1080.0),
+        &mut CascadeLayers::default(),
+        &Layer::default(),
+    );
+    let body = match &root.contents[0] {
+        StyledContent::Element(body) => body,
+        _ => unreachable!(),
+    };
+    // `display: none` is cascaded like any other declaration, so it can be overridden
+    let hidden: Vec<bool> = body
+        .contents
+        .iter()
+        .map(|c| match c {
+            StyledContent::Element(p) => p.is_hidden(),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(hidden, [false, false]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_inheritance() {
+    let input = "<div id=a><p id=x class=b>a</p><p id=y>b</p></div>";
+    let sheet = stylesheet("#a { color: black } .b { color: green }");
+    let device = Device::screen(1600.0, 1080.0);
+    let color = |id| {
+        styles_of(input, &[(&sheet, Origin::Author)], &device, id)
+            .get("color")
+            .cloned()
+    };
+    // An element's own declarations beat inherited values, however specific their selectors
+    assert_eq!(color("x"), Some(Value::Keyword("green")));
+    assert_eq!(color("y"), Some(css::keyword_to_value("black")));
+}
+
+#[cfg(test)]
+#[test]
+fn test_layer_precedence() {
+    let sheet = stylesheet(
+        "@layer base, theme;
+        @layer theme { .b { color: green } #a { padding: 1px } #a.b { margin: 2px !important } }
+        @layer base { #a { color: red } p { margin: 1px !important } }
+        p { padding: 2px } #a.b { margin: 3px !important }",
+    );
+    let p = styles_of(
+        "<p id=a class=b>a</p>",
+        &[(&sheet, Origin::Author)],
+        &Device::screen(1600.0, 1080.0),
+        "a",
+    );
+    // Later layers win over earlier ones, and unlayered rules over layered ones, whatever the
+    // specificity of their selectors
+    assert_eq!(p.get("color"), Some(&Value::Keyword("green")));
+    let px = |n| Some(Value::Length(n, css::Unit::Px));
+    assert_eq!(p.get("padding").cloned(), px(2.0));
+    // For important declarations, earlier layers win
+    assert_eq!(p.get("margin").cloned(), px(1.0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_layers_and_supports() {
+    let sheet = stylesheet(
+        "p { color: blue }
+        @layer base { p { color: red; margin: 1px } }
+        @supports (display: block) and selector(p > a) { p { padding: 1px } }
+        @supports not (display: block) { p { border: 1px } }
+        @supports not (display: grid) { p { width: 1px } }",
+    );
+    let p = styles_of(
+        "<p>a</p>",
+        &[(&sheet, Origin::Author)],
+        &Device::screen(1600.0, 1080.0),
+        "p",
+    );
+    // Rules outside of layers win over those in them, even when they're written first
+    assert_eq!(p.get("color"), Some(&Value::Keyword("blue")));
+    let px = Some(&Value::Length(1.0, css::Unit::Px));
+    assert_eq!(p.get("margin"), px);
+    assert_eq!(p.get("padding"), px);
+    assert_eq!(p.get("border"), None);
+    // Fallbacks for features browsah doesn't implement are used
+    assert_eq!(p.get("width"), px);
+}
+
+#[cfg(test)]
+#[test]
+fn test_media() {
+    let sheet = stylesheet(
+        "p { color: red }
+        @media (max-width: 600px) { p { color: blue } }
+        @media print { p { display: none } }",
+    );
+    // Whether the paragraph is blue when shown on `device`
+    let blue = |device: &Device| {
+        styles_of("<p>a</p>", &[(&sheet, Origin::Author)], device, "p").get("color")
+            == Some(&Value::Keyword("blue"))
+    };
+    assert!(!blue(&Device::screen(1600.0, 1080.0)));
+    assert!(blue(&Device::screen(400.0, 800.0)));
 }
 
 // Taken from https://chromium.googlesource.com/chromium/blink/+/refs/heads/main/Source/core/css/html.css
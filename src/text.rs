@@ -0,0 +1,299 @@
+use crate::layout::{get_white_space, WhiteSpace};
+use crate::style::{StyledContent, StyledElement};
+use css::Value;
+use html::Document;
+
+/// Elements which are inline when no stylesheet says otherwise
+static INLINE: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "br", "cite", "code", "data", "dfn", "em", "font", "i", "img",
+    "input", "kbd", "label", "mark", "q", "s", "samp", "select", "small", "span", "strong", "sub",
+    "sup", "time", "tt", "u", "var", "button", "textarea",
+];
+
+/// Block elements which are set apart from their surroundings by a blank line
+static PARAGRAPHS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "pre",
+    "ul",
+    "ol",
+    "dl",
+    "table",
+    "figure",
+    "hr",
+];
+
+/// How an element's text is laid out, from its `display`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Display {
+    Block,
+    Inline,
+    ListItem,
+    TableRow,
+    TableCell,
+}
+
+impl Display {
+    fn of(element: &StyledElement, name: &str) -> Self {
+        match element.styles.get("display") {
+            Some(Value::Keyword(display)) => match *display {
+                "inline" | "inline-block" | "inline-flex" | "inline-grid" | "inline-table" => {
+                    Display::Inline
+                }
+                "list-item" => Display::ListItem,
+                "table-row" => Display::TableRow,
+                "table-cell" => Display::TableCell,
+                _ => Display::Block,
+            },
+            // Without a stylesheet, fall back to how HTML elements are usually displayed
+            _ => match name {
+                "li" => Display::ListItem,
+                "tr" => Display::TableRow,
+                "td" | "th" => Display::TableCell,
+                name if INLINE.contains(&name) => Display::Inline,
+                _ => Display::Block,
+            },
+        }
+    }
+}
+
+/// Get the text a styled tree displays, with a line per block and list items and table cells
+/// laid out in a way that reads well as plain text
+pub fn extract(document: &Document, root: &StyledElement) -> String {
+    let mut writer = TextWriter::default();
+    writer.element(document, root);
+    writer.text
+}
+
+#[derive(Default)]
+struct TextWriter {
+    text: String,
+    // Collapsed whitespace which is only written if more text follows on the line
+    pending_space: bool,
+    // Line breaks which are only written if more text follows
+    pending_lines: usize,
+    // The next item number of each list the writer is in, or `None` for unordered lists
+    lists: Vec<Option<i64>>,
+    // Whether the next table cell is the first in its row
+    first_cell: bool,
+}
+
+impl TextWriter {
+    fn element(&mut self, document: &Document, element: &StyledElement) {
+        let data = match document.element(element.node) {
+            Some(data) if !element.is_hidden() => data,
+            _ => return,
+        };
+        let name = data.name.as_str();
+        let display = Display::of(element, name);
+        // `white-space` is inherited, so this is the same as layout uses for the element's text
+        let white_space = get_white_space(&element.styles);
+        // Lists inside lists aren't set apart
+        let nested_list = matches!(name, "ul" | "ol") && !self.lists.is_empty();
+        let lines = if PARAGRAPHS.contains(&name) && !nested_list {
+            2
+        } else {
+            1
+        };
+
+        match display {
+            Display::Inline => {}
+            Display::Block => self.block_break(lines),
+            Display::ListItem => {
+                self.block_break(1);
+                let depth = self.lists.len().max(1) - 1;
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "* ".to_string(),
+                };
+                self.write_raw(&format!("{}{}", "  ".repeat(depth), marker));
+            }
+            Display::TableRow => {
+                self.block_break(1);
+                self.first_cell = true;
+            }
+            Display::TableCell => {
+                if !self.first_cell {
+                    self.write_raw("\t");
+                }
+                self.first_cell = false;
+            }
+        }
+        match name {
+            "br" => self.line_break(),
+            "img" => {
+                if let Some(alt) = data.get_attribute("alt") {
+                    self.write(alt, white_space);
+                }
+            }
+            "ul" => self.lists.push(None),
+            "ol" => {
+                let start = data
+                    .get_attribute("start")
+                    .and_then(|s| s.trim().parse().ok())
+                    .unwrap_or(1);
+                self.lists.push(Some(start));
+            }
+            _ => {}
+        }
+
+        for content in &element.contents {
+            match content {
+                StyledContent::Element(child) => self.element(document, child),
+                StyledContent::Text(text) => self.write(&text.contents, white_space),
+            }
+        }
+
+        if matches!(name, "ul" | "ol") {
+            self.lists.pop();
+        }
+        if matches!(
+            display,
+            Display::Block | Display::ListItem | Display::TableRow
+        ) {
+            self.block_break(lines);
+        }
+    }
+
+    /// End the current line, and leave `lines - 1` blank lines before any more text
+    fn block_break(&mut self, lines: usize) {
+        self.pending_lines = self.pending_lines.max(lines);
+        self.pending_space = false;
+    }
+
+    /// Start a new line, even if the current one is empty
+    fn line_break(&mut self) {
+        self.flush();
+        self.text.push('\n');
+        self.pending_space = false;
+    }
+
+    /// Write any line breaks and space which are waiting for more text
+    fn flush(&mut self) {
+        if !self.text.is_empty() {
+            let newlines = self.text.chars().rev().take_while(|&c| c == '\n').count();
+            let needed = self.pending_lines.saturating_sub(newlines);
+            self.text.push_str(&"\n".repeat(needed));
+        }
+        self.pending_lines = 0;
+        if self.pending_space {
+            self.text.push(' ');
+            self.pending_space = false;
+        }
+    }
+
+    /// Write text without processing its whitespace
+    fn write_raw(&mut self, text: &str) {
+        self.flush();
+        self.text.push_str(text);
+    }
+
+    /// Write text, collapsing its whitespace as `white_space` says to
+    fn write(&mut self, text: &str, white_space: WhiteSpace) {
+        match white_space {
+            WhiteSpace::Pre | WhiteSpace::PreWrap => {
+                if !text.is_empty() {
+                    self.write_raw(text);
+                }
+            }
+            WhiteSpace::Normal | WhiteSpace::Nowrap | WhiteSpace::PreLine => {
+                for c in text.chars() {
+                    if c == '\n' && white_space == WhiteSpace::PreLine {
+                        self.line_break();
+                    } else if c.is_ascii_whitespace() {
+                        // Spaces at the start of a line are dropped
+                        self.pending_space = self.pending_lines == 0
+                            && !self.text.is_empty()
+                            && !self.text.ends_with(['\n', ' ', '\t']);
+                    } else {
+                        self.flush();
+                        self.text.push(c);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_extract() {
+    use crate::style::{Origin, USER_AGENT_CSS};
+    let text = |html: &str, css: &str| {
+        let (_, document) = html::document(html).unwrap();
+        let sheet = css::stylesheet(css);
+        let device = css::Device::screen(1600.0, 1080.0);
+        let mut root = StyledElement::new(&document, document.document_element());
+        let layers = &mut css::CascadeLayers::default();
+        root.apply_styles(
+            &document,
+            &USER_AGENT_CSS,
+            Origin::UserAgent,
+            &device,
+            layers,
+            &css::Layer::default(),
+        );
+        root.apply_styles(
+            &document,
+            &sheet,
+            Origin::Author,
+            &device,
+            layers,
+            &css::Layer::default(),
+        );
+        extract(&document, &root)
+    };
+    assert_eq!(
+        text(
+            "<title>Title</title><h1>Heading</h1><p>Some <b>bold</b>\n  text<br>Next</p>\
+             <div>A</div><div><div>B</div></div>",
+            ""
+        ),
+        "Heading\n\nSome bold text\nNext\n\nA\nB"
+    );
+    assert_eq!(
+        text(
+            "<ul><li>One<li>Two<ol start=3><li>Three<li>Four</ol></ul>",
+            ""
+        ),
+        "* One\n* Two\n  3. Three\n  4. Four"
+    );
+    assert_eq!(
+        text(
+            "<table><tr><th>Name<th>Value<tr><td>a<td><img alt=1></table>",
+            ""
+        ),
+        "Name\tValue\na\t1"
+    );
+    assert_eq!(
+        text(
+            "<pre>  a\n   b</pre><p class=hidden>Hidden</p>",
+            ".hidden { display: none }"
+        ),
+        "  a\n   b"
+    );
+    // Whitespace is handled as the computed `white-space` says, whatever the element
+    assert_eq!(
+        text(
+            "<pre>  a\n   b</pre><div class=pre>  c\n <b> d</b></div>",
+            "pre { white-space: normal } .pre { white-space: pre }"
+        ),
+        "a b\n\n  c\n  d"
+    );
+    assert_eq!(
+        text(
+            "<span>Inline</span><em class=block>Block</em>",
+            ".block { display: block }"
+        ),
+        "Inline\nBlock"
+    );
+}
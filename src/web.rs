@@ -1,98 +1,944 @@
-use std::cell::RefCell;
-use tracing::{info, span, Level};
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::rc::Rc;
+use tracing::{debug, info, span, Level};
 use url::Url;
 
-use crate::style::{StyledElement, USER_AGENT_CSS};
-use css::Stylesheet;
-use html::{self, DOMContent, DOMElement};
+use crate::metadata::{self, Alternate, OpenGraph, Resource, ResourceKind, Robots, Viewport};
+use crate::style::{Origin, StyledElement, USER_AGENT_CSS};
+use css::{CascadeLayers, Device, Layer, Stylesheet};
+use html::{self, Document, ElementData, Namespace, NodeData, NodeId};
 
 pub struct Page<'a> {
     url: Url,
-    dom: DOMElement,
+    // The URL relative URLs in the document are resolved against
+    base_url: Url,
+    document: Document,
+    // What the page is shown on, which decides which media queries match
+    device: Device,
+    // The cascade layers declared by the stylesheets of each origin so far
+    layers: RefCell<HashMap<Origin, CascadeLayers>>,
     pub style_tree: RefCell<StyledElement<'a>>,
 }
 
+/// The cascade layer an `@import` put a stylesheet in, inside the layer of the stylesheet which
+/// imported it. The layer is only declared once the stylesheet is applied, so that layers are
+/// in the order they first appear in the cascade
+#[derive(Debug)]
+pub struct ImportLayer {
+    parent: Option<Rc<ImportLayer>>,
+    // `None` for an anonymous layer
+    name: Option<String>,
+    layer: OnceCell<Layer>,
+}
+
+impl ImportLayer {
+    /// The layer among `layers`, declaring it and the layers it's in if they haven't been
+    fn resolve(&self, layers: &mut CascadeLayers) -> Layer {
+        self.layer
+            .get_or_init(|| {
+                let parent = self
+                    .parent
+                    .as_ref()
+                    .map(|p| p.resolve(layers))
+                    .unwrap_or_default();
+                layers.layer(&parent, self.name.as_deref())
+            })
+            .clone()
+    }
+}
+
+/// The text of a stylesheet of a page, and where it came from
+struct PageStylesheet {
+    text: String,
+    // The URL it was fetched from, or `None` for the text of a `<style>`
+    url: Option<Url>,
+    // The `<link>` or `<style>` which brought it into the page, directly or through imports
+    element: NodeId,
+    // The layer it was imported into, if any
+    layer: Option<Rc<ImportLayer>>,
+}
+
 impl<'a> Page<'a> {
-    /// Browses to and parses a web page without applying style information (except for the default)
-    pub fn browse(url: &str) -> Self {
+    /// Browses to and parses a web page without applying style information (except for the default).
+    /// The page is parsed as it downloads, so it is never held in memory whole, but styles are
+    /// only applied once all of it has been parsed
+    pub fn browse(url: &str, device: Device) -> Self {
         let url = Url::parse(url).expect("Could not parse URL");
-        info!("Downloading HTML");
-        let resp = Page::get_text_resource(url.as_str()).expect("Could not get page");
-        info!("Parsing HTML");
-        let doc = html::document(resp.as_str())
-            .expect("Could not parse HTML")
-            .1;
+        info!("Downloading and parsing HTML");
+        let (reader, content_type) = Page::open_resource(url.as_str()).expect("Could not get page");
+        // The start of a page which doesn't declare its encoding is kept, in case it has to be
+        // parsed again
+        let mut parser = html::Parser::new();
+        if let Some(content_type) = content_type {
+            parser.set_content_type(&content_type);
+        }
+        parser.read_from(reader).expect("Could not read page");
+        if let Some(encoding) = parser.encoding() {
+            debug!("Parsed HTML as {}", encoding.name());
+        }
+        let doc = parser.finish();
+        doc.errors
+            .iter()
+            .for_each(|e| debug!("HTML parse error: {}", e));
         info!("Constructing page");
-        let page = Self::from_dom(doc, url);
-        page.style_tree
-            .borrow_mut()
-            .apply_styles(&USER_AGENT_CSS.rules);
+        let page = Self::from_document(doc, url, device);
+        page.apply_styles(&USER_AGENT_CSS, Origin::UserAgent, None);
         page
     }
 
-    pub fn from_dom(dom: DOMElement, url: Url) -> Self {
-        let style = RefCell::new(dom.clone().into());
-        Self {
+    pub fn from_document(document: Document, url: Url, device: Device) -> Self {
+        let style = RefCell::new(StyledElement::new(&document, document.document_element()));
+        let mut page = Self {
+            base_url: url.clone(),
             url,
-            dom,
+            document,
+            device,
+            layers: RefCell::default(),
             style_tree: style,
+        };
+        page.base_url = page.document_base_url();
+        page
+    }
+
+    /// The URL of the document, or the `href` of its `<base>` element resolved against it.
+    /// <https://html.spec.whatwg.org/multipage/urls-and-fetching.html#document-base-url>
+    fn document_base_url(&self) -> Url {
+        self.base_href()
+            .and_then(|href| self.url.join(href).ok())
+            .filter(|base| !["data", "javascript"].contains(&base.scheme()))
+            .unwrap_or_else(|| self.url.clone())
+    }
+
+    /// The text of the document's first HTML `<title>` element, with whitespace collapsed. The
+    /// `<title>`s of SVG images in the page are left out
+    pub fn title(&self) -> Option<String> {
+        let document = &self.document;
+        let title = document
+            .get_elements_by_name(document.document_element(), "title", true)
+            .into_iter()
+            .find(|&t| document.element(t).map(|e| e.namespace) == Some(Namespace::Html))?;
+        let text = document.text_content(title);
+        Some(text.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    /// The `href` of the document's first `<base>` element which has one
+    pub fn base_href(&self) -> Option<&str> {
+        let document = &self.document;
+        document
+            .get_elements_by_name(document.document_element(), "base", true)
+            .into_iter()
+            .find_map(|b| document.element(b)?.get_attribute("href"))
+            .map(String::as_str)
+    }
+
+    /// The elements named `name` directly in the `<head>`
+    fn head_elements(&self, name: &str) -> Vec<NodeId> {
+        let document = &self.document;
+        match document
+            .get_elements_by_name(document.document_element(), "head", false)
+            .first()
+        {
+            Some(&head) => document.get_elements_by_name(head, name, false),
+            None => vec![],
         }
     }
 
-    pub fn get_stylesheet_text(&self) -> Vec<String> {
+    /// The `<link>` elements which have `rel` among their relations
+    fn link_elements(&self, rel: &str) -> Vec<(NodeId, &ElementData)> {
+        self.head_elements("link")
+            .into_iter()
+            .filter_map(|l| Some((l, self.document.element(l)?)))
+            .filter(|(_, l)| {
+                l.get_attribute("rel").map(|r| {
+                    r.split_ascii_whitespace()
+                        .any(|r| r.eq_ignore_ascii_case(rel))
+                }) == Some(true)
+            })
+            .collect()
+    }
+
+    /// Get the text of the page's stylesheets in the order they cascade, with the stylesheets
+    /// brought in by `@import` just before the stylesheet which imports them. Each comes with
+    /// the layer it was imported into, if any
+    pub fn get_stylesheet_text(&self) -> Vec<(String, Option<Rc<ImportLayer>>)> {
+        self.stylesheets()
+            .into_iter()
+            .map(|sheet| (sheet.text, sheet.layer))
+            .collect()
+    }
+
+    /// The page's stylesheets in the order they cascade, along with where each came from
+    fn stylesheets(&self) -> Vec<PageStylesheet> {
         info!("Getting stylesheets");
         let mut sheets = Vec::new();
-        if let Some(head) = self.dom.get_elements_by_name("head", false).get(0) {
-            head.get_elements_by_name("link", false)
-                .iter()
-                .filter(|l| l.get_attribute("rel") == Some(&"stylesheet".into()))
-                .for_each(|s| {
-                    if let Some(href) = s.get_attribute("href") {
-                        if let Ok(resource) = self.get_linked_text_resource(href) {
-                            sheets.push(resource);
-                        }
-                    }
-                });
-            head.get_elements_by_name("style", false)
-                .iter()
-                .for_each(|e| {
-                    if let Some(DOMContent::Text(t)) = e.contents.get(0).cloned() {
-                        sheets.push(t);
-                    }
-                })
-        }
+        let document = &self.document;
+        let alternates = self.link_elements("alternate");
+        self.link_elements("stylesheet")
+            .into_iter()
+            // Alternative stylesheets are only used once chosen by the user
+            .filter(|s| !alternates.contains(s))
+            .for_each(|(id, s)| {
+                if let Some(url) = s
+                    .get_attribute("href")
+                    .and_then(|h| self.resolve_url(h).ok())
+                {
+                    self.push_linked_stylesheet(url, id, None, &mut sheets, &mut vec![]);
+                }
+            });
+        self.head_elements("style").into_iter().for_each(|e| {
+            let first = document
+                .node(e)
+                .first_child()
+                .map(|c| &document.node(c).data);
+            if let Some(NodeData::Text(t)) = first {
+                let sheet = PageStylesheet {
+                    text: t.clone(),
+                    url: None,
+                    element: e,
+                    layer: None,
+                };
+                self.push_stylesheet(sheet, &mut sheets, &mut vec![]);
+            }
+        });
         sheets
     }
 
-    pub fn get_styles(&'a self, styles: &'a [String]) -> Vec<Stylesheet> {
+    /// Fetch the stylesheet at `url` and add it to `sheets` in `layer`. `element` is the
+    /// `<link>` or `<style>` whose stylesheet led to this one, and `importing` is the chain of
+    /// stylesheets whose imports did, which is used to stop import cycles
+    fn push_linked_stylesheet(
+        &self,
+        url: Url,
+        element: NodeId,
+        layer: Option<Rc<ImportLayer>>,
+        sheets: &mut Vec<PageStylesheet>,
+        importing: &mut Vec<Url>,
+    ) {
+        if importing.contains(&url) {
+            debug!("Skipping stylesheet {} which imports itself", url);
+            return;
+        }
+        match Page::get_text_resource(url.as_str()) {
+            Ok(text) => {
+                importing.push(url.clone());
+                let sheet = PageStylesheet {
+                    text,
+                    url: Some(url),
+                    element,
+                    layer,
+                };
+                self.push_stylesheet(sheet, sheets, importing);
+                importing.pop();
+            }
+            Err(e) => debug!("Could not get stylesheet {}: {}", url, e),
+        }
+    }
+
+    /// Add `sheet` to `sheets`, after the stylesheets it imports. Imports whose media queries
+    /// don't match the page's device are left out
+    fn push_stylesheet(
+        &self,
+        sheet: PageStylesheet,
+        sheets: &mut Vec<PageStylesheet>,
+        importing: &mut Vec<Url>,
+    ) {
+        let PageStylesheet { layer, element, .. } = &sheet;
+        let url = sheet.url.as_ref().unwrap_or(&self.base_url);
+        for import in css::stylesheet(&sheet.text).imports {
+            if !import.media.matches(&self.device) {
+                debug!("Skipping import {} for other media", import.url);
+                continue;
+            }
+            let import_layer = match import.layer {
+                Some(name) => Some(Rc::new(ImportLayer {
+                    parent: layer.clone(),
+                    name: name.map(str::to_string),
+                    layer: OnceCell::new(),
+                })),
+                None => layer.clone(),
+            };
+            match url.join(&import.url) {
+                Ok(import_url) => self.push_linked_stylesheet(
+                    import_url,
+                    *element,
+                    import_layer,
+                    sheets,
+                    importing,
+                ),
+                Err(e) => debug!("Could not resolve import {}: {}", import.url, e),
+            }
+        }
+        sheets.push(sheet);
+    }
+
+    /// Apply the rules of a stylesheet from `origin` which match the page's device to the style
+    /// tree. `layer` is the layer the stylesheet was imported into, if any
+    pub fn apply_styles(
+        &self,
+        sheet: &Stylesheet<'a>,
+        origin: Origin,
+        layer: Option<&ImportLayer>,
+    ) {
+        let mut layers = self.layers.borrow_mut();
+        let layers = layers.entry(origin).or_default();
+        let layer = layer.map(|l| l.resolve(layers)).unwrap_or_default();
+        self.style_tree.borrow_mut().apply_styles(
+            &self.document,
+            sheet,
+            origin,
+            &self.device,
+            layers,
+            &layer,
+        );
+    }
+
+    pub fn get_styles(
+        &'a self,
+        styles: &'a [(String, Option<Rc<ImportLayer>>)],
+    ) -> Vec<Stylesheet<'a>> {
         info!("Parsing stylesheets");
-        styles
-            .iter()
-            .filter_map(|s| css::stylesheet(s).ok())
-            .map(|(_, s)| s)
-            .collect()
+        styles.iter().map(|(s, _)| css::stylesheet(s)).collect()
+    }
+
+    /// The text the page displays, as plain text
+    pub fn text(&self) -> String {
+        crate::text::extract(&self.document, &self.style_tree.borrow())
     }
 
+    /// Resolve a URL from the document, such as a link or an image source
     fn resolve_url(&self, url: &str) -> Result<Url, url::ParseError> {
-        self.url.join(url)
+        self.base_url.join(url)
     }
 
-    fn get_text_resource(url: &str) -> Result<String, ureq::Error> {
+    /// Start fetching a resource, returning a reader for its body along with the `Content-Type`
+    /// it was served with if any
+    #[allow(clippy::result_large_err)]
+    fn open_resource(url: &str) -> Result<(Box<dyn Read>, Option<String>), ureq::Error> {
         let url = Url::parse(url).expect("Could not parse URL");
         let span = span!(Level::DEBUG, "Loading resource", "{}", &url);
         let _enter = span.enter();
         if url.scheme() == "file" {
-            Ok(std::fs::read_to_string(url.path()).expect("Could not access file"))
+            // Decodes percent-escapes, like `%20` for a space
+            let path = url.to_file_path().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Not a local file path")
+            })?;
+            let file = File::open(path)?;
+            Ok((Box::new(BufReader::new(file)), None))
         } else {
-            Ok(ureq::get(url.as_str())
-                .call()?
-                .into_string()
-                .expect("Could not get text"))
+            let resp = ureq::get(url.as_str()).call()?;
+            let content_type = resp.header("Content-Type").map(str::to_string);
+            Ok((resp.into_reader(), content_type))
+        }
+    }
+
+    /// Fetch a resource, along with the `Content-Type` it was served with if any
+    #[allow(clippy::result_large_err)]
+    fn get_resource(url: &str) -> Result<(Vec<u8>, Option<String>), ureq::Error> {
+        let (mut reader, content_type) = Page::open_resource(url)?;
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Ok((bytes, content_type))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn get_text_resource(url: &str) -> Result<String, ureq::Error> {
+        let (bytes, _) = Page::get_resource(url)?;
+        // TODO: Detect the encoding of stylesheets from `@charset`
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+// Metadata isn't needed for rendering, but is for crawling
+impl<'a> Page<'a> {
+    /// The `content` of the first `<meta>` with the `name` (or `property`) `name`
+    pub fn meta(&self, name: &str) -> Option<&str> {
+        self.head_elements("meta")
+            .into_iter()
+            .filter_map(|m| self.document.element(m))
+            .find(|m| {
+                let matches = |attribute| {
+                    m.get_attribute(attribute)
+                        .map(|n| n.eq_ignore_ascii_case(name))
+                        == Some(true)
+                };
+                matches("name") || matches("property")
+            })?
+            .get_attribute("content")
+            .map(String::as_str)
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.meta("description")
+    }
+
+    pub fn robots(&self) -> Robots {
+        self.meta("robots").map(Robots::parse).unwrap_or_default()
+    }
+
+    pub fn viewport(&self) -> Option<Viewport> {
+        self.meta("viewport").map(Viewport::parse)
+    }
+
+    pub fn open_graph(&self) -> OpenGraph {
+        let property = |name| self.meta(name).map(str::to_string);
+        OpenGraph {
+            title: property("og:title"),
+            description: property("og:description"),
+            kind: property("og:type"),
+            url: property("og:url"),
+            image: property("og:image"),
+            site_name: property("og:site_name"),
+        }
+    }
+
+    /// The URLs of the `<link>` elements which have `rel` among their relations
+    pub fn links(&self, rel: &str) -> Vec<Url> {
+        self.link_elements(rel)
+            .into_iter()
+            .filter_map(|(_, l)| self.resolve_url(l.get_attribute("href")?).ok())
+            .collect()
+    }
+
+    pub fn icon(&self) -> Option<Url> {
+        self.links("icon").into_iter().next()
+    }
+
+    pub fn canonical(&self) -> Option<Url> {
+        self.links("canonical").into_iter().next()
+    }
+
+    pub fn alternates(&self) -> Vec<Alternate> {
+        self.link_elements("alternate")
+            .into_iter()
+            .filter_map(|(_, l)| {
+                Some(Alternate {
+                    url: self.resolve_url(l.get_attribute("href")?).ok()?,
+                    hreflang: l.get_attribute("hreflang").cloned(),
+                    media_type: l.get_attribute("type").cloned(),
+                })
+            })
+            .collect()
+    }
+
+    /// Describe the page's metadata, one `name: value` line per property which is given,
+    /// followed by the URLs it refers to
+    pub fn metadata(&self) -> String {
+        let mut lines = vec![];
+        let mut line = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                lines.push(format!("{}: {}", name, value));
+            }
+        };
+        line("title", self.title());
+        line("description", self.description().map(str::to_string));
+        let robots = self.robots();
+        line("robots:index", Some(robots.index.to_string()));
+        line("robots:follow", Some(robots.follow.to_string()));
+        if let Some(viewport) = self.viewport() {
+            line("viewport:width", viewport.width);
+            line("viewport:height", viewport.height);
+            let scale = |scale: Option<f64>| scale.map(|s| s.to_string());
+            line("viewport:initial-scale", scale(viewport.initial_scale));
+            line("viewport:minimum-scale", scale(viewport.minimum_scale));
+            line("viewport:maximum-scale", scale(viewport.maximum_scale));
+            line(
+                "viewport:user-scalable",
+                viewport.user_scalable.map(|s| s.to_string()),
+            );
+        }
+        let open_graph = self.open_graph();
+        line("og:title", open_graph.title);
+        line("og:description", open_graph.description);
+        line("og:type", open_graph.kind);
+        line("og:url", open_graph.url);
+        line("og:image", open_graph.image);
+        line("og:site_name", open_graph.site_name);
+        line("icon", self.icon().map(String::from));
+        line("canonical", self.canonical().map(String::from));
+        for alternate in self.alternates() {
+            let mut value = alternate.url.to_string();
+            if let Some(hreflang) = alternate.hreflang {
+                value += &format!(" hreflang={}", hreflang);
+            }
+            if let Some(media_type) = alternate.media_type {
+                value += &format!(" type={}", media_type);
+            }
+            line("alternate", Some(value));
+        }
+        for resource in self.resources() {
+            let element = self
+                .document
+                .element(resource.element)
+                .expect("Resources come from elements");
+            line(
+                "resource",
+                Some(format!(
+                    "{} {} <{}>",
+                    resource.kind, resource.url, element.name
+                )),
+            );
+        }
+        lines.join("\n")
+    }
+
+    /// Every URL the document refers to, in document order. References which can't be
+    /// resolved are left out. The page's stylesheets are fetched as they are for styling it,
+    /// and the `@import`s and `url()`s in them come after the element which linked them
+    pub fn resources(&self) -> Vec<Resource> {
+        let document = &self.document;
+        let sheets = self.stylesheets();
+        let mut resources = vec![];
+        let mut add = |base: &Url, url: &str, kind, element| {
+            if let Ok(url) = base.join(url.trim()) {
+                resources.push(Resource { url, kind, element });
+            }
+        };
+        let base = &self.base_url;
+        for id in document.descendants(document.document_node()) {
+            let element = match document.element(id) {
+                Some(e) => e,
+                None => continue,
+            };
+            let attribute = |name| element.get_attribute(name).map(String::as_str);
+            match element.name.as_str() {
+                "a" | "area" => {
+                    if let Some(href) = attribute("href") {
+                        add(base, href, ResourceKind::Hyperlink, id);
+                    }
+                }
+                "img" | "source" => {
+                    if let Some(src) = attribute("src") {
+                        let kind = match element.name.as_str() {
+                            "img" => ResourceKind::Image,
+                            _ => ResourceKind::Media,
+                        };
+                        add(base, src, kind, id);
+                    }
+                    if let Some(srcset) = attribute("srcset") {
+                        metadata::srcset_urls(srcset)
+                            .into_iter()
+                            .for_each(|url| add(base, url, ResourceKind::Image, id));
+                    }
+                }
+                "audio" | "video" => {
+                    if let Some(src) = attribute("src") {
+                        add(base, src, ResourceKind::Media, id);
+                    }
+                }
+                "script" => {
+                    if let Some(src) = attribute("src") {
+                        add(base, src, ResourceKind::Script, id);
+                    }
+                }
+                "link" => {
+                    if let Some(href) = attribute("href") {
+                        add(base, href, ResourceKind::Link, id);
+                    }
+                }
+                "style" => {
+                    for (url, kind) in css_references(&document.text_content(id)) {
+                        add(base, &url, kind, id);
+                    }
+                }
+                _ => {}
+            }
+            if let Some(declarations) = attribute("style").map(css::declarations) {
+                declarations
+                    .iter()
+                    .flat_map(|d| d.value.urls())
+                    .for_each(|url| add(base, url, ResourceKind::CssUrl, id));
+            }
+            // The stylesheets fetched because of this element, resolved against their own URLs
+            for sheet in sheets.iter().filter(|s| s.element == id) {
+                if let Some(sheet_url) = &sheet.url {
+                    for (url, kind) in css_references(&sheet.text) {
+                        add(sheet_url, &url, kind, id);
+                    }
+                }
+            }
+        }
+        resources
+    }
+}
+
+/// The URLs of the `@import`s and `url()`s of a stylesheet
+fn css_references(text: &str) -> Vec<(String, ResourceKind)> {
+    let sheet = css::stylesheet(text);
+    let imports = sheet
+        .imports
+        .iter()
+        .map(|import| (import.url.to_string(), ResourceKind::Import));
+    let urls = sheet
+        .rules
+        .iter()
+        .flat_map(css::Rule::declarations)
+        .flat_map(|d| d.value.urls())
+        .map(|url| (url.to_string(), ResourceKind::CssUrl));
+    imports.chain(urls).collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_title() {
+    let page = |i| {
+        let (_, document) = html::document(i).unwrap();
+        Page::from_document(
+            document,
+            Url::parse("http://example.com").unwrap(),
+            Device::screen(1600.0, 1080.0),
+        )
+    };
+    assert_eq!(
+        page("<title>\n  Fish &amp;\tChips <b> </title>").title(),
+        Some("Fish & Chips <b>".to_string())
+    );
+    assert_eq!(page("<p>Untitled").title(), None);
+    assert_eq!(
+        page("<svg><title>Icon</title></svg><title>Page</title>").title(),
+        Some("Page".to_string())
+    );
+    assert_eq!(page("<svg><title>Icon</title></svg>").title(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_metadata() {
+    let (_, document) = html::document(
+        r#"<head>
+        <base href="https://example.com/a/">
+        <meta name="Description" content="A page">
+        <meta name="robots" content="noindex">
+        <meta name="viewport" content="width=device-width, initial-scale=1">
+        <meta property="og:title" content="Page">
+        <meta property="og:type" content="article">
+        <link rel="shortcut icon" href="/favicon.ico">
+        <link rel=canonical href="https://example.com/page">
+        <link rel=alternate hreflang=fr href="fr/page">
+        <link rel=alternate type="application/rss+xml" href="feed.xml">
+        </head>"#,
+    )
+    .unwrap();
+    let page = Page::from_document(
+        document,
+        Url::parse("http://example.com/b/c").unwrap(),
+        Device::screen(1600.0, 1080.0),
+    );
+    assert_eq!(page.description(), Some("A page"));
+    assert_eq!(page.meta("og:site_name"), None);
+    assert_eq!(
+        page.robots(),
+        Robots {
+            index: false,
+            follow: true
+        }
+    );
+    assert_eq!(page.viewport().unwrap().initial_scale, Some(1.0));
+    assert_eq!(
+        page.open_graph(),
+        OpenGraph {
+            title: Some("Page".to_string()),
+            kind: Some("article".to_string()),
+            ..Default::default()
         }
+    );
+    assert_eq!(page.base_href(), Some("https://example.com/a/"));
+    assert_eq!(
+        page.icon().map(String::from),
+        Some("https://example.com/favicon.ico".to_string())
+    );
+    assert_eq!(
+        page.canonical().map(String::from),
+        Some("https://example.com/page".to_string())
+    );
+    let alternates = page.alternates();
+    let alternates: Vec<(String, Option<&str>, Option<&str>)> = alternates
+        .iter()
+        .map(|a| {
+            (
+                a.url.to_string(),
+                a.hreflang.as_deref(),
+                a.media_type.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        alternates,
+        [
+            (
+                "https://example.com/a/fr/page".to_string(),
+                Some("fr"),
+                None
+            ),
+            (
+                "https://example.com/a/feed.xml".to_string(),
+                None,
+                Some("application/rss+xml")
+            )
+        ]
+    );
+    assert_eq!(
+        page.metadata(),
+        "description: A page
+robots:index: false
+robots:follow: true
+viewport:width: device-width
+viewport:initial-scale: 1
+og:title: Page
+og:type: article
+icon: https://example.com/favicon.ico
+canonical: https://example.com/page
+alternate: https://example.com/a/fr/page hreflang=fr
+alternate: https://example.com/a/feed.xml type=application/rss+xml
+resource: link https://example.com/favicon.ico <link>
+resource: link https://example.com/page <link>
+resource: link https://example.com/a/fr/page <link>
+resource: link https://example.com/a/feed.xml <link>"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_base_url() {
+    let base_url = |i| {
+        let (_, document) = html::document(i).unwrap();
+        let page = Page::from_document(
+            document,
+            Url::parse("http://example.com/a/b").unwrap(),
+            Device::screen(1600.0, 1080.0),
+        );
+        page.resolve_url("c").unwrap().to_string()
+    };
+    assert_eq!(base_url("<p>"), "http://example.com/a/c");
+    assert_eq!(
+        base_url("<base href='https://example.org/x/'>"),
+        "https://example.org/x/c"
+    );
+    // Relative base URLs are resolved against the document's URL
+    assert_eq!(base_url("<base href='../d/'>"), "http://example.com/d/c");
+    // Only the first `<base>` with an `href` is used
+    assert_eq!(
+        base_url("<base target=_blank><base href='/e/'><base href='/f/'>"),
+        "http://example.com/e/c"
+    );
+    assert_eq!(
+        base_url("<base href='javascript:alert(1)'>"),
+        "http://example.com/a/c"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_resources() {
+    let (_, document) = html::document(
+        r#"<head>
+        <base href="https://example.com/a/">
+        <link rel=icon href="style.css">
+        <style>@import "print.css"; body { background: url(bg.png) }
+        @media print { @font-face { src: url(font.woff) } }</style>
+        <script src="/app.js"></script>
+        </head>
+        <body>
+        <a href="page#top">Link</a><a name=anchor>No link</a>
+        <img src="small.png" srcset="large.png 2x, huge.png 3x">
+        <div style="background-image: url('div.png')"></div>
+        <video src="clip.webm"><source src="clip.mp4"></video>
+        <a href="http://[bad">Bad</a>
+        </body>"#,
+    )
+    .unwrap();
+    let page = Page::from_document(
+        document,
+        Url::parse("http://example.com/").unwrap(),
+        Device::screen(1600.0, 1080.0),
+    );
+    let resources: Vec<(String, ResourceKind, &str)> = page
+        .resources()
+        .into_iter()
+        .map(|r| {
+            let element = page.document.element(r.element).unwrap();
+            (r.url.to_string(), r.kind, element.name.as_str())
+        })
+        .collect();
+    let resource =
+        |url: &str, kind, element| (format!("https://example.com{}", url), kind, element);
+    assert_eq!(
+        resources,
+        [
+            resource("/a/style.css", ResourceKind::Link, "link"),
+            resource("/a/print.css", ResourceKind::Import, "style"),
+            resource("/a/bg.png", ResourceKind::CssUrl, "style"),
+            resource("/a/font.woff", ResourceKind::CssUrl, "style"),
+            resource("/app.js", ResourceKind::Script, "script"),
+            resource("/a/page#top", ResourceKind::Hyperlink, "a"),
+            resource("/a/small.png", ResourceKind::Image, "img"),
+            resource("/a/large.png", ResourceKind::Image, "img"),
+            resource("/a/huge.png", ResourceKind::Image, "img"),
+            resource("/a/div.png", ResourceKind::CssUrl, "div"),
+            resource("/a/clip.webm", ResourceKind::Media, "video"),
+            resource("/a/clip.mp4", ResourceKind::Media, "source"),
+        ]
+    );
+    let metadata = page.metadata();
+    let lines: Vec<&str> = metadata
+        .lines()
+        .filter(|l| l.starts_with("resource: "))
+        .collect();
+    assert_eq!(lines.len(), 12);
+    assert_eq!(
+        lines[0],
+        "resource: link https://example.com/a/style.css <link>"
+    );
+    assert_eq!(
+        lines[11],
+        "resource: media https://example.com/a/clip.mp4 <source>"
+    );
+}
+
+/// Make a new directory for a test to write files in. Each run gets its own directory. It has a
+/// space in its name, so the URL of every file in it has a percent-escape which has to be
+/// decoded to find the file
+#[cfg(test)]
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir =
+        std::env::temp_dir().join(format!("browsah {}-{}-{}", name, std::process::id(), nanos));
+    std::fs::create_dir_all(dir.join("css")).unwrap();
+    dir
+}
+
+#[cfg(test)]
+#[test]
+fn test_imports() {
+    let dir = test_dir("test-imports");
+    let main = r#"@import "a.css"; @import url(print.css) print; @import "missing.css";
+        @import "main.css" screen; main { color: red }"#;
+    let a = r#"@import "b.css"; a { color: red }"#;
+    let b = r#"@import "a.css"; b { color: red }"#;
+    let c = "c { color: red }";
+    let style = r#"@import "css/c.css" (min-width: 600px); style { color: red }"#;
+    for (name, text) in [
+        ("main", main),
+        ("a", a),
+        ("b", b),
+        ("c", c),
+        ("print", "print { color: red }"),
+    ] {
+        std::fs::write(dir.join(format!("css/{}.css", name)), text).unwrap();
     }
 
-    fn get_linked_text_resource(&self, url: &str) -> Result<String, ureq::Error> {
-        let url = self.resolve_url(url).expect("Could not resolve URL");
-        Page::get_text_resource(url.as_str())
+    let (_, document) = html::document(&format!(
+        r#"<link rel=stylesheet href="css/main.css"><style>{}</style>"#,
+        style
+    ))
+    .unwrap();
+    let url = Url::from_file_path(dir.join("index.html")).unwrap();
+    let page = Page::from_document(document, url, Device::screen(1600.0, 1080.0));
+    // Imports come before the sheet importing them, and cycles, missing sheets and imports
+    // for other media are skipped
+    let texts: Vec<_> = page
+        .get_stylesheet_text()
+        .into_iter()
+        .map(|(text, _)| text)
+        .collect();
+    assert_eq!(texts, [b, a, main, c, style]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_stylesheet_resources() {
+    let dir = test_dir("test-stylesheet-resources");
+    std::fs::write(
+        dir.join("css/main.css"),
+        r#"@import "a.css"; body { background: url(../bg.png) }"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("css/a.css"), "a { background: url(a.png) }").unwrap();
+    let (_, document) = html::document(
+        r#"<link rel=stylesheet href="css/main.css"><style>@import "css/a.css";</style>"#,
+    )
+    .unwrap();
+    let url = Url::from_file_path(dir.join("index.html")).unwrap();
+    let page = Page::from_document(document, url.clone(), Device::screen(1600.0, 1080.0));
+    let resources: Vec<(String, ResourceKind, &str)> = page
+        .resources()
+        .into_iter()
+        .map(|r| {
+            let element = page.document.element(r.element).unwrap();
+            let path = r.url.as_str().strip_prefix(url.join(".").unwrap().as_str());
+            (path.unwrap().to_string(), r.kind, element.name.as_str())
+        })
+        .collect();
+    std::fs::remove_dir_all(dir).unwrap();
+    // The references in linked and imported stylesheets are resolved against their own URLs,
+    // and come after the element which brought them in
+    let resource = |url: &str, kind, element| (url.to_string(), kind, element);
+    assert_eq!(
+        resources,
+        [
+            resource("css/main.css", ResourceKind::Link, "link"),
+            resource("css/a.png", ResourceKind::CssUrl, "link"),
+            resource("css/a.css", ResourceKind::Import, "link"),
+            resource("bg.png", ResourceKind::CssUrl, "link"),
+            resource("css/a.css", ResourceKind::Import, "style"),
+            resource("css/a.png", ResourceKind::CssUrl, "style"),
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_layers() {
+    use crate::style::StyledContent;
+    use css::{Unit, Value};
+
+    let dir = test_dir("test-import-layers");
+    for (name, text) in [
+        (
+            "a",
+            r#"@import "c.css" layer(inner); #x { color: red; margin: 2px } p { border: 2px }"#,
+        ),
+        ("b", "#x { padding: 1px }"),
+        ("c", "#x { border: 1px }"),
+    ] {
+        std::fs::write(dir.join(format!("css/{}.css", name)), text).unwrap();
+    }
+    let style = r#"@import "css/a.css" layer(base); @import url(css/b.css) layer;
+        p { color: green; padding: 2px } @layer base { p { margin: 1px } }"#;
+    let (_, document) = html::document(&format!("<style>{}</style><p id=x>", style)).unwrap();
+    let url = Url::from_file_path(dir.join("index.html")).unwrap();
+    let page = Page::from_document(document, url, Device::screen(1600.0, 1080.0));
+    let stylesheets = page.get_stylesheet_text();
+    let styles = page.get_styles(&stylesheets);
+    for (sheet, (_, layer)) in styles.iter().zip(&stylesheets) {
+        page.apply_styles(sheet, Origin::Author, layer.as_deref());
     }
+    std::fs::remove_dir_all(dir).unwrap();
+
+    let root = page.style_tree.borrow();
+    let p = match &root.contents[..] {
+        [.., StyledContent::Element(body)] => match &body.contents[0] {
+            StyledContent::Element(p) => p,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    let px = |n| Some(Value::Length(n, Unit::Px));
+    // Rules outside of layers beat those imported into a named or anonymous layer, whatever
+    // their specificity
+    assert_eq!(
+        p.styles.get("color").cloned(),
+        Some(Value::Keyword("green"))
+    );
+    assert_eq!(p.styles.get("padding").cloned(), px(2.0));
+    // The rules of a layer beat those imported into a layer inside it
+    assert_eq!(p.styles.get("border").cloned(), px(2.0));
+    // An imported layer is the same as a layer of that name in the importing stylesheet
+    assert_eq!(p.styles.get("margin").cloned(), px(2.0));
 }